@@ -0,0 +1,213 @@
+//! Benchmarks of the IO hot path - var ints, packet framing, encryption, and the bridge forwarding
+//! packets between loopback sockets.
+//!
+//! Run with `cargo bench`, or `cargo bench -- <filter>` for a single group.
+
+use std::{hint::black_box, io::Cursor, sync::Arc};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use magma::{
+    bridge::{self, BridgeOptions, ProtocolState, Session},
+    config::{self, Config, Proxy},
+    cryptor::CipherStream,
+    io::{Packet, ProtocolAsyncReadExt, ProtocolReadExt, ProtocolWriteExt, UncompressedPacket},
+    protocol::version::ProtocolVersion,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+
+/// Var ints of every encoded length, from one byte to five.
+const VAR_INTS: &[i32] = &[0, 300, 25565, 2_097_152, -1];
+
+/// The sizes of packet data benchmarked, from a movement packet to a chunk.
+const PACKET_SIZES: &[usize] = &[32, 1024, 64 * 1024];
+
+/// The compression threshold vanilla servers default to.
+const COMPRESSION_THRESHOLD: i32 = 256;
+
+/// How many bytes are encrypted or forwarded each iteration.
+const CHUNK: usize = 256 * 1024;
+
+/// A serverbound play packet ID no version assigns, so the bridge forwards it untouched.
+const UNKNOWN_PACKET: i32 = 0x7F;
+
+/// A packet of the given size, filled with data which compresses like real packets do.
+fn packet(size: usize) -> UncompressedPacket {
+    UncompressedPacket {
+        id: UNKNOWN_PACKET,
+        data: (0..size).map(|i| (i % 31) as u8).collect::<Vec<_>>().into(),
+    }
+}
+
+fn var_int(c: &mut Criterion) {
+    let mut group = c.benchmark_group("var_int");
+    for &value in VAR_INTS {
+        group.bench_with_input(BenchmarkId::new("write", value), &value, |b, &value| {
+            let mut buf = Vec::with_capacity(5);
+            b.iter(|| {
+                buf.clear();
+                buf.write_var_int(black_box(value)).unwrap();
+            })
+        });
+        let mut encoded = vec![];
+        encoded.write_var_int(value).unwrap();
+        group.bench_with_input(BenchmarkId::new("read", value), &encoded, |b, encoded| {
+            b.iter(|| {
+                let mut encoded = Cursor::new(black_box(encoded));
+                ProtocolReadExt::read_var_int(&mut encoded).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn framing(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("framing");
+    for &size in PACKET_SIZES {
+        let packet = packet(size);
+        let uncompressed = packet.clone().into_raw();
+        let compressed = packet.compress(COMPRESSION_THRESHOLD).unwrap().into_raw();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &packet, |b, packet| {
+            b.iter(|| packet.clone().into_raw())
+        });
+        group.bench_with_input(BenchmarkId::new("compress", size), &packet, |b, packet| {
+            b.iter(|| packet.compress(COMPRESSION_THRESHOLD).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("read_frame", size),
+            &uncompressed,
+            |b, raw| {
+                b.iter(|| {
+                    let mut raw = Cursor::new(&raw[..]);
+                    runtime
+                        .block_on(ProtocolAsyncReadExt::read_frame(&mut raw))
+                        .unwrap()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decompress", size),
+            &compressed,
+            |b, raw| {
+                b.iter(|| {
+                    let mut raw = Cursor::new(&raw[..]);
+                    let frame = ProtocolReadExt::read_frame(&mut raw).unwrap();
+                    Packet::from_frame(frame, true)
+                        .unwrap()
+                        .decompress()
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn cipher(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let secret = [7; 16];
+    let plaintext = vec![0x42; CHUNK];
+    let mut group = c.benchmark_group("cipher");
+    group.throughput(Throughput::Bytes(CHUNK as u64));
+
+    group.bench_function("encrypt", |b| {
+        let mut stream = CipherStream::new(tokio::io::sink());
+        stream.enable(&secret);
+        b.iter(|| {
+            runtime.block_on(async {
+                stream.write_all(&plaintext).await.unwrap();
+                stream.flush().await.unwrap();
+            })
+        })
+    });
+    group.bench_function("decrypt", |b| {
+        let mut buf = vec![0; CHUNK];
+        b.iter(|| {
+            let mut stream = CipherStream::new(&plaintext[..]);
+            stream.enable(&secret);
+            runtime.block_on(stream.read_exact(&mut buf)).unwrap();
+        })
+    });
+    group.finish();
+}
+
+/// Bridge a client and a server over loopback sockets, both already in the play state, returning
+/// the client's and the server's ends.
+async fn bridged(proxy: Proxy) -> (TcpStream, TcpStream) {
+    let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let back = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(front.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (client_stream, _) = front.accept().await.unwrap();
+    let server_stream = TcpStream::connect(back.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = back.accept().await.unwrap();
+    for stream in [&client, &client_stream, &server, &server_stream] {
+        stream.set_nodelay(true).unwrap();
+    }
+
+    tokio::spawn(async move {
+        bridge::create(
+            ProtocolState::Play,
+            ProtocolVersion::DEFAULT,
+            BridgeOptions::from(&proxy.routes[0]),
+            Arc::new(Session::default()),
+            client_stream,
+            server_stream,
+        )
+        .await
+    });
+    (client, server)
+}
+
+/// Load a proxy with a single route, which forwards play packets as they are.
+async fn proxy() -> Proxy {
+    let path = std::env::temp_dir().join(format!("magma-bench-{}.toml", std::process::id()));
+    tokio::fs::write(
+        &path,
+        "version = 1\ndebug = false\n\n[[proxies]]\naddress = \"127.0.0.1:0\"\ndomain = \"bench.test\"\ntarget = \"127.0.0.1:25565\"\n",
+    )
+    .await
+    .unwrap();
+    let config = config::from_path(&path).await.unwrap().build().unwrap();
+    tokio::fs::remove_file(&path).await.unwrap();
+    config.proxies.into_iter().next().unwrap()
+}
+
+fn pump(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (mut client, mut server) = runtime.block_on(async { bridged(proxy().await).await });
+    let mut group = c.benchmark_group("pump");
+    for &size in PACKET_SIZES {
+        // forward about the same number of bytes whatever the packet size
+        let raw = packet(size).into_raw();
+        let count = (CHUNK / raw.len()).max(1);
+        let batch: Bytes = raw.repeat(count).into();
+        let mut buf = vec![0; batch.len()];
+        group.throughput(Throughput::Bytes(batch.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("upstream", size), &batch, |b, batch| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let (write, read) =
+                        tokio::join!(client.write_all(batch), server.read_exact(&mut buf));
+                    write.unwrap();
+                    read.unwrap();
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, var_int, framing, cipher, pump);
+criterion_main!(benches);
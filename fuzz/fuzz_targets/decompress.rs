@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use magma::io::ProtocolReadExt;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = ProtocolReadExt::read_compressed_packet(&mut Cursor::new(data)) {
+        let _ = packet.decompress();
+    }
+});
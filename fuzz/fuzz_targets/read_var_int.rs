@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use magma::io::{ProtocolAsyncReadExt, ProtocolReadExt};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ProtocolReadExt::read_var_int(&mut Cursor::new(data));
+    let _ = block_on(ProtocolAsyncReadExt::read_var_int(&mut Cursor::new(data)));
+});
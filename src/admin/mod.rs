@@ -0,0 +1,570 @@
+//! Defines the admin API, an HTTP API for inspecting and operating a running instance.
+//!
+//! The API is served on its own address, separately from the proxies, and should not be exposed
+//! to the public internet. It can require clients to present a certificate signed by an
+//! operator-provided CA, and a bearer token - read-only tokens can view the API, while operator
+//! tokens can also act on it. A small dashboard, built on the same API, is served at `/`.
+
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast::error::RecvError, mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    announce,
+    bridge::Control,
+    config::{AdminConfig, AdminRole, LATEST_CONFIG_VERSION},
+    drain::{self, DrainSnapshot},
+    dump, health,
+    history::{History, SessionQuery, SessionRecord},
+    metrics,
+    protocol::version::ProtocolVersion,
+    registry::{
+        self, BackendSnapshot, ListenerSnapshot, ListenerState, RouteSnapshot, SessionSnapshot,
+        TenantSnapshot,
+    },
+    reload::{ReloadResult, Reloader},
+    secrets,
+    status::{self, StatusCacheSnapshot},
+    tail::{self, LogLine, TailFilter},
+    tls::Acceptor,
+    transfer,
+    tunnel::constant_time_eq,
+};
+
+/// The reason shown to players disconnected without one.
+const DEFAULT_DISCONNECT_REASON: &str = "You were disconnected by an operator";
+
+/// The state shared by admin API handlers.
+#[derive(Clone, Default)]
+pub struct AdminState {
+    /// The connection history store, if enabled.
+    pub history: Option<Arc<History>>,
+    /// The configuration reloader.
+    pub reloader: Option<Arc<Reloader>>,
+}
+
+/// An error returned by an admin API handler.
+struct ApiError(StatusCode, anyhow::Error);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, format!("{:#}", self.1)).into_response()
+    }
+}
+
+/// Spawns the admin API, and returns a handle to its task.
+pub fn spawn(config: AdminConfig, state: AdminState) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        if config.tokens.is_empty() {
+            warn!(
+                "The admin API has no tokens - anyone who can reach it can operate this instance"
+            );
+        }
+        let app = router(state);
+        match config.tls {
+            None => {
+                info!("Serving admin API on http://{}", config.listen_addr);
+                axum::Server::try_bind(&config.listen_addr)
+                    .context("failed to bind admin API")?
+                    .serve(app.into_make_service())
+                    .await?;
+            }
+            Some(tls) => {
+                let acceptor = Acceptor::new(tls)?;
+                let listener = TcpListener::bind(config.listen_addr)
+                    .await
+                    .context("failed to bind admin API")?;
+                info!("Serving admin API on https://{}", config.listen_addr);
+
+                // complete handshakes off the accept loop, so a slow client can't stall others
+                let (tx, rx) = mpsc::channel(16);
+                tokio::task::spawn(async move {
+                    loop {
+                        let Ok((stream, peer)) = listener.accept().await else {
+                            continue;
+                        };
+                        let acceptor = acceptor.acceptor();
+                        let tx = tx.clone();
+                        tokio::task::spawn(async move {
+                            match acceptor.accept(stream).await {
+                                Ok(stream) => {
+                                    let _ = tx.send(Ok::<_, std::io::Error>(stream)).await;
+                                }
+                                Err(err) => {
+                                    debug!("Admin API TLS handshake with {} failed: {}", peer, err)
+                                }
+                            }
+                        });
+                    }
+                });
+                let incoming = stream::unfold(rx, |mut rx| async move {
+                    rx.recv().await.map(|stream| (stream, rx))
+                });
+                axum::Server::builder(hyper::server::accept::from_stream(incoming))
+                    .serve(app.into_make_service())
+                    .await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Build the admin API router.
+fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/overview", get(overview))
+        .route("/live", get(live))
+        .route("/live/:id/kick", post(kick))
+        .route("/sessions", get(sessions))
+        .route("/sessions/:id/disconnect", post(disconnect))
+        .route("/sessions/:id/transfer", post(transfer_session))
+        .route(
+            "/backends/:target/drain",
+            get(drain_status)
+                .post(drain_backend)
+                .delete(undrain_backend),
+        )
+        .route("/announce", post(announce_message))
+        .route("/reload", get(last_reload).post(reload))
+        .route("/dump", get(dump_state))
+        .route("/metrics", get(prometheus))
+        .route("/version", get(version))
+        .route("/logs", get(logs))
+        .route_layer(middleware::from_fn(authorize))
+        // the dashboard holds no data itself, and asks for a token when the API needs one
+        .route("/", get(dashboard))
+        // probes from orchestrators and load balancers don't carry tokens
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(state)
+}
+
+/// Require a bearer token with a role allowing the request - reading requires any token, and
+/// anything else requires an operator token. The tokens are those of the latest configuration.
+async fn authorize<B>(request: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    let secrets = secrets::current();
+    let tokens = &secrets.admin_tokens;
+    if tokens.is_empty() {
+        return Ok(next.run(request).await);
+    }
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // compare against every token, so timing doesn't reveal which one matched
+    let role = tokens.iter().fold(None, |role, token| {
+        match constant_time_eq(presented.as_bytes(), token.token.as_bytes()) {
+            true => Some(token.role),
+            false => role,
+        }
+    });
+    let required = match *request.method() {
+        Method::GET | Method::HEAD => AdminRole::ReadOnly,
+        _ => AdminRole::Operator,
+    };
+    match role {
+        None => Err(ApiError(
+            StatusCode::UNAUTHORIZED,
+            anyhow::anyhow!("A valid bearer token is required"),
+        )),
+        Some(role) if role < required => Err(ApiError(
+            StatusCode::FORBIDDEN,
+            anyhow::anyhow!("This action requires an operator token"),
+        )),
+        Some(_) => Ok(next.run(request).await),
+    }
+}
+
+/// Serve the dashboard.
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+/// What is deployed - the build, and what it supports.
+#[derive(Serialize)]
+struct VersionInfo {
+    /// The version of the crate.
+    version: &'static str,
+    /// The git commit the binary was built from.
+    git_sha: &'static str,
+    /// The protocol versions whose packets Magma understands.
+    protocol_versions: Vec<i32>,
+    /// The protocol version broadcast by default.
+    default_protocol_version: i32,
+    /// The optional features compiled in.
+    features: Vec<&'static str>,
+    /// The configuration version this build reads.
+    config_version: u8,
+}
+
+/// Report the build and what it supports, so deployments can be verified.
+async fn version() -> Json<VersionInfo> {
+    let features = [
+        ("nats", cfg!(feature = "nats")),
+        ("kafka", cfg!(feature = "kafka")),
+        ("mock", cfg!(feature = "mock")),
+    ];
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("VERGEN_GIT_SHA"),
+        protocol_versions: (ProtocolVersion::OLDEST.0..=ProtocolVersion::LATEST.0).collect(),
+        default_protocol_version: ProtocolVersion::DEFAULT.0,
+        features: features
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| feature)
+            .collect(),
+        config_version: LATEST_CONFIG_VERSION,
+    })
+}
+
+/// Report that the process is alive.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Report whether the instance is ready for traffic - its configuration is loaded, every
+/// listener is bound, and every route has a healthy backend. Lists what isn't ready otherwise.
+async fn readyz(State(state): State<AdminState>) -> (StatusCode, String) {
+    let Some(reloader) = state.reloader else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "configuration not loaded".to_string(),
+        );
+    };
+    let listeners: HashMap<_, _> = registry::listeners()
+        .into_iter()
+        .map(|listener| (listener.addr, listener.state))
+        .collect();
+    let mut problems = vec![];
+    for proxy in reloader.proxies().await {
+        match listeners.get(&proxy.listen_addr) {
+            Some(ListenerState::Running) => {}
+            Some(state) => problems.push(format!("listener {} is {:?}", proxy.listen_addr, state)),
+            None => problems.push(format!("listener {} is not bound", proxy.listen_addr)),
+        }
+        // routes without static backends find them per connection, so can't be checked
+        for route in proxy.routes.iter().filter(|route| !route.to.is_empty()) {
+            if !route.to.iter().any(|&target| health::is_healthy(target)) {
+                problems.push(format!("route {} has no healthy backends", route.from));
+            }
+        }
+    }
+    match problems.is_empty() {
+        true => (StatusCode::OK, "ok".to_string()),
+        false => (StatusCode::SERVICE_UNAVAILABLE, problems.join("\n")),
+    }
+}
+
+/// The traffic of every route and tenant, the health of every backend and listener, and the age
+/// of every cached status response.
+#[derive(Serialize)]
+struct Overview {
+    routes: Vec<RouteSnapshot>,
+    tenants: Vec<TenantSnapshot>,
+    backends: Vec<BackendSnapshot>,
+    listeners: Vec<ListenerSnapshot>,
+    status_cache: Vec<StatusCacheSnapshot>,
+    drains: Vec<DrainSnapshot>,
+}
+
+/// Summarise routes, tenants, backends, listeners, cached status responses and drains.
+async fn overview() -> Json<Overview> {
+    Json(Overview {
+        routes: registry::routes(),
+        tenants: registry::tenants(),
+        backends: registry::backends(),
+        listeners: registry::listeners(),
+        status_cache: status::snapshot(),
+        drains: drain::snapshot(),
+    })
+}
+
+/// List live sessions, oldest first.
+async fn live() -> Json<Vec<SessionSnapshot>> {
+    Json(registry::sessions())
+}
+
+/// The body of a disconnect request.
+#[derive(Deserialize)]
+struct DisconnectRequest {
+    /// The chat component shown to the player.
+    reason: Option<Value>,
+}
+
+/// Kick a live session, showing the player the default reason.
+async fn kick(Path(id): Path<u64>) -> Result<StatusCode, ApiError> {
+    disconnect(Path(id), None).await
+}
+
+/// Disconnect a live session, showing the player the given reason, if any.
+async fn disconnect(
+    Path(id): Path<u64>,
+    request: Option<Json<DisconnectRequest>>,
+) -> Result<StatusCode, ApiError> {
+    let reason = request
+        .and_then(|Json(request)| request.reason)
+        .unwrap_or_else(|| json!({ "text": DEFAULT_DISCONNECT_REASON }));
+    match registry::control(id, Control::Disconnect(reason)) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("No live session with id {}", id),
+        )),
+    }
+}
+
+/// The body of a transfer request.
+#[derive(Deserialize)]
+struct TransferRequest {
+    /// The route to move the player to.
+    route: String,
+    /// The backend of the route to move the player to, rather than one picked as usual.
+    backend: Option<SocketAddr>,
+    /// The host the client reconnects to, if not the route's domain.
+    host: Option<String>,
+    /// The port the client reconnects to, if not that of the route's listener.
+    port: Option<u16>,
+}
+
+/// Move a live session to another route, and optionally a particular backend of it, by asking
+/// the client to reconnect.
+async fn transfer_session(
+    State(state): State<AdminState>,
+    Path(id): Path<u64>,
+    Json(request): Json<TransferRequest>,
+) -> Result<StatusCode, ApiError> {
+    let not_found = |message: String| ApiError(StatusCode::NOT_FOUND, anyhow::anyhow!(message));
+    let reloader = state
+        .reloader
+        .ok_or_else(|| not_found("No proxies are being served".to_string()))?;
+    let session = registry::sessions()
+        .into_iter()
+        .find(|session| session.id == id)
+        .ok_or_else(|| not_found(format!("No live session with id {}", id)))?;
+    let proxies = reloader.proxies().await;
+    let (proxy, route) = proxies
+        .iter()
+        .find_map(|proxy| {
+            let route = proxy
+                .routes
+                .iter()
+                .find(|route| route.from == request.route)?;
+            Some((proxy, route))
+        })
+        .ok_or_else(|| not_found(format!("No route {}", request.route)))?;
+    if let Some(backend) = request
+        .backend
+        .filter(|backend| !route.to.contains(backend))
+    {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("{} is not a backend of {}", backend, route.from),
+        ));
+    }
+
+    let (reply, replied) = oneshot::channel();
+    let control = Control::Transfer {
+        host: request.host.unwrap_or_else(|| route.from.clone()),
+        port: request.port.unwrap_or(proxy.listen_addr.port()),
+        reply,
+    };
+    if !registry::control(id, control) {
+        return Err(not_found(format!("No live session with id {}", id)));
+    }
+    // the reply is dropped if the session closes before its bridge handles the transfer
+    let result = replied
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("the session closed")));
+    match result {
+        Ok(()) => {
+            if let Some(backend) = request.backend {
+                transfer::pin(session.peer.ip(), &route.from, backend);
+            }
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(err) => Err(ApiError(
+            StatusCode::CONFLICT,
+            err.context("The session can't be transferred"),
+        )),
+    }
+}
+
+/// The body of a drain request.
+#[derive(Deserialize)]
+struct DrainRequest {
+    /// The minutes over which the backend's players are transferred to the route's other
+    /// backends. Players are left to leave on their own if unset.
+    transfer_over_mins: Option<u64>,
+}
+
+/// Start draining a backend, taking it out of selection.
+async fn drain_backend(
+    State(state): State<AdminState>,
+    Path(target): Path<SocketAddr>,
+    request: Option<Json<DrainRequest>>,
+) -> Result<(StatusCode, Json<DrainSnapshot>), ApiError> {
+    let transfer_over = request
+        .and_then(|Json(request)| request.transfer_over_mins)
+        .map(|mins| Duration::from_secs(mins * 60));
+    if transfer_over.is_some() && state.reloader.is_none() {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("No proxies are being served to transfer players to"),
+        ));
+    }
+    let status = match drain::start(target, transfer_over, state.reloader) {
+        true => StatusCode::ACCEPTED,
+        false => StatusCode::OK,
+    };
+    Ok((status, drain_status(Path(target)).await?))
+}
+
+/// Report the progress of draining a backend.
+async fn drain_status(Path(target): Path<SocketAddr>) -> Result<Json<DrainSnapshot>, ApiError> {
+    drain::snapshot()
+        .into_iter()
+        .find(|drain| drain.target == target)
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                anyhow::anyhow!("{} is not being drained", target),
+            )
+        })
+}
+
+/// Stop draining a backend, returning it to selection.
+async fn undrain_backend(Path(target): Path<SocketAddr>) -> Result<StatusCode, ApiError> {
+    match drain::stop(target) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("{} is not being drained", target),
+        )),
+    }
+}
+
+/// The body of an announce request.
+#[derive(Deserialize)]
+struct AnnounceRequest {
+    /// The chat component shown, or plain text.
+    message: Value,
+    /// The routes whose players are shown the message - every route if empty.
+    #[serde(default)]
+    routes: Vec<String>,
+    /// Whether the message is shown above the hotbar.
+    #[serde(default)]
+    action_bar: bool,
+}
+
+/// Show a message to the players on the given routes.
+async fn announce_message(Json(request): Json<AnnounceRequest>) -> Json<Value> {
+    let sessions = announce::announce(&request.message, &request.routes, request.action_bar);
+    info!("Sent an announcement to {} sessions", sessions);
+    Json(json!({ "sessions": sessions }))
+}
+
+/// The result of the most recent configuration reload, if any.
+async fn last_reload(State(state): State<AdminState>) -> Json<Option<ReloadResult>> {
+    Json(state.reloader.and_then(|reloader| reloader.last()))
+}
+
+/// Reload the configuration.
+async fn reload(State(state): State<AdminState>) -> Result<Json<ReloadResult>, ApiError> {
+    let reloader = state.reloader.ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Configuration reloading is not enabled"),
+        )
+    })?;
+    Ok(Json(reloader.reload().await))
+}
+
+/// Render a state dump as plain text, and write it to the log.
+async fn dump_state(State(state): State<AdminState>) -> Result<String, ApiError> {
+    let reloader = state.reloader.ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("No proxies are being served"),
+        )
+    })?;
+    let dump = dump::render(&reloader).await;
+    info!("Dumping state\n{}", dump);
+    Ok(dump)
+}
+
+/// Render the metrics in the Prometheus text exposition format.
+async fn prometheus() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+/// Stream the recent and live log lines matching the filter as server-sent events.
+async fn logs(
+    Query(filter): Query<TailFilter>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (recent, receiver) = tail::follow();
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(line) => return Some((line, receiver)),
+                // slow followers miss lines rather than holding the log up
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let lines = stream::iter(recent)
+        .chain(live)
+        .filter(move |line| std::future::ready(filter.matches(line)))
+        .map(|line: LogLine| {
+            // log lines always serialize
+            Ok(Event::default().json_data(line).unwrap_or_default())
+        });
+    Sse::new(lines).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+/// List completed sessions from the connection history, most recent first.
+async fn sessions(
+    State(state): State<AdminState>,
+    Query(query): Query<SessionQuery>,
+) -> Result<Json<Vec<SessionRecord>>, ApiError> {
+    let history = state.history.ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Connection history is not enabled"),
+        )
+    })?;
+    Ok(Json(history.query(query).await?))
+}
@@ -0,0 +1,135 @@
+//! Defines an integration with the Agones allocator service, for Kubernetes game server fleets.
+//!
+//! Routes backed by a fleet have no fixed targets. Instead, Magma requests an allocation from the
+//! fleet for each connection, routes the connection to the allocated game server, and optionally
+//! deletes the game server through the Kubernetes API once the connection closes - so each session
+//! gets a fresh server.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::config::AgonesConfig;
+
+/// How long an allocation or release request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A game server allocated to a connection.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    /// The name of the game server.
+    pub name: String,
+    /// The address of the game server.
+    pub address: SocketAddr,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AllocationResponse {
+    game_server_name: String,
+    address: IpAddr,
+    ports: Vec<Port>,
+}
+
+#[derive(Deserialize)]
+struct Port {
+    name: String,
+    port: u16,
+}
+
+/// The HTTP client of each allocator, which are built once as they may load certificates.
+fn client(config: &AgonesConfig) -> Result<reqwest::Client> {
+    static CLIENTS: OnceLock<Mutex<HashMap<String, reqwest::Client>>> = OnceLock::new();
+    let mut clients = CLIENTS.get_or_init(Default::default).lock().unwrap();
+    if let Some(client) = clients.get(&config.allocator) {
+        return Ok(client.clone());
+    }
+    let mut builder = reqwest::Client::builder().timeout(REQUEST_TIMEOUT);
+    if let Some(ca) = &config.ca {
+        let pem = std::fs::read(ca).with_context(|| format!("Failed to read {:?}", ca))?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    if let Some((cert, key)) = &config.identity {
+        let cert = std::fs::read(cert).with_context(|| format!("Failed to read {:?}", cert))?;
+        let key = std::fs::read(key).with_context(|| format!("Failed to read {:?}", key))?;
+        builder = builder.identity(reqwest::Identity::from_pkcs8_pem(&cert, &key)?);
+    }
+    let client = builder.build()?;
+    clients.insert(config.allocator.clone(), client.clone());
+    Ok(client)
+}
+
+/// Allocate a game server from the configured fleet.
+pub async fn allocate(config: &AgonesConfig) -> Result<Allocation> {
+    let response: AllocationResponse = client(config)?
+        .post(format!(
+            "{}/gameserverallocation",
+            config.allocator.trim_end_matches('/')
+        ))
+        .json(&json!({
+            "namespace": config.namespace,
+            "gameServerSelectors": [{
+                "matchLabels": { "agones.dev/fleet": config.fleet },
+            }],
+        }))
+        .send()
+        .await?
+        .error_for_status()
+        .context("the fleet has no ready game servers")?
+        .json()
+        .await?;
+    let port = response
+        .ports
+        .iter()
+        .find(|port| port.name == config.port_name)
+        .or_else(|| response.ports.first())
+        .with_context(|| format!("Game server {} has no ports", response.game_server_name))?;
+    debug!(
+        "Allocated game server {} from fleet {}",
+        response.game_server_name, config.fleet
+    );
+    Ok(Allocation {
+        address: SocketAddr::new(response.address, port.port),
+        name: response.game_server_name,
+    })
+}
+
+/// Release a game server once its connection has closed, deleting it if configured. Failures are
+/// logged, as the connection is already gone.
+pub async fn release(config: &AgonesConfig, allocation: Allocation) {
+    let Some(api) = &config.release_api else {
+        return;
+    };
+    let result = async {
+        let mut request = client(config)?.delete(format!(
+            "{}/apis/agones.dev/v1/namespaces/{}/gameservers/{}",
+            api.trim_end_matches('/'),
+            config.namespace,
+            allocation.name
+        ));
+        if let Some(token) = &config.release_token {
+            let token = tokio::fs::read_to_string(token)
+                .await
+                .with_context(|| format!("Failed to read {:?}", token))?;
+            request = request.bearer_auth(token.trim());
+        }
+        request.send().await?.error_for_status()?;
+        anyhow::Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => debug!("Released game server {}", allocation.name),
+        Err(err) => warn!(
+            "Failed to release game server {}: {:#}",
+            allocation.name, err
+        ),
+    }
+}
@@ -0,0 +1,70 @@
+//! Defines announcements, which show players a message from the proxy itself.
+//!
+//! Announcements are injected into the connections of players on the chosen routes as system
+//! chat packets, either in chat or above the hotbar - such as a countdown before maintenance.
+//! They can be sent on demand over the admin API, or scheduled in the configuration to repeat on
+//! an interval or at fixed times of day.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{Days, TimeZone, Utc};
+use futures::future::join_all;
+use serde_json::Value;
+use tokio::{task::JoinHandle, time::sleep};
+use tracing::info;
+
+use crate::{
+    bridge::Control,
+    config::{Announcement, AnnouncementTiming},
+    registry,
+};
+
+/// Show a message to the players on the given routes, or on every route if none are given.
+/// Returns how many sessions were sent the message.
+pub fn announce(message: &Value, routes: &[String], action_bar: bool) -> usize {
+    registry::control_routes(routes, || Control::Announce {
+        message: message.clone(),
+        action_bar,
+    })
+}
+
+/// How long until an announcement is next due.
+fn until_next(timing: &AnnouncementTiming) -> Duration {
+    match timing {
+        AnnouncementTiming::Every(interval) => *interval,
+        AnnouncementTiming::At { timezone, times } => {
+            let now = Utc::now();
+            let today = now.with_timezone(timezone).date_naive();
+            let next = [today, today + Days::new(1)]
+                .into_iter()
+                .flat_map(|date| times.iter().map(move |time| date.and_time(*time)))
+                .filter_map(|local| timezone.from_local_datetime(&local).earliest())
+                .map(|time| time.with_timezone(&Utc))
+                .filter(|time| *time > now)
+                .min();
+            next.and_then(|next| (next - now).to_std().ok())
+                // a day in which none of the times exist, such as across a DST change
+                .unwrap_or(Duration::from_secs(24 * 60 * 60))
+        }
+    }
+}
+
+/// Spawns a task sending the scheduled announcements, and returns a handle to the task.
+pub fn spawn(announcements: Vec<Announcement>) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let tasks = announcements.into_iter().map(|announcement| async move {
+            loop {
+                sleep(until_next(&announcement.timing)).await;
+                let count = announce(
+                    &announcement.message,
+                    &announcement.routes,
+                    announcement.action_bar,
+                );
+                info!("Sent a scheduled announcement to {} sessions", count);
+            }
+        });
+        join_all(tasks).await;
+        Ok(())
+    })
+}
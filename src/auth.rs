@@ -0,0 +1,418 @@
+//! Authenticates players with the session server, for routes whose backends are in offline mode.
+//!
+//! Magma takes the place of an online-mode server for the first half of the login: it asks the
+//! client to encrypt the connection, and checks with the session server that the player joined
+//! with the resulting hash. The client's login start is then replayed through the encrypted
+//! connection, so the bridge and the backend see it as if the client had just sent it.
+//!
+//! Session server lookups go through a pipeline shared by every route. Concurrent logins with the
+//! same username and hash share one lookup, only so many lookups are in flight at once, and only
+//! so many wait for one to finish - logins past that are rejected at once, rather than piling up
+//! behind a slow or rate limited session server when a wave of bots logs in.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, Bytes};
+use reqwest::StatusCode;
+use rsa::{pkcs8::EncodePublicKey, Pkcs1v15Encrypt, RsaPrivateKey};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{OnceCell, Semaphore},
+};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{
+    bridge::{CloseReason, Stream},
+    config::{AuthConfig, Route},
+    cryptor::{server_hash, CipherStream},
+    error::ProtocolError,
+    io::{
+        ProcotolAsyncWriteExt, ProtocolAsyncReadExt, ProtocolReadExt, ProtocolWriteExt,
+        UncompressedPacket,
+    },
+    protocol::{
+        packets::{EncryptionRequest, EncryptionResponse, LoginStart, PacketCodec},
+        version::ProtocolVersion,
+    },
+    reply,
+};
+
+/// How long a client has to send each packet of its login.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for the session server before giving up on a lookup.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// The length of the shared secret clients encrypt their connection with.
+const SECRET_LENGTH: usize = 16;
+
+/// Why a player couldn't be authenticated.
+#[derive(Debug, Clone, Error)]
+pub enum AuthError {
+    /// The session server has no record of the player joining with the hash.
+    #[error("the player did not join through the session server")]
+    NotJoined,
+    /// Too many lookups were already waiting.
+    #[error("too many session server lookups are queued")]
+    Overloaded,
+    /// The session server couldn't be reached, or answered with an error.
+    #[error("the session server is unavailable: {0}")]
+    Unavailable(String),
+}
+
+/// The profile of an authenticated player.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// The uuid of the player.
+    pub id: Uuid,
+    /// The username of the player.
+    pub name: String,
+}
+
+/// A profile, as the session server returns it.
+#[derive(Deserialize)]
+struct Joined {
+    id: String,
+    name: String,
+}
+
+/// A lookup shared by the logins waiting for its answer.
+type Lookup = Arc<OnceCell<Result<Profile, AuthError>>>;
+
+/// Authenticates players with the session server.
+pub struct Authenticator {
+    /// The key clients encrypt their shared secret with.
+    key: RsaPrivateKey,
+    /// The DER encoding of the public half of the key.
+    public_key: Vec<u8>,
+    /// The base URL of the session server.
+    session_server: String,
+    /// The HTTP client used to query the session server.
+    client: reqwest::Client,
+    /// Limits the number of lookups in flight at once.
+    permits: Semaphore,
+    /// The number of lookups waiting for a permit.
+    queued: AtomicUsize,
+    /// The maximum number of lookups waiting for a permit.
+    max_queued: usize,
+    /// The lookups in flight, by username and hash.
+    lookups: Mutex<HashMap<(String, String), Lookup>>,
+    /// The message logins are rejected with while the queue is full.
+    overflow_message: String,
+}
+
+impl Authenticator {
+    /// Generate the key clients encrypt their shared secret with, and prepare the session server
+    /// client.
+    pub fn new(config: AuthConfig) -> Result<Arc<Self>> {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024)?;
+        let public_key = key.to_public_key().to_public_key_der()?.as_bytes().to_vec();
+        Ok(Arc::new(Self {
+            key,
+            public_key,
+            session_server: config.session_server.trim_end_matches('/').to_string(),
+            client: reqwest::Client::builder().timeout(LOOKUP_TIMEOUT).build()?,
+            permits: Semaphore::new(config.max_concurrent_lookups),
+            queued: AtomicUsize::new(0),
+            max_queued: config.max_queued_lookups,
+            lookups: Mutex::new(HashMap::new()),
+            overflow_message: config.overflow_message,
+        }))
+    }
+
+    /// Authenticate a client logging in to the given route, encrypting its connection. Returns the
+    /// encrypted connection, with the client's login start replayed ahead of what it sends next.
+    ///
+    /// Clients with a username the route rejects aren't authenticated, so they are turned away by
+    /// the bridge as usual, without a lookup. Clients which fail authentication are disconnected,
+    /// and the error carries the [CloseReason].
+    pub async fn login<C: Stream>(
+        &self,
+        route: &Route,
+        version: ProtocolVersion,
+        client_stream: C,
+    ) -> Result<Replayed<CipherStream<C>>> {
+        let mut client_stream = CipherStream::new(client_stream);
+        let login_start = recv(&mut client_stream).await?;
+        if login_start.id != LoginStart::ID {
+            return Err(anyhow::Error::from(ProtocolError::UnexpectedPacket {
+                expected: LoginStart::ID,
+                actual: login_start.id,
+            })
+            .context(CloseReason::DecodeError));
+        }
+        // the username is the first field of the login start in every version
+        let username = ProtocolReadExt::read_string(&mut login_start.as_cursor())
+            .context(CloseReason::DecodeError)?;
+        let replay = login_start.into_raw();
+        if let Some(usernames) = route.usernames.as_ref() {
+            if !usernames.allows(&username) {
+                return Ok(Replayed::new(replay, client_stream));
+            }
+        }
+
+        let verify_token: [u8; 4] = rand::random();
+        let mut request = EncryptionRequest {
+            server_id: String::new(),
+            public_key: self.public_key.clone(),
+            verify_token: verify_token.to_vec(),
+        }
+        .encode()?;
+        // 1.20.5+ end the packet with whether the client should authenticate
+        if version >= ProtocolVersion::V1_20_5 {
+            let mut data = request.data.to_vec();
+            data.write_bool(true)?;
+            request.data = data.into();
+        }
+        client_stream.write_uncompressed_packet(&request).await?;
+        client_stream.flush().await?;
+
+        let response = recv(&mut client_stream).await?;
+        let secret = self
+            .decrypt_response(&response, version, &verify_token)
+            .context(CloseReason::DecodeError)?;
+        client_stream.enable(&secret);
+
+        let hash = server_hash("", &secret, &self.public_key);
+        let err = match self.has_joined(&username, &hash).await {
+            Ok(profile) => {
+                debug!("Authenticated {} as {}", profile.name, profile.id);
+                return Ok(Replayed::new(replay, client_stream));
+            }
+            Err(err) => err,
+        };
+        let (reason, close_reason) = match &err {
+            AuthError::NotJoined => (
+                json!({ "translate": "multiplayer.disconnect.unverified_username" }),
+                CloseReason::Kicked,
+            ),
+            AuthError::Overloaded => (
+                json!({ "text": self.overflow_message }),
+                CloseReason::RateLimited,
+            ),
+            AuthError::Unavailable(_) => (
+                json!({ "translate": "multiplayer.disconnect.authservers_down" }),
+                CloseReason::Kicked,
+            ),
+        };
+        reply::disconnect_component(&mut client_stream, &reason).await?;
+        Err(anyhow::Error::from(err).context(close_reason))
+    }
+
+    /// Decrypt the shared secret of an encryption response, checking the verify token.
+    fn decrypt_response(
+        &self,
+        response: &UncompressedPacket,
+        version: ProtocolVersion,
+        verify_token: &[u8],
+    ) -> Result<Vec<u8>> {
+        if response.id != EncryptionResponse::ID {
+            bail!(ProtocolError::UnexpectedPacket {
+                expected: EncryptionResponse::ID,
+                actual: response.id,
+            });
+        }
+        let mut buf = response.as_cursor();
+        let secret = buf.read_byte_array()?;
+        // 1.19 - 1.19.2 clients with a chat key sign a salt instead of returning the token. The
+        // token only proves the client has the key, which the join already does - the hash
+        // can't be computed without the secret it encrypted
+        let token = match version < ProtocolVersion::V1_19_3 && !buf.read_bool()? {
+            true => None,
+            false => Some(buf.read_byte_array()?),
+        };
+        if buf.has_remaining() {
+            bail!("encryption response has trailing data");
+        }
+
+        let secret = self
+            .key
+            .decrypt(Pkcs1v15Encrypt, &secret)
+            .context("failed to decrypt the shared secret")?;
+        if secret.len() != SECRET_LENGTH {
+            bail!("shared secret is {} bytes long", secret.len());
+        }
+        if let Some(token) = token {
+            let token = self
+                .key
+                .decrypt(Pkcs1v15Encrypt, &token)
+                .context("failed to decrypt the verify token")?;
+            if token != verify_token {
+                bail!("client sent the wrong verify token");
+            }
+        }
+        Ok(secret)
+    }
+
+    /// Check with the session server that a player has joined with the given hash, returning
+    /// their profile.
+    ///
+    /// Concurrent checks of the same username and hash share one lookup. Lookups wait for a
+    /// permit while the maximum number are in flight, and fail with [AuthError::Overloaded] if
+    /// the maximum number are already waiting.
+    pub async fn has_joined(
+        &self,
+        username: &str,
+        server_hash: &str,
+    ) -> Result<Profile, AuthError> {
+        let key = (username.to_string(), server_hash.to_string());
+        let lookup = self
+            .lookups
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = lookup
+            .get_or_init(|| self.lookup(username, server_hash))
+            .await
+            .clone();
+        // answers aren't cached - the next login with the same hash looks it up again
+        let mut lookups = self.lookups.lock().unwrap();
+        if lookups
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &lookup))
+        {
+            lookups.remove(&key);
+        }
+        result
+    }
+
+    /// Look up whether a player has joined, once a permit is free.
+    async fn lookup(&self, username: &str, server_hash: &str) -> Result<Profile, AuthError> {
+        let _permit = match self.permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _queued =
+                    Queued::join(&self.queued, self.max_queued).ok_or(AuthError::Overloaded)?;
+                self.permits
+                    .acquire()
+                    .await
+                    .expect("the lookup semaphore is never closed")
+            }
+        };
+        let unavailable = |err: reqwest::Error| AuthError::Unavailable(err.to_string());
+        let response = self
+            .client
+            .get(format!(
+                "{}/session/minecraft/hasJoined",
+                self.session_server
+            ))
+            .query(&[("username", username), ("serverId", server_hash)])
+            .send()
+            .await
+            .map_err(unavailable)?;
+        match response.status() {
+            StatusCode::OK => {
+                let joined: Joined = response.json().await.map_err(unavailable)?;
+                let id = Uuid::parse_str(&joined.id)
+                    .map_err(|err| AuthError::Unavailable(err.to_string()))?;
+                Ok(Profile {
+                    id,
+                    name: joined.name,
+                })
+            }
+            StatusCode::NO_CONTENT => Err(AuthError::NotJoined),
+            status => Err(AuthError::Unavailable(format!(
+                "session server answered {}",
+                status
+            ))),
+        }
+    }
+}
+
+/// Read the next packet of a login, failing with the [CloseReason] if the client is gone or slow.
+async fn recv<C: Stream>(client_stream: &mut CipherStream<C>) -> Result<UncompressedPacket> {
+    client_stream
+        .read_uncompressed_packet_timeout(LOGIN_TIMEOUT)
+        .await
+        .map_err(|err| {
+            let reason = match err.downcast_ref::<ProtocolError>() {
+                Some(err) if err.is_timeout() => CloseReason::Timeout,
+                Some(_) => CloseReason::DecodeError,
+                None => CloseReason::ClientEof,
+            };
+            err.context(reason)
+        })
+}
+
+/// A place in the queue for a lookup permit, given up when dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Queued<'a> {
+    /// Join the queue, unless the maximum number are already waiting.
+    fn join(queued: &'a AtomicUsize, max: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(queued))
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A stream which yields bytes already read from it once more, before reading on.
+#[derive(Debug)]
+pub struct Replayed<S> {
+    replay: Bytes,
+    inner: S,
+}
+
+impl<S> Replayed<S> {
+    /// Wrap a stream, replaying the given bytes first.
+    pub fn new(replay: Bytes, inner: S) -> Self {
+        Self { replay, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replayed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.replay.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let length = self.replay.len().min(buf.remaining());
+        buf.put_slice(&self.replay.split_to(length));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replayed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
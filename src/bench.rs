@@ -0,0 +1,185 @@
+//! Defines the `magma bench` load-testing tool.
+//!
+//! The benchmark opens a number of concurrent synthetic clients against a target, each performing
+//! either a status ping or an offline-mode login, and reports the achieved connection rate,
+//! latency percentiles, and any errors encountered. This is useful for validating the limits of
+//! the proxy itself, independent of any real players.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, ValueEnum};
+use tokio::time::timeout;
+use tracing::info;
+
+use crate::{client::ClientBuilder, protocol::version::ProtocolVersion};
+
+/// Arguments for the `bench` subcommand.
+#[derive(Args)]
+pub struct BenchArgs {
+    /// The address of the proxy or server to benchmark.
+    #[clap(long)]
+    target: SocketAddr,
+    /// The server address sent in the handshake, used by the proxy to pick a route.
+    #[clap(long, default_value = "localhost")]
+    domain: String,
+    /// The flow each synthetic client performs.
+    #[clap(long, value_enum, default_value = "status")]
+    mode: BenchMode,
+    /// The number of concurrent clients.
+    #[clap(long, default_value_t = 16)]
+    concurrency: usize,
+    /// The total number of connections to make.
+    #[clap(long, default_value_t = 1000)]
+    connections: usize,
+    /// The protocol version sent in the handshake.
+    #[clap(long, default_value_t = ProtocolVersion::DEFAULT.0)]
+    protocol_version: i32,
+    /// The per-connection timeout, in milliseconds.
+    #[clap(long, default_value_t = 5000)]
+    timeout: u64,
+}
+
+/// The flow performed by each synthetic client.
+#[derive(Clone, Copy, ValueEnum)]
+enum BenchMode {
+    /// Handshake, then request the server status and ping.
+    Status,
+    /// Handshake, then perform an offline-mode login.
+    Login,
+}
+
+/// The results collected by a single worker.
+#[derive(Default)]
+struct WorkerResults {
+    /// The latency of each successful connection.
+    latencies: Vec<Duration>,
+    /// The number of failures, keyed by error message.
+    errors: HashMap<String, usize>,
+}
+
+/// Run the benchmark, printing a report once all connections have completed.
+pub async fn run(args: BenchArgs) -> Result<()> {
+    if args.concurrency == 0 {
+        bail!("Concurrency must be at least 1");
+    }
+
+    info!(
+        "Benchmarking {} with {} connection(s), {} at a time...",
+        args.target, args.connections, args.concurrency
+    );
+
+    let args = Arc::new(args);
+    let remaining = Arc::new(AtomicUsize::new(args.connections));
+    let start = Instant::now();
+
+    // spawn workers, each pulling connections from the shared counter until none remain
+    let mut handles = vec![];
+    for worker in 0..args.concurrency {
+        let args = args.clone();
+        let remaining = remaining.clone();
+        handles.push(tokio::task::spawn(async move {
+            let mut results = WorkerResults::default();
+            let mut sequence = 0;
+            while remaining
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                let username = format!("bench_{}_{}", worker, sequence);
+                sequence += 1;
+
+                let begin = Instant::now();
+                match timeout(
+                    Duration::from_millis(args.timeout),
+                    run_once(&args, &username),
+                )
+                .await
+                {
+                    Ok(Ok(())) => results.latencies.push(begin.elapsed()),
+                    Ok(Err(err)) => *results.errors.entry(format!("{:#}", err)).or_default() += 1,
+                    Err(_) => *results.errors.entry("timed out".to_string()).or_default() += 1,
+                }
+            }
+            results
+        }));
+    }
+
+    // merge worker results
+    let mut latencies = vec![];
+    let mut errors: HashMap<String, usize> = HashMap::new();
+    for handle in handles {
+        let results = handle.await.context("benchmark worker panicked")?;
+        latencies.extend(results.latencies);
+        for (err, count) in results.errors {
+            *errors.entry(err).or_default() += count;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    report(&latencies, &errors, elapsed);
+    Ok(())
+}
+
+/// Perform a single synthetic connection.
+async fn run_once(args: &BenchArgs, username: &str) -> Result<()> {
+    let builder = ClientBuilder::offline(username)
+        .version(ProtocolVersion(args.protocol_version))
+        .domain(&args.domain);
+    match args.mode {
+        BenchMode::Status => {
+            builder.status(args.target).await?;
+        }
+        BenchMode::Login => {
+            builder.login(args.target).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the benchmark report.
+fn report(latencies: &[Duration], errors: &HashMap<String, usize>, elapsed: Duration) {
+    let mut latencies = latencies.to_vec();
+    latencies.sort();
+
+    let total = latencies.len() + errors.values().sum::<usize>();
+    println!();
+    println!("Completed {} connection(s) in {:.2?}", total, elapsed);
+    println!(
+        "  successful:  {} ({:.1} conn/s)",
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!("  failed:      {}", total - latencies.len());
+
+    if !latencies.is_empty() {
+        println!();
+        println!("Latency:");
+        for (label, p) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            println!("  {}:  {:.2?}", label, percentile(&latencies, p));
+        }
+    }
+
+    if !errors.is_empty() {
+        println!();
+        println!("Errors:");
+        let mut errors: Vec<_> = errors.iter().collect();
+        errors.sort_by(|a, b| b.1.cmp(a.1));
+        for (err, count) in errors {
+            println!("  {:>6}x  {}", count, err);
+        }
+    }
+}
+
+/// Compute the given percentile of a sorted list of latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[idx]
+}
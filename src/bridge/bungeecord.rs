@@ -0,0 +1,218 @@
+//! Handles the BungeeCord plugin channel, through which backend plugins written for BungeeCord or
+//! Velocity ask the proxy to move players, and about who is online.
+//!
+//! Plugins send requests as plugin messages on the `bungeecord:main` channel, through the
+//! connection of any player on the server. Each request starts with the name of a subchannel, and
+//! is answered through the same connection, as BungeeCord would. Server names are those of the
+//! `[commands]` block, and requests about unknown servers or players go unanswered.
+
+use std::io::{Cursor, Write};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tracing::{debug, trace};
+
+use crate::{
+    io::{Packet, ProtocolReadExt, ProtocolWriteExt, UncompressedPacket},
+    protocol::version::LogicalPacket,
+    registry,
+};
+
+use super::{BridgeState, Control};
+
+/// The channel names BungeeCord answers on - the legacy name is still sent by some plugins.
+const CHANNELS: [&str; 2] = ["bungeecord:main", "BungeeCord"];
+/// The server name standing for every server.
+const ALL: &str = "ALL";
+
+/// Handle a plugin message sent by the server, returning whether it was intercepted. Intercepted
+/// messages must not be forwarded to the client.
+pub fn intercept(
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &Packet,
+) -> Result<bool> {
+    if !state.bungeecord || logical_packet != Some(LogicalPacket::PluginMessageClientbound) {
+        return Ok(false);
+    }
+    let packet = packet.clone().decompress()?;
+    let mut buf = packet.as_cursor();
+    let channel = buf.read_string()?;
+    if !CHANNELS.contains(&channel.as_str()) {
+        return Ok(false);
+    }
+
+    // a malformed request is the plugin's problem, not the player's
+    match handle(state, &mut buf) {
+        Ok(Some(data)) => {
+            let id = state
+                .protocol_version
+                .packet_id(LogicalPacket::PluginMessageServerbound)
+                .context("plugin messages are unknown in this version")?;
+            let mut reply = vec![];
+            reply.write_string(channel)?;
+            reply.write_all(&data)?;
+            let _ = state.to_server.send(UncompressedPacket {
+                id,
+                data: reply.into(),
+            });
+        }
+        Ok(None) => {}
+        Err(err) => debug!("Ignoring malformed BungeeCord request: {:#}", err),
+    }
+    Ok(true)
+}
+
+/// Handle a request, returning the data of the reply, if any.
+fn handle(state: &BridgeState, buf: &mut Cursor<&[u8]>) -> Result<Option<Vec<u8>>> {
+    let subchannel = read_utf(buf)?;
+    trace!("Handling BungeeCord request {}", subchannel);
+    let mut reply = vec![];
+    write_utf(&mut reply, &subchannel)?;
+    match subchannel.as_str() {
+        "Connect" => {
+            let server = read_utf(buf)?;
+            if let Some((host, port)) = state.server(&server).cloned() {
+                let _ = state.session.control.send(transfer(host, port));
+            }
+            return Ok(None);
+        }
+        "ConnectOther" => {
+            let player = read_utf(buf)?;
+            let server = read_utf(buf)?;
+            if let (Some(player), Some((host, port))) =
+                (registry::player(&player), state.server(&server).cloned())
+            {
+                registry::control(player.id, transfer(host, port));
+            }
+            return Ok(None);
+        }
+        "IP" => {
+            let Some(session) = registry::sessions()
+                .into_iter()
+                .find(|session| session.connection_id == state.session.connection_id)
+            else {
+                return Ok(None);
+            };
+            write_utf(&mut reply, &session.peer.ip().to_string())?;
+            reply.write_all(&(session.peer.port() as i32).to_be_bytes())?;
+        }
+        "IPOther" => {
+            let Some(player) = registry::player(&read_utf(buf)?) else {
+                return Ok(None);
+            };
+            write_utf(&mut reply, player.username.as_deref().unwrap_or_default())?;
+            write_utf(&mut reply, &player.peer.ip().to_string())?;
+            reply.write_all(&(player.peer.port() as i32).to_be_bytes())?;
+        }
+        "PlayerCount" | "PlayerList" => {
+            let server = read_utf(buf)?;
+            let usernames = match server.as_str() {
+                ALL => registry::sessions()
+                    .into_iter()
+                    .filter_map(|session| session.username)
+                    .collect(),
+                name => match state.server(name) {
+                    Some((domain, _)) => registry::usernames(domain),
+                    None => return Ok(None),
+                },
+            };
+            write_utf(&mut reply, &server)?;
+            match subchannel.as_str() {
+                "PlayerCount" => reply.write_all(&(usernames.len() as i32).to_be_bytes())?,
+                _ => write_utf(&mut reply, &usernames.join(", "))?,
+            }
+        }
+        "GetServers" => {
+            let names: Vec<_> = state
+                .commands
+                .iter()
+                .flat_map(|commands| commands.servers.keys().cloned())
+                .collect();
+            write_utf(&mut reply, &names.join(", "))?;
+        }
+        "GetServer" => {
+            // routes outside the server directory are known by their domain
+            let name = state
+                .commands
+                .iter()
+                .flat_map(|commands| &commands.servers)
+                .find(|(_, (host, _))| *host == state.session.route)
+                .map_or(state.session.route.as_str(), |(name, _)| name.as_str());
+            write_utf(&mut reply, name)?;
+        }
+        "ServerIP" => {
+            let server = read_utf(buf)?;
+            let Some((host, port)) = state.server(&server).cloned() else {
+                return Ok(None);
+            };
+            write_utf(&mut reply, &server)?;
+            write_utf(&mut reply, &host)?;
+            reply.write_u16(port)?;
+        }
+        "Message" | "MessageRaw" => {
+            let player = read_utf(buf)?;
+            let message = read_utf(buf)?;
+            let message: Value = match subchannel.as_str() {
+                // legacy formatting codes are still rendered within text
+                "Message" => json!({ "text": message }),
+                _ => serde_json::from_str(&message).context("invalid chat component")?,
+            };
+            let announce = || Control::Announce {
+                message: message.clone(),
+                action_bar: false,
+            };
+            match player.as_str() {
+                ALL => {
+                    registry::control_routes(&[], announce);
+                }
+                player => {
+                    if let Some(player) = registry::player(player) {
+                        registry::control(player.id, announce());
+                    }
+                }
+            }
+            return Ok(None);
+        }
+        "KickPlayer" => {
+            let player = read_utf(buf)?;
+            let reason = read_utf(buf)?;
+            if let Some(player) = registry::player(&player) {
+                registry::control(player.id, Control::Disconnect(json!({ "text": reason })));
+            }
+            return Ok(None);
+        }
+        _ => {
+            debug!("Ignoring unsupported BungeeCord request {}", subchannel);
+            return Ok(None);
+        }
+    }
+    Ok(Some(reply))
+}
+
+/// Build a control transferring a player, whether or not it succeeds.
+fn transfer(host: String, port: u16) -> Control {
+    let (reply, _) = oneshot::channel();
+    Control::Transfer { host, port, reply }
+}
+
+/// Read a string as written by Java's `DataOutput.writeUTF`.
+fn read_utf(buf: &mut Cursor<&[u8]>) -> Result<String> {
+    let len = buf.read_u16()? as usize;
+    let start = buf.position() as usize;
+    let bytes = buf
+        .get_ref()
+        .get(start..start + len)
+        .context("string is longer than the message")?;
+    buf.set_position((start + len) as u64);
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Write a string as read by Java's `DataInput.readUTF`.
+fn write_utf<W: Write>(buf: &mut W, value: &str) -> Result<()> {
+    let len = u16::try_from(value.len()).context("string is too long")?;
+    buf.write_u16(len)?;
+    buf.write_all(value.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,277 @@
+//! Handles the Magma control channel, through which first-party backend plugins make requests of
+//! the proxy.
+//!
+//! Requests are plugin messages on the `magma:control` channel, sent through the connection of any
+//! player on the server, and are answered through the same connection. Every message starts with
+//! the version of the schema, which is documented in the README - requests in a version Magma
+//! doesn't speak are answered with [UNSUPPORTED].
+//!
+//! Requests only reach the route of the player whose connection carried them, so a backend can't
+//! read or move the players of routes it doesn't serve.
+
+use std::{
+    io::{Cursor, Write},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, trace};
+
+use crate::{
+    io::{Packet, ProtocolReadExt, ProtocolWriteExt, UncompressedPacket},
+    protocol::version::LogicalPacket,
+    registry,
+};
+
+use super::{BridgeState, Control, Session};
+
+/// The name of the channel.
+const CHANNEL: &str = "magma:control";
+/// The version of the schema.
+const VERSION: u8 = 1;
+/// The most metadata entries a session may have.
+const MAX_METADATA: usize = 64;
+
+/// Move a player to a named server.
+const TRANSFER: u8 = 0x01;
+/// Describe a route and who is on it.
+const ROUTE_INFO: u8 = 0x02;
+/// Set or clear a metadata entry of a player's session.
+const SET_METADATA: u8 = 0x03;
+/// Read a metadata entry of a player's session.
+const GET_METADATA: u8 = 0x04;
+
+/// The request succeeded.
+const OK: u8 = 0x00;
+/// No player with the given username is logged in.
+const UNKNOWN_PLAYER: u8 = 0x01;
+/// No server has the given name.
+const UNKNOWN_SERVER: u8 = 0x02;
+/// The version or operation isn't supported.
+const UNSUPPORTED: u8 = 0x03;
+/// The request was understood, but couldn't be carried out.
+const FAILED: u8 = 0x04;
+/// The request targets a route other than the one it was sent through.
+const FORBIDDEN: u8 = 0x05;
+
+/// The status and data of a response.
+struct Response {
+    status: u8,
+    data: Vec<u8>,
+}
+
+impl Response {
+    /// A successful response with the given data.
+    fn ok(data: Vec<u8>) -> Self {
+        Self { status: OK, data }
+    }
+
+    /// A failed response, with a message describing why.
+    fn error(status: u8, message: &str) -> Self {
+        let mut data = vec![];
+        // writing to a vector can't fail
+        let _ = data.write_string(message.to_string());
+        Self { status, data }
+    }
+}
+
+/// Handle a plugin message sent by the server, returning whether it was intercepted. Intercepted
+/// messages must not be forwarded to the client.
+pub fn intercept(
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &Packet,
+) -> Result<bool> {
+    if !state.control_channel || logical_packet != Some(LogicalPacket::PluginMessageClientbound) {
+        return Ok(false);
+    }
+    let packet = packet.clone().decompress()?;
+    let mut buf = packet.as_cursor();
+    if buf.read_string()? != CHANNEL {
+        return Ok(false);
+    }
+
+    // a request which can't be told apart from another can't be answered
+    let (version, request_id, operation) = match read_header(&mut buf) {
+        Ok(header) => header,
+        Err(err) => {
+            debug!("Ignoring malformed control request: {:#}", err);
+            return Ok(true);
+        }
+    };
+    trace!(
+        "Handling control request {} (version {}, operation {})",
+        request_id,
+        version,
+        operation
+    );
+    let response = match version {
+        VERSION => handle(state, request_id, operation, &mut buf)
+            .unwrap_or_else(|err| Some(Response::error(FAILED, &format!("{:#}", err)))),
+        _ => Some(Response::error(
+            UNSUPPORTED,
+            &format!("unsupported schema version {}", version),
+        )),
+    };
+    if let Some(response) = response {
+        send(
+            &state.to_server,
+            plugin_message_id(state)?,
+            request_id,
+            response,
+        )?;
+    }
+    Ok(true)
+}
+
+/// Read the version of a request, its id, and its operation.
+fn read_header(buf: &mut Cursor<&[u8]>) -> Result<(u8, i32, u8)> {
+    Ok((buf.read_u8()?, buf.read_var_int()?, buf.read_u8()?))
+}
+
+/// Handle a request, returning its response - unless it is answered later.
+fn handle(
+    state: &BridgeState,
+    request_id: i32,
+    operation: u8,
+    buf: &mut Cursor<&[u8]>,
+) -> Result<Option<Response>> {
+    let response = match operation {
+        TRANSFER => {
+            let player = buf.read_string()?;
+            let server = buf.read_string()?;
+            let Some(session) = find(state, &player) else {
+                return Ok(Some(Response::error(UNKNOWN_PLAYER, &player)));
+            };
+            let Some((host, port)) = state.server(&server).cloned() else {
+                return Ok(Some(Response::error(UNKNOWN_SERVER, &server)));
+            };
+            let (reply, replied) = oneshot::channel();
+            let _ = session
+                .control
+                .send(Control::Transfer { host, port, reply });
+            // answer once the player's bridge has tried the transfer
+            let packet_id = plugin_message_id(state)?;
+            let to_server = state.to_server.clone();
+            tokio::task::spawn(async move {
+                let response = match replied.await {
+                    Ok(Ok(())) => Response::ok(vec![]),
+                    Ok(Err(err)) => Response::error(FAILED, &format!("{:#}", err)),
+                    Err(_) => Response::error(FAILED, "the session closed"),
+                };
+                let _ = send(&to_server, packet_id, request_id, response);
+            });
+            return Ok(None);
+        }
+        ROUTE_INFO => {
+            let route = buf.read_string()?;
+            // routes are named by server, or by domain
+            let domain = match route.as_str() {
+                "" => state.session.route.clone(),
+                name => state
+                    .server(name)
+                    .map_or_else(|| name.to_string(), |(host, _)| host.clone()),
+            };
+            if domain != state.session.route {
+                return Ok(Some(Response::error(
+                    FORBIDDEN,
+                    &format!("{} is not the route of this connection", route),
+                )));
+            }
+            let name = state
+                .commands
+                .iter()
+                .flat_map(|commands| &commands.servers)
+                .find(|(_, (host, _))| *host == domain)
+                .map_or("", |(name, _)| name.as_str());
+            let usernames = registry::usernames(&domain);
+            let mut data = vec![];
+            data.write_string(domain.clone())?;
+            data.write_string(name.to_string())?;
+            data.write_var_int(usernames.len() as i32)?;
+            for username in usernames {
+                data.write_string(username)?;
+            }
+            Response::ok(data)
+        }
+        SET_METADATA => {
+            let player = buf.read_string()?;
+            let key = buf.read_string()?;
+            let value = match buf.read_bool()? {
+                true => Some(buf.read_string()?),
+                false => None,
+            };
+            let Some(session) = find(state, &player) else {
+                return Ok(Some(Response::error(UNKNOWN_PLAYER, &player)));
+            };
+            let mut metadata = session.metadata.lock().unwrap();
+            match value {
+                Some(_) if metadata.len() >= MAX_METADATA && !metadata.contains_key(&key) => {
+                    Response::error(FAILED, "the session has too much metadata")
+                }
+                Some(value) => {
+                    metadata.insert(key, value);
+                    Response::ok(vec![])
+                }
+                None => {
+                    metadata.remove(&key);
+                    Response::ok(vec![])
+                }
+            }
+        }
+        GET_METADATA => {
+            let player = buf.read_string()?;
+            let key = buf.read_string()?;
+            let Some(session) = find(state, &player) else {
+                return Ok(Some(Response::error(UNKNOWN_PLAYER, &player)));
+            };
+            let value = session.metadata.lock().unwrap().get(&key).cloned();
+            let mut data = vec![];
+            data.write_bool(value.is_some())?;
+            if let Some(value) = value {
+                data.write_string(value)?;
+            }
+            Response::ok(data)
+        }
+        _ => Response::error(UNSUPPORTED, &format!("unsupported operation {}", operation)),
+    };
+    Ok(Some(response))
+}
+
+/// Find the session of a player on the route of the request by username, or the session carrying
+/// the request if none is given.
+fn find(state: &BridgeState, username: &str) -> Option<Arc<Session>> {
+    match username {
+        "" => Some(state.session.clone()),
+        username => registry::session(registry::player_on(&state.session.route, username)?.id),
+    }
+}
+
+/// The id of serverbound plugin messages in the bridge's protocol version.
+fn plugin_message_id(state: &BridgeState) -> Result<i32> {
+    state
+        .protocol_version
+        .packet_id(LogicalPacket::PluginMessageServerbound)
+        .context("plugin messages are unknown in this version")
+}
+
+/// Queue a response as a plugin message with the given id.
+fn send(
+    to_server: &mpsc::UnboundedSender<UncompressedPacket>,
+    packet_id: i32,
+    request_id: i32,
+    response: Response,
+) -> Result<()> {
+    let mut data = vec![];
+    data.write_string(CHANNEL.to_string())?;
+    data.write_u8(VERSION)?;
+    data.write_var_int(request_id)?;
+    data.write_u8(response.status)?;
+    data.write_all(&response.data)?;
+    let _ = to_server.send(UncompressedPacket {
+        id: packet_id,
+        data: data.into(),
+    });
+    Ok(())
+}
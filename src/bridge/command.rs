@@ -0,0 +1,103 @@
+//! Handles commands the proxy intercepts, which move players between routes without reaching the
+//! server.
+//!
+//! Players move by being transferred, so only the commands of 1.20.5+ clients are intercepted -
+//! older clients' commands are forwarded as usual, where a backend plugin may still handle them.
+
+use anyhow::Result;
+use serde_json::json;
+use tokio::sync::oneshot;
+use tracing::info;
+
+use crate::{
+    io::{Packet, ProtocolReadExt, ProtocolWriteExt, UncompressedPacket},
+    protocol::version::{LogicalPacket, ProtocolVersion},
+};
+
+use super::{BridgeState, Control};
+
+/// The size of each argument signature of a signed command.
+const SIGNATURE_SIZE: u64 = 256;
+
+/// Handle a command sent by the client, returning whether it was intercepted. Intercepted commands
+/// must not be forwarded to the server.
+pub fn intercept(
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &Packet,
+) -> Result<bool> {
+    let Some(commands) = &state.commands else {
+        return Ok(false);
+    };
+    let signed = match logical_packet {
+        Some(LogicalPacket::ChatCommand) => false,
+        Some(LogicalPacket::ChatCommandSigned) => true,
+        _ => return Ok(false),
+    };
+    if state.protocol_version < ProtocolVersion::V1_20_5 {
+        return Ok(false);
+    }
+
+    let packet = packet.clone().decompress()?;
+    let mut buf = packet.as_cursor();
+    let line = buf.read_string()?;
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(command) if command == commands.command => words.next(),
+        Some(alias) => match commands.aliases.get(alias) {
+            Some(name) => Some(name.as_str()),
+            None => return Ok(false),
+        },
+        None => return Ok(false),
+    };
+
+    // the server counts the messages the client has seen through signed commands, so it must
+    // still be told about those acknowledged by one it won't receive
+    if signed {
+        buf.read_i64()?;
+        buf.read_i64()?;
+        for _ in 0..buf.read_var_int()? {
+            buf.read_string()?;
+            buf.set_position(buf.position() + SIGNATURE_SIZE);
+        }
+        let message_count = buf.read_var_int()?;
+        if let Some(id) = state
+            .protocol_version
+            .packet_id(LogicalPacket::MessageAcknowledgment)
+        {
+            let mut data = vec![];
+            data.write_var_int(message_count)?;
+            let _ = state.to_server.send(UncompressedPacket {
+                id,
+                data: data.into(),
+            });
+        }
+    }
+
+    let reply = |text: String, color: &str| Control::Announce {
+        message: json!({ "text": text, "color": color }),
+        action_bar: false,
+    };
+    let control = match name.map(|name| (name, commands.servers.get(name))) {
+        None => {
+            let names: Vec<_> = commands.servers.keys().map(String::as_str).collect();
+            reply(format!("Servers: {}", names.join(", ")), "yellow")
+        }
+        Some((name, None)) => reply(format!("Unknown server {}", name), "red"),
+        Some((name, Some((host, _)))) if *host == state.session.route => {
+            reply(format!("You are already connected to {}", name), "red")
+        }
+        Some((name, Some((host, port)))) => {
+            info!("Moving the player to server {}", name);
+            // the transfer can't fail for a client whose commands can be read
+            let (reply, _) = oneshot::channel();
+            Control::Transfer {
+                host: host.clone(),
+                port: *port,
+                reply,
+            }
+        }
+    };
+    let _ = state.session.control.send(control);
+    Ok(true)
+}
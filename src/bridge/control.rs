@@ -0,0 +1,193 @@
+//! Defines controls, which let the rest of Magma act on a bridged session.
+//!
+//! Each session has a channel of controls, which the downstream half of its bridge handles
+//! alongside the packets it relays - so packets sent by a control are queued in order with the
+//! server's, and written before the connection is closed.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    io::{Packet, ProtocolWriteExt, UncompressedPacket},
+    protocol::{
+        chat,
+        packets::{Disconnect, PacketCodec},
+        version::{LogicalPacket, ProtocolVersion},
+    },
+};
+
+use super::{
+    outbox::{Outbox, Outgoing},
+    reencode, BridgeState, ClientState, CloseReason, ProtocolState,
+};
+
+/// An action taken on a session from outside its bridge.
+#[derive(Debug)]
+pub enum Control {
+    /// Close the connection without telling the client, for the given reason.
+    Close(String),
+    /// Disconnect the client with the given chat component, then close the connection.
+    Disconnect(Value),
+    /// Ask the client to reconnect to the given host and port, replying with whether it could
+    /// be asked. Only 1.20.5+ clients can be transferred.
+    Transfer {
+        host: String,
+        port: u16,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Show the client a system message, in chat or above the hotbar. Clients which aren't
+    /// playing, or whose connection is encrypted end to end, are skipped.
+    Announce { message: Value, action_bar: bool },
+}
+
+/// Handle the controls sent to a session, until one closes it.
+///
+/// Returns once the packets closing the session have been queued, so the caller can flush them
+/// before closing the connection. Controls which close the session abruptly fail instead.
+pub async fn handle(
+    state: &BridgeState,
+    controls: &mut mpsc::UnboundedReceiver<Control>,
+    outbox: &Outbox,
+) -> Result<()> {
+    // the session holds the sender, so the channel never closes while bridged
+    while let Some(control) = controls.recv().await {
+        match control {
+            Control::Close(reason) => {
+                let _ = state
+                    .session
+                    .closed_by
+                    .set((CloseReason::Kicked, reason.clone()));
+                bail!(reason);
+            }
+            Control::Disconnect(reason) => {
+                let _ = state.session.closed_by.set((
+                    CloseReason::Kicked,
+                    "disconnected by an operator".to_string(),
+                ));
+                return match disconnect(state, &reason).await? {
+                    Some(packet) => outbox.send(Outgoing::Packet(packet)).await,
+                    // clients which can't be sent a reason are simply closed
+                    None => Err(anyhow!("disconnected by an operator")),
+                };
+            }
+            Control::Transfer { host, port, reply } => {
+                // the client closes the connection itself once it has the packet
+                let result = match transfer(state, &host, port).await {
+                    Ok(packet) => outbox.send(Outgoing::Packet(packet)).await,
+                    Err(err) => Err(err),
+                };
+                if result.is_ok() {
+                    let _ = state.session.closed_by.set((
+                        CloseReason::Transferred,
+                        format!("transferred to {}:{}", host, port),
+                    ));
+                }
+                let _ = reply.send(result);
+            }
+            Control::Announce {
+                message,
+                action_bar,
+            } => {
+                if let Some(packet) = announce(state, &message, action_bar).await? {
+                    outbox.send(Outgoing::Packet(packet)).await?;
+                }
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// Build a packet disconnecting the client with the given reason, if one can be sent in its
+/// current state.
+async fn disconnect(state: &BridgeState, reason: &Value) -> Result<Option<Packet>> {
+    let client = state.client.read().await;
+    // encrypted connections can't be written to, and status connections can't be disconnected
+    let logical_packet = match client.protocol_state {
+        _ if client.encrypted => return Ok(None),
+        ProtocolState::Handshaking | ProtocolState::Status => return Ok(None),
+        ProtocolState::Login => LogicalPacket::LoginDisconnect,
+        ProtocolState::Configuration => LogicalPacket::ConfigurationDisconnect,
+        ProtocolState::Play => LogicalPacket::PlayDisconnect,
+    };
+    // the login disconnect is the same in every version, so even unknown versions can be told why
+    let id = match state.protocol_version.packet_id(logical_packet) {
+        Some(id) => id,
+        None if logical_packet == LogicalPacket::LoginDisconnect => Disconnect::ID,
+        None => return Ok(None),
+    };
+    let mut data = vec![];
+    chat::write_component(
+        &mut data,
+        &state.localize(reason),
+        state.protocol_version,
+        client.protocol_state,
+    )
+    .context("failed to encode disconnect reason")?;
+    Ok(Some(for_client(state, &client, id, data)?))
+}
+
+/// Build a packet transferring the client to the given host and port, failing if the client
+/// can't be transferred in its current state.
+async fn transfer(state: &BridgeState, host: &str, port: u16) -> Result<Packet> {
+    if state.protocol_version < ProtocolVersion::V1_20_5 {
+        bail!(
+            "protocol version {} predates transfers",
+            state.protocol_version
+        );
+    }
+    let client = state.client.read().await;
+    let logical_packet = match client.protocol_state {
+        _ if client.encrypted => bail!("the connection is encrypted end to end"),
+        ProtocolState::Configuration => LogicalPacket::ConfigurationTransfer,
+        ProtocolState::Play => LogicalPacket::PlayTransfer,
+        other => bail!("the client is in the {:?} state", other),
+    };
+    let id = state
+        .protocol_version
+        .packet_id(logical_packet)
+        .context("transfers are unknown in this version")?;
+    let mut data = vec![];
+    data.write_string(host.to_string())?;
+    data.write_var_int(port as i32)?;
+    for_client(state, &client, id, data)
+}
+
+/// Build a system chat packet showing the client a message, if it can be sent one.
+async fn announce(
+    state: &BridgeState,
+    message: &Value,
+    action_bar: bool,
+) -> Result<Option<Packet>> {
+    let client = state.client.read().await;
+    if client.encrypted || client.protocol_state != ProtocolState::Play {
+        return Ok(None);
+    }
+    let Some(id) = state.protocol_version.packet_id(LogicalPacket::SystemChat) else {
+        return Ok(None);
+    };
+    let mut data = vec![];
+    chat::write_component(
+        &mut data,
+        &state.localize(message),
+        state.protocol_version,
+        ProtocolState::Play,
+    )
+    .context("failed to encode announcement")?;
+    match state.protocol_version {
+        // 1.19 sends the position as a var int, where 2 is above the hotbar
+        ProtocolVersion::V1_19 => data.write_var_int(if action_bar { 2 } else { 1 })?,
+        _ => data.write_bool(action_bar)?,
+    }
+    Ok(Some(for_client(state, &client, id, data)?))
+}
+
+/// Encode a packet for the client's compression settings.
+fn for_client(state: &BridgeState, client: &ClientState, id: i32, data: Vec<u8>) -> Result<Packet> {
+    let packet = Packet::Uncompressed(UncompressedPacket {
+        id,
+        data: data.into(),
+    });
+    let level = state.compression.map(|compression| compression.level);
+    reencode(packet, None, client.compression_threshold, level)
+}
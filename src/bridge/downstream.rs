@@ -1,68 +1,257 @@
 //! Handles the downstream connection from the server to the client.
 
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
-use anyhow::Result;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use tokio::{
+    io::{ReadHalf, WriteHalf},
+    select,
+};
+use tracing::{debug, trace};
 
-use crate::io::{Packet, ProcotolAsyncWriteExt, ProtocolAsyncReadExt};
+use crate::{
+    config::PlayerSample,
+    io::{Packet, ProtocolAsyncReadExt},
+    metrics::{self, Timing},
+    motd,
+    protocol::{
+        packets::{LoginPluginRequest, PacketCodec, SetCompression, StatusResponse},
+        version::{Direction, LogicalPacket, ProtocolVersion},
+    },
+    reply,
+};
 
-use super::{BridgeState, ProtocolState};
+use super::{
+    bungeecord, channel, closed, control,
+    outbox::{self, Outbox, Outgoing},
+    reencode, BridgeState, CloseReason, ProtocolState, Side, Stream, STATUS_TIMEOUT,
+};
 
 /// Create a state machine to handle downstream packets - that is, packets from the server to the client.
-pub async fn handle_downstream(
+pub async fn handle_downstream<S: Stream, C: Stream>(
     state: Arc<BridgeState>,
-    mut server_rx: OwnedReadHalf,
-    mut client_tx: OwnedWriteHalf,
+    server_rx: ReadHalf<S>,
+    client_tx: WriteHalf<C>,
+) -> Result<()> {
+    let (outbox, drain) = outbox::channel(state.backpressure, state.session.clone());
+    let mut controls = state.session.controls.lock().unwrap().take();
+    let read = async move {
+        // a control ends reading early, and the packets it queued are written before closing
+        select! {
+            result = read_downstream(&state, server_rx, &outbox) => result,
+            result = async {
+                match &mut controls {
+                    Some(controls) => control::handle(&state, controls, &outbox).await,
+                    None => std::future::pending().await,
+                }
+            } => result,
+            _ = state.cancel.cancelled() => Ok(()),
+        }
+    };
+    // the reader's errors are classified first, so any left unclassified are the writer's
+    let read = async move { read.await.map_err(|err| closed(Side::Server, err)) };
+    outbox::pump(read, drain, client_tx)
+        .await
+        .map_err(|err| closed(Side::Client, err))
+}
+
+/// Read packets from the server, queueing them for the client.
+async fn read_downstream<S: Stream>(
+    state: &BridgeState,
+    mut server_rx: ReadHalf<S>,
+    outbox: &Outbox,
 ) -> Result<()> {
     loop {
-        let protocol_state = &{ state.server.read().await }.protocol_state;
-        match protocol_state {
-            ProtocolState::Handshaking => {
-                unreachable!("downstream handshake")
-            }
-            ProtocolState::Status => {
-                handle_downstream_status(&mut server_rx, &mut client_tx).await?
-            }
-            ProtocolState::Login => {
-                handle_downstream_login(state.clone(), &mut server_rx, &mut client_tx).await?
-            }
-            ProtocolState::Play => {
-                handle_downstream_play(state.clone(), &mut server_rx, &mut client_tx).await?
+        // once encrypted, packets can no longer be read - simply relay bytes
+        if state.server.read().await.encrypted {
+            return outbox
+                .relay(&mut server_rx, &state.session.downstream)
+                .await;
+        }
+
+        // read the next frame before inspecting the state, as it may change while we wait - but a
+        // server answering a status ping must do so promptly
+        let protocol_state = state.server.read().await.protocol_state;
+        let frame = match protocol_state {
+            ProtocolState::Status => server_rx.read_frame_timeout(STATUS_TIMEOUT).await?,
+            _ => server_rx.read_frame().await?,
+        };
+        state
+            .session
+            .downstream
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        // packets which can't be handled close the bridge as undecodable
+        let packet = inspect_downstream(state, frame)
+            .await
+            .context(CloseReason::DecodeError)?;
+        if let Some(packet) = packet {
+            outbox.send(Outgoing::Packet(packet)).await?;
+        }
+    }
+}
+
+/// Decode a frame read from the server, returning the packet to queue for the client, if it isn't
+/// withheld.
+async fn inspect_downstream(state: &BridgeState, frame: Bytes) -> Result<Option<Packet>> {
+    let (protocol_state, server_threshold, client_threshold) = {
+        let server = state.server.read().await;
+        let client = state.client.read().await;
+        (
+            server.protocol_state,
+            server.compression_threshold,
+            client.compression_threshold,
+        )
+    };
+    let mut packet = Packet::from_frame(frame, server_threshold.is_some())?;
+
+    // inspect the packet, updating state before it is forwarded
+    let logical_packet =
+        state
+            .protocol_version
+            .logical_packet(protocol_state, Direction::Clientbound, packet.id()?);
+    match protocol_state {
+        ProtocolState::Handshaking => {
+            unreachable!("downstream handshake")
+        }
+        ProtocolState::Status => handle_downstream_status(state, logical_packet, &mut packet)?,
+        ProtocolState::Login => handle_downstream_login(state, logical_packet, &mut packet).await?,
+        ProtocolState::Configuration => {
+            handle_downstream_configuration(state, logical_packet).await
+        }
+        ProtocolState::Play => {
+            if bungeecord::intercept(state, logical_packet, &packet)?
+                || channel::intercept(state, logical_packet, &packet)?
+            {
+                return Ok(None);
             }
         }
     }
+
+    // thresholds are those from before the packet was handled, as compression only applies
+    // to the packets after Set Compression
+    let level = state.compression.map(|compression| compression.level);
+    Ok(Some(reencode(
+        packet,
+        server_threshold,
+        client_threshold,
+        level,
+    )?))
 }
 
 /// Handle status packets.
-async fn handle_downstream_status(
-    server_rx: &mut OwnedReadHalf,
-    client_tx: &mut OwnedWriteHalf,
+fn handle_downstream_status(
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &mut Packet,
 ) -> Result<()> {
-    let packet = server_rx.read_uncompressed_packet().await?;
-    client_tx.write_uncompressed_packet(&packet).await?;
+    let sample = !matches!(state.player_sample, PlayerSample::Pass);
+    let overridden = state.players.is_some()
+        || state.motds.is_some()
+        || state.favicon.is_some()
+        || state.enforce_secure_chat.is_some()
+        || sample;
+    if logical_packet != Some(LogicalPacket::StatusResponse) || !overridden {
+        return Ok(());
+    }
+    let mut response = StatusResponse::decode(&packet.clone().decompress()?)?;
+    if let Some(players) = state.players {
+        response.json = reply::override_players(&response.json, players)?;
+    }
+    if let Some(motds) = &state.motds {
+        let motd = motd::pick(&state.session.route, motds);
+        response.json = reply::override_description(&response.json, motd)?;
+    }
+    if sample {
+        response.json = reply::override_sample(&response.json, &state.player_sample)?;
+    }
+    if let Some(enforced) = state.enforce_secure_chat {
+        response.json = reply::override_secure_chat(&response.json, enforced)?;
+    }
+    if let Some(favicon) = &state.favicon {
+        response.json = reply::default_favicon(&response.json, favicon)?;
+    }
+    *packet = Packet::Uncompressed(response.encode()?);
     Ok(())
 }
 
 /// Handle login packets.
 async fn handle_downstream_login(
-    state: Arc<BridgeState>,
-    server_rx: &mut OwnedReadHalf,
-    client_tx: &mut OwnedWriteHalf,
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &mut Packet,
 ) -> Result<()> {
-    todo!()
+    // the logins of unknown versions can't be followed, so their clients are counted as logged in
+    // as soon as the server answers
+    if !state.protocol_version.is_known() {
+        state.end_login();
+    }
+    match logical_packet {
+        // the server requested encryption - we can no longer read packets, so the client is
+        // counted as logged in
+        Some(LogicalPacket::EncryptionRequest) => {
+            trace!("Server requested encryption");
+            state.server.write().await.encrypted = true;
+            state.end_login();
+        }
+        // compression applies to both directions once the client receives this packet - the
+        // client may be sent a different threshold to the server's
+        Some(LogicalPacket::SetCompression) => {
+            let threshold = SetCompression::decode(&packet.clone().decompress()?)?.threshold;
+            trace!("Server set compression threshold to {}", threshold);
+            let client_threshold = state
+                .compression
+                .and_then(|compression| compression.threshold)
+                .unwrap_or(threshold);
+            if client_threshold != threshold {
+                trace!(
+                    "Overriding client compression threshold to {}",
+                    client_threshold
+                );
+                let set_compression = SetCompression {
+                    threshold: client_threshold,
+                };
+                *packet = Packet::Uncompressed(set_compression.encode()?);
+            }
+            state.server.write().await.compression_threshold =
+                (threshold >= 0).then_some(threshold);
+            state.client.write().await.compression_threshold =
+                (client_threshold >= 0).then_some(client_threshold);
+        }
+        // relay login plugin queries, such as Velocity forwarding, as-is
+        Some(LogicalPacket::LoginPluginRequest) => {
+            let request = LoginPluginRequest::decode(&packet.clone().decompress()?)?;
+            trace!(
+                "Relaying login plugin request {} on channel {}",
+                request.message_id,
+                request.channel
+            );
+        }
+        // 1.20.2+ servers enter the configuration state, older servers go straight to play
+        Some(LogicalPacket::LoginSuccess) => {
+            debug!("Client successfully logged in");
+            state.end_login();
+            metrics::observe(
+                Timing::Login,
+                &state.session.route,
+                state.created_at.elapsed(),
+            );
+            match state.protocol_version >= ProtocolVersion::V1_20_2 {
+                true => state.server.write().await.protocol_state = ProtocolState::Configuration,
+                false => state.set_protocol_state(ProtocolState::Play).await,
+            }
+        }
+        _ => {}
+    }
+    Ok(())
 }
 
-/// Handle play packets.
-async fn handle_downstream_play(
-    state: Arc<BridgeState>,
-    server_rx: &mut OwnedReadHalf,
-    client_tx: &mut OwnedWriteHalf,
-) -> Result<()> {
-    let packet = match { state.server.read().await }.compressed {
-        true => Packet::Compressed(server_rx.read_compressed_packet().await?),
-        false => Packet::Uncompressed(server_rx.read_uncompressed_packet().await?),
-    };
-
-    todo!("handle client packet encryption")
+/// Handle configuration packets.
+async fn handle_downstream_configuration(
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+) {
+    if let Some(LogicalPacket::FinishConfiguration) = logical_packet {
+        state.server.write().await.protocol_state = ProtocolState::Play;
+    }
 }
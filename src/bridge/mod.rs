@@ -3,22 +3,66 @@
 //! Once a client has connected to the proxy, the proxy will attempt to connect to the upstream,
 //! and if successful, will create a bridge to proxy data between the two streams.
 
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Display},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use tokio::{net::TcpStream, sync::RwLock, try_join};
-use tracing::debug;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    select,
+    sync::{mpsc, RwLock},
+    time::{error::Elapsed, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, Instrument};
 
 use crate::{
     bridge::{downstream::handle_downstream, upstream::handle_upstream},
-    cryptor::Cryptor,
+    config::{
+        Backpressure, ChatSigning, Commands, CompressionOverride, Messages, Motds, PlayerSample,
+        Route,
+    },
+    crash,
+    error::ProtocolError,
+    io::{Packet, UncompressedPacket},
+    protocol::version::ProtocolVersion,
+    ratelimit::LoginPermit,
+    reply::Players,
+    username::UsernamePolicy,
 };
 
+mod bungeecord;
+mod channel;
+mod command;
+mod control;
 mod downstream;
+mod outbox;
 mod upstream;
 
+pub use control::Control;
+
+/// How long the half of a bridge still open has to shut down once the other has closed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long either peer of a status ping has to send each packet, as nothing keeps it alive.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A stream which can be bridged, such as a TCP socket or a tunneled stream.
+pub trait Stream: AsyncRead + AsyncWrite + Debug + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Debug + Send + Unpin + 'static> Stream for T {}
+
 /// The protocol state.
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
 pub enum ProtocolState {
     /// The protocol is awaiting a handshake.
     #[default]
@@ -27,6 +71,9 @@ pub enum ProtocolState {
     Status,
     /// Login state - the protocol is awaiting a login.
     Login,
+    /// Configuration state - the server is configuring the client before or during play. Only
+    /// present in 1.20.2+.
+    Configuration,
     /// Play state - the protocol is connected to a server.
     Play,
 }
@@ -38,94 +85,456 @@ impl TryFrom<i32> for ProtocolState {
         match value {
             0 => Ok(ProtocolState::Handshaking),
             1 => Ok(ProtocolState::Status),
-            2 => Ok(ProtocolState::Login),
-            3 => Ok(ProtocolState::Play),
+            // transferred clients (1.20.5+) log in as usual, and backends see a plain login
+            2 | 3 => Ok(ProtocolState::Login),
             _ => Err(anyhow::anyhow!("Invalid protocol state {}", value)),
         }
     }
 }
 
-impl From<&ProtocolState> for i32 {
-    fn from(protocol_state: &ProtocolState) -> Self {
+impl TryFrom<&ProtocolState> for i32 {
+    type Error = anyhow::Error;
+
+    fn try_from(protocol_state: &ProtocolState) -> Result<Self, Self::Error> {
         match protocol_state {
-            ProtocolState::Handshaking => 0,
-            ProtocolState::Status => 1,
-            ProtocolState::Login => 2,
-            ProtocolState::Play => 3,
+            ProtocolState::Handshaking => Ok(0),
+            ProtocolState::Status => Ok(1),
+            ProtocolState::Login => Ok(2),
+            ProtocolState::Play => Ok(3),
+            ProtocolState::Configuration => Err(anyhow::anyhow!(
+                "The configuration state cannot be requested in a handshake"
+            )),
         }
     }
 }
 
-impl From<ProtocolState> for i32 {
-    fn from(protocol_state: ProtocolState) -> Self {
-        (&protocol_state).into()
+impl TryFrom<ProtocolState> for i32 {
+    type Error = anyhow::Error;
+
+    fn try_from(protocol_state: ProtocolState) -> Result<Self, Self::Error> {
+        (&protocol_state).try_into()
     }
 }
 
 /// Stores the state of a bridge, comprised of the protocol state of the client and server.
 pub struct BridgeState {
-    /// The state of the client connection.
+    /// The protocol version declared by the client in its handshake.
+    pub protocol_version: ProtocolVersion,
+    /// The state of the client connection - that is, of packets sent by the client.
     pub client: RwLock<ClientState>,
-    /// The state of the server connection.
+    /// The state of the server connection - that is, of packets sent by the server.
     pub server: RwLock<ServerState>,
+    /// The compression settings to use with the client, if they differ from the server's.
+    pub compression: Option<CompressionOverride>,
+    /// How much is buffered for a peer which reads slower than the other sends.
+    pub backpressure: Backpressure,
+    /// The player counts to show in status responses, if they differ from the server's.
+    pub players: Option<Players>,
+    /// The commands handled by the proxy rather than the server, if any.
+    pub commands: Option<Arc<Commands>>,
+    /// Whether the server may use the BungeeCord plugin channel.
+    pub bungeecord: bool,
+    /// Whether the server may use the Magma control channel.
+    pub control_channel: bool,
+    /// Translations of the messages the proxy shows the client, if any.
+    pub messages: Option<Arc<Messages>>,
+    /// The MOTDs to show in status responses, if they differ from the server's.
+    pub motds: Option<Arc<Motds>>,
+    /// The favicon to show in status responses without one of their own.
+    pub favicon: Option<String>,
+    /// What status responses show as the sample of online players.
+    pub player_sample: PlayerSample,
+    /// Whether status responses claim the server enforces secure chat, if overridden.
+    pub enforce_secure_chat: Option<bool>,
+    /// What happens to the chat signing data the client sends the server.
+    pub chat_signing: ChatSigning,
+    /// The slowest the client may send a login packet, in bytes per second, or 0 if unlimited.
+    pub min_receive_rate: u32,
+    /// Which usernames the client may log in with, if restricted.
+    pub usernames: Option<Arc<UsernamePolicy>>,
+    /// Whether the server is in offline mode, so the client's profile must not reach it as-is.
+    pub offline_mode: bool,
+    /// Queues packets the proxy itself sends the server, such as replies to plugin messages.
+    pub to_server: mpsc::UnboundedSender<UncompressedPacket>,
+    /// What the bridge has learned about the session.
+    pub session: Arc<Session>,
+    /// When the bridge was created, just after the handshake was relayed to the server.
+    pub created_at: Instant,
+    /// Cancelled once the bridge is closing, so both halves stop reading and shut their writers
+    /// down.
+    pub cancel: CancellationToken,
+}
+
+/// One of a bridge's connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    /// The client's connection.
+    Client,
+    /// The server's connection.
+    Server,
+}
+
+/// Why a connection closed. Every way a bridge can end is classified as one of these, and carried
+/// by the error it ended with, so logs, metrics, events and the connection history agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The client closed its connection, or it failed.
+    ClientEof,
+    /// The server closed its connection, or it failed.
+    ServerEof,
+    /// A peer sent a packet which couldn't be decoded.
+    DecodeError,
+    /// A peer was too slow, such as a client dripping its login or a peer falling behind.
+    Timeout,
+    /// The session was closed on purpose, such as by the admin API or a backend plugin.
+    Kicked,
+    /// The client was transferred to another server.
+    Transferred,
+    /// The backend couldn't be reached, so the connection was never bridged.
+    BackendUnreachable,
+    /// The client was turned away by a rate limit.
+    RateLimited,
+}
+
+impl CloseReason {
+    /// The label value of the reason.
+    pub fn label(self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::ServerEof => "server_eof",
+            CloseReason::DecodeError => "decode_error",
+            CloseReason::Timeout => "timeout",
+            CloseReason::Kicked => "kicked",
+            CloseReason::Transferred => "transferred",
+            CloseReason::BackendUnreachable => "backend_unreachable",
+            CloseReason::RateLimited => "rate_limited",
+        }
+    }
+
+    /// The reason a connection closes when the given side reaches its end.
+    fn eof(side: Side) -> Self {
+        match side {
+            Side::Client => CloseReason::ClientEof,
+            Side::Server => CloseReason::ServerEof,
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::ClientEof => "client connection closed",
+            CloseReason::ServerEof => "server connection closed",
+            CloseReason::DecodeError => "undecodable packet",
+            CloseReason::Timeout => "timed out",
+            CloseReason::Kicked => "kicked",
+            CloseReason::Transferred => "transferred",
+            CloseReason::BackendUnreachable => "backend unreachable",
+            CloseReason::RateLimited => "rate limited",
+        })
+    }
+}
+
+/// Attach why the bridge closed to an error of the given side's connection, unless it already has
+/// a reason.
+fn closed(side: Side, err: anyhow::Error) -> anyhow::Error {
+    if err.downcast_ref::<CloseReason>().is_some() {
+        return err;
+    }
+    let timed_out = err.chain().any(|cause| {
+        cause.is::<Elapsed>()
+            || cause
+                .downcast_ref::<ProtocolError>()
+                .is_some_and(ProtocolError::is_timeout)
+    });
+    let reason = match timed_out {
+        true => CloseReason::Timeout,
+        false if err.chain().any(|cause| cause.is::<ProtocolError>()) => CloseReason::DecodeError,
+        false => CloseReason::eof(side),
+    };
+    err.context(reason)
+}
+
+/// Information gathered about a session as it is bridged, such as the traffic relayed.
+#[derive(Debug)]
+pub struct Session {
+    /// The ID of the connection, shared by its events and log lines.
+    pub connection_id: String,
+    /// The domain of the route the connection was matched to.
+    pub route: String,
+    /// The bytes of packet data sent by the client.
+    pub upstream: AtomicU64,
+    /// The bytes of packet data sent by the server.
+    pub downstream: AtomicU64,
+    /// The bytes queued to be written to either connection.
+    pub buffered: AtomicUsize,
+    /// The username the client logged in with, if it has logged in.
+    pub username: OnceLock<String>,
+    /// Sends controls to the session's bridge.
+    pub control: mpsc::UnboundedSender<Control>,
+    /// Receives controls, until the bridge takes them.
+    pub(crate) controls: Mutex<Option<mpsc::UnboundedReceiver<Control>>>,
+    /// Why the session was closed on purpose, and a description of what closed it, if it was.
+    pub closed_by: OnceLock<(CloseReason, String)>,
+    /// Metadata set by backend plugins over the control channel.
+    pub metadata: Mutex<BTreeMap<String, String>>,
+    /// The locale the client last declared, such as `en_us`, once it has declared one.
+    pub locale: Mutex<Option<String>>,
+    /// The client's place among its IP's logins in progress, given up once it has logged in.
+    pub login_permit: Mutex<Option<LoginPermit>>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        let (control, controls) = mpsc::unbounded_channel();
+        Self {
+            connection_id: String::new(),
+            route: String::new(),
+            upstream: AtomicU64::new(0),
+            downstream: AtomicU64::new(0),
+            buffered: AtomicUsize::new(0),
+            username: OnceLock::new(),
+            control,
+            controls: Mutex::new(Some(controls)),
+            closed_by: OnceLock::new(),
+            metadata: Mutex::default(),
+            locale: Mutex::default(),
+            login_permit: Mutex::default(),
+        }
+    }
+}
+
+/// How a bridge treats the connections it relays, mostly taken from the route they matched.
+///
+/// Options are built [from](BridgeOptions::from) a route, with what is only known per connection,
+/// such as the player counts and favicon of status responses, set on top.
+#[derive(Debug, Clone, Default)]
+pub struct BridgeOptions {
+    /// The compression settings to use with the client, if they differ from the server's.
+    pub compression: Option<CompressionOverride>,
+    /// How much is buffered for a peer which reads slower than the other sends.
+    pub backpressure: Backpressure,
+    /// The player counts to show in status responses, if they differ from the server's.
+    pub players: Option<Players>,
+    /// The commands handled by the proxy rather than the server, if any.
+    pub commands: Option<Arc<Commands>>,
+    /// Whether the server may use the BungeeCord plugin channel.
+    pub bungeecord: bool,
+    /// Whether the server may use the Magma control channel.
+    pub control_channel: bool,
+    /// Translations of the messages the proxy shows the client, if any.
+    pub messages: Option<Arc<Messages>>,
+    /// The MOTDs to show in status responses, if they differ from the server's.
+    pub motds: Option<Arc<Motds>>,
+    /// The favicon to show in status responses without one of their own.
+    pub favicon: Option<String>,
+    /// What status responses show as the sample of online players.
+    pub player_sample: PlayerSample,
+    /// Whether status responses claim the server enforces secure chat, if overridden.
+    pub enforce_secure_chat: Option<bool>,
+    /// What happens to the chat signing data the client sends the server.
+    pub chat_signing: ChatSigning,
+    /// The slowest the client may send a login packet, in bytes per second, or 0 if unlimited.
+    pub min_receive_rate: u32,
+    /// Which usernames the client may log in with, if restricted.
+    pub usernames: Option<Arc<UsernamePolicy>>,
+    /// Whether the server is in offline mode, so the client's profile must not reach it as-is.
+    pub offline_mode: bool,
+}
+
+impl From<&Route> for BridgeOptions {
+    fn from(route: &Route) -> Self {
+        Self {
+            compression: route.compression,
+            backpressure: route.backpressure,
+            commands: route.commands.clone(),
+            bungeecord: route.bungeecord,
+            control_channel: route.control_channel,
+            messages: route.messages.clone(),
+            motds: route.motds.clone(),
+            player_sample: route.player_sample.clone(),
+            enforce_secure_chat: route.enforce_secure_chat,
+            chat_signing: route.chat_signing,
+            usernames: route.usernames.clone(),
+            offline_mode: route.offline_mode,
+            ..Default::default()
+        }
+    }
 }
 
 /// Stores the state of a client connection.
 pub struct ClientState {
     /// The protocol state.
     protocol_state: ProtocolState,
-    /// Whether the connection is compressed.
-    compressed: bool,
-    /// The connection cryptor.
-    cryptor: Cryptor,
+    /// The compression threshold of the connection, if compressed.
+    compression_threshold: Option<i32>,
+    /// Whether the client and server have negotiated encryption between themselves, in which
+    /// case packets can no longer be read and are forwarded as raw bytes.
+    encrypted: bool,
 }
 
 /// Stores the state of a server connection.
 pub struct ServerState {
     /// The protocol state.
     protocol_state: ProtocolState,
-    /// Whether the connection is compressed.
-    compressed: bool,
+    /// The compression threshold of the connection, if compressed.
+    compression_threshold: Option<i32>,
+    /// Whether the client and server have negotiated encryption between themselves.
+    encrypted: bool,
 }
 
-impl From<ProtocolState> for BridgeState {
-    fn from(state: ProtocolState) -> Self {
+impl BridgeState {
+    /// Create a new bridge state, with both connections in the given state.
+    pub fn new(
+        state: ProtocolState,
+        protocol_version: ProtocolVersion,
+        options: BridgeOptions,
+        session: Arc<Session>,
+        to_server: mpsc::UnboundedSender<UncompressedPacket>,
+    ) -> Self {
         Self {
+            protocol_version,
             client: RwLock::new(ClientState {
-                protocol_state: state.clone(),
-                compressed: false,
-                cryptor: Cryptor::Uninitialized,
+                protocol_state: state,
+                compression_threshold: None,
+                encrypted: false,
             }),
             server: RwLock::new(ServerState {
                 protocol_state: state,
-                compressed: false,
+                compression_threshold: None,
+                encrypted: false,
             }),
+            compression: options.compression,
+            backpressure: options.backpressure,
+            players: options.players,
+            commands: options.commands,
+            bungeecord: options.bungeecord,
+            control_channel: options.control_channel,
+            messages: options.messages,
+            motds: options.motds,
+            favicon: options.favicon,
+            player_sample: options.player_sample,
+            enforce_secure_chat: options.enforce_secure_chat,
+            chat_signing: options.chat_signing,
+            min_receive_rate: options.min_receive_rate,
+            usernames: options.usernames,
+            offline_mode: options.offline_mode,
+            to_server,
+            session,
+            created_at: Instant::now(),
+            cancel: CancellationToken::new(),
         }
     }
+
+    /// The host and port clients reconnect to for a server named in the commands block.
+    fn server(&self, name: &str) -> Option<&(String, u16)> {
+        self.commands.as_ref()?.servers.get(name)
+    }
+
+    /// Translate a chat component the proxy shows the client into the client's locale.
+    fn localize(&self, component: &Value) -> Value {
+        match &self.messages {
+            Some(messages) => {
+                let locale = self.session.locale.lock().unwrap().clone();
+                messages.translate_component(locale.as_deref(), component)
+            }
+            None => component.clone(),
+        }
+    }
+
+    /// Give up the client's place among its IP's logins in progress, once it has logged in or the
+    /// rest of its login can no longer be followed.
+    fn end_login(&self) {
+        self.session.login_permit.lock().unwrap().take();
+    }
+
+    /// Set the protocol state of both connections.
+    async fn set_protocol_state(&self, protocol_state: ProtocolState) {
+        self.client.write().await.protocol_state = protocol_state;
+        self.server.write().await.protocol_state = protocol_state;
+    }
 }
 
-/// Consume the provided streams and bridge data between them.
+/// Re-encode a packet read from one connection for the other connection's compression threshold.
+///
+/// Packets are relayed verbatim when both connections agree, and are otherwise decompressed and
+/// recompressed at the given level.
+fn reencode(
+    packet: Packet,
+    from_threshold: Option<i32>,
+    to_threshold: Option<i32>,
+    level: Option<u8>,
+) -> Result<Packet> {
+    if from_threshold == to_threshold && level.is_none() {
+        return Ok(packet);
+    }
+    let packet = packet.decompress()?;
+    match to_threshold {
+        Some(threshold) => Ok(Packet::Compressed(
+            packet.compress_with_level(threshold, level.unwrap_or(6))?,
+        )),
+        None => Ok(Packet::Uncompressed(packet)),
+    }
+}
+
+/// Consume the provided streams and bridge data between them, recording the session.
+///
+/// The bridge closes as soon as either connection does, returning why. Bridges which end in an
+/// error carry the reason as its context, and can be told apart with
+/// [anyhow::Error::downcast_ref].
 #[tracing::instrument(skip_all, name = "bridge", fields(server_addr))]
-pub async fn create(
+pub async fn create<C: Stream, S: Stream>(
     state: ProtocolState,
-    client_stream: TcpStream,
-    server_stream: TcpStream,
-) -> Result<()> {
+    protocol_version: ProtocolVersion,
+    options: BridgeOptions,
+    session: Arc<Session>,
+    client_stream: C,
+    server_stream: S,
+) -> Result<CloseReason> {
     // create state
-    let state = Arc::new(BridgeState::from(state));
+    let (to_server, from_proxy) = mpsc::unbounded_channel();
+    let state = Arc::new(BridgeState::new(
+        state,
+        protocol_version,
+        options,
+        session,
+        to_server,
+    ));
 
     // split streams
-    let (client_rx, client_tx) = client_stream.into_split();
-    let (server_rx, server_tx) = server_stream.into_split();
+    let (client_rx, client_tx) = io::split(client_stream);
+    let (server_rx, server_tx) = io::split(server_stream);
 
     // spawn upstream and downstream tasks
-    let upstream = tokio::task::spawn(handle_upstream(state.clone(), client_rx, server_tx));
-    let downstream = tokio::task::spawn(handle_downstream(state.clone(), server_rx, client_tx));
+    // the tasks log within the connection's span, so their lines carry its ID, and report the
+    // connection if they panic
+    let mut upstream = tokio::task::spawn(crash::inherit(
+        handle_upstream(state.clone(), client_rx, server_tx, from_proxy).in_current_span(),
+    ));
+    let mut downstream = tokio::task::spawn(crash::inherit(
+        handle_downstream(state.clone(), server_rx, client_tx).in_current_span(),
+    ));
 
     debug!("Bridge initialized");
 
-    // wait for either task to finish
-    try_join!(upstream, downstream)
-        .map(|_| ())
-        .map_err(|e| e.into())
+    // the halves are cancelled however the bridge ends, even if this future is dropped
+    let _cancel = state.cancel.clone().drop_guard();
+
+    // wait for either task to finish, then cancel the other - which writes what it had queued and
+    // shuts its writer down - aborting it if its peer doesn't take the last packets in time
+    let (side, result, other) = select! {
+        result = &mut upstream => (Side::Client, result?, &mut downstream),
+        result = &mut downstream => (Side::Server, result?, &mut upstream),
+    };
+    state.cancel.cancel();
+    if timeout(SHUTDOWN_TIMEOUT, &mut *other).await.is_err() {
+        debug!("Bridge half did not shut down in time");
+        other.abort();
+    }
+    // sessions closed on purpose report why, rather than how their connections ended
+    if let Some((reason, _)) = state.session.closed_by.get() {
+        return Ok(*reason);
+    }
+    result.map(|()| CloseReason::eof(side))
 }
@@ -0,0 +1,221 @@
+//! Defines outboxes, which queue what one half of a bridge reads for the other half to write.
+//!
+//! The queue is bounded by the [Backpressure] high water mark - once a slow peer has that many
+//! bytes queued for it, reading from the other peer pauses until it catches up, and a peer which
+//! doesn't catch up in time is disconnected. The writer flushes whenever the queue empties, so a
+//! burst of packets is written together, and keeps writing what was queued after the reader stops.
+//!
+//! The bytes queued in both directions count towards the connection's memory budget, and a
+//! connection which exceeds it is disconnected.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    select,
+    sync::{mpsc, Semaphore},
+    time::timeout,
+};
+
+use crate::{
+    config::Backpressure,
+    io::{Packet, ProcotolAsyncWriteExt},
+};
+
+use super::Session;
+
+/// The bytes read at a time once a connection is encrypted.
+const RAW_CHUNK_SIZE: usize = 8192;
+
+/// How long what was queued before the reader stopped has to be written.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something queued to be written.
+pub enum Outgoing {
+    /// A packet.
+    Packet(Packet),
+    /// Raw bytes, relayed once the connection is encrypted.
+    Raw(Bytes),
+}
+
+impl Outgoing {
+    /// The number of bytes written.
+    fn size(&self) -> usize {
+        match self {
+            Outgoing::Packet(packet) => packet.size(),
+            Outgoing::Raw(bytes) => bytes.len(),
+        }
+    }
+}
+
+/// The sending half of an outbox, held by the reader.
+pub struct Outbox {
+    /// The queue, with the share of the budget each entry holds.
+    queue: mpsc::UnboundedSender<(Outgoing, u32)>,
+    /// The bytes which may still be queued before the reader waits.
+    budget: Arc<Semaphore>,
+    /// The limits of the queue.
+    backpressure: Backpressure,
+    /// The session, which counts the bytes queued in both directions.
+    session: Arc<Session>,
+}
+
+/// The receiving half of an outbox, drained by the writer.
+pub struct Drain {
+    /// The queue, with the share of the budget each entry holds.
+    queue: mpsc::UnboundedReceiver<(Outgoing, u32)>,
+    /// The bytes which may still be queued before the reader waits.
+    budget: Arc<Semaphore>,
+    /// The session, which counts the bytes queued in both directions.
+    session: Arc<Session>,
+}
+
+/// Create an outbox, and the drain writing its contents.
+pub fn channel(backpressure: Backpressure, session: Arc<Session>) -> (Outbox, Drain) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let budget = Arc::new(Semaphore::new(high_water_mark(&backpressure) as usize));
+    let outbox = Outbox {
+        queue: tx,
+        budget: budget.clone(),
+        backpressure,
+        session: session.clone(),
+    };
+    let drain = Drain {
+        queue: rx,
+        budget,
+        session,
+    };
+    (outbox, drain)
+}
+
+/// Run a reader, which owns the outbox, alongside the drain writing its contents to the writer.
+///
+/// Once the reader stops, even if it failed, what it queued is still written - so the packets a peer
+/// sent just before closing, such as the pong ending a status ping, reach the other peer. The drain
+/// failing stops the reader at once.
+pub async fn pump<F, W>(read: F, drain: Drain, writer: W) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+    W: AsyncWrite + Send + Unpin,
+{
+    let write = drain.run(writer);
+    tokio::pin!(read, write);
+    select! {
+        // the reader drops the outbox as it finishes, so the drain stops once it is empty
+        result = &mut read => {
+            let written = match timeout(FLUSH_TIMEOUT, write).await {
+                Ok(written) => written,
+                Err(elapsed) => Err(anyhow::Error::new(elapsed)
+                    .context("peer did not read the last packets in time")),
+            };
+            result.and(written)
+        }
+        result = &mut write => result,
+    }
+}
+
+/// The high water mark, as a number of permits.
+fn high_water_mark(backpressure: &Backpressure) -> u32 {
+    backpressure.high_water_mark.min(u32::MAX as usize) as u32
+}
+
+impl Outbox {
+    /// Queue something to be written, waiting while the queue is over the high water mark.
+    ///
+    /// Fails if the writer doesn't catch up within the slow peer timeout, or has stopped.
+    pub async fn send(&self, outgoing: Outgoing) -> Result<()> {
+        let size = outgoing.size();
+        let buffered = self.session.buffered.fetch_add(size, Ordering::Relaxed) + size;
+        if let Some(max) = self.backpressure.max_buffered {
+            if buffered > max {
+                bail!(
+                    "connection has {} bytes queued, over its budget of {}",
+                    buffered,
+                    max
+                );
+            }
+        }
+        // anything larger than the high water mark waits for the queue to empty
+        let cost = (size as u64).min(high_water_mark(&self.backpressure) as u64) as u32;
+        let permit = timeout(
+            self.backpressure.slow_peer_timeout,
+            self.budget.acquire_many(cost),
+        )
+        .await
+        .map_err(|elapsed| {
+            anyhow::Error::new(elapsed).context(format!(
+                "peer was over {} bytes behind for {:?}",
+                self.backpressure.high_water_mark, self.backpressure.slow_peer_timeout
+            ))
+        })?
+        // the budget is never closed
+        .map_err(|_| anyhow!("outbox closed"))?;
+        // the writer returns the budget once it has written the entry
+        permit.forget();
+        self.queue
+            .send((outgoing, cost))
+            .map_err(|_| anyhow!("writer stopped"))
+    }
+
+    /// Relay raw bytes from the given reader until it closes, adding them to the counter.
+    pub async fn relay<R: AsyncRead + Unpin>(
+        &self,
+        reader: &mut R,
+        count: &AtomicU64,
+    ) -> Result<()> {
+        let mut buf = BytesMut::new();
+        loop {
+            buf.reserve(RAW_CHUNK_SIZE);
+            let read = reader.read_buf(&mut buf).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            count.fetch_add(read as u64, Ordering::Relaxed);
+            self.send(Outgoing::Raw(buf.split().freeze())).await?;
+        }
+    }
+}
+
+impl Drain {
+    /// Write everything queued to the given writer until the outbox is dropped, then shut the
+    /// writer down.
+    pub async fn run<W: AsyncWrite + Send + Unpin>(mut self, writer: W) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        while let Some(entry) = self.queue.recv().await {
+            self.write(&mut writer, entry).await?;
+            // write whatever was queued meanwhile before flushing, so bursts are written together
+            while let Ok(entry) = self.queue.try_recv() {
+                self.write(&mut writer, entry).await?;
+            }
+            writer.flush().await?;
+        }
+        // the peer may already have gone, leaving nothing to shut down
+        let _ = writer.shutdown().await;
+        Ok(())
+    }
+
+    /// Write a queued entry, and return its share of the budgets.
+    async fn write<W: AsyncWrite + Send + Unpin>(
+        &self,
+        writer: &mut BufWriter<W>,
+        (outgoing, cost): (Outgoing, u32),
+    ) -> Result<()> {
+        let size = outgoing.size();
+        match outgoing {
+            Outgoing::Packet(packet) => writer.write_packet(&packet).await?,
+            Outgoing::Raw(bytes) => writer.write_all(&bytes).await?,
+        }
+        self.budget.add_permits(cost as usize);
+        self.session.buffered.fetch_sub(size, Ordering::Relaxed);
+        Ok(())
+    }
+}
@@ -1,137 +1,323 @@
 //! Handles the upstream connection from the client to the server.
 
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
-use anyhow::{bail, Result};
-
-use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde_json::json;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    io::{ReadHalf, WriteHalf},
+    select,
+    sync::mpsc,
+};
+use tracing::{debug, info, trace};
+
+use crate::{
+    config::ChatSigning,
+    io::{Packet, ProtocolAsyncReadExt, ProtocolReadExt, UncompressedPacket},
+    protocol::{
+        packets::{LoginPluginResponse, LoginStart, PacketCodec},
+        version::{Direction, LogicalPacket, ProtocolVersion, VersionedPacket},
+    },
+    username,
 };
 
-use crate::io::{ProcotolAsyncWriteExt, ProtocolAsyncReadExt};
+use super::{
+    closed, command,
+    outbox::{self, Outbox, Outgoing},
+    reencode, BridgeState, CloseReason, Control, ProtocolState, Side, Stream, STATUS_TIMEOUT,
+};
 
-use super::{BridgeState, ProtocolState};
+/// The message clients too new for an offline route are disconnected with.
+const UNSUPPORTED_VERSION_MESSAGE: &str =
+    "This server doesn't support your version of Minecraft yet.";
 
 /// Create a state machine to handle upstream packets - that is, packets from the client to the server.
-pub async fn handle_upstream(
+pub async fn handle_upstream<C: Stream, S: Stream>(
     state: Arc<BridgeState>,
-    mut client_rx: OwnedReadHalf,
-    mut server_tx: OwnedWriteHalf,
+    client_rx: ReadHalf<C>,
+    server_tx: WriteHalf<S>,
+    mut from_proxy: mpsc::UnboundedReceiver<UncompressedPacket>,
+) -> Result<()> {
+    let (outbox, drain) = outbox::channel(state.backpressure, state.session.clone());
+    let read = async move {
+        // packets from the proxy itself are queued between those read from the client, until the
+        // bridge closes
+        select! {
+            result = read_upstream(&state, client_rx, &outbox) => result,
+            result = forward_from_proxy(&state, &mut from_proxy, &outbox) => result,
+            _ = state.cancel.cancelled() => Ok(()),
+        }
+    };
+    // the reader's errors are classified first, so any left unclassified are the writer's
+    let read = async move { read.await.map_err(|err| closed(Side::Client, err)) };
+    outbox::pump(read, drain, server_tx)
+        .await
+        .map_err(|err| closed(Side::Server, err))
+}
+
+/// Queue the packets the proxy itself sends the server, for as long as the bridge is open.
+async fn forward_from_proxy(
+    state: &BridgeState,
+    from_proxy: &mut mpsc::UnboundedReceiver<UncompressedPacket>,
+    outbox: &Outbox,
+) -> Result<()> {
+    // the state holds the sender, so the channel never closes while bridged
+    while let Some(packet) = from_proxy.recv().await {
+        let server = state.server.read().await;
+        // encrypted connections can't be written to
+        if server.encrypted {
+            continue;
+        }
+        let packet = reencode(
+            Packet::Uncompressed(packet),
+            None,
+            server.compression_threshold,
+            None,
+        )?;
+        drop(server);
+        outbox.send(Outgoing::Packet(packet)).await?;
+    }
+    std::future::pending().await
+}
+
+/// Read packets from the client, queueing them for the server.
+async fn read_upstream<C: Stream>(
+    state: &BridgeState,
+    mut client_rx: ReadHalf<C>,
+    outbox: &Outbox,
 ) -> Result<()> {
     loop {
-        let protocol_state = &{ state.server.read().await }.protocol_state;
-        match protocol_state {
-            ProtocolState::Handshaking => {
-                unreachable!("downstream handshake")
+        let (encrypted, protocol_state) = {
+            let client = state.client.read().await;
+            (client.encrypted, client.protocol_state)
+        };
+        // once encrypted, packets can no longer be read - simply relay bytes
+        if encrypted {
+            return outbox.relay(&mut client_rx, &state.session.upstream).await;
+        }
+
+        // read the next frame before inspecting the state, as it may change while we wait - but
+        // clients still logging in must send it promptly, so they can't drip bytes to hold the
+        // connection open, and status pings are only given so long to finish
+        let frame = match protocol_state {
+            ProtocolState::Status => client_rx.read_frame_timeout(STATUS_TIMEOUT).await?,
+            ProtocolState::Login if state.min_receive_rate > 0 => {
+                client_rx.read_frame_at_rate(state.min_receive_rate).await?
             }
-            ProtocolState::Status => handle_upstream_status(&mut client_rx, &mut server_tx).await?,
-            ProtocolState::Login => {
-                handle_upstream_login(state.clone(), &mut client_rx, &mut server_tx).await?
+            _ => client_rx.read_frame().await?,
+        };
+        state
+            .session
+            .upstream
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        // packets which can't be handled close the bridge as undecodable
+        let packet = inspect_upstream(state, frame)
+            .await
+            .context(CloseReason::DecodeError)?;
+        if let Some(packet) = packet {
+            outbox.send(Outgoing::Packet(packet)).await?;
+        }
+    }
+}
+
+/// Decode a frame read from the client, returning the packet to queue for the server, if it isn't
+/// withheld.
+async fn inspect_upstream(state: &BridgeState, frame: Bytes) -> Result<Option<Packet>> {
+    let (protocol_state, client_threshold, server_threshold) = {
+        let client = state.client.read().await;
+        let server = state.server.read().await;
+        (
+            client.protocol_state,
+            client.compression_threshold,
+            server.compression_threshold,
+        )
+    };
+    let mut packet = Packet::from_frame(frame, client_threshold.is_some())?;
+
+    // inspect the packet, updating state before it is forwarded
+    let logical_packet =
+        state
+            .protocol_version
+            .logical_packet(protocol_state, Direction::Serverbound, packet.id()?);
+    match protocol_state {
+        ProtocolState::Handshaking => {
+            unreachable!("upstream handshake")
+        }
+        ProtocolState::Status => {}
+        ProtocolState::Login => {
+            if handle_upstream_login(state, logical_packet, &mut packet).await? {
+                return Ok(None);
+            }
+        }
+        ProtocolState::Configuration => {
+            handle_upstream_configuration(state, logical_packet, &packet).await?
+        }
+        ProtocolState::Play => {
+            handle_upstream_play(state, logical_packet, &packet).await?;
+            // without the chat session, the server treats the player's chat as unsigned
+            if state.chat_signing == ChatSigning::Strip
+                && logical_packet == Some(LogicalPacket::PlayerSession)
+            {
+                trace!("Withholding the client's chat session");
+                return Ok(None);
             }
-            ProtocolState::Play => {
-                handle_upstream_play(state.clone(), &mut client_rx, &mut server_tx).await?
+            if command::intercept(state, logical_packet, &packet)? {
+                return Ok(None);
             }
         }
     }
-}
 
-/// Handle status packets.
-async fn handle_upstream_status(
-    client_rx: &mut OwnedReadHalf,
-    server_tx: &mut OwnedWriteHalf,
-) -> Result<()> {
-    let packet = client_rx.read_uncompressed_packet().await?;
-    server_tx.write_uncompressed_packet(&packet).await?;
-    Ok(())
+    Ok(Some(reencode(
+        packet,
+        client_threshold,
+        server_threshold,
+        None,
+    )?))
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-pub struct MojangAuthResponse {
-    #[serde(rename = "id")]
-    pub id: String,
-    #[serde(rename = "name")]
-    pub name: String,
-    #[serde(rename = "properties")]
-    pub properties: Vec<Property>,
+/// Handle login packets, returning whether the packet is withheld from the server.
+async fn handle_upstream_login(
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &mut Packet,
+) -> Result<bool> {
+    // unknown versions have no registry, but every version starts its login with the username, so
+    // their clients are held to the same policies
+    let logical_packet = match logical_packet {
+        None if !state.protocol_version.is_known() && packet.id()? == LoginStart::ID => {
+            Some(LogicalPacket::LoginStart)
+        }
+        logical_packet => logical_packet,
+    };
+    match logical_packet {
+        Some(LogicalPacket::LoginStart) => {
+            let mut login_start = LoginStart::decode_versioned(
+                &packet.clone().decompress()?,
+                state.protocol_version,
+            )?;
+            debug!("Client logging in as {}", login_start.username);
+            // rejected clients are disconnected before the server hears of their login
+            if let Some(usernames) = state
+                .usernames
+                .as_ref()
+                .filter(|usernames| !usernames.allows(&login_start.username))
+            {
+                info!("Rejecting username {:?}", login_start.username);
+                refuse_login(
+                    state,
+                    format!("username {:?} rejected", login_start.username),
+                    &usernames.message,
+                );
+                return Ok(true);
+            }
+            // 1.19 - 1.19.2 clients send their public key with the login start, which is written
+            // back without it
+            if state.chat_signing == ChatSigning::Strip
+                && state.protocol_version < ProtocolVersion::V1_19_3
+            {
+                trace!("Withholding the client's public key");
+                *packet =
+                    Packet::Uncompressed(login_start.encode_versioned(state.protocol_version)?);
+            }
+            // offline backends would otherwise take the uuid and public key the client claims -
+            // which can't be replaced in versions newer than Magma knows
+            if state.offline_mode && state.protocol_version > ProtocolVersion::LATEST {
+                info!(
+                    "Refusing protocol version {} on an offline route",
+                    state.protocol_version
+                );
+                refuse_login(
+                    state,
+                    format!("protocol version {} refused", state.protocol_version),
+                    UNSUPPORTED_VERSION_MESSAGE,
+                );
+                return Ok(true);
+            }
+            if state.offline_mode {
+                trace!("Replacing the client's uuid with its offline uuid");
+                login_start.uuid = login_start
+                    .uuid
+                    .map(|_| username::offline_uuid(&login_start.username));
+                *packet =
+                    Packet::Uncompressed(login_start.encode_versioned(state.protocol_version)?);
+            }
+            let _ = state.session.username.set(login_start.username);
+        }
+        // the client and server are negotiating encryption - we can no longer read packets
+        Some(LogicalPacket::EncryptionResponse) => {
+            trace!("Client enabled encryption");
+            state.client.write().await.encrypted = true;
+            state.end_login();
+        }
+        // relay answers to login plugin queries, such as Velocity forwarding, as-is
+        Some(LogicalPacket::LoginPluginResponse) => {
+            let response = LoginPluginResponse::decode(&packet.clone().decompress()?)?;
+            trace!(
+                "Relaying login plugin response {} (understood: {})",
+                response.message_id,
+                response.data.is_some()
+            );
+        }
+        // 1.20.2+ clients enter the configuration state once they acknowledge the login
+        Some(LogicalPacket::LoginAcknowledged) => {
+            state.client.write().await.protocol_state = ProtocolState::Configuration;
+        }
+        _ => {}
+    }
+    Ok(false)
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-pub struct Property {
-    #[serde(rename = "name")]
-    pub name: String,
-    #[serde(rename = "value")]
-    pub value: String,
-    #[serde(rename = "signature")]
-    pub signature: String,
+/// Disconnect a client before the server hears of its login, recording why.
+fn refuse_login(state: &BridgeState, description: String, message: &str) {
+    let _ = state
+        .session
+        .closed_by
+        .set((CloseReason::Kicked, description));
+    let reason = json!({ "text": message });
+    let _ = state.session.control.send(Control::Disconnect(reason));
 }
 
-/// Handle login packets.
-async fn handle_upstream_login(
-    state: Arc<BridgeState>,
-    client_rx: &mut OwnedReadHalf,
-    server_tx: &mut OwnedWriteHalf,
+/// Handle configuration packets.
+async fn handle_upstream_configuration(
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &Packet,
 ) -> Result<()> {
-    // read the login start packet from the client
-    let login_start = client_rx.read_uncompressed_packet().await?;
-    server_tx.write_uncompressed_packet(&login_start).await?;
-
-    // read login info
-    let mut login_start = login_start.as_cursor();
-    let username = login_start.read_string().await?;
-    let uuid = login_start.read_uuid().await?;
-
-    // read the client encryption response packet
-    let encryption_response = client_rx.read_uncompressed_packet().await?;
-    if encryption_response.id != 0x01 {
-        bail!(
-            "Expected encryption response packet, got {:?}",
-            encryption_response.id
-        );
+    match logical_packet {
+        Some(LogicalPacket::AcknowledgeFinishConfiguration) => {
+            state.client.write().await.protocol_state = ProtocolState::Play;
+        }
+        Some(LogicalPacket::ConfigurationClientInformation) => record_locale(state, packet)?,
+        _ => {}
     }
-    let mut encryption_response = encryption_response.as_cursor();
-    let shared_secret_length = encryption_response.read_var_int().await?;
-    let mut shared_secret = vec![0u8; shared_secret_length as usize];
-    let shared_secret = encryption_response.read_exact(&mut shared_secret).await?;
-    let verify_token_length = encryption_response.read_var_int().await?;
-    let mut verify_token = vec![0u8; verify_token_length as usize];
-    let verify_token = encryption_response.read_exact(&mut verify_token).await?;
-
-    // make auth request to mojang
-    let response: MojangAuthResponse = reqwest::get(format!(
-		"https://sessionserver.mojang.com/session/minecraft/hasJoined?username={}&serverId={}&ip={}",
-		username,
-		"",
-		"",
-	))
-    .await?
-    .json()
-    .await?;
-
     Ok(())
 }
 
 /// Handle play packets.
 async fn handle_upstream_play(
-    state: Arc<BridgeState>,
-    client_rx: &mut OwnedReadHalf,
-    server_tx: &mut OwnedWriteHalf,
+    state: &BridgeState,
+    logical_packet: Option<LogicalPacket>,
+    packet: &Packet,
 ) -> Result<()> {
-    // buffers for reading data
-    let mut data = [0u8; 1024];
-    client_rx.read_exact(&mut data).await?;
-    // lock cryptor and decrypt packet
-    let raw = match {
-        let mut client = state.client.write().await;
-        client.cryptor.next_packet(&mut data).await?
-    } {
-        Some(raw) => raw,
-        None => return Ok(()),
-    };
-    // write packet to server
-    server_tx.write_all(&raw).await?;
+    match logical_packet {
+        // once the client acknowledges a reconfiguration, both sides return to configuration -
+        // the server will only send configuration packets once it receives the acknowledgement
+        Some(LogicalPacket::ConfigurationAcknowledged) => {
+            state.set_protocol_state(ProtocolState::Configuration).await;
+        }
+        // clients before 1.20.2 only declare their settings once playing
+        Some(LogicalPacket::PlayClientInformation) => record_locale(state, packet)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Record the locale a client declares in its settings, which it resends whenever they change.
+fn record_locale(state: &BridgeState, packet: &Packet) -> Result<()> {
+    let packet = packet.clone().decompress()?;
+    let locale = ProtocolReadExt::read_string(&mut packet.as_cursor())?.to_lowercase();
+    trace!("Client declared locale {}", locale);
+    *state.session.locale.lock().unwrap() = Some(locale);
     Ok(())
 }
@@ -0,0 +1,377 @@
+//! A minimal Minecraft protocol client.
+//!
+//! The client speaks just enough of the protocol to drive synthetic connections against a proxy
+//! or server - it is used by tooling such as `magma bench`, and is not intended to be a complete
+//! client implementation.
+//!
+//! Logins are offline-mode by default. Online-mode servers need a [Session], which the client uses
+//! to join the server through the session server before enabling encryption.
+//!
+//! Most callers should use [ClientBuilder], which connects, handshakes and logs in in one go:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use magma::client::ClientBuilder;
+//!
+//! let addr = "127.0.0.1:25565".parse()?;
+//! let status = ClientBuilder::offline("Steve").status(addr).await?;
+//! let mut client = ClientBuilder::offline("Steve")
+//!     .domain("play.example.com")
+//!     .login(addr)
+//!     .await?;
+//! let packet = client.recv().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use rand::RngCore;
+use rsa::{pkcs8::DecodePublicKey, Pkcs1v15Encrypt, RsaPublicKey};
+use serde_json::json;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use uuid::Uuid;
+
+use crate::{
+    bridge::ProtocolState,
+    cryptor::{server_hash, CipherStream},
+    io::{ProcotolAsyncWriteExt, ProtocolAsyncReadExt, UncompressedPacket},
+    protocol::{
+        packets::{
+            Disconnect, EncryptionRequest, EncryptionResponse, Handshake, LoginPluginRequest,
+            LoginPluginResponse, LoginStart, LoginSuccess, PacketCodec, PingRequest, PongResponse,
+            SetCompression, StatusRequest, StatusResponse,
+        },
+        version::{LogicalPacket, ProtocolVersion, VersionedPacket},
+    },
+};
+
+/// The session server used by vanilla clients.
+pub const MOJANG_SESSION_SERVER: &str = "https://sessionserver.mojang.com";
+
+/// The credentials of a player, used to join online-mode servers.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The access token of the player.
+    pub access_token: String,
+    /// The UUID of the player's profile.
+    pub uuid: Uuid,
+    /// The base URL of the session server to join through.
+    pub session_server: String,
+}
+
+impl Session {
+    /// Create a session which joins through Mojang's session server.
+    pub fn new(access_token: String, uuid: Uuid) -> Self {
+        Self {
+            access_token,
+            uuid,
+            session_server: MOJANG_SESSION_SERVER.to_string(),
+        }
+    }
+
+    /// Tell the session server the player is joining the server with the given hash.
+    pub async fn join(&self, server_hash: &str) -> Result<()> {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/session/minecraft/join",
+                self.session_server.trim_end_matches('/')
+            ))
+            .json(&json!({
+                "accessToken": self.access_token,
+                "selectedProfile": self.uuid.simple().to_string(),
+                "serverId": server_hash,
+            }))
+            .send()
+            .await?
+            .error_for_status()
+            .context("the session server rejected the join")?;
+        Ok(())
+    }
+}
+
+/// Builds connections to a Minecraft server.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    username: String,
+    session: Option<Session>,
+    protocol_version: ProtocolVersion,
+    domain: Option<String>,
+}
+
+impl ClientBuilder {
+    /// Build clients which log in offline with the given username.
+    pub fn offline(username: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            session: None,
+            protocol_version: ProtocolVersion::DEFAULT,
+            domain: None,
+        }
+    }
+
+    /// Log in online with the given session, so online-mode servers can be joined.
+    pub fn online(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Set the protocol version sent in the handshake. Defaults to [ProtocolVersion::DEFAULT].
+    pub fn version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// Set the server address sent in the handshake, which proxies route on. Defaults to the IP
+    /// of the address connected to.
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// The server address to send in the handshake to the given address.
+    fn domain_for(&self, addr: SocketAddr) -> String {
+        self.domain.clone().unwrap_or(addr.ip().to_string())
+    }
+
+    /// Connect to the server and ping it, returning its status.
+    pub async fn status(&self, addr: SocketAddr) -> Result<StatusResponse> {
+        Client::connect(addr)
+            .await?
+            .status(self.protocol_version, &self.domain_for(addr), addr.port())
+            .await
+    }
+
+    /// Connect to the server and log in, returning the client once Login Success has been
+    /// received.
+    pub async fn login(&self, addr: SocketAddr) -> Result<Client> {
+        let mut client = Client::connect(addr).await?;
+        client
+            .handshake(
+                self.protocol_version,
+                &self.domain_for(addr),
+                addr.port(),
+                ProtocolState::Login,
+            )
+            .await?;
+        client
+            .login(self.protocol_version, &self.username, self.session.as_ref())
+            .await?;
+        Ok(client)
+    }
+}
+
+/// A connection to a Minecraft server.
+pub struct Client {
+    /// The underlying stream, which is encrypted once an online-mode login enables it.
+    stream: CipherStream<TcpStream>,
+    /// The compression threshold, if compression has been enabled by the server.
+    compression_threshold: Option<i32>,
+    /// The UUID assigned by the server, once logged in.
+    uuid: Option<Uuid>,
+}
+
+impl Client {
+    /// Connect to the server at the given address.
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .context("failed to connect to server")?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: CipherStream::new(stream),
+            compression_threshold: None,
+            uuid: None,
+        })
+    }
+
+    /// Send a handshake packet, switching the connection to the given state.
+    pub async fn handshake(
+        &mut self,
+        protocol_version: ProtocolVersion,
+        server_address: &str,
+        server_port: u16,
+        next_state: ProtocolState,
+    ) -> Result<()> {
+        let handshake = Handshake {
+            protocol_version: protocol_version.0,
+            server_address: server_address.to_string(),
+            server_port,
+            next_state,
+        };
+        self.send(&handshake.encode()?).await
+    }
+
+    /// Ping the server, returning its status.
+    ///
+    /// This performs the handshake itself, and checks the server echoes the ping payload.
+    pub async fn status(
+        &mut self,
+        protocol_version: ProtocolVersion,
+        server_address: &str,
+        server_port: u16,
+    ) -> Result<StatusResponse> {
+        self.handshake(
+            protocol_version,
+            server_address,
+            server_port,
+            ProtocolState::Status,
+        )
+        .await?;
+        self.send(&StatusRequest {}.encode()?).await?;
+        let status = StatusResponse::decode(&self.recv().await?)?;
+
+        let payload = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        self.send(&PingRequest { payload }.encode()?).await?;
+        let pong = PongResponse::decode(&self.recv().await?)?;
+        if pong.payload != payload {
+            bail!("Server answered ping {} with {}", payload, pong.payload);
+        }
+        Ok(status)
+    }
+
+    /// Perform an offline-mode login with the given username.
+    ///
+    /// Returns the UUID assigned by the server once Login Success has been received.
+    pub async fn login_offline(
+        &mut self,
+        protocol_version: ProtocolVersion,
+        username: &str,
+    ) -> Result<Uuid> {
+        self.login(protocol_version, username, None).await
+    }
+
+    /// Perform an online-mode login, joining the server with the given session.
+    ///
+    /// Servers which don't request encryption are joined as they would be offline. Returns the
+    /// UUID assigned by the server once Login Success has been received.
+    pub async fn login_online(
+        &mut self,
+        protocol_version: ProtocolVersion,
+        username: &str,
+        session: &Session,
+    ) -> Result<Uuid> {
+        self.login(protocol_version, username, Some(session)).await
+    }
+
+    /// Log in, encrypting the connection if the server requests it and a session is given.
+    async fn login(
+        &mut self,
+        protocol_version: ProtocolVersion,
+        username: &str,
+        session: Option<&Session>,
+    ) -> Result<Uuid> {
+        let login_start = LoginStart {
+            username: username.to_string(),
+            uuid: Some(session.map_or(Uuid::nil(), |session| session.uuid)),
+        };
+        self.send(&login_start.encode_versioned(protocol_version)?)
+            .await?;
+
+        loop {
+            let packet = self.recv().await?;
+            match packet.id {
+                Disconnect::ID => {
+                    let disconnect = Disconnect::decode(&packet)?;
+                    bail!("Disconnected during login: {}", disconnect.reason)
+                }
+                EncryptionRequest::ID => {
+                    let Some(session) = session else {
+                        bail!("Server requested encryption - online-mode servers need a session")
+                    };
+                    self.enable_encryption(&EncryptionRequest::decode(&packet)?, session)
+                        .await?;
+                }
+                LoginSuccess::ID => {
+                    let login_success = LoginSuccess::decode(&packet)?;
+                    // 1.20.2+ clients must acknowledge the login before entering configuration
+                    if let Some(id) = protocol_version.packet_id(LogicalPacket::LoginAcknowledged) {
+                        self.send(&UncompressedPacket {
+                            id,
+                            data: Bytes::new(),
+                        })
+                        .await?;
+                    }
+                    self.uuid = Some(login_success.uuid);
+                    return Ok(login_success.uuid);
+                }
+                SetCompression::ID => {
+                    let threshold = SetCompression::decode(&packet)?.threshold;
+                    self.compression_threshold = (threshold >= 0).then_some(threshold);
+                }
+                // we don't understand any plugin channels
+                LoginPluginRequest::ID => {
+                    let request = LoginPluginRequest::decode(&packet)?;
+                    let response = LoginPluginResponse {
+                        message_id: request.message_id,
+                        data: None,
+                    };
+                    self.send(&response.encode()?).await?;
+                }
+                id => bail!("Unexpected packet during login: {:#04x}", id),
+            }
+        }
+    }
+
+    /// Answer an encryption request - join the server through the session server, send the
+    /// encrypted shared secret and verify token, and encrypt the connection from then on.
+    async fn enable_encryption(
+        &mut self,
+        request: &EncryptionRequest,
+        session: &Session,
+    ) -> Result<()> {
+        let key = RsaPublicKey::from_public_key_der(&request.public_key)
+            .context("server sent an invalid public key")?;
+        let mut secret = [0u8; 16];
+        let response = {
+            let mut rng = rand::thread_rng();
+            rng.fill_bytes(&mut secret);
+            EncryptionResponse {
+                shared_secret: key.encrypt(&mut rng, Pkcs1v15Encrypt, &secret)?,
+                verify_token: key.encrypt(&mut rng, Pkcs1v15Encrypt, &request.verify_token)?,
+            }
+        };
+
+        // the server checks this join before accepting the response
+        session
+            .join(&server_hash(
+                &request.server_id,
+                &secret,
+                &request.public_key,
+            ))
+            .await?;
+        self.send(&response.encode()?).await?;
+        self.stream.enable(&secret);
+        Ok(())
+    }
+
+    /// The UUID assigned by the server, once logged in.
+    pub fn uuid(&self) -> Option<Uuid> {
+        self.uuid
+    }
+
+    /// Send a packet to the server, compressing it if required.
+    pub async fn send(&mut self, packet: &UncompressedPacket) -> Result<()> {
+        self.stream
+            .write_framed_packet(packet, self.compression_threshold)
+            .await?;
+        // encrypted writes are buffered
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Receive the next packet from the server.
+    pub async fn recv(&mut self) -> Result<UncompressedPacket> {
+        self.stream
+            .read_framed_packet(self.compression_threshold)
+            .await
+    }
+}
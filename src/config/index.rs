@@ -0,0 +1,68 @@
+//! Indexes the routes of a listener by domain, so connections are matched to one without scanning
+//! every route - listeners hosting thousands of servers would otherwise compare each handshake
+//! with every domain.
+
+use std::collections::HashMap;
+
+use super::Route;
+
+/// The routes of a listener, indexed by domain.
+///
+/// Plain domains are looked up directly. Templated routes are bucketed by the part of their
+/// suffix from its first dot, such as `.example.com` for `{name}-mc.example.com`, so only the
+/// patterns sharing a parent domain with the hostname are tried.
+#[derive(Debug, Default)]
+pub struct RouteIndex {
+    /// The positions of the routes with a plain domain, by their lowercased domain.
+    exact: HashMap<String, usize>,
+    /// The positions of the templated routes, in order, by the parent domain of their suffix.
+    wildcards: HashMap<String, Vec<usize>>,
+}
+
+impl RouteIndex {
+    /// Index a listener's routes. Where a domain is repeated, the first route with it is kept.
+    pub fn new(routes: &[Route]) -> Self {
+        let mut index = Self::default();
+        for (i, route) in routes.iter().enumerate() {
+            match &route.pattern {
+                Some(pattern) => index
+                    .wildcards
+                    .entry(parent(pattern.suffix()).to_string())
+                    .or_default()
+                    .push(i),
+                None => {
+                    index.exact.entry(route.from.to_lowercase()).or_insert(i);
+                }
+            }
+        }
+        index
+    }
+
+    /// Find the position of the route with a plain domain, ignoring case.
+    pub fn exact(&self, domain: &str) -> Option<usize> {
+        self.exact.get(&domain.to_lowercase()).copied()
+    }
+
+    /// Find the positions of the templated routes whose pattern could match a domain, in the order
+    /// the routes were declared.
+    pub fn wildcards(&self, domain: &str) -> Vec<usize> {
+        let domain = domain.to_lowercase();
+        // patterns whose suffix has no dot are bucketed under the empty parent
+        let parents = domain
+            .match_indices('.')
+            .map(|(i, _)| &domain[i..])
+            .chain([""]);
+        let mut candidates: Vec<_> = parents
+            .filter_map(|parent| self.wildcards.get(parent))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates
+    }
+}
+
+/// The parent domain of a pattern's suffix, from its first dot, or empty if it has none.
+fn parent(suffix: &str) -> &str {
+    suffix.find('.').map_or("", |i| &suffix[i..])
+}
@@ -1,15 +1,37 @@
 //! Defines the configuration for Magma, as well as migration logic.
 
+mod index;
 mod v1;
 
-use std::{net::SocketAddr, path::Path};
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Result};
 use mc_chat::TextComponent;
+use rsa::RsaPublicKey;
 use serde::Deserialize;
 use tokio::fs::read_to_string;
+use toml::{Table, Value};
 
+pub use self::index::RouteIndex;
 use self::v1::ConfigV1;
+use crate::{
+    error::ConfigError,
+    hook::Hook,
+    link::LinkCompression,
+    protocol::version::ProtocolVersion,
+    schedule::Schedule,
+    template::{Pattern, Resolver},
+    username::UsernamePolicy,
+    wake::Wake,
+};
 
 /// The internal configuration definition. Magma automatially maps from
 /// configuration files to this structure.
@@ -19,32 +41,415 @@ pub struct MagmaConfig {
     pub debug: bool,
     /// A list of proxy servers.
     pub proxies: Vec<Proxy>,
+    /// The tunnel this instance takes part in, if any.
+    pub tunnel: Option<TunnelConfig>,
+    /// The sinks connection lifecycle events are published to.
+    pub events: Vec<EventSink>,
+    /// The admin API, if enabled.
+    pub admin: Option<AdminConfig>,
+    /// The connection history store, if enabled.
+    pub history: Option<HistoryConfig>,
+    /// The IP reputation lookup, if enabled.
+    pub reputation: Option<ReputationConfig>,
+    /// The file security events are written to, if enabled.
+    pub security_log: Option<PathBuf>,
+    /// The tarpit denied connections are held in, if enabled.
+    pub tarpit: Option<TarpitConfig>,
+    /// The limit on open file descriptors raised to at startup, if any.
+    pub max_open_files: Option<u64>,
+    /// The RCON proxies.
+    pub rcon: Vec<RconConfig>,
+    /// The active health checks of backends.
+    pub health_checks: Vec<HealthCheckConfig>,
+    /// The backends stopped while idle.
+    pub idle_shutdowns: Vec<IdleConfig>,
+    /// Where crash reports are written and sent.
+    pub crash_reports: CrashConfig,
+    /// The memory watchdog, if enabled.
+    pub memory: Option<MemoryConfig>,
+    /// The statsd exporter, if enabled.
+    pub statsd: Option<StatsdConfig>,
+    /// The InfluxDB exporter, if enabled.
+    pub influx: Option<InfluxConfig>,
+    /// The announcements sent on a schedule.
+    pub announcements: Vec<Announcement>,
+    /// How logins to authenticating routes are checked with the session server.
+    pub authentication: AuthConfig,
+}
+
+/// The configuration of the session server lookups authenticating routes make.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// The base URL of the session server.
+    pub session_server: String,
+    /// The maximum number of lookups in flight at once.
+    pub max_concurrent_lookups: usize,
+    /// The maximum number of lookups waiting for one of those to finish, past which logins are
+    /// rejected.
+    pub max_queued_lookups: usize,
+    /// The message logins are rejected with while the queue is full.
+    pub overflow_message: String,
+}
+
+/// A message shown to the players of a set of routes on a schedule.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    /// The chat component shown.
+    pub message: serde_json::Value,
+    /// The routes whose players are shown the message, or every route if empty.
+    pub routes: Vec<String>,
+    /// Whether the message is shown above the hotbar, rather than in chat.
+    pub action_bar: bool,
+    /// When the message is shown.
+    pub timing: AnnouncementTiming,
+}
+
+/// When an announcement is shown.
+#[derive(Debug, Clone)]
+pub enum AnnouncementTiming {
+    /// Repeatedly, on an interval.
+    Every(Duration),
+    /// At the given times of day, in a timezone.
+    At {
+        timezone: chrono_tz::Tz,
+        times: Vec<chrono::NaiveTime>,
+    },
+}
+
+/// The configuration of the InfluxDB exporter.
+#[derive(Debug, Clone)]
+pub struct InfluxConfig {
+    /// The URL line protocol is posted to.
+    pub url: String,
+    /// The token sent in the `Authorization` header, if any.
+    pub token: Option<String>,
+    /// The prefix of every measurement name.
+    pub prefix: String,
+    /// The tags added to every point.
+    pub tags: BTreeMap<String, String>,
+    /// How often metrics are written.
+    pub interval: Duration,
+}
+
+/// The configuration of the statsd exporter.
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    /// The address of the agent, as `host:port`.
+    pub address: String,
+    /// The prefix of every metric name.
+    pub prefix: String,
+    /// The tags sent with every metric.
+    pub tags: BTreeMap<String, String>,
+    /// How often gauges are sent.
+    pub interval: Duration,
+}
+
+/// The configuration of the memory watchdog.
+#[derive(Debug, Clone)]
+pub struct MemoryConfig {
+    /// The resident set size, in bytes, past which logins are refused.
+    pub rss_limit: u64,
+}
+
+/// The configuration of crash reports.
+#[derive(Debug, Clone)]
+pub struct CrashConfig {
+    /// The directory reports are written to.
+    pub directory: PathBuf,
+    /// The URL reports are posted to as JSON, if any.
+    pub webhook: Option<String>,
+}
+
+/// The configuration of stopping a set of backends while idle.
+#[derive(Debug)]
+pub struct IdleConfig {
+    /// The routes whose players keep the backends running.
+    pub domains: Vec<String>,
+    /// The backends to stop.
+    pub targets: Vec<SocketAddr>,
+    /// How long the routes must be empty before the backends are stopped.
+    pub after: Duration,
+    /// The hook stopping a backend.
+    pub hook: Hook,
+}
+
+/// The configuration of active health checks for a set of backends.
+#[derive(Debug)]
+pub struct HealthCheckConfig {
+    /// The backends to probe.
+    pub targets: Vec<SocketAddr>,
+    /// How often backends are probed.
+    pub interval: Duration,
+    /// How long a probe may take before it fails.
+    pub timeout: Duration,
+    /// How backends are probed.
+    pub probe: Probe,
+    /// Whether to fetch the favicon of healthy backends, to show while they are down.
+    pub fetch_favicon: bool,
+}
+
+/// How a backend is probed.
+#[derive(Debug)]
+pub enum Probe {
+    /// Connect to the backend.
+    Tcp,
+    /// Log in to the backend's RCON console, optionally running a command.
+    Rcon {
+        /// The RCON port of the backend.
+        port: u16,
+        /// The RCON password of the backend.
+        password: String,
+        /// A command to run once logged in.
+        command: Option<String>,
+        /// Text the command's response must contain.
+        expect: Option<String>,
+    },
+}
+
+/// The configuration of an RCON proxy.
+#[derive(Debug)]
+pub struct RconConfig {
+    /// The binding address of the proxy.
+    pub listen_addr: SocketAddr,
+    /// The password clients must log in with.
+    pub password: String,
+    /// The password used to log in to the backends.
+    pub backend_password: String,
+    /// The RCON addresses of the backends sessions are relayed to.
+    pub targets: Vec<SocketAddr>,
+}
+
+/// The configuration of the tarpit.
+#[derive(Debug, Clone)]
+pub struct TarpitConfig {
+    /// How long denied connections are held.
+    pub duration: Duration,
+    /// The maximum number of connections held at once.
+    pub max_connections: usize,
+}
+
+/// The configuration of IP reputation lookups.
+#[derive(Debug)]
+pub struct ReputationConfig {
+    /// The path to a local database of VPN and datacenter networks.
+    pub database: Option<PathBuf>,
+    /// The external API to consult, if any.
+    pub api: Option<ReputationApi>,
+    /// How long API results are cached.
+    pub cache_ttl: Duration,
+}
+
+/// An external IP reputation API.
+#[derive(Debug)]
+pub struct ReputationApi {
+    /// The URL to query, with `{ip}` replaced by the client's address.
+    pub url: String,
+    /// The JSON pointer to the verdict in the response, with `{ip}` replaced by the client's
+    /// address. The verdict may be a boolean, a number, or a string such as `"yes"`.
+    pub pointer: String,
+}
+
+/// The configuration of the admin API.
+#[derive(Debug)]
+pub struct AdminConfig {
+    /// The binding address of the admin API.
+    pub listen_addr: SocketAddr,
+    /// The mutual TLS configuration, if clients must connect with TLS.
+    pub tls: Option<TlsConfig>,
+    /// The bearer tokens clients authenticate with. The API is open if there are none.
+    pub tokens: Vec<AdminToken>,
+}
+
+/// A bearer token for the admin API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminToken {
+    /// The token.
+    pub token: String,
+    /// What the token allows.
+    pub role: AdminRole,
+}
+
+/// What an admin API token allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// View routes, backends and sessions.
+    ReadOnly,
+    /// Everything read-only tokens allow, as well as kicking sessions and modifying routes.
+    Operator,
+}
+
+/// The configuration of the connection history store.
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    /// The path to the SQLite database.
+    pub path: PathBuf,
+    /// How long completed sessions are kept.
+    pub retention: Duration,
+}
+
+/// An external system connection lifecycle events are published to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventSink {
+    /// Publish events to NATS, under `<subject>.<event type>`.
+    Nats {
+        /// The URL of the NATS server.
+        url: String,
+        /// The subject prefix to publish to.
+        subject: String,
+    },
+    /// Publish events to a Kafka topic, keyed by event type.
+    Kafka {
+        /// The addresses of the Kafka brokers.
+        brokers: Vec<String>,
+        /// The topic to publish to.
+        topic: String,
+    },
 }
 
 /// The configuration for a proxy server.
 #[derive(Debug)]
 pub struct Proxy {
     /// The protocol version to broadcast.
-    pub protocol_version: usize,
+    pub protocol_version: ProtocolVersion,
     /// The binding address of the server.
     pub listen_addr: SocketAddr,
+    /// The transport clients connect with.
+    pub transport: Transport,
     /// A list of routes this server uses.
     pub routes: Vec<Route>,
+    /// The routes indexed by domain, built once the routes are.
+    pub route_index: RouteIndex,
     /// The fallback method this server uses.
     pub fallback_method: FallbackMethod,
+    /// The maximum number of pending connections queued by the kernel.
+    pub backlog: u32,
+    /// Stop accepting connections while fewer than this many file descriptors remain.
+    pub min_fd_headroom: Option<u64>,
+    /// Whether to answer GS4 queries on the same port, over UDP.
+    pub query: bool,
+    /// The MOTD reported to GS4 queries.
+    pub query_motd: String,
+    /// Whether an IPv6 listener only accepts IPv6 clients, so an IPv4 listener can share its port.
+    pub v6_only: bool,
+    /// How fast clients may connect, by what they connect for.
+    pub rate_limits: RateLimits,
+    /// How fast clients must send their handshake and login packets.
+    pub slow_clients: SlowClients,
+    /// How the addresses of clients behind an anti-DDoS provider are taken from their handshake.
+    pub real_ip: Option<RealIp>,
+}
+
+impl Proxy {
+    /// Find the route with a plain domain, ignoring case.
+    pub fn route(&self, domain: &str) -> Option<&Route> {
+        self.route_index.exact(domain).map(|i| &self.routes[i])
+    }
+
+    /// Find the route a domain is matched to - a route with the domain itself, or failing that the
+    /// first templated route matching it, with the name it captures.
+    pub fn find_route(&self, domain: &str) -> Option<(&Route, Option<String>)> {
+        if let Some(route) = self.route(domain) {
+            return Some((route, None));
+        }
+        self.route_index
+            .wildcards(domain)
+            .into_iter()
+            .find_map(|i| {
+                let route = &self.routes[i];
+                let name = route.pattern.as_ref()?.capture(domain)?;
+                Some((route, Some(name)))
+            })
+    }
+}
+
+/// How a listener takes the addresses of clients from the RealIP payloads an anti-DDoS provider,
+/// such as TCPShield, appends to their handshake.
+#[derive(Debug, Clone)]
+pub struct RealIp {
+    /// The public key the provider signs payloads with.
+    pub public_key: RsaPublicKey,
+    /// How old a payload may be before it is rejected.
+    pub max_age: Duration,
+    /// Whether clients without a payload are rejected, as they didn't connect through the provider.
+    pub required: bool,
+}
+
+/// How fast clients may connect to a listener, by what they connect for.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RateLimits {
+    /// The limits of status pings.
+    pub status: RateLimit,
+    /// The limits of logins, including transfers.
+    pub login: RateLimit,
+    /// How long an IP must wait between logins, if throttled.
+    pub login_throttle: Option<Duration>,
+    /// The most logins an IP may have in progress at once, if limited.
+    pub max_concurrent_logins: Option<usize>,
+}
+
+/// The rate limits of one kind of connection.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// The most connections per minute from each source IP, if limited.
+    pub per_ip: Option<u32>,
+    /// The most connections per minute across the listener, if limited.
+    pub per_listener: Option<u32>,
+}
+
+/// How fast clients must send their handshake and login packets, so they can't hold connections
+/// open by sending them a few bytes at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct SlowClients {
+    /// How long a client has to send its whole handshake, from when it connects.
+    pub handshake_timeout: Duration,
+    /// The slowest a client may send the rest of a login packet once its length has arrived, in
+    /// bytes per second, or 0 if unlimited.
+    pub min_receive_rate: u32,
+}
+
+impl Default for SlowClients {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            min_receive_rate: DEFAULT_MIN_RECEIVE_RATE,
+        }
+    }
 }
 
 impl Default for Proxy {
     fn default() -> Self {
         Self {
-            protocol_version: 761,
+            protocol_version: ProtocolVersion::DEFAULT,
             listen_addr: "127.0.0.1:25565".parse().unwrap(),
+            transport: Transport::default(),
             routes: Vec::new(),
+            route_index: RouteIndex::default(),
             fallback_method: FallbackMethod::default(),
+            backlog: DEFAULT_BACKLOG,
+            min_fd_headroom: None,
+            query: false,
+            query_motd: DEFAULT_QUERY_MOTD.to_string(),
+            v6_only: false,
+            rate_limits: RateLimits::default(),
+            slow_clients: SlowClients::default(),
+            real_ip: None,
         }
     }
 }
 
+/// The transport a proxy server accepts clients over.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Plain TCP, as used by the vanilla client.
+    #[default]
+    Tcp,
+    /// Binary WebSocket messages, as used by web-based clients.
+    Websocket,
+}
+
 /// A server route configuration.
 #[derive(Debug)]
 pub struct Route {
@@ -52,17 +457,349 @@ pub struct Route {
     pub from: String,
     /// Where the server should proxy connections to.
     pub to: Vec<SocketAddr>,
+    /// Where connections are proxied to while none of the primary targets are healthy.
+    pub backups: Vec<SocketAddr>,
+    /// Whether new connections return to the primary targets once one recovers.
+    pub fail_back: bool,
     /// The selection algorithm to use.
     pub selection_algorithm: SelectionAlgorithmKind,
+    /// Compression settings to use with clients, if they should differ from the server's.
+    pub compression: Option<CompressionOverride>,
+    /// How much is buffered for a client or server which reads slower than the other sends.
+    pub backpressure: Backpressure,
+    /// The number of sockets kept connected to each target for status connections.
+    pub status_pool: usize,
+    /// How long a status response is fresh for, if status pings are answered from a cache.
+    pub status_cache: Option<Duration>,
+    /// Whether to reach the targets through the tunnel hub, rather than directly.
+    pub tunnel: bool,
+    /// How to treat clients connecting from VPNs and datacenters.
+    pub vpn_policy: VpnPolicy,
+    /// How the hostname clients connect with is verified to resolve to this proxy, if it is.
+    pub dns_verification: Option<DnsVerification>,
+    /// When the route is open, if it is restricted.
+    pub schedule: Option<Schedule>,
+    /// The maximum number of players logged in through the route, if limited.
+    pub max_players: Option<usize>,
+    /// How long a player's slot and backend are held after their backend fails, if they are.
+    pub resume_grace: Option<Duration>,
+    /// The message logins are rejected with while the route is full.
+    pub full_message: String,
+    /// How to wake the route's targets when they are down, if they are started on demand.
+    pub wake: Option<Wake>,
+    /// The Agones fleet connections are allocated a game server from, instead of the targets.
+    pub agones: Option<AgonesConfig>,
+    /// The tenant the route belongs to, if any.
+    pub tenant: Option<Tenant>,
+    /// The pattern of the domain, if the route is templated.
+    pub pattern: Option<Pattern>,
+    /// How templated routes resolve the name captured from the domain into a target.
+    pub resolver: Option<Resolver>,
+    /// The commands handled by the proxy for players on the route, if any.
+    pub commands: Option<Arc<Commands>>,
+    /// Whether the route's backends may use the BungeeCord plugin channel.
+    pub bungeecord: bool,
+    /// Whether the route's backends may use the Magma control channel.
+    pub control_channel: bool,
+    /// Whether connections to the route's targets are made from the client's address.
+    pub transparent: bool,
+    /// Translations of the messages shown to the route's players, if any.
+    pub messages: Option<Arc<Messages>>,
+    /// The MOTDs shown in place of the backend's, if any.
+    pub motds: Option<Arc<Motds>>,
+    /// What status responses show as the sample of online players.
+    pub player_sample: PlayerSample,
+    /// Whether status responses claim the server enforces secure chat, if overridden.
+    pub enforce_secure_chat: Option<bool>,
+    /// What happens to the chat signing data clients send the route's backends.
+    pub chat_signing: ChatSigning,
+    /// The hostname sent to the route's backends in the handshake.
+    pub hostname_rewrite: HostnameRewrite,
+    /// Which usernames the route accepts, if restricted.
+    pub usernames: Option<Arc<UsernamePolicy>>,
+    /// Whether the route's backends are in offline mode, so clients can't pass them a profile of
+    /// their own - their uuid is derived from their username, and forwarding data they put in their
+    /// hostname is removed.
+    pub offline_mode: bool,
+    /// Whether Magma authenticates logins with the session server itself, encrypting the client's
+    /// connection, as the route's backends are in offline mode and won't.
+    pub authenticate: bool,
 }
 
-#[derive(Default, Debug)]
+/// The hostname sent to backends in the handshake, which some backends and virtual host plugins
+/// route or check players by.
+#[derive(Default, Debug, Clone)]
+pub enum HostnameRewrite {
+    /// Send the address and port of the listener the client connected to.
+    #[default]
+    Listener,
+    /// Send the hostname and port the client connected with.
+    Preserve,
+    /// Send this hostname, with the listener's port.
+    Fixed(String),
+    /// Send this hostname, with the listener's port, after substituting `{host}` with the hostname
+    /// the client connected with, `{name}` with the name captured by a templated route, and `{ip}`
+    /// with the client's address.
+    Template(String),
+}
+
+/// What happens to the chat signing data clients send backends - the public key of 1.19 - 1.19.2
+/// clients, and the chat session of 1.19.3+ clients.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatSigning {
+    /// Forward it to the backend, which can then verify signed messages.
+    #[default]
+    Preserve,
+    /// Withhold it, so the backend treats the player's chat as unsigned. Backends must not enforce
+    /// secure chat, or players will be kicked when they chat.
+    Strip,
+}
+
+/// What status responses show as the sample of online players, hovered over in the server list.
+#[derive(Default, Debug, Clone)]
+pub enum PlayerSample {
+    /// Show the backend's sample.
+    #[default]
+    Pass,
+    /// Show no sample.
+    Hide,
+    /// Show these lines instead, such as a link to the network's Discord.
+    Replace(Vec<String>),
+}
+
+/// MOTDs a route shows in turn, in place of the description of its status responses.
+#[derive(Debug)]
+pub struct Motds {
+    /// The chat components shown, of which there is at least one.
+    pub motds: Vec<serde_json::Value>,
+    /// How the MOTD of each status response is chosen.
+    pub rotation: MotdRotation,
+}
+
+/// How the MOTD of a status response is chosen.
+#[derive(Debug, Clone, Copy)]
+pub enum MotdRotation {
+    /// Show the next MOTD with each ping.
+    Sequential,
+    /// Show a random MOTD with each ping.
+    Random,
+    /// Show each MOTD for this long, in turn.
+    Timed(Duration),
+}
+
+/// Translations of the messages the proxy shows players, chosen by the locale of their client.
+#[derive(Debug)]
+pub struct Messages {
+    /// The locale used for clients whose locale has no translation, or isn't known yet.
+    pub fallback: Option<String>,
+    /// The translations of each locale, from each message as configured to its translation.
+    pub translations: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Messages {
+    /// Translate a message into the given locale, or into the fallback locale if it has no
+    /// translation. Messages without either translation are shown as configured.
+    pub fn translate<'a>(&'a self, locale: Option<&str>, message: &'a str) -> &'a str {
+        let translation = |locale: &str| self.translations.get(locale)?.get(message);
+        locale
+            .and_then(|locale| translation(&locale.to_lowercase()))
+            .or_else(|| translation(self.fallback.as_ref()?))
+            .map_or(message, String::as_str)
+    }
+
+    /// Translate the text of a chat component, as [Messages::translate] does.
+    pub fn translate_component(
+        &self,
+        locale: Option<&str>,
+        component: &serde_json::Value,
+    ) -> serde_json::Value {
+        let mut component = component.clone();
+        match &mut component {
+            serde_json::Value::String(text) => *text = self.translate(locale, text).to_string(),
+            serde_json::Value::Object(object) => {
+                if let Some(serde_json::Value::String(text)) = object.get_mut("text") {
+                    *text = self.translate(locale, text).to_string();
+                }
+            }
+            _ => {}
+        }
+        component
+    }
+}
+
+/// Commands handled by the proxy rather than the backend, which move players between routes.
+#[derive(Debug)]
+pub struct Commands {
+    /// The command listing the servers, or moving to the one named, such as `server`.
+    pub command: String,
+    /// The servers players can move to by name, as the host and port their client reconnects to.
+    pub servers: BTreeMap<String, (String, u16)>,
+    /// Commands moving players straight to the named server, such as `hub`.
+    pub aliases: BTreeMap<String, String>,
+}
+
+/// A tenant, grouping the routes of one customer under shared limits.
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    /// The name of the tenant, which labels its routes and sessions.
+    pub name: String,
+    /// The maximum number of live sessions across the tenant's routes.
+    pub max_connections: Option<usize>,
+    /// The bytes the tenant's sessions may transfer per calendar month, in UTC.
+    pub bandwidth_budget: Option<u64>,
+    /// The message logins are rejected with while the tenant is over its limits.
+    pub message: String,
+}
+
+/// The configuration of allocating game servers from an Agones fleet.
+#[derive(Debug, Clone)]
+pub struct AgonesConfig {
+    /// The URL of the allocator service.
+    pub allocator: String,
+    /// The namespace of the fleet.
+    pub namespace: String,
+    /// The name of the fleet.
+    pub fleet: String,
+    /// The name of the game server port to connect to.
+    pub port_name: String,
+    /// The CA certificate of the allocator and Kubernetes API, if not publicly trusted.
+    pub ca: Option<PathBuf>,
+    /// The client certificate and key to authenticate to the allocator with.
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// The Kubernetes API to delete game servers through once their connection closes, if they
+    /// should be released.
+    pub release_api: Option<String>,
+    /// The bearer token file to authenticate to the Kubernetes API with.
+    pub release_token: Option<PathBuf>,
+}
+
+/// How a route treats clients connecting from VPNs and datacenters. Policies other than
+/// [VpnPolicy::Allow] require IP reputation lookups to be configured.
+#[derive(Default, Debug, Clone)]
+pub enum VpnPolicy {
+    /// Treat VPN clients like any other.
+    #[default]
+    Allow,
+    /// Drop the connection.
+    Deny,
+    /// Allow the connection, but log it and emit a flag event.
+    Flag,
+    /// Route the connection to these targets instead, such as a verification limbo.
+    Limbo(Vec<SocketAddr>),
+}
+
+/// Verifies that the hostname a client connects with resolves to this proxy, so scanners which
+/// connect directly while guessing hostnames can't reach routes whose DNS points elsewhere, such
+/// as at an anti-DDoS provider.
+#[derive(Debug, Clone)]
+pub struct DnsVerification {
+    /// The public addresses of this proxy, one of which the hostname must resolve to.
+    pub addresses: Vec<IpAddr>,
+    /// How long the addresses a hostname resolves to are cached for.
+    pub cache_ttl: Duration,
+}
+
+/// Overrides the compression negotiated with clients, independently of the server. Frames are
+/// recompressed in flight, which costs CPU - prefer leaving compression to the server.
+///
+/// The override takes effect when the server enables compression, and has no effect on
+/// connections the server encrypts.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionOverride {
+    /// The threshold to send to clients, or [None] to use the server's threshold. Negative
+    /// values disable compression towards clients.
+    pub threshold: Option<i32>,
+    /// The zlib compression level (0 - 10) of packets sent to clients.
+    pub level: u8,
+}
+
+/// How much a bridge buffers for a peer which reads slower than the other peer sends.
+///
+/// Packets read from one connection are queued for the other until the queue reaches the high
+/// water mark, after which reading pauses until the slow peer catches up. A peer which doesn't
+/// catch up within the timeout is disconnected, rather than buffering without bound.
+#[derive(Debug, Clone, Copy)]
+pub struct Backpressure {
+    /// The bytes queued for a peer before reading from the other connection pauses.
+    pub high_water_mark: usize,
+    /// How long reading may stay paused before the slow peer is disconnected.
+    pub slow_peer_timeout: Duration,
+    /// The bytes a connection may have queued in both directions before it is disconnected, if
+    /// limited.
+    pub max_buffered: Option<usize>,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Self {
+            high_water_mark: 256 * 1024,
+            slow_peer_timeout: Duration::from_secs(30),
+            max_buffered: None,
+        }
+    }
+}
+
+/// The role of this instance in a tunnel.
+#[derive(Debug)]
+pub enum TunnelConfig {
+    /// Forward tunneled routes to a hub.
+    Edge(EdgeConfig),
+    /// Accept tunnels from edges.
+    Hub(HubConfig),
+}
+
+/// The configuration of a tunnel edge.
+#[derive(Debug)]
+pub struct EdgeConfig {
+    /// The address of the hub.
+    pub hub_addr: SocketAddr,
+    /// The token shared with the hub.
+    pub token: String,
+    /// The link compression to offer the hub.
+    pub compression: LinkCompression,
+    /// The number of links to keep open to the hub. Streams are spread across them.
+    pub connections: usize,
+    /// The mutual TLS configuration, if the link should be encrypted.
+    pub tls: Option<TlsConfig>,
+    /// The name to verify the hub's certificate against. Defaults to the hub's IP address.
+    pub server_name: Option<String>,
+}
+
+/// The configuration of a tunnel hub.
+#[derive(Debug)]
+pub struct HubConfig {
+    /// The binding address of the hub.
+    pub listen_addr: SocketAddr,
+    /// The token shared with edges.
+    pub token: String,
+    /// The link compression to allow edges to use.
+    pub compression: LinkCompression,
+    /// The backends edges may reach through the hub.
+    pub allowed_targets: Vec<SocketAddr>,
+    /// The mutual TLS configuration, if edges must connect with TLS.
+    pub tls: Option<TlsConfig>,
+}
+
+/// A mutual TLS configuration. Certificates are reloaded on `SIGHUP`.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The path to the PEM certificate chain to present to peers.
+    pub cert: PathBuf,
+    /// The path to the PEM private key of the certificate.
+    pub key: PathBuf,
+    /// The path to the PEM CA certificates peers must be signed by.
+    pub ca: PathBuf,
+}
+
+#[derive(Default, Debug, Clone)]
 pub enum FallbackMethod {
     /// Drop the connection.
     #[default]
     Drop,
     /// Return a status message to the client.
     Status(TextComponent),
+    /// Send the client to the route of this domain instead.
+    Route(String),
 }
 
 /// The server selection algorithm.
@@ -73,21 +810,171 @@ pub enum SelectionAlgorithmKind {
     RoundRobin,
 }
 
+/// The default listen backlog.
+pub const DEFAULT_BACKLOG: u32 = 1024;
+
+/// The default MOTD reported to GS4 queries.
+pub const DEFAULT_QUERY_MOTD: &str = "A Minecraft Server";
+
+/// The default time clients have to send their handshake.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The default slowest rate, in bytes per second, clients may send login packets at.
+pub const DEFAULT_MIN_RECEIVE_RATE: u32 = 256;
+
+/// The address routes given on the command line listen on, without `--listen`.
+pub const DEFAULT_OVERRIDE_LISTEN: &str = "0.0.0.0:25565";
+
+/// The configuration the overrides apply to when there is no configuration file.
+const EMPTY_CONFIG: &str = "version = 1\ndebug = false\nproxies = []\n";
+
+/// Settings given on the command line, which override or extend the configuration file.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    /// The address every proxy entry listens on, in place of its own.
+    pub listen: Option<String>,
+    /// Routes added to those of the configuration file.
+    pub routes: Vec<RouteOverride>,
+}
+
+/// A route given on the command line, such as `from=play.example.com,to=127.0.0.1:25570`.
+#[derive(Debug, Clone)]
+pub struct RouteOverride {
+    /// The domains of the route.
+    pub from: Vec<String>,
+    /// The targets of the route.
+    pub to: Vec<SocketAddr>,
+}
+
+impl FromStr for RouteOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut route = RouteOverride {
+            from: Vec::new(),
+            to: Vec::new(),
+        };
+        for pair in s.split(',') {
+            let (key, value) = pair
+                .split_once('=')
+                .with_context(|| format!("Expected key=value, got {:?}", pair))?;
+            match key.trim() {
+                "from" => route.from.push(value.trim().to_string()),
+                "to" => route.to.push(
+                    value
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("Invalid target {}", value))?,
+                ),
+                key => bail!("Unknown route key {:?} - expected from or to", key),
+            }
+        }
+        if route.from.is_empty() || route.to.is_empty() {
+            bail!("A route needs at least one from and one to");
+        }
+        Ok(route)
+    }
+}
+
 /// The latest configuration version.
-static LATEST_CONFIG_VERSION: u8 = 1;
+pub static LATEST_CONFIG_VERSION: u8 = 1;
 
-pub async fn from_path<P>(path: P) -> Result<impl Config>
+pub async fn from_path<P>(path: P) -> Result<impl Config, ConfigError>
 where
     P: AsRef<Path>,
 {
-    let buf = read_to_string(path.as_ref())
-        .await
-        .context("Failed to read configuration file")?;
+    load(&[path.as_ref().to_path_buf()], &Overrides::default()).await
+}
 
-    let config: VersionedConfig = toml::from_str(&buf).context("Failed to parse configuration")?;
+/// Load the configuration files at the given paths, merged in order, with the command-line
+/// overrides applied.
+pub async fn load(paths: &[PathBuf], overrides: &Overrides) -> Result<impl Config, ConfigError> {
+    let table = merge_files(paths, overrides).await?;
+    let config: VersionedConfig = toml::Value::Table(table.clone()).try_into()?;
     match config.version {
-        1 => toml::from_str::<ConfigV1>(&buf).context("Failed to parse configuration"),
-        _ => bail!("Unknown config version: {}", config.version),
+        1 => {
+            let mut config: ConfigV1 = toml::Value::Table(table).try_into()?;
+            config
+                .resolve_panels()
+                .await
+                .map_err(ConfigError::Invalid)?;
+            Ok(config)
+        }
+        _ => Err(ConfigError::UnknownVersion(config.version)),
+    }
+}
+
+/// Read and deep-merge the configuration files at the given paths, in order, then apply the
+/// command-line overrides - the effective configuration, before it is interpreted.
+///
+/// Tables are merged key by key, so a later file only needs the keys it changes. Any other value,
+/// including an array such as `[[proxies]]`, replaces the earlier value whole. Missing files are
+/// skipped if the overrides add routes, so Magma can run without a configuration file.
+pub async fn merge_files(paths: &[PathBuf], overrides: &Overrides) -> Result<Table, ConfigError> {
+    let mut merged: Option<Table> = None;
+    for path in paths {
+        let buf = match read_to_string(path).await {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == ErrorKind::NotFound && !overrides.routes.is_empty() => {
+                continue
+            }
+            Err(err) => return Err(ConfigError::Read(err)),
+        };
+        let table: Table = toml::from_str(&buf)?;
+        merged = Some(match merged {
+            Some(mut merged) => {
+                merge(&mut merged, table);
+                merged
+            }
+            None => table,
+        });
+    }
+    let mut merged = match merged {
+        Some(merged) => merged,
+        None => toml::from_str(EMPTY_CONFIG)?,
+    };
+    apply_overrides(&mut merged, overrides);
+    Ok(merged)
+}
+
+/// Merge an overlay into a base table, the overlay taking precedence.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Apply the overrides given on the command line - routes are added as proxy entries of their
+/// own, and the listen address replaces that of every entry.
+fn apply_overrides(config: &mut Table, overrides: &Overrides) {
+    let proxies = config
+        .entry("proxies")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    let Value::Array(proxies) = proxies else {
+        // left for the configuration to reject when it is interpreted
+        return;
+    };
+    for route in &overrides.routes {
+        let mut entry = Table::new();
+        entry.insert("address".into(), DEFAULT_OVERRIDE_LISTEN.into());
+        entry.insert("domains".into(), route.from.clone().into());
+        let targets: Vec<_> = route.to.iter().map(ToString::to_string).collect();
+        entry.insert("targets".into(), targets.into());
+        proxies.push(Value::Table(entry));
+    }
+    if let Some(listen) = &overrides.listen {
+        for proxy in proxies.iter_mut() {
+            if let Value::Table(proxy) = proxy {
+                proxy.insert("address".into(), listen.as_str().into());
+                proxy.remove("addresses");
+                proxy.remove("listen");
+            }
+        }
     }
 }
 
@@ -96,7 +983,7 @@ pub trait Config {
     /// Test if this configuration is of the latest version.
     fn is_latest(&self) -> bool;
     /// Build this configuration into a list of proxy configurations.
-    fn build(self) -> Result<MagmaConfig>;
+    fn build(self) -> Result<MagmaConfig, ConfigError>;
 }
 
 /// A migration from one configuration version to another.
@@ -1,10 +1,40 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use regex::Regex;
 use serde::Deserialize;
+use serde_json::json;
 use tracing::warn;
 
-use super::{Config, FallbackMethod, MagmaConfig, Proxy, Route, SelectionAlgorithmKind};
+use super::{
+    AdminConfig, AdminToken, AgonesConfig, Announcement, AnnouncementTiming, AuthConfig,
+    Backpressure, ChatSigning, Commands, CompressionOverride, Config, CrashConfig, DnsVerification,
+    EdgeConfig, EventSink, FallbackMethod, HealthCheckConfig, HistoryConfig, HostnameRewrite,
+    HubConfig, IdleConfig, InfluxConfig, MagmaConfig, MemoryConfig, Messages, MotdRotation, Motds,
+    PlayerSample, Probe, Proxy, RateLimit, RateLimits, RconConfig, RealIp, ReputationApi,
+    ReputationConfig, Route, RouteIndex, SelectionAlgorithmKind, SlowClients, StatsdConfig,
+    TarpitConfig, Tenant, TlsConfig, Transport, TunnelConfig, VpnPolicy, DEFAULT_BACKLOG,
+    DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MIN_RECEIVE_RATE, DEFAULT_QUERY_MOTD,
+};
+use crate::{
+    client::MOJANG_SESSION_SERVER,
+    error::ConfigError,
+    hook::Hook,
+    link::LinkCompression,
+    panel::Panel,
+    protocol::version::ProtocolVersion,
+    realip,
+    schedule::{self, Schedule},
+    template::{self, Pattern, Resolver},
+    username::UsernamePolicy,
+    wake::Wake,
+};
 
 /// The Moss configuration object.
 #[derive(Deserialize)]
@@ -15,16 +45,339 @@ pub struct ConfigV1 {
     pub debug: bool,
     /// A list of server entries.
     pub proxies: Vec<ProxyEntry>,
+    /// The tunnel configuration.
+    pub tunnel: Option<TunnelEntry>,
+    /// A list of event sinks.
+    #[serde(default = "Vec::new")]
+    pub events: Vec<EventSink>,
+    /// The admin API configuration.
+    pub admin: Option<AdminEntry>,
+    /// The connection history configuration.
+    pub history: Option<HistoryEntry>,
+    /// The IP reputation configuration.
+    pub reputation: Option<ReputationEntry>,
+    /// The path to write security events to.
+    pub security_log: Option<PathBuf>,
+    /// The tarpit configuration.
+    pub tarpit: Option<TarpitEntry>,
+    /// Stop accepting connections while fewer than this many file descriptors remain.
+    pub min_fd_headroom: Option<u64>,
+    /// Raise the limit on open file descriptors to this at startup, up to the hard limit.
+    pub max_open_files: Option<u64>,
+    /// The tenants routes can belong to.
+    #[serde(default = "Vec::new")]
+    pub tenants: Vec<TenantEntry>,
+    /// The crash report configuration.
+    #[serde(default)]
+    pub crash_reports: CrashReportsEntry,
+    /// The memory watchdog configuration.
+    pub memory: Option<MemoryEntry>,
+    /// The statsd exporter configuration.
+    pub statsd: Option<StatsdEntry>,
+    /// The InfluxDB exporter configuration.
+    pub influx: Option<InfluxEntry>,
+    /// The scheduled announcements.
+    #[serde(default = "Vec::new")]
+    pub announcements: Vec<AnnouncementEntry>,
+    /// The commands handled by the proxy.
+    pub commands: Option<CommandsEntry>,
+    /// How logins to authenticating routes are checked with the session server.
+    #[serde(default)]
+    pub authentication: AuthenticationEntry,
+}
+
+/// A proxy command block.
+#[derive(Deserialize)]
+pub struct CommandsEntry {
+    /// The command listing the servers, or moving to the one named.
+    #[serde(default = "default_server_command")]
+    pub command: String,
+    /// The domains of the servers players can move to, by name.
+    pub servers: BTreeMap<String, String>,
+    /// Commands moving players straight to the named server.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+}
+
+fn default_server_command() -> String {
+    "server".to_string()
+}
+
+/// A scheduled announcement block.
+#[derive(Deserialize)]
+pub struct AnnouncementEntry {
+    /// The chat component shown, or plain text.
+    pub message: serde_json::Value,
+    /// The routes whose players are shown the message - every route if empty.
+    #[serde(default = "Vec::new")]
+    pub routes: Vec<String>,
+    /// Whether the message is shown above the hotbar.
+    #[serde(default)]
+    pub action_bar: bool,
+    /// How often the message is shown, in seconds.
+    pub every_secs: Option<u64>,
+    /// The times of day the message is shown, such as `21:55`.
+    #[serde(default = "Vec::new")]
+    pub at: Vec<String>,
+    /// The timezone of the times of day.
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+/// An InfluxDB exporter configuration block.
+#[derive(Deserialize)]
+pub struct InfluxEntry {
+    /// The URL line protocol is posted to.
+    pub url: String,
+    /// The token to authenticate with.
+    pub token: Option<String>,
+    /// The prefix of every measurement name.
+    #[serde(default = "default_metrics_prefix")]
+    pub prefix: String,
+    /// The tags added to every point.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// How often metrics are written, in seconds.
+    #[serde(default = "default_influx_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_influx_interval() -> u64 {
+    30
+}
+
+/// A statsd exporter configuration block.
+#[derive(Deserialize)]
+pub struct StatsdEntry {
+    /// The address of the agent.
+    pub address: String,
+    /// The prefix of every metric name.
+    #[serde(default = "default_metrics_prefix")]
+    pub prefix: String,
+    /// The tags sent with every metric.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// How often gauges are sent, in seconds.
+    #[serde(default = "default_statsd_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_metrics_prefix() -> String {
+    "magma".to_string()
+}
+
+fn default_statsd_interval() -> u64 {
+    10
+}
+
+/// A memory watchdog configuration block.
+#[derive(Deserialize)]
+pub struct MemoryEntry {
+    /// The resident set size, in megabytes, past which logins are refused.
+    pub rss_limit_mb: u64,
+}
+
+/// A crash report configuration block.
+#[derive(Deserialize)]
+pub struct CrashReportsEntry {
+    /// The directory reports are written to.
+    #[serde(default = "default_crash_directory")]
+    pub directory: PathBuf,
+    /// The URL reports are posted to.
+    pub webhook: Option<String>,
+}
+
+impl Default for CrashReportsEntry {
+    fn default() -> Self {
+        Self {
+            directory: default_crash_directory(),
+            webhook: None,
+        }
+    }
+}
+
+fn default_crash_directory() -> PathBuf {
+    PathBuf::from("crash-reports")
+}
+
+/// A tenant block.
+#[derive(Deserialize)]
+pub struct TenantEntry {
+    /// The name of the tenant.
+    pub name: String,
+    /// The maximum number of live sessions across the tenant's routes.
+    pub max_connections: Option<usize>,
+    /// The megabytes the tenant's sessions may transfer per calendar month.
+    pub bandwidth_budget_mb: Option<u64>,
+    /// The message logins are rejected with while the tenant is over its limits.
+    #[serde(default = "default_tenant_message")]
+    pub message: String,
+}
+
+fn default_tenant_message() -> String {
+    "This server is unavailable right now - try again later!".to_string()
+}
+
+/// A tarpit configuration block.
+#[derive(Deserialize)]
+pub struct TarpitEntry {
+    /// How long denied connections are held, in seconds.
+    #[serde(default = "default_tarpit_duration_secs")]
+    pub duration_secs: u64,
+    /// The maximum number of connections held at once.
+    #[serde(default = "default_tarpit_max_connections")]
+    pub max_connections: usize,
+}
+
+fn default_tarpit_duration_secs() -> u64 {
+    60
+}
+
+fn default_tarpit_max_connections() -> usize {
+    1024
+}
+
+/// An IP reputation configuration block.
+#[derive(Deserialize)]
+pub struct ReputationEntry {
+    /// The path to a database of networks, one address or CIDR block per line.
+    pub database: Option<PathBuf>,
+    /// The URL of the reputation API, with `{ip}` replaced by the client's address.
+    pub api_url: Option<String>,
+    /// The JSON pointer to the verdict in API responses.
+    pub api_pointer: Option<String>,
+    /// How long API results are cached, in seconds.
+    #[serde(default = "default_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    60 * 60
+}
+
+/// An admin API configuration block.
+#[derive(Deserialize)]
+pub struct AdminEntry {
+    /// The address to serve the admin API on.
+    pub address: SocketAddr,
+    /// The mutual TLS configuration.
+    pub tls: Option<TlsEntry>,
+    /// The bearer tokens clients authenticate with.
+    #[serde(default = "Vec::new")]
+    pub tokens: Vec<AdminToken>,
+}
+
+/// A connection history configuration block.
+#[derive(Deserialize)]
+pub struct HistoryEntry {
+    /// The path to the SQLite database.
+    pub path: PathBuf,
+    /// The number of days completed sessions are kept.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+fn default_retention_days() -> u64 {
+    30
+}
+
+/// An authentication configuration block.
+#[derive(Deserialize)]
+pub struct AuthenticationEntry {
+    /// The base URL of the session server.
+    #[serde(default = "default_session_server")]
+    pub session_server: String,
+    /// The maximum number of session server lookups in flight at once.
+    #[serde(default = "default_max_concurrent_lookups")]
+    pub max_concurrent_lookups: usize,
+    /// The maximum number of lookups waiting for one of those to finish.
+    #[serde(default = "default_max_queued_lookups")]
+    pub max_queued_lookups: usize,
+    /// The message logins are rejected with while the queue is full.
+    #[serde(default = "default_overflow_message")]
+    pub overflow_message: String,
+}
+
+impl Default for AuthenticationEntry {
+    fn default() -> Self {
+        Self {
+            session_server: default_session_server(),
+            max_concurrent_lookups: default_max_concurrent_lookups(),
+            max_queued_lookups: default_max_queued_lookups(),
+            overflow_message: default_overflow_message(),
+        }
+    }
+}
+
+fn default_session_server() -> String {
+    MOJANG_SESSION_SERVER.to_string()
+}
+
+fn default_max_concurrent_lookups() -> usize {
+    16
+}
+
+fn default_max_queued_lookups() -> usize {
+    256
+}
+
+fn default_overflow_message() -> String {
+    "Too many players are logging in right now - try again shortly!".to_string()
+}
+
+/// A tunnel configuration block.
+#[derive(Deserialize)]
+pub struct TunnelEntry {
+    /// Whether this instance is an edge or a hub.
+    pub mode: TunnelMode,
+    /// The address of the hub, for edges.
+    pub hub: Option<SocketAddr>,
+    /// The address to accept tunnels on, for hubs.
+    pub address: Option<SocketAddr>,
+    /// The token shared by the edge and hub.
+    pub token: String,
+    /// The zstd level to compress the link with. Compression is disabled if unset.
+    pub compression_level: Option<i32>,
+    /// The number of links to keep open to the hub, for edges.
+    pub connections: Option<usize>,
+    /// The backends edges may reach, for hubs.
+    #[serde(default = "Vec::new")]
+    pub allowed_targets: Vec<SocketAddr>,
+    /// The mutual TLS configuration.
+    pub tls: Option<TlsEntry>,
+}
+
+/// A mutual TLS configuration block.
+#[derive(Deserialize)]
+pub struct TlsEntry {
+    /// The path to the certificate chain.
+    pub cert: PathBuf,
+    /// The path to the private key.
+    pub key: PathBuf,
+    /// The path to the trusted CA certificates.
+    pub ca: PathBuf,
+    /// The name to verify the hub's certificate against, for edges.
+    pub server_name: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelMode {
+    /// Forward tunneled routes to a hub.
+    Edge,
+    /// Accept tunnels from edges.
+    Hub,
 }
 
 /// A server entry block.
 #[derive(Deserialize)]
 pub struct ProxyEntry {
-    /// The proxy listening address.
-    pub address: Option<SocketAddr>,
-    /// A list of addresses to listen on.
+    /// The proxy listening address, or a range of ports such as `0.0.0.0:25565-25575`.
+    #[serde(alias = "listen")]
+    pub address: Option<String>,
+    /// A list of addresses or port ranges to listen on.
     #[serde(default = "Vec::new")]
-    pub addresses: Vec<SocketAddr>,
+    pub addresses: Vec<String>,
     /// The proxy domain.
     pub domain: Option<String>,
     /// A list of valid domains.
@@ -35,8 +388,549 @@ pub struct ProxyEntry {
     #[serde(default = "Vec::new")]
     /// A list of target servers.
     pub targets: Vec<SocketAddr>,
+    /// The targets used while none of the primary targets are healthy.
+    #[serde(default = "Vec::new")]
+    pub backup_targets: Vec<SocketAddr>,
+    /// Whether new connections return to the primary targets as soon as one recovers, rather than
+    /// staying on the backups while any of them are healthy.
+    #[serde(default = "default_fail_back")]
+    pub fail_back: bool,
     /// The selection algorithm to use.
     pub selection_algorithm: Option<SelectionAlgorithm>,
+    /// The transport clients of this entry connect with. Routes are only reachable over the
+    /// transport of their entry.
+    #[serde(default)]
+    pub transport: Transport,
+    /// The compression threshold to send to clients, if it should differ from the server's.
+    pub compression_threshold: Option<i32>,
+    /// The zlib compression level (0 - 10) of packets sent to clients.
+    pub compression_level: Option<u8>,
+    /// Whether to reach the targets through the tunnel hub.
+    #[serde(default)]
+    pub tunnel: bool,
+    /// The kilobytes queued for a slow client or server before reading from the other pauses.
+    pub high_water_mark_kb: Option<usize>,
+    /// How long reading may stay paused before a slow client or server is disconnected.
+    pub slow_peer_timeout_secs: Option<u64>,
+    /// The kilobytes a connection may have queued in both directions before it is disconnected.
+    pub max_buffered_kb: Option<usize>,
+    /// The number of sockets kept connected to each target for status connections.
+    #[serde(default)]
+    pub status_pool_size: usize,
+    /// How long, in seconds, a cached status response is fresh for. Status pings are answered
+    /// from the cache if set.
+    pub status_cache_ttl_secs: Option<u64>,
+    /// The maximum number of pending connections queued by the kernel.
+    pub backlog: Option<u32>,
+    /// Whether to answer GS4 queries on the listening port.
+    #[serde(default)]
+    pub query: bool,
+    /// The MOTD reported to GS4 queries.
+    pub query_motd: Option<String>,
+    /// How fast clients may connect to the listener, by what they connect for.
+    pub rate_limits: Option<RateLimitsEntry>,
+    /// How fast clients must send their handshake and login packets to the listener.
+    pub slow_clients: Option<SlowClientsEntry>,
+    /// How the listener takes the addresses of clients behind an anti-DDoS provider.
+    pub real_ip: Option<RealIpEntry>,
+    /// What the listener does with clients connecting with an unknown domain.
+    pub fallback: Option<FallbackEntry>,
+    /// The message shown to clients with an unknown domain, with the `status` fallback.
+    #[serde(default = "default_fallback_message")]
+    pub fallback_message: String,
+    /// The domain of the route clients with an unknown domain are sent to, with the `route`
+    /// fallback.
+    pub fallback_route: Option<String>,
+    /// The RCON proxy for the targets of this entry.
+    pub rcon: Option<RconEntry>,
+    /// The active health check of the targets of this entry.
+    pub health_check: Option<HealthCheckEntry>,
+    /// How to treat clients connecting from VPNs and datacenters.
+    #[serde(default)]
+    pub vpn_policy: VpnPolicyEntry,
+    /// The targets VPN clients are routed to, with the `limbo` policy.
+    #[serde(default = "Vec::new")]
+    pub limbo_targets: Vec<SocketAddr>,
+    /// Verify that the hostname clients connect with resolves to this proxy.
+    pub dns_verification: Option<DnsVerificationEntry>,
+    /// When the routes of this entry are open.
+    pub schedule: Option<ScheduleEntry>,
+    /// Which usernames the routes of this entry accept.
+    pub usernames: Option<UsernamesEntry>,
+    /// Whether the targets of this entry are in offline mode.
+    #[serde(default)]
+    pub offline_mode: bool,
+    /// The maximum number of players logged in through each route of this entry.
+    pub max_players: Option<usize>,
+    /// How long, in seconds, a player's slot and target are held after their target fails.
+    pub resume_grace_secs: Option<u64>,
+    /// The message logins are rejected with while a route is full.
+    #[serde(default = "default_full_message")]
+    pub full_message: String,
+    /// How to wake the targets of this entry when they are down.
+    pub wake: Option<WakeEntry>,
+    /// How to stop the targets of this entry while no players are online.
+    pub idle_shutdown: Option<IdleEntry>,
+    /// The panel server behind this entry.
+    pub panel: Option<PanelEntry>,
+    /// The Agones fleet to allocate game servers from, instead of using targets.
+    pub agones: Option<AgonesEntry>,
+    /// The tenant the routes of this entry belong to.
+    pub tenant: Option<String>,
+    /// The target of templated domains, with the captured `{name}` substituted.
+    pub target_template: Option<String>,
+    /// A URL returning the target of templated domains, with the captured `{name}` substituted.
+    pub target_lookup_url: Option<String>,
+    /// A command printing the target of templated domains, given the captured name in
+    /// `MAGMA_NAME`.
+    pub target_lookup_command: Option<String>,
+    /// Whether the targets of this entry may use the BungeeCord plugin channel.
+    #[serde(default)]
+    pub bungeecord_channel: bool,
+    /// Whether the targets of this entry may use the Magma control channel.
+    #[serde(default)]
+    pub control_channel: bool,
+    /// Whether to connect to the targets from the client's own address, on Linux.
+    #[serde(default)]
+    pub transparent: bool,
+    /// Translations of the messages shown to players of this entry.
+    pub messages: Option<MessagesEntry>,
+    /// The MOTDs shown in the server list in place of the targets', in turn.
+    #[serde(default = "Vec::new")]
+    pub motds: Vec<String>,
+    /// How the MOTD of each status response is chosen.
+    #[serde(default)]
+    pub motd_rotation: MotdRotationEntry,
+    /// How long each MOTD is shown for, in seconds, with the `timed` rotation.
+    #[serde(default = "default_motd_interval_secs")]
+    pub motd_interval_secs: u64,
+    /// What status responses show as the sample of online players.
+    #[serde(default)]
+    pub player_sample: PlayerSampleEntry,
+    /// The lines shown as the player sample, with the `replace` option.
+    #[serde(default = "Vec::new")]
+    pub player_sample_lines: Vec<String>,
+    /// Whether status responses claim the targets enforce secure chat, if overridden.
+    pub enforce_secure_chat: Option<bool>,
+    /// What happens to the chat signing data clients send the targets.
+    #[serde(default)]
+    pub chat_signing: ChatSigningEntry,
+    /// The hostname sent to the targets in the handshake.
+    #[serde(default)]
+    pub hostname_rewrite: HostnameRewriteEntry,
+    /// The hostname sent with the `fixed` rewrite, or its template with the `template` rewrite.
+    pub rewritten_hostname: Option<String>,
+    /// Whether logins to this entry are authenticated with the session server by Magma.
+    #[serde(default)]
+    pub authenticate: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum HostnameRewriteEntry {
+    /// Send the listener's address.
+    #[default]
+    Listener,
+    /// Send the hostname the client connected with.
+    Preserve,
+    /// Send the rewritten hostname.
+    Fixed,
+    /// Send the rewritten hostname, with placeholders substituted.
+    Template,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatSigningEntry {
+    /// Forward chat signing data to the targets.
+    #[default]
+    Preserve,
+    /// Withhold chat signing data from the targets.
+    Strip,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerSampleEntry {
+    /// Show the targets' sample.
+    #[default]
+    Pass,
+    /// Show no sample.
+    Hide,
+    /// Show the configured lines.
+    Replace,
+}
+
+fn default_motd_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum MotdRotationEntry {
+    /// Show the next MOTD with each ping.
+    #[default]
+    Sequential,
+    /// Show a random MOTD with each ping.
+    Random,
+    /// Show each MOTD for an interval, in turn.
+    Timed,
+}
+
+/// A messages block, translating messages into the locales of clients.
+#[derive(Deserialize)]
+pub struct MessagesEntry {
+    /// The locale used for clients whose locale has no translation.
+    pub fallback: Option<String>,
+    /// The translations of each locale, such as `de_de`, keyed by the message as configured.
+    #[serde(flatten)]
+    pub translations: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+fn default_fail_back() -> bool {
+    true
+}
+
+/// A rate limits block.
+#[derive(Deserialize)]
+pub struct RateLimitsEntry {
+    /// The limits of status pings.
+    #[serde(default)]
+    pub status: RateLimitEntry,
+    /// The limits of logins.
+    #[serde(default)]
+    pub login: RateLimitEntry,
+    /// How long, in milliseconds, an IP must wait between logins.
+    pub login_throttle_ms: Option<u64>,
+    /// The most logins an IP may have in progress at once.
+    pub max_concurrent_logins: Option<usize>,
+}
+
+/// The rate limits of one kind of connection, per minute.
+#[derive(Deserialize, Default)]
+pub struct RateLimitEntry {
+    /// The most connections per minute from each source IP.
+    pub per_ip: Option<u32>,
+    /// The most connections per minute across the listener.
+    pub per_listener: Option<u32>,
+}
+
+/// A DNS verification block.
+#[derive(Deserialize)]
+pub struct DnsVerificationEntry {
+    /// The public addresses of this proxy.
+    pub addresses: Vec<IpAddr>,
+    /// How long, in seconds, the addresses a hostname resolves to are cached for.
+    #[serde(default = "default_dns_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+}
+
+fn default_dns_cache_ttl_secs() -> u64 {
+    300
+}
+
+/// A slow clients block.
+#[derive(Deserialize)]
+pub struct SlowClientsEntry {
+    /// How long, in milliseconds, clients have to send their handshake.
+    pub handshake_timeout_ms: Option<u64>,
+    /// The slowest clients may send a login packet, in bytes per second, or 0 if unlimited.
+    pub min_receive_rate: Option<u32>,
+}
+
+/// A RealIP block.
+#[derive(Deserialize)]
+pub struct RealIpEntry {
+    /// The path to the PEM-encoded public key the provider signs payloads with.
+    pub public_key: PathBuf,
+    /// How old, in seconds, a payload may be.
+    #[serde(default = "default_real_ip_max_age_secs")]
+    pub max_age_secs: u64,
+    /// Whether clients without a payload are rejected.
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_real_ip_max_age_secs() -> u64 {
+    5
+}
+
+/// An Agones fleet block.
+#[derive(Deserialize)]
+pub struct AgonesEntry {
+    /// The URL of the allocator service.
+    pub allocator: String,
+    /// The namespace of the fleet.
+    #[serde(default = "default_agones_namespace")]
+    pub namespace: String,
+    /// The name of the fleet.
+    pub fleet: String,
+    /// The name of the game server port to connect to.
+    #[serde(default = "default_agones_port_name")]
+    pub port_name: String,
+    /// The path to the CA certificate of the allocator and Kubernetes API.
+    pub ca: Option<PathBuf>,
+    /// The path to the client certificate to authenticate to the allocator with.
+    pub cert: Option<PathBuf>,
+    /// The path to the client key to authenticate to the allocator with.
+    pub key: Option<PathBuf>,
+    /// The Kubernetes API to delete game servers through once their connection closes.
+    pub release_api: Option<String>,
+    /// The path to the bearer token to authenticate to the Kubernetes API with.
+    pub release_token: Option<PathBuf>,
+}
+
+fn default_agones_namespace() -> String {
+    "default".to_string()
+}
+
+fn default_agones_port_name() -> String {
+    "default".to_string()
+}
+
+/// A panel server block.
+#[derive(Deserialize)]
+pub struct PanelEntry {
+    /// The base URL of the panel.
+    pub url: String,
+    /// A client API key with access to the server.
+    pub key: String,
+    /// The short identifier of the server.
+    pub server: String,
+    /// The IP to reach the server at, overriding the IP of its allocation.
+    pub host: Option<IpAddr>,
+}
+
+impl PanelEntry {
+    fn panel(&self) -> Panel {
+        Panel {
+            url: self.url.clone(),
+            key: self.key.clone(),
+            server: self.server.clone(),
+        }
+    }
+}
+
+fn default_full_message() -> String {
+    "The server is full - try again later!".to_string()
+}
+
+/// A wake-on-connect block.
+#[derive(Deserialize)]
+pub struct WakeEntry {
+    /// The hook starting a target.
+    #[serde(flatten)]
+    pub hook: HookEntry,
+    /// How long logins are held while a target starts, in seconds. Logins are turned away if
+    /// unset.
+    pub hold_secs: Option<u64>,
+    /// How long to wait before triggering the hook again for the same target, in seconds.
+    #[serde(default = "default_wake_cooldown_secs")]
+    pub cooldown_secs: u64,
+    /// The message logins are turned away with while a target starts.
+    #[serde(default = "default_starting_message")]
+    pub message: String,
+    /// The MOTD shown while a target starts.
+    #[serde(default = "default_starting_motd")]
+    pub motd: String,
+}
+
+fn default_wake_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_starting_message() -> String {
+    "The server is starting - try again in 30 seconds!".to_string()
+}
+
+fn default_starting_motd() -> String {
+    "Starting...".to_string()
+}
+
+/// An idle shutdown block.
+#[derive(Deserialize)]
+pub struct IdleEntry {
+    /// The hook stopping a target.
+    #[serde(flatten)]
+    pub hook: HookEntry,
+    /// How long the routes must be empty before the targets are stopped, in minutes.
+    #[serde(default = "default_idle_after_mins")]
+    pub after_mins: u64,
+}
+
+fn default_idle_after_mins() -> u64 {
+    15
+}
+
+/// A hook, which sets exactly one of `command`, `url`, `container` or `panel`.
+#[derive(Deserialize)]
+pub struct HookEntry {
+    /// A shell command to run.
+    pub command: Option<String>,
+    /// A URL to request.
+    pub url: Option<String>,
+    /// The method to request the URL with.
+    #[serde(default = "default_hook_method")]
+    pub method: String,
+    /// A Docker container to start or stop.
+    pub container: Option<String>,
+    /// The URL of the Docker Engine API.
+    #[serde(default = "default_docker_url")]
+    pub docker_url: String,
+    /// Whether to send power signals to the entry's panel server.
+    #[serde(default)]
+    pub panel: bool,
+}
+
+fn default_hook_method() -> String {
+    "POST".to_string()
+}
+
+fn default_docker_url() -> String {
+    "http://localhost:2375".to_string()
+}
+
+/// A health check block.
+#[derive(Deserialize)]
+pub struct HealthCheckEntry {
+    /// How targets are probed.
+    #[serde(default)]
+    pub mode: HealthCheckMode,
+    /// How often targets are probed, in seconds.
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    /// How long a probe may take, in seconds.
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
+    /// The RCON port of the targets, for RCON probes.
+    #[serde(default = "default_rcon_port")]
+    pub rcon_port: u16,
+    /// The RCON password of the targets, for RCON probes.
+    pub rcon_password: Option<String>,
+    /// A command to run once logged in, for RCON probes.
+    pub rcon_command: Option<String>,
+    /// Text the command's response must contain, for RCON probes.
+    pub rcon_expect: Option<String>,
+    /// Whether to fetch the favicon of healthy targets, to show while they are down.
+    #[serde(default)]
+    pub fetch_favicon: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckMode {
+    /// Connect to targets.
+    #[default]
+    Tcp,
+    /// Log in to the RCON console of targets.
+    Rcon,
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_check_timeout_secs() -> u64 {
+    3
+}
+
+/// An RCON proxy block.
+#[derive(Deserialize)]
+pub struct RconEntry {
+    /// The address to accept RCON clients on.
+    pub address: SocketAddr,
+    /// The password clients must log in with.
+    pub password: String,
+    /// The password used to log in to the backends.
+    pub backend_password: String,
+    /// The RCON port of the entry's targets.
+    #[serde(default = "default_rcon_port")]
+    pub backend_port: u16,
+    /// A designated admin target to relay sessions to, instead of the entry's targets.
+    pub target: Option<SocketAddr>,
+}
+
+fn default_rcon_port() -> u16 {
+    25575
+}
+
+/// A usernames block.
+#[derive(Deserialize)]
+pub struct UsernamesEntry {
+    /// Whether usernames must follow the vanilla rules.
+    #[serde(default = "default_vanilla_usernames")]
+    pub vanilla: bool,
+    /// Regular expressions matching the usernames to reject.
+    #[serde(default = "Vec::new")]
+    pub deny: Vec<String>,
+    /// The message rejected clients are disconnected with.
+    #[serde(default = "default_username_message")]
+    pub message: String,
+}
+
+fn default_vanilla_usernames() -> bool {
+    true
+}
+
+fn default_username_message() -> String {
+    "You can't join with that username.".to_string()
+}
+
+/// A route schedule block.
+#[derive(Deserialize)]
+pub struct ScheduleEntry {
+    /// The timezone of the hours, such as `Europe/London`.
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// The windows during which the routes are open, such as `07:00-22:00`.
+    pub hours: Vec<String>,
+    /// The message logins are rejected with while closed.
+    #[serde(default = "default_closed_message")]
+    pub message: String,
+    /// The MOTD shown while closed.
+    #[serde(default = "default_closed_motd")]
+    pub motd: String,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_closed_message() -> String {
+    "This server is closed right now - come back later!".to_string()
+}
+
+fn default_closed_motd() -> String {
+    "Closed".to_string()
+}
+
+fn default_fallback_message() -> String {
+    "There is no server at this address!".to_string()
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackEntry {
+    /// Drop the connection.
+    Drop,
+    /// Answer status pings and disconnect logins with the fallback message.
+    Status,
+    /// Send the client to the fallback route.
+    Route,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VpnPolicyEntry {
+    /// Treat VPN clients like any other.
+    #[default]
+    Allow,
+    /// Drop VPN clients.
+    Deny,
+    /// Log VPN clients.
+    Flag,
+    /// Route VPN clients to the limbo targets.
+    Limbo,
 }
 
 #[derive(Deserialize, Default, Clone)]
@@ -49,24 +943,412 @@ pub enum SelectionAlgorithm {
     RoundRobin,
 }
 
+impl ConfigV1 {
+    /// Resolve the targets of entries which take them from their panel server.
+    pub async fn resolve_panels(&mut self) -> Result<()> {
+        for (i, proxy) in self.proxies.iter_mut().enumerate() {
+            let Some(panel) = &proxy.panel else {
+                continue;
+            };
+            if proxy.target.is_some() || !proxy.targets.is_empty() {
+                continue;
+            }
+            let target = panel
+                .panel()
+                .allocation(panel.host)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to resolve the panel allocation of proxy entry {}",
+                        i
+                    )
+                })?;
+            proxy.target = Some(target);
+        }
+        Ok(())
+    }
+}
+
 impl Config for ConfigV1 {
     fn is_latest(&self) -> bool {
-        true
+        self.version == super::LATEST_CONFIG_VERSION
     }
 
-    fn build(self) -> Result<MagmaConfig> {
+    fn build(self) -> Result<MagmaConfig, ConfigError> {
+        self.build_config().map_err(ConfigError::Invalid)
+    }
+}
+
+impl ConfigV1 {
+    /// Build the proxies, and the services they use.
+    fn build_config(self) -> Result<MagmaConfig> {
         let mut proxies: HashMap<SocketAddr, Proxy> = HashMap::new();
+        let mut rcon = vec![];
+        let mut health_checks = vec![];
+        let mut tenants: Vec<Tenant> = vec![];
+        for entry in self.tenants {
+            if tenants.iter().any(|tenant| tenant.name == entry.name) {
+                bail!("Tenant {:?} is defined more than once", entry.name);
+            }
+            tenants.push(Tenant {
+                name: entry.name,
+                max_connections: entry.max_connections,
+                bandwidth_budget: entry.bandwidth_budget_mb.map(|mb| mb * 1024 * 1024),
+                message: entry.message,
+            });
+        }
+        let mut idle_shutdowns = vec![];
+        let tunnel = self.tunnel.map(build_tunnel).transpose()?;
+        let is_edge = matches!(tunnel, Some(TunnelConfig::Edge(_)));
+        if self.authentication.max_concurrent_lookups == 0 {
+            bail!("Authentication must allow at least one concurrent lookup");
+        }
+        let reputation = self.reputation.map(build_reputation).transpose()?;
+        let statsd = match self.statsd {
+            Some(statsd) if statsd.interval_secs == 0 => {
+                bail!("The statsd interval must be at least 1 second")
+            }
+            statsd => statsd.map(|statsd| StatsdConfig {
+                address: statsd.address,
+                prefix: statsd.prefix,
+                tags: statsd.tags,
+                interval: Duration::from_secs(statsd.interval_secs),
+            }),
+        };
+        let announcements = self
+            .announcements
+            .iter()
+            .enumerate()
+            .map(|(i, announcement)| {
+                build_announcement(announcement)
+                    .with_context(|| format!("Announcement {} is invalid", i))
+            })
+            .collect::<Result<_>>()?;
+        let influx = match self.influx {
+            Some(influx) if influx.interval_secs == 0 => {
+                bail!("The InfluxDB interval must be at least 1 second")
+            }
+            influx => influx.map(|influx| InfluxConfig {
+                url: influx.url,
+                token: influx.token,
+                prefix: influx.prefix,
+                tags: influx.tags,
+                interval: Duration::from_secs(influx.interval_secs),
+            }),
+        };
 
         for (i, proxy) in self.proxies.into_iter().enumerate() {
-            let addresses = proxy
+            let mut addresses = proxy
                 .address
-                .map(|addr| vec![addr])
-                .unwrap_or_else(|| proxy.addresses);
+                .as_ref()
+                .map_or(&proxy.addresses[..], std::slice::from_ref)
+                .iter()
+                .map(|address| parse_listen(address))
+                .collect::<Result<Vec<_>>>()?
+                .concat();
+            // overlapping ranges would otherwise add the entry's routes to a listener twice
+            addresses.sort();
+            addresses.dedup();
             if addresses.is_empty() {
                 warn!("Proxy entry {} for domain(s) {:?} did not provide any addresses or ports to bind to - it will be ignored", i, proxy.domains);
                 continue;
             }
 
+            // compression overrides apply to every route of this entry
+            if let Some(level) = proxy.compression_level {
+                if level > 10 {
+                    bail!(
+                        "Proxy entry {} has an invalid compression level {} (max: 10)",
+                        i,
+                        level
+                    );
+                }
+            }
+            let compression = match (proxy.compression_threshold, proxy.compression_level) {
+                (None, None) => None,
+                (threshold, level) => Some(CompressionOverride {
+                    threshold,
+                    level: level.unwrap_or(6),
+                }),
+            };
+            let defaults = Backpressure::default();
+            let backpressure = Backpressure {
+                high_water_mark: proxy
+                    .high_water_mark_kb
+                    .map_or(defaults.high_water_mark, |kb| kb * 1024),
+                slow_peer_timeout: proxy
+                    .slow_peer_timeout_secs
+                    .map_or(defaults.slow_peer_timeout, Duration::from_secs),
+                max_buffered: proxy.max_buffered_kb.map(|kb| kb * 1024),
+            };
+            if backpressure.high_water_mark == 0 {
+                bail!("Proxy entry {} has a high water mark of 0", i);
+            }
+
+            let vpn_policy = match proxy.vpn_policy {
+                VpnPolicyEntry::Allow => VpnPolicy::Allow,
+                VpnPolicyEntry::Deny => VpnPolicy::Deny,
+                VpnPolicyEntry::Flag => VpnPolicy::Flag,
+                VpnPolicyEntry::Limbo if proxy.limbo_targets.is_empty() => {
+                    bail!(
+                        "Proxy entry {} uses the limbo VPN policy, but has no limbo targets",
+                        i
+                    )
+                }
+                VpnPolicyEntry::Limbo => VpnPolicy::Limbo(proxy.limbo_targets.clone()),
+            };
+            if proxy.vpn_policy != VpnPolicyEntry::Allow && reputation.is_none() {
+                bail!(
+                    "Proxy entry {} has a VPN policy, but IP reputation lookups are not configured",
+                    i
+                );
+            }
+
+            let real_ip = proxy
+                .real_ip
+                .as_ref()
+                .map(build_real_ip)
+                .transpose()
+                .with_context(|| format!("Proxy entry {} has an invalid RealIP block", i))?;
+
+            let dns_verification = match &proxy.dns_verification {
+                Some(entry) if entry.addresses.is_empty() => {
+                    bail!("Proxy entry {} verifies DNS, but lists no addresses", i)
+                }
+                Some(entry) => Some(DnsVerification {
+                    addresses: entry.addresses.clone(),
+                    cache_ttl: Duration::from_secs(entry.cache_ttl_secs),
+                }),
+                None => None,
+            };
+
+            let schedule = proxy
+                .schedule
+                .as_ref()
+                .map(build_schedule)
+                .transpose()
+                .with_context(|| format!("Proxy entry {} has an invalid schedule", i))?;
+
+            let usernames = proxy
+                .usernames
+                .as_ref()
+                .map(build_usernames)
+                .transpose()
+                .with_context(|| format!("Proxy entry {} has an invalid usernames block", i))?
+                .map(Arc::new);
+
+            let wake = proxy
+                .wake
+                .as_ref()
+                .map(|wake| build_wake(wake, proxy.panel.as_ref()))
+                .transpose()
+                .with_context(|| format!("Proxy entry {} has an invalid wake block", i))?;
+            if wake.is_some() && proxy.tunnel {
+                bail!("Proxy entry {} is tunneled, and cannot wake its targets", i);
+            }
+
+            if let Some(entry) = &proxy.idle_shutdown {
+                idle_shutdowns.push(IdleConfig {
+                    domains: proxy
+                        .domain
+                        .clone()
+                        .map(|domain| vec![domain])
+                        .unwrap_or_else(|| proxy.domains.clone()),
+                    targets: proxy
+                        .target
+                        .map(|target| vec![target])
+                        .unwrap_or_else(|| proxy.targets.clone()),
+                    after: Duration::from_secs(entry.after_mins.max(1) * 60),
+                    hook: build_hook(&entry.hook, "stop", proxy.panel.as_ref()).with_context(
+                        || format!("Proxy entry {} has an invalid idle shutdown hook", i),
+                    )?,
+                });
+            }
+            if !proxy.backup_targets.is_empty() && proxy.health_check.is_none() {
+                warn!("Proxy entry {} has backup targets, but no health check - they will never be used", i);
+            }
+            if let Some(entry) = &proxy.health_check {
+                let mut targets = proxy
+                    .target
+                    .map(|target| vec![target])
+                    .unwrap_or_else(|| proxy.targets.clone());
+                targets.extend(&proxy.backup_targets);
+                health_checks.push(
+                    build_health_check(entry, targets).with_context(|| {
+                        format!("Proxy entry {} has an invalid health check", i)
+                    })?,
+                );
+            }
+            if let Some(entry) = &proxy.rcon {
+                let targets = proxy
+                    .target
+                    .map(|target| vec![target])
+                    .unwrap_or_else(|| proxy.targets.clone());
+                rcon.push(
+                    build_rcon(entry, targets)
+                        .with_context(|| format!("Proxy entry {} has an invalid RCON proxy", i))?,
+                );
+            }
+
+            let tenant = match &proxy.tenant {
+                Some(name) => Some(
+                    tenants
+                        .iter()
+                        .find(|tenant| &tenant.name == name)
+                        .cloned()
+                        .with_context(|| {
+                            format!("Proxy entry {} belongs to unknown tenant {:?}", i, name)
+                        })?,
+                ),
+                None => None,
+            };
+
+            let resolver = match (
+                &proxy.target_template,
+                &proxy.target_lookup_url,
+                &proxy.target_lookup_command,
+            ) {
+                (None, None, None) => None,
+                (Some(template), None, None) => Some(Resolver::Template(template.clone())),
+                (None, Some(url), None) => Some(Resolver::Http(url.clone())),
+                (None, None, Some(command)) => Some(Resolver::Command(command.clone())),
+                _ => bail!(
+                    "Proxy entry {} must set at most one of target_template, target_lookup_url or target_lookup_command",
+                    i
+                ),
+            };
+            let templated = proxy
+                .domain
+                .iter()
+                .chain(&proxy.domains)
+                .any(|domain| domain.contains(template::PLACEHOLDER));
+            if templated != resolver.is_some() {
+                bail!(
+                    "Proxy entry {} must use a target template or lookup exactly when its domains contain {}",
+                    i,
+                    template::PLACEHOLDER
+                );
+            }
+
+            let agones = match &proxy.agones {
+                Some(entry) => Some(AgonesConfig {
+                    allocator: entry.allocator.clone(),
+                    namespace: entry.namespace.clone(),
+                    fleet: entry.fleet.clone(),
+                    port_name: entry.port_name.clone(),
+                    ca: entry.ca.clone(),
+                    identity: match (&entry.cert, &entry.key) {
+                        (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+                        (None, None) => None,
+                        _ => bail!(
+                            "Proxy entry {} must give both an Agones cert and key, or neither",
+                            i
+                        ),
+                    },
+                    release_api: entry.release_api.clone(),
+                    release_token: entry.release_token.clone(),
+                }),
+                None => None,
+            };
+            if agones.is_some() && proxy.tunnel {
+                bail!(
+                    "Proxy entry {} is tunneled, and cannot use an Agones fleet",
+                    i
+                );
+            }
+
+            let fallback_method = match proxy.fallback {
+                None | Some(FallbackEntry::Drop) => None,
+                Some(FallbackEntry::Status) => Some(FallbackMethod::Status(
+                    serde_json::from_value(json!({ "text": proxy.fallback_message }))
+                        .context("Invalid fallback message")?,
+                )),
+                Some(FallbackEntry::Route) => Some(FallbackMethod::Route(
+                    proxy.fallback_route.clone().with_context(|| {
+                        format!(
+                            "Proxy entry {} uses the route fallback, but has no fallback route",
+                            i
+                        )
+                    })?,
+                )),
+            };
+
+            let messages = proxy.messages.as_ref().map(|entry| {
+                // clients declare their locale in lowercase
+                Arc::new(Messages {
+                    fallback: entry.fallback.as_ref().map(|locale| locale.to_lowercase()),
+                    translations: entry
+                        .translations
+                        .iter()
+                        .map(|(locale, messages)| (locale.to_lowercase(), messages.clone()))
+                        .collect(),
+                })
+            });
+
+            let motds = match proxy.motds.is_empty() {
+                true => None,
+                false => Some(Arc::new(Motds {
+                    motds: proxy
+                        .motds
+                        .iter()
+                        .map(|motd| json!({ "text": motd }))
+                        .collect(),
+                    rotation: match proxy.motd_rotation {
+                        MotdRotationEntry::Sequential => MotdRotation::Sequential,
+                        MotdRotationEntry::Random => MotdRotation::Random,
+                        MotdRotationEntry::Timed if proxy.motd_interval_secs == 0 => {
+                            bail!("Proxy entry {} rotates its MOTDs every 0 seconds", i)
+                        }
+                        MotdRotationEntry::Timed => {
+                            MotdRotation::Timed(Duration::from_secs(proxy.motd_interval_secs))
+                        }
+                    },
+                })),
+            };
+
+            let player_sample = match proxy.player_sample {
+                PlayerSampleEntry::Pass => PlayerSample::Pass,
+                PlayerSampleEntry::Hide => PlayerSample::Hide,
+                PlayerSampleEntry::Replace => {
+                    PlayerSample::Replace(proxy.player_sample_lines.clone())
+                }
+            };
+
+            let hostname_rewrite = match (proxy.hostname_rewrite, &proxy.rewritten_hostname) {
+                (HostnameRewriteEntry::Listener, _) => HostnameRewrite::Listener,
+                (HostnameRewriteEntry::Preserve, _) => HostnameRewrite::Preserve,
+                (HostnameRewriteEntry::Fixed, Some(hostname)) => {
+                    HostnameRewrite::Fixed(hostname.clone())
+                }
+                (HostnameRewriteEntry::Template, Some(template)) => {
+                    HostnameRewrite::Template(template.clone())
+                }
+                (_, None) => bail!(
+                    "Proxy entry {} rewrites the hostname, but has no rewritten hostname",
+                    i
+                ),
+            };
+
+            if proxy.transparent && proxy.tunnel {
+                bail!(
+                    "Proxy entry {} is tunneled, and cannot connect transparently",
+                    i
+                );
+            }
+            if proxy.transparent && !cfg!(target_os = "linux") {
+                bail!(
+                    "Proxy entry {} is transparent, which is only supported on Linux",
+                    i
+                );
+            }
+
+            if proxy.tunnel && !is_edge {
+                bail!(
+                    "Proxy entry {} is tunneled, but this instance is not a tunnel edge",
+                    i
+                );
+            }
+
             for address in addresses {
                 // collect domains
                 let domains = proxy
@@ -87,8 +1369,8 @@ impl Config for ConfigV1 {
                     .target
                     .map(|target| vec![target])
                     .unwrap_or_else(|| proxy.targets.clone());
-                // ignore empty targets
-                if targets.is_empty() {
+                // ignore empty targets, unless they are allocated or resolved
+                if targets.is_empty() && agones.is_none() && resolver.is_none() {
                     warn!(
                         "Proxy entry {} does not specify any targets - it will be ignored",
                         i
@@ -102,6 +1384,8 @@ impl Config for ConfigV1 {
                     .map(|domain| Route {
                         from: domain.clone(),
                         to: targets.clone(),
+                        backups: proxy.backup_targets.clone(),
+                        fail_back: proxy.fail_back,
                         selection_algorithm: proxy
                             .selection_algorithm
                             .clone()
@@ -112,31 +1396,128 @@ impl Config for ConfigV1 {
                                 }
                             })
                             .unwrap_or_default(),
+                        compression,
+                        backpressure,
+                        status_pool: proxy.status_pool_size,
+                        status_cache: proxy.status_cache_ttl_secs.map(Duration::from_secs),
+                        tunnel: proxy.tunnel,
+                        vpn_policy: vpn_policy.clone(),
+                        dns_verification: dns_verification.clone(),
+                        schedule: schedule.clone(),
+                        max_players: proxy.max_players,
+                        resume_grace: proxy.resume_grace_secs.map(Duration::from_secs),
+                        full_message: proxy.full_message.clone(),
+                        wake: wake.clone(),
+                        agones: agones.clone(),
+                        tenant: tenant.clone(),
+                        pattern: Pattern::parse(domain),
+                        resolver: resolver.clone(),
+                        commands: None,
+                        bungeecord: proxy.bungeecord_channel,
+                        control_channel: proxy.control_channel,
+                        transparent: proxy.transparent,
+                        messages: messages.clone(),
+                        motds: motds.clone(),
+                        player_sample: player_sample.clone(),
+                        enforce_secure_chat: proxy.enforce_secure_chat,
+                        chat_signing: match proxy.chat_signing {
+                            ChatSigningEntry::Preserve => ChatSigning::Preserve,
+                            ChatSigningEntry::Strip => ChatSigning::Strip,
+                        },
+                        hostname_rewrite: hostname_rewrite.clone(),
+                        usernames: usernames.clone(),
+                        offline_mode: proxy.offline_mode,
+                        authenticate: proxy.authenticate,
                     })
                     .collect();
 
                 match proxies.get_mut(&address) {
                     Some(entry) => {
-                        // ensure we are not about to overrite existing domains
-                        if entry
+                        // a listener can only speak one transport
+                        if entry.transport != proxy.transport {
+                            warn!("Proxy entry {} uses the {:?} transport, but {} is already listening with the {:?} transport - it will be ignored", i, proxy.transport, address, entry.transport);
+                            continue;
+                        }
+                        // ensure we are not about to overrite existing domains - a domain sent to
+                        // other targets can't be guessed between, so it fails the configuration
+                        let taken: Vec<_> = entry
                             .routes
                             .iter()
-                            .any(|route| domains.contains(&route.from))
-                        {
+                            .filter(|route| {
+                                domains
+                                    .iter()
+                                    .any(|domain| domain.eq_ignore_ascii_case(&route.from))
+                            })
+                            .collect();
+                        if let Some(route) = taken.iter().find(|route| {
+                            route.to != targets || route.backups != proxy.backup_targets
+                        }) {
+                            bail!(
+                                "Proxy entry {} routes {} on {} to {:?}, but another entry already routes it to {:?} - remove one of them, or give them different domains",
+                                i,
+                                route.from,
+                                address,
+                                targets,
+                                route.to
+                            );
+                        }
+                        if !taken.is_empty() {
                             warn!("The domain(s) {:?} have already been specified for use in another proxy", domains);
                             continue;
                         };
 
+                        // a shared listener takes the largest backlog asked for
+                        if let Some(backlog) = proxy.backlog {
+                            entry.backlog = entry.backlog.max(backlog);
+                        }
+                        // as is any entry's request to answer queries
+                        entry.query |= proxy.query;
+                        if let Some(motd) = &proxy.query_motd {
+                            entry.query_motd = motd.clone();
+                        }
+                        if let Some(fallback_method) = &fallback_method {
+                            entry.fallback_method = fallback_method.clone();
+                        }
+                        if let Some(rate_limits) = &proxy.rate_limits {
+                            entry.rate_limits = build_rate_limits(rate_limits);
+                        }
+                        if let Some(slow_clients) = &proxy.slow_clients {
+                            entry.slow_clients = build_slow_clients(slow_clients);
+                        }
+                        if real_ip.is_some() {
+                            entry.real_ip = real_ip.clone();
+                        }
                         entry.routes.append(&mut routes)
                     }
                     None => {
                         proxies.insert(
                             address,
                             Proxy {
-                                protocol_version: 761,
+                                protocol_version: ProtocolVersion::DEFAULT,
                                 listen_addr: address,
-                                fallback_method: FallbackMethod::Drop,
+                                transport: proxy.transport,
+                                fallback_method: fallback_method.clone().unwrap_or_default(),
                                 routes,
+                                route_index: RouteIndex::default(),
+                                backlog: proxy.backlog.unwrap_or(DEFAULT_BACKLOG),
+                                min_fd_headroom: self.min_fd_headroom,
+                                query: proxy.query,
+                                query_motd: proxy
+                                    .query_motd
+                                    .clone()
+                                    .unwrap_or_else(|| DEFAULT_QUERY_MOTD.to_string()),
+                                v6_only: false,
+                                rate_limits: proxy
+                                    .rate_limits
+                                    .as_ref()
+                                    .map(build_rate_limits)
+                                    .unwrap_or_default(),
+                                slow_clients: proxy
+                                    .slow_clients
+                                    .as_ref()
+                                    .map(build_slow_clients)
+                                    .unwrap_or_default(),
+                                real_ip: real_ip.clone(),
                             },
                         );
                     }
@@ -144,9 +1525,432 @@ impl Config for ConfigV1 {
             }
         }
 
+        let mut proxies: Vec<_> = proxies.into_values().collect();
+        // dual-stack IPv6 listeners also take IPv4 connections on their port, so they can't share
+        // it with an IPv4 listener unless they only accept IPv6
+        let v4_ports: Vec<_> = proxies
+            .iter()
+            .filter(|proxy| proxy.listen_addr.is_ipv4())
+            .map(|proxy| proxy.listen_addr.port())
+            .collect();
+        for proxy in &mut proxies {
+            proxy.v6_only =
+                proxy.listen_addr.is_ipv6() && v4_ports.contains(&proxy.listen_addr.port());
+            proxy.route_index = RouteIndex::new(&proxy.routes);
+        }
+        for proxy in &proxies {
+            validate_routes(proxy)?;
+        }
+        // fallback routes must be served by the listener, without a name to capture
+        for proxy in &proxies {
+            if let FallbackMethod::Route(domain) = &proxy.fallback_method {
+                if proxy.route(domain).is_none() {
+                    bail!(
+                        "The fallback route {} is not a route of the listener on {}",
+                        domain,
+                        proxy.listen_addr
+                    );
+                }
+            }
+        }
+        if let Some(entry) = self.commands {
+            let commands =
+                Arc::new(build_commands(entry, &proxies).context("The commands block is invalid")?);
+            for route in proxies.iter_mut().flat_map(|proxy| &mut proxy.routes) {
+                route.commands = Some(commands.clone());
+            }
+        }
+
         Ok(MagmaConfig {
             debug: self.debug,
-            proxies: proxies.into_values().collect(),
+            proxies,
+            tunnel,
+            events: self.events,
+            admin: self.admin.map(|admin| AdminConfig {
+                listen_addr: admin.address,
+                tls: admin.tls.map(|tls| TlsConfig {
+                    cert: tls.cert,
+                    key: tls.key,
+                    ca: tls.ca,
+                }),
+                tokens: admin.tokens,
+            }),
+            history: self.history.map(|history| HistoryConfig {
+                path: history.path,
+                retention: Duration::from_secs(history.retention_days * 24 * 60 * 60),
+            }),
+            reputation,
+            security_log: self.security_log,
+            max_open_files: self.max_open_files,
+            rcon,
+            health_checks,
+            idle_shutdowns,
+            tarpit: self.tarpit.map(|tarpit| TarpitConfig {
+                duration: Duration::from_secs(tarpit.duration_secs),
+                max_connections: tarpit.max_connections,
+            }),
+            crash_reports: CrashConfig {
+                directory: self.crash_reports.directory,
+                webhook: self.crash_reports.webhook,
+            },
+            memory: self.memory.map(|memory| MemoryConfig {
+                rss_limit: memory.rss_limit_mb * 1024 * 1024,
+            }),
+            statsd,
+            influx,
+            announcements,
+            authentication: AuthConfig {
+                session_server: self.authentication.session_server,
+                max_concurrent_lookups: self.authentication.max_concurrent_lookups,
+                max_queued_lookups: self.authentication.max_queued_lookups,
+                overflow_message: self.authentication.overflow_message,
+            },
         })
     }
 }
+
+/// Check a listener's routes don't conflict - that none is unreachable behind another, and none
+/// sends clients back to the listener itself.
+fn validate_routes(proxy: &Proxy) -> Result<()> {
+    let listener = proxy.listen_addr;
+    for (i, route) in proxy.routes.iter().enumerate() {
+        // a target on the listener's own address would connect clients to it again, forever
+        let looped = route.to.iter().chain(&route.backups).find(|target| {
+            **target == listener
+                || (target.port() == listener.port()
+                    && listener.ip().is_unspecified()
+                    && (target.ip().is_loopback() || target.ip().is_unspecified()))
+        });
+        if let Some(target) = looped {
+            bail!(
+                "The route {} on {} targets {}, which is the listener itself - point it at the backend server instead",
+                route.from,
+                listener,
+                target
+            );
+        }
+
+        let Some(pattern) = &route.pattern else {
+            continue;
+        };
+        // a repeated pattern would never be matched, as the first always is
+        if let Some(earlier) = proxy.routes[..i]
+            .iter()
+            .find(|earlier| earlier.pattern.as_ref() == Some(pattern))
+        {
+            bail!(
+                "The templated routes {} and {} on {} match the same domains, so only the first is used - remove one of them",
+                earlier.from,
+                route.from,
+                listener
+            );
+        }
+        // exact domains take precedence, which is intended, but easily overlooked
+        for exact in proxy.routes.iter().filter(|exact| exact.pattern.is_none()) {
+            if pattern.capture(&exact.from).is_some() {
+                warn!(
+                    "The domain {} on {} is also matched by the templated route {} - it is always routed to {:?}",
+                    exact.from, listener, route.from, exact.to
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Build a wake-on-connect block.
+fn build_wake(wake: &WakeEntry, panel: Option<&PanelEntry>) -> Result<Wake> {
+    Ok(Wake {
+        hook: build_hook(&wake.hook, "start", panel)?,
+        hold: wake.hold_secs.map(Duration::from_secs),
+        cooldown: Duration::from_secs(wake.cooldown_secs),
+        message: wake.message.clone(),
+        motd: wake.motd.clone(),
+    })
+}
+
+/// Build a hook, with the given action for Docker containers and panel servers.
+fn build_hook(hook: &HookEntry, action: &'static str, panel: Option<&PanelEntry>) -> Result<Hook> {
+    match (&hook.command, &hook.url, &hook.container, hook.panel) {
+        (Some(command), None, None, false) => Ok(Hook::Command(command.clone())),
+        (None, Some(url), None, false) => Ok(Hook::Http {
+            method: hook
+                .method
+                .to_uppercase()
+                .parse()
+                .with_context(|| format!("Invalid method {:?}", hook.method))?,
+            url: url.clone(),
+        }),
+        (None, None, Some(container), false) => Ok(Hook::Docker {
+            url: hook.docker_url.clone(),
+            container: container.clone(),
+            action,
+        }),
+        (None, None, None, true) => Ok(Hook::Panel {
+            panel: panel
+                .context("A panel hook requires the entry to have a panel server")?
+                .panel(),
+            signal: action,
+        }),
+        _ => bail!("A hook must set exactly one of command, url, container or panel"),
+    }
+}
+
+/// Build a health check block for an entry with the given targets.
+fn build_health_check(
+    health_check: &HealthCheckEntry,
+    targets: Vec<SocketAddr>,
+) -> Result<HealthCheckConfig> {
+    let probe = match health_check.mode {
+        HealthCheckMode::Tcp => Probe::Tcp,
+        HealthCheckMode::Rcon => Probe::Rcon {
+            port: health_check.rcon_port,
+            password: health_check
+                .rcon_password
+                .clone()
+                .context("RCON health checks need an rcon_password")?,
+            command: health_check.rcon_command.clone(),
+            expect: health_check.rcon_expect.clone(),
+        },
+    };
+    if health_check.rcon_expect.is_some() && health_check.rcon_command.is_none() {
+        bail!("rcon_expect requires an rcon_command");
+    }
+    Ok(HealthCheckConfig {
+        targets,
+        interval: Duration::from_secs(health_check.interval_secs.max(1)),
+        timeout: Duration::from_secs(health_check.timeout_secs.max(1)),
+        probe,
+        fetch_favicon: health_check.fetch_favicon,
+    })
+}
+
+/// Build the rate limits of a listener.
+fn build_rate_limits(entry: &RateLimitsEntry) -> RateLimits {
+    let limit = |entry: &RateLimitEntry| RateLimit {
+        per_ip: entry.per_ip,
+        per_listener: entry.per_listener,
+    };
+    RateLimits {
+        status: limit(&entry.status),
+        login: limit(&entry.login),
+        login_throttle: entry.login_throttle_ms.map(Duration::from_millis),
+        max_concurrent_logins: entry.max_concurrent_logins.map(|max| max.max(1)),
+    }
+}
+
+/// Build the slow client limits of a listener.
+fn build_slow_clients(entry: &SlowClientsEntry) -> SlowClients {
+    SlowClients {
+        handshake_timeout: entry
+            .handshake_timeout_ms
+            .map_or(DEFAULT_HANDSHAKE_TIMEOUT, |ms| {
+                Duration::from_millis(ms.max(1))
+            }),
+        min_receive_rate: entry.min_receive_rate.unwrap_or(DEFAULT_MIN_RECEIVE_RATE),
+    }
+}
+
+/// Build the RealIP settings of a listener, loading the provider's public key.
+fn build_real_ip(entry: &RealIpEntry) -> Result<RealIp> {
+    let pem = std::fs::read_to_string(&entry.public_key)
+        .with_context(|| format!("failed to read public key {:?}", entry.public_key))?;
+    Ok(RealIp {
+        public_key: realip::parse_key(&pem)?,
+        max_age: Duration::from_secs(entry.max_age_secs),
+        required: entry.required,
+    })
+}
+
+/// Build an RCON proxy block for an entry with the given targets.
+fn build_rcon(rcon: &RconEntry, targets: Vec<SocketAddr>) -> Result<RconConfig> {
+    if rcon.password.is_empty() {
+        bail!("The RCON password must not be empty");
+    }
+    let targets = match rcon.target {
+        Some(target) => vec![target],
+        None => targets
+            .into_iter()
+            .map(|target| SocketAddr::new(target.ip(), rcon.backend_port))
+            .collect(),
+    };
+    if targets.is_empty() {
+        bail!("RCON proxies need a target");
+    }
+    Ok(RconConfig {
+        listen_addr: rcon.address,
+        password: rcon.password.clone(),
+        backend_password: rcon.backend_password.clone(),
+        targets,
+    })
+}
+
+/// Build a route schedule block.
+fn build_schedule(schedule: &ScheduleEntry) -> Result<Schedule> {
+    if schedule.hours.is_empty() {
+        bail!("Schedules must list at least one window of hours");
+    }
+    Ok(Schedule {
+        timezone: schedule::parse_timezone(&schedule.timezone)?,
+        windows: schedule
+            .hours
+            .iter()
+            .map(|window| schedule::parse_window(window))
+            .collect::<Result<_>>()?,
+        message: schedule.message.clone(),
+        motd: schedule.motd.clone(),
+    })
+}
+
+/// Build a usernames block, compiling its deny patterns.
+fn build_usernames(usernames: &UsernamesEntry) -> Result<UsernamePolicy> {
+    Ok(UsernamePolicy {
+        vanilla: usernames.vanilla,
+        deny: usernames
+            .deny
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).with_context(|| format!("Invalid deny pattern {:?}", pattern))
+            })
+            .collect::<Result<_>>()?,
+        message: usernames.message.clone(),
+    })
+}
+
+/// Parse a listening address, expanding a range of ports such as `0.0.0.0:25565-25575` into an
+/// address for each port.
+fn parse_listen(address: &str) -> Result<Vec<SocketAddr>> {
+    if let Ok(address) = address.parse() {
+        return Ok(vec![address]);
+    }
+    let (ip, ports) = address
+        .rsplit_once(':')
+        .with_context(|| format!("Invalid listen address {}", address))?;
+    let ip: IpAddr = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .with_context(|| format!("Invalid IP address in listen address {}", address))?;
+    let (start, end) = ports
+        .split_once('-')
+        .with_context(|| format!("Invalid port in listen address {}", address))?;
+    let start: u16 = start
+        .parse()
+        .with_context(|| format!("Invalid port range in listen address {}", address))?;
+    let end: u16 = end
+        .parse()
+        .with_context(|| format!("Invalid port range in listen address {}", address))?;
+    if start > end {
+        bail!("The port range of listen address {} is backwards", address);
+    }
+    Ok((start..=end)
+        .map(|port| SocketAddr::new(ip, port))
+        .collect())
+}
+
+/// Build a proxy command block, resolving each server to the listener of its route.
+fn build_commands(entry: CommandsEntry, proxies: &[Proxy]) -> Result<Commands> {
+    let mut servers = BTreeMap::new();
+    for (name, domain) in entry.servers {
+        let proxy = proxies
+            .iter()
+            .find(|proxy| proxy.routes.iter().any(|route| route.from == domain))
+            .with_context(|| format!("Server {:?} has no route for {:?}", name, domain))?;
+        servers.insert(name, (domain, proxy.listen_addr.port()));
+    }
+    if let Some((alias, name)) = entry
+        .aliases
+        .iter()
+        .find(|(_, name)| !servers.contains_key(*name))
+    {
+        bail!("Alias {:?} names unknown server {:?}", alias, name);
+    }
+    Ok(Commands {
+        command: entry.command,
+        servers,
+        aliases: entry.aliases,
+    })
+}
+
+/// Build a scheduled announcement block.
+fn build_announcement(announcement: &AnnouncementEntry) -> Result<Announcement> {
+    let timing = match (announcement.every_secs, announcement.at.is_empty()) {
+        (Some(0), _) => bail!("Announcements must be at least 1 second apart"),
+        (Some(secs), true) => AnnouncementTiming::Every(Duration::from_secs(secs)),
+        (None, false) => AnnouncementTiming::At {
+            timezone: schedule::parse_timezone(&announcement.timezone)?,
+            times: announcement
+                .at
+                .iter()
+                .map(|time| schedule::parse_time(time))
+                .collect::<Result<_>>()?,
+        },
+        _ => bail!("Announcements must set exactly one of every_secs and at"),
+    };
+    Ok(Announcement {
+        message: announcement.message.clone(),
+        routes: announcement.routes.clone(),
+        action_bar: announcement.action_bar,
+        timing,
+    })
+}
+
+/// Build an IP reputation configuration block.
+fn build_reputation(reputation: ReputationEntry) -> Result<ReputationConfig> {
+    let api = match (reputation.api_url, reputation.api_pointer) {
+        (Some(url), Some(pointer)) => Some(ReputationApi { url, pointer }),
+        (None, None) => None,
+        _ => bail!("The reputation API requires both api_url and api_pointer"),
+    };
+    if reputation.database.is_none() && api.is_none() {
+        bail!("IP reputation lookups require a database, an API, or both");
+    }
+    Ok(ReputationConfig {
+        database: reputation.database,
+        api,
+        cache_ttl: Duration::from_secs(reputation.cache_ttl_secs),
+    })
+}
+
+/// Build a tunnel configuration block.
+fn build_tunnel(tunnel: TunnelEntry) -> Result<TunnelConfig> {
+    if tunnel.token.is_empty() {
+        bail!("The tunnel token must not be empty");
+    }
+    let compression = tunnel
+        .compression_level
+        .map(LinkCompression::Zstd)
+        .unwrap_or_default();
+    let server_name = tunnel.tls.as_ref().and_then(|tls| tls.server_name.clone());
+    let tls = tunnel.tls.map(|tls| TlsConfig {
+        cert: tls.cert,
+        key: tls.key,
+        ca: tls.ca,
+    });
+    match tunnel.mode {
+        TunnelMode::Edge => Ok(TunnelConfig::Edge(EdgeConfig {
+            hub_addr: tunnel
+                .hub
+                .context("Tunnel edges must specify the hub address")?,
+            token: tunnel.token,
+            compression,
+            connections: tunnel.connections.unwrap_or(4),
+            tls,
+            server_name,
+        })),
+        TunnelMode::Hub => {
+            if tunnel.allowed_targets.is_empty() {
+                warn!("The tunnel hub does not allow any targets - edges will be unable to reach any backends");
+            }
+            Ok(TunnelConfig::Hub(HubConfig {
+                listen_addr: tunnel
+                    .address
+                    .context("Tunnel hubs must specify an address to listen on")?,
+                token: tunnel.token,
+                compression,
+                allowed_targets: tunnel.allowed_targets,
+                tls,
+            }))
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Defines crash reports, written when a task panics.
+//!
+//! A panic in a connection's task only takes down that task, and would otherwise go unnoticed in
+//! its dropped handle. The panic hook installed by [install] writes a report - the panic message,
+//! where it happened, a backtrace, the version of Magma, and the connection being handled - to a
+//! file, and can post it to a webhook so operators hear about it.
+
+use std::{
+    backtrace::Backtrace,
+    fmt::Write,
+    fs,
+    future::Future,
+    net::SocketAddr,
+    panic::{self, PanicHookInfo},
+    path::Path,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::config::CrashConfig;
+
+tokio::task_local! {
+    /// The connection the current task is handling.
+    static CONNECTION: ConnectionContext;
+}
+
+/// The connection a task is handling, reported if it panics.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionContext {
+    /// The ID of the connection, shared by its events and log lines.
+    pub connection_id: String,
+    /// The address of the client.
+    pub peer: SocketAddr,
+    /// The address of the listener the client connected to.
+    pub listener: SocketAddr,
+}
+
+/// Run a future handling the given connection.
+pub async fn scope<F: Future>(context: ConnectionContext, future: F) -> F::Output {
+    CONNECTION.scope(context, future).await
+}
+
+/// Wrap a future so it handles the same connection as the current task, for futures which will
+/// be spawned as tasks of their own.
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let context = CONNECTION.try_with(Clone::clone).ok();
+    async move {
+        match context {
+            Some(context) => CONNECTION.scope(context, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// A report of a panic.
+#[derive(Debug, Serialize)]
+struct CrashReport {
+    /// When the panic happened, in milliseconds since the Unix epoch.
+    timestamp: u64,
+    /// The version of Magma, and the commit it was built from.
+    version: String,
+    /// The name of the thread which panicked.
+    thread: String,
+    /// The panic message.
+    message: String,
+    /// Where the panic happened.
+    location: Option<String>,
+    /// The connection being handled, if any.
+    connection: Option<ConnectionContext>,
+    /// The backtrace of the panic.
+    backtrace: String,
+}
+
+impl CrashReport {
+    /// Capture a report of the current panic.
+    fn capture(info: &PanicHookInfo) -> Self {
+        let payload = info.payload();
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_string(),
+            },
+        };
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            version: format!("{} ({})", env!("CARGO_PKG_VERSION"), env!("VERGEN_GIT_SHA")),
+            thread: thread::current().name().unwrap_or("<unnamed>").to_string(),
+            message,
+            location: info.location().map(ToString::to_string),
+            connection: CONNECTION.try_with(Clone::clone).ok(),
+            backtrace: Backtrace::force_capture().to_string(),
+        }
+    }
+
+    /// Render the report as text.
+    fn render(&self) -> String {
+        // writing to a string can't fail
+        let mut out = String::new();
+        let _ = writeln!(out, "Magma crash report");
+        let _ = writeln!(out, "Time: {}", self.timestamp);
+        let _ = writeln!(out, "Version: {}", self.version);
+        let _ = writeln!(out, "Thread: {}", self.thread);
+        let _ = writeln!(out, "Message: {}", self.message);
+        if let Some(location) = &self.location {
+            let _ = writeln!(out, "Location: {}", location);
+        }
+        if let Some(connection) = &self.connection {
+            let _ = writeln!(
+                out,
+                "Connection: {} from {} on {}",
+                connection.connection_id, connection.peer, connection.listener
+            );
+        }
+        let _ = write!(out, "\nBacktrace:\n{}", self.backtrace);
+        out
+    }
+
+    /// Write the report to a new file in the given directory, returning its path.
+    fn write(&self, directory: &Path) -> std::io::Result<String> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(format!("crash-{}.txt", self.timestamp));
+        fs::write(&path, self.render())?;
+        Ok(path.display().to_string())
+    }
+}
+
+/// Install a panic hook writing crash reports, after the existing hook has run.
+pub fn install(config: CrashConfig) {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let report = CrashReport::capture(info);
+        match report.write(&config.directory) {
+            Ok(path) => error!(
+                "A task panicked: {} - crash report written to {}",
+                report.message, path
+            ),
+            Err(err) => error!(
+                "A task panicked: {} - failed to write crash report: {}",
+                report.message, err
+            ),
+        }
+        // the webhook is only posted while the runtime is still running
+        if let (Some(url), Ok(runtime)) = (
+            config.webhook.clone(),
+            tokio::runtime::Handle::try_current(),
+        ) {
+            runtime.spawn(async move {
+                let result = reqwest::Client::new()
+                    .post(&url)
+                    .json(&report)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(err) = result {
+                    warn!("Failed to post crash report to {}: {}", url, err);
+                }
+            });
+        }
+    }));
+}
@@ -1,72 +1,156 @@
-//! Handles encryption and decryption of packets between the client and proxy.
+//! Handles the encryption of connections once a login has enabled it.
 
-use std::io::{Cursor, Read};
+use std::{
+    fmt::{self, Debug},
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
 
 use aes::{
-    cipher::{BlockDecryptMut, KeyIvInit},
+    cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit},
     Aes128,
 };
-use anyhow::Result;
-
-use crate::io::ProtocolAsyncReadExt;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 type Decryptor = cfb8::Decryptor<Aes128>;
 type Encryptor = cfb8::Encryptor<Aes128>;
 
-#[derive(Default)]
-pub enum Cryptor {
-    #[default]
-    Uninitialized,
-    Initialized {
-        encryptor: Box<Encryptor>,
-        decryptor: Box<Decryptor>,
-        inbuffer: Vec<u8>,
-        outbuffer: Vec<u8>,
-    },
+/// A stream which is encrypted with AES/CFB8 once the login has enabled encryption.
+///
+/// Writes are encrypted into a buffer before reaching the inner stream, so like a `BufWriter`,
+/// the stream must be flushed for them to be sent.
+pub struct CipherStream<S> {
+    inner: S,
+    ciphers: Option<(Box<Encryptor>, Box<Decryptor>)>,
+    pending: Vec<u8>,
+}
+
+impl<S> CipherStream<S> {
+    /// Wrap a stream, which is unencrypted until [CipherStream::enable] is called.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            ciphers: None,
+            pending: vec![],
+        }
+    }
+
+    /// Encrypt everything read and written from now on with the given shared secret.
+    pub fn enable(&mut self, secret: &[u8]) {
+        self.ciphers = Some((
+            Box::new(Encryptor::new(secret.into(), secret.into())),
+            Box::new(Decryptor::new(secret.into(), secret.into())),
+        ));
+    }
+
+    /// Get a reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> Debug for CipherStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CipherStream")
+            .field("encrypted", &self.ciphers.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CipherStream<S> {
+    /// Write out as much of the pending data as the inner stream accepts.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
 }
 
-impl Cryptor {
-    /// Create a new cryptor instance.
-    pub fn new(key: &[u8]) -> Self {
-        Self::Initialized {
-            inbuffer: Vec::with_capacity(512),
-            outbuffer: Vec::with_capacity(512),
-            decryptor: Box::new(Decryptor::new(key.into(), key.into())),
-            encryptor: Box::new(Encryptor::new(key.into(), key.into())),
+impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some((_, decryptor)) = &mut self.ciphers {
+            // CFB8 works a byte at a time
+            for byte in &mut buf.filled_mut()[start..] {
+                decryptor.decrypt_block_mut(std::slice::from_mut(byte).into());
+            }
         }
+        Poll::Ready(Ok(()))
     }
+}
 
-    /// Read the next packet from the stream.
-    pub async fn next_packet(&mut self, data: &mut [u8]) -> Result<Option<Vec<u8>>> {
-        let (decryptor, buffer) = match self {
-            Cryptor::Initialized {
-                encryptor: _,
-                decryptor,
-                inbuffer,
-                outbuffer: _,
-            } => (decryptor, inbuffer),
-            _ => panic!(),
-        };
-        // decrypt data
-        decryptor.decrypt_block_mut(data.into());
-        buffer.extend_from_slice(data);
-        // create cursor and read packet length
-        let mut cursor = Cursor::new(&buffer);
-        let packet_length = cursor.read_var_int().await? as usize;
-        // attempt to fetch data - could make this zero copy
-        let mut buf = vec![0u8; packet_length];
-        let bytes_read = cursor.read(&mut buf)?;
-        // ensure we have a full packet
-        if bytes_read < packet_length {
-            return Ok(None);
+impl<S: AsyncWrite + Unpin> AsyncWrite for CipherStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // encrypted bytes can't be taken back, so finish the previous write before accepting more
+        if !this.pending.is_empty() {
+            ready!(this.poll_pending(cx))?;
+        }
+        let (encryptor, _) = this.ciphers.as_mut().unwrap();
+        let start = this.pending.len();
+        this.pending.extend_from_slice(buf);
+        for byte in &mut this.pending[start..] {
+            encryptor.encrypt_block_mut(std::slice::from_mut(byte).into());
+        }
+        // the rest is sent on the next write or flush
+        if let Poll::Ready(Err(err)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(err));
         }
-        // update internal buffer
-        buffer.drain(0..packet_length);
-        Ok(Some(buf))
+        Poll::Ready(Ok(buf.len()))
     }
 
-    /// Encrypt the given data.
-    pub fn encrypt_packet(&mut self, data: &[u8]) -> Vec<u8> {
-        todo!()
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Compute the hash a client joins a server with, and the server checks with the session server.
+///
+/// This is a SHA-1 digest, formatted as a signed hexadecimal number.
+pub fn server_hash(server_id: &str, secret: &[u8], public_key: &[u8]) -> String {
+    let mut digest: [u8; 20] = Sha1::new()
+        .chain_update(server_id.as_bytes())
+        .chain_update(secret)
+        .chain_update(public_key)
+        .finalize()
+        .into();
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        // two's complement, to print the magnitude
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            (*byte, carry) = (!*byte).overflowing_add(carry as u8);
+        }
+    }
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let hex = hex.trim_start_matches('0');
+    match negative {
+        true => format!("-{}", hex),
+        false => hex.to_string(),
     }
 }
@@ -0,0 +1,80 @@
+//! Defines DNS verification, which checks that the hostname a client connects with resolves to
+//! this proxy.
+//!
+//! Routes behind DNS-based protection, such as an anti-DDoS provider, are only meant to be reached
+//! through it - but a scanner which finds the proxy's address can connect directly while guessing
+//! hostnames. Verifying the hostname turns those connections away, as the hostnames they guess
+//! resolve to the provider rather than here. Lookups are cached, so each hostname is only resolved
+//! once per TTL. Verification fails closed - a hostname which can't be resolved, or is an address
+//! rather than a name, is rejected.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tokio::{net::lookup_host, time::timeout};
+use tracing::debug;
+
+use crate::config::DnsVerification;
+
+/// The maximum number of cached lookups, after which expired lookups are evicted.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+/// How long to wait for a lookup before rejecting the hostname.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The addresses each hostname resolved to, and when it was looked up.
+type Cache = Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>;
+
+/// The addresses hostnames resolved to, and when they were looked up.
+fn cache() -> &'static Cache {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Test whether the hostname resolves to one of the addresses of this proxy.
+pub async fn verify(hostname: &str, verification: &DnsVerification) -> bool {
+    // connecting by address is exactly what verification is meant to stop
+    if hostname.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    let resolved = match cache().lock().unwrap().get(hostname) {
+        Some((resolved, looked_up)) if looked_up.elapsed() < verification.cache_ttl => {
+            Some(resolved.clone())
+        }
+        _ => None,
+    };
+    let resolved = match resolved {
+        Some(resolved) => resolved,
+        None => {
+            let resolved = resolve(hostname).await;
+            let mut cache = cache().lock().unwrap();
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                let ttl = verification.cache_ttl;
+                cache.retain(|_, (_, looked_up)| looked_up.elapsed() < ttl);
+            }
+            cache.insert(hostname.to_string(), (resolved.clone(), Instant::now()));
+            resolved
+        }
+    };
+    resolved
+        .iter()
+        .any(|address| verification.addresses.contains(address))
+}
+
+/// Resolve a hostname, or nothing if it can't be resolved in time.
+async fn resolve(hostname: &str) -> Vec<IpAddr> {
+    match timeout(LOOKUP_TIMEOUT, lookup_host((hostname, 0))).await {
+        Ok(Ok(addresses)) => addresses.map(|address| address.ip()).collect(),
+        Ok(Err(err)) => {
+            debug!("Failed to resolve {}: {}", hostname, err);
+            Vec::new()
+        }
+        Err(_) => {
+            debug!("Timed out resolving {}", hostname);
+            Vec::new()
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Defines draining, which takes a backend out of selection so it can be restarted without cutting
+//! off its players.
+//!
+//! A draining backend is given no new connections, unless every other candidate of a route is
+//! draining too. Its players can optionally be transferred to the route's other backends, spread
+//! evenly over a window so they don't all reconnect at once - players whose clients can't be
+//! transferred stay until they leave. Once the backend has no sessions left it is reported as
+//! drained, and stays out of selection until the drain is cancelled.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    sync::oneshot,
+    task::JoinHandle,
+    time::{interval, timeout},
+};
+use tracing::{debug, info};
+
+use crate::{
+    bridge::Control,
+    registry::{self, SessionSnapshot},
+    reload::Reloader,
+};
+
+/// How often a draining backend's sessions are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a session has to accept a transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A backend being drained.
+struct Drain {
+    /// When the drain started, in milliseconds since the Unix epoch.
+    started_at: u64,
+    /// When the backend had no sessions left, in milliseconds since the Unix epoch.
+    drained_at: Option<u64>,
+    /// The task watching the backend's sessions.
+    task: JoinHandle<()>,
+}
+
+/// The backends being drained.
+fn drains() -> &'static Mutex<HashMap<SocketAddr, Drain>> {
+    static DRAINS: OnceLock<Mutex<HashMap<SocketAddr, Drain>>> = OnceLock::new();
+    DRAINS.get_or_init(Default::default)
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Remove draining backends from the candidates for a connection, unless every one is draining.
+pub fn exclude(targets: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let drains = drains().lock().unwrap();
+    let available: Vec<_> = targets
+        .iter()
+        .copied()
+        .filter(|target| !drains.contains_key(target))
+        .collect();
+    match available.is_empty() {
+        true => targets,
+        false => available,
+    }
+}
+
+/// Start draining a backend, transferring its players elsewhere over the given window if set.
+/// Returns false if the backend is already being drained.
+pub fn start(
+    target: SocketAddr,
+    transfer_over: Option<Duration>,
+    reloader: Option<Arc<Reloader>>,
+) -> bool {
+    let mut drains = drains().lock().unwrap();
+    if drains.contains_key(&target) {
+        return false;
+    }
+    info!("Draining backend {}", target);
+    let task = tokio::task::spawn(watch(target, transfer_over, reloader));
+    drains.insert(
+        target,
+        Drain {
+            started_at: now(),
+            drained_at: None,
+            task,
+        },
+    );
+    true
+}
+
+/// Stop draining a backend, returning it to selection. Returns false if it wasn't being drained.
+pub fn stop(target: SocketAddr) -> bool {
+    match drains().lock().unwrap().remove(&target) {
+        Some(drain) => {
+            drain.task.abort();
+            info!("Stopped draining backend {}", target);
+            true
+        }
+        None => false,
+    }
+}
+
+/// A snapshot of a backend being drained.
+#[derive(Debug, Serialize)]
+pub struct DrainSnapshot {
+    /// The address of the backend.
+    pub target: SocketAddr,
+    /// When the drain started, in milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// The number of sessions the backend still has.
+    pub connections: usize,
+    /// When the backend had no sessions left, in milliseconds since the Unix epoch, once it has.
+    pub drained_at: Option<u64>,
+}
+
+/// Snapshot every backend being drained, ordered by address.
+pub fn snapshot() -> Vec<DrainSnapshot> {
+    let sessions = registry::sessions();
+    let mut drains: Vec<_> = drains()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(target, drain)| DrainSnapshot {
+            target: *target,
+            started_at: drain.started_at,
+            connections: sessions
+                .iter()
+                .filter(|session| session.target == *target)
+                .count(),
+            drained_at: drain.drained_at,
+        })
+        .collect();
+    drains.sort_by_key(|drain| drain.target);
+    drains
+}
+
+/// Watch a draining backend until it has no sessions left, transferring its players over the
+/// window if set.
+async fn watch(
+    target: SocketAddr,
+    transfer_over: Option<Duration>,
+    reloader: Option<Arc<Reloader>>,
+) {
+    let started = Instant::now();
+    let mut ticks = interval(CHECK_INTERVAL);
+    // each session is only asked once - clients which can't be transferred are left to leave
+    let mut attempted = HashSet::new();
+    loop {
+        ticks.tick().await;
+        let sessions: Vec<_> = registry::sessions()
+            .into_iter()
+            .filter(|session| session.target == target)
+            .collect();
+        if sessions.is_empty() {
+            if let Some(drain) = drains().lock().unwrap().get_mut(&target) {
+                drain.drained_at = Some(now());
+            }
+            info!("Backend {} is drained", target);
+            return;
+        }
+        let (Some(window), Some(reloader)) = (transfer_over, &reloader) else {
+            continue;
+        };
+        let pending: Vec<_> = sessions
+            .into_iter()
+            .filter(|session| session.username.is_some() && !attempted.contains(&session.id))
+            .collect();
+        // transfer at an even pace, so the last players are moved as the window ends
+        let remaining = window.saturating_sub(started.elapsed());
+        let due = match remaining.is_zero() {
+            true => pending.len(),
+            false => {
+                let ticks_left = (remaining.as_secs_f64() / CHECK_INTERVAL.as_secs_f64()).ceil();
+                (pending.len() as f64 / ticks_left.max(1.0)).ceil() as usize
+            }
+        };
+        for session in pending.into_iter().take(due) {
+            attempted.insert(session.id);
+            transfer(reloader, &session).await;
+        }
+    }
+}
+
+/// Ask a session's client to reconnect to its route, which picks another backend.
+async fn transfer(reloader: &Reloader, session: &SessionSnapshot) {
+    let proxies = reloader.proxies().await;
+    let Some(proxy) = proxies.iter().find(|proxy| {
+        proxy
+            .routes
+            .iter()
+            .any(|route| route.from == session.domain)
+    }) else {
+        return;
+    };
+    let (reply, replied) = oneshot::channel();
+    let control = Control::Transfer {
+        host: session.domain.clone(),
+        port: proxy.listen_addr.port(),
+        reply,
+    };
+    if !registry::control(session.id, control) {
+        return;
+    }
+    match timeout(TRANSFER_TIMEOUT, replied).await {
+        Ok(Ok(Ok(()))) => debug!(
+            "Transferred session {} off its draining backend",
+            session.id
+        ),
+        Ok(Ok(Err(err))) => debug!("Session {} can't be transferred: {:#}", session.id, err),
+        _ => debug!("Session {} didn't accept its transfer", session.id),
+    }
+}
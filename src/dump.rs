@@ -0,0 +1,118 @@
+//! Defines state dumps - a readable snapshot of Magma's internal state, written to the log.
+//!
+//! A dump is written on `SIGUSR1`, and is also served at `GET /dump` by the admin API, so a
+//! misbehaving instance can be inspected without the admin API, or anything else, enabled. It
+//! lists the listeners, the routing table with per-route connection counts, backend health, and
+//! the length of internal queues.
+
+use std::{collections::HashMap, fmt::Write, sync::Arc};
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::{
+    events, memory,
+    registry::{self, ListenerState},
+    reload::Reloader,
+    security,
+    signals::{self, Trigger},
+};
+
+/// Render a snapshot of the state of the proxies managed by the reloader.
+pub async fn render(reloader: &Reloader) -> String {
+    let listeners: HashMap<_, _> = registry::listeners()
+        .into_iter()
+        .map(|listener| (listener.addr, listener))
+        .collect();
+    let routes: HashMap<_, _> = registry::routes()
+        .into_iter()
+        .map(|route| (route.domain.clone(), route))
+        .collect();
+
+    // writing to a string can't fail
+    let mut out = String::new();
+    let _ = writeln!(out, "==== Magma state dump ====");
+    for proxy in reloader.proxies().await {
+        let (state, restarts) = listeners
+            .get(&proxy.listen_addr)
+            .map_or((ListenerState::Running, 0), |listener| {
+                (listener.state, listener.restarts)
+            });
+        let _ = writeln!(
+            out,
+            "Listener {} ({:?}, {:?}, {} restart(s))",
+            proxy.listen_addr, proxy.transport, state, restarts
+        );
+        for route in &proxy.routes {
+            let connections = routes.get(&route.from).map_or(0, |route| route.connections);
+            let _ = write!(
+                out,
+                "  {} -> {:?} ({} connection(s), {:?})",
+                route.from, route.to, connections, route.selection_algorithm
+            );
+            if let Some(tenant) = &route.tenant {
+                let _ = write!(out, " [tenant {}]", tenant.name);
+            }
+            let _ = writeln!(out);
+        }
+    }
+
+    let backends = registry::backends();
+    let _ = writeln!(out, "Backends ({}):", backends.len());
+    for backend in backends {
+        let _ = writeln!(
+            out,
+            "  {} {} ({} session(s)){}",
+            backend.target,
+            match backend.healthy {
+                true => "healthy",
+                false => "unhealthy",
+            },
+            backend.connections,
+            backend
+                .error
+                .map(|err| format!(" - {}", err))
+                .unwrap_or_default()
+        );
+    }
+
+    let tenants = registry::tenants();
+    if !tenants.is_empty() {
+        let _ = writeln!(out, "Tenants ({}):", tenants.len());
+        for tenant in tenants {
+            let _ = writeln!(
+                out,
+                "  {} ({} connection(s), {} byte(s) this month)",
+                tenant.name, tenant.connections, tenant.bytes_this_month
+            );
+        }
+    }
+
+    let _ = writeln!(out, "Live sessions: {}", registry::sessions().len());
+    if let Some(rss) = memory::rss() {
+        let _ = writeln!(
+            out,
+            "Memory: {} MB ({:?} pressure)",
+            rss / 1024 / 1024,
+            memory::pressure()
+        );
+    }
+    let _ = writeln!(out, "Queued events: {}", events::queued());
+    if let Some(queued) = security::queued() {
+        let _ = writeln!(out, "Queued security log lines: {}", queued);
+    }
+    let _ = write!(out, "==== End of state dump ====");
+    out
+}
+
+/// Spawns a task writing a state dump to the log on `SIGUSR1`, and returns a handle to the task.
+pub fn spawn(reloader: Arc<Reloader>) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let mut signals = signals::listen(Trigger::User1, "state will not be dumped on SIGUSR1");
+        while signals::recv(&mut signals).await.is_some() {
+            info!("Dumping state\n{}", render(&reloader).await);
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,121 @@
+//! Defines the error types of the library.
+//!
+//! Most functions return [anyhow::Result], but the errors that callers need to tell apart are
+//! typed, and can be recovered with [anyhow::Error::downcast_ref]. [ProtocolError]s only affect
+//! the connection they happened on, [ConfigError]s are raised while loading a configuration, and
+//! [MagmaError]s are raised by listeners, whose supervisor uses [MagmaError::is_fatal] to decide
+//! whether to restart them.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use thiserror::Error;
+
+/// A malformed or unexpected packet. These only affect the connection they happened on.
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    /// A var int or var long was longer than its maximum length.
+    #[error("VarInt too long")]
+    VarIntTooLong,
+    /// A length prefix was negative, or larger than allowed.
+    #[error("Length {length} is out of bounds (max: {max})")]
+    LengthOutOfBounds {
+        /// The length read.
+        length: i32,
+        /// The maximum length allowed.
+        max: i32,
+    },
+    /// A packet had no content.
+    #[error("Attempted to read empty packet")]
+    EmptyPacket,
+    /// A boolean was neither 0 nor 1.
+    #[error("Invalid boolean value {0}")]
+    InvalidBool(u8),
+    /// A compressed packet could not be inflated, or inflated to the wrong length.
+    #[error("Failed to decompress packet")]
+    Decompression,
+    /// A packet had a different id to the one expected.
+    #[error("Expected packet with id {expected:#04x}, got {actual:#04x}")]
+    UnexpectedPacket {
+        /// The id expected.
+        expected: i32,
+        /// The id received.
+        actual: i32,
+    },
+    /// A packet arrived slower than the minimum rate allowed.
+    #[error("Packet of {length} bytes arrived slower than {min_rate} bytes/s")]
+    TooSlow {
+        /// The length of the packet.
+        length: usize,
+        /// The minimum rate, in bytes per second.
+        min_rate: u32,
+    },
+    /// A packet didn't arrive within its deadline.
+    #[error("No packet arrived within {0:?}")]
+    Timeout(Duration),
+}
+
+impl ProtocolError {
+    /// Test whether the error is a peer being too slow, rather than sending something malformed.
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::TooSlow { .. } | ProtocolError::Timeout(_)
+        )
+    }
+}
+
+/// An invalid configuration.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    #[error("Failed to read configuration file")]
+    Read(#[source] io::Error),
+    /// The configuration file is not valid TOML, or has the wrong shape.
+    #[error("Failed to parse configuration")]
+    Parse(#[from] toml::de::Error),
+    /// The configuration file has a version Magma doesn't know.
+    #[error("Unknown config version: {0}")]
+    UnknownVersion(u8),
+    /// The configuration parsed, but its settings are invalid or inconsistent.
+    #[error(transparent)]
+    Invalid(anyhow::Error),
+}
+
+/// An error which stops a listener.
+#[derive(Debug, Error)]
+pub enum MagmaError {
+    /// The listener could not bind its address.
+    #[error("Failed to bind {addr}")]
+    Bind {
+        /// The address of the listener.
+        addr: SocketAddr,
+        /// Why binding failed.
+        #[source]
+        source: io::Error,
+    },
+    /// The configuration is invalid.
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    /// Any other error.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl MagmaError {
+    /// Test whether the error can't be fixed by retrying, so the listener should not be restarted.
+    ///
+    /// Binding an address which is in use is retried, as the previous owner may be shutting down,
+    /// but an address which doesn't exist or needs privileges never will be.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            MagmaError::Bind { source, .. } => matches!(
+                source.kind(),
+                io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::AddrNotAvailable
+                    | io::ErrorKind::InvalidInput
+            ),
+            MagmaError::Config(_) => true,
+            MagmaError::Other(_) => false,
+        }
+    }
+}
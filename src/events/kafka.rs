@@ -0,0 +1,57 @@
+//! Publishes events to Kafka.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use rskafka::{
+    client::{
+        partition::{Compression, UnknownTopicHandling},
+        ClientBuilder,
+    },
+    record::Record,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use super::Envelope;
+
+/// Publish events to the given Kafka topic, keyed by event type.
+#[tracing::instrument(name = "kafka", skip_all, fields(topic = %topic))]
+pub async fn export(
+    mut events: broadcast::Receiver<Envelope>,
+    brokers: Vec<String>,
+    topic: String,
+) -> Result<()> {
+    let client = ClientBuilder::new(brokers)
+        .build()
+        .await
+        .context("failed to connect to Kafka")?;
+    let partition = client
+        .partition_client(topic.clone(), 0, UnknownTopicHandling::Retry)
+        .await
+        .context("failed to open Kafka topic")?;
+    info!("Publishing events to Kafka");
+
+    loop {
+        let envelope = match events.recv().await {
+            Ok(envelope) => envelope,
+            Err(RecvError::Lagged(n)) => {
+                warn!("Event exporter fell behind - dropped {} event(s)", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let record = Record {
+            key: Some(envelope.kind().as_bytes().to_vec()),
+            value: Some(serde_json::to_vec(&envelope)?),
+            headers: BTreeMap::new(),
+            timestamp: chrono::Utc::now(),
+        };
+        if let Err(err) = partition
+            .produce(vec![record], Compression::NoCompression)
+            .await
+        {
+            warn!("Failed to publish event: {}", err);
+        }
+    }
+}
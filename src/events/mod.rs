@@ -0,0 +1,160 @@
+//! Defines connection lifecycle events, and exporters which publish them to external systems.
+//!
+//! Events are emitted on a process-wide bus as connections move through the proxy. Exporters
+//! subscribe to the bus and forward each event as a JSON document, so downstream analytics and
+//! anti-cheat pipelines can consume them in real time. Emitting an event is cheap, and events are
+//! simply dropped if nothing is subscribed.
+
+use std::{
+    net::SocketAddr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::{bridge::CloseReason, config::EventSink};
+
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "nats")]
+mod nats;
+
+/// The number of events buffered for each subscriber before the oldest are dropped.
+const BUS_CAPACITY: usize = 4096;
+
+/// A connection lifecycle event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A client sent its handshake.
+    Join {
+        /// The ID of the connection, shared by its events and log lines.
+        connection_id: String,
+        /// The address of the client.
+        peer: SocketAddr,
+        /// The address of the listener the client connected to.
+        listener: SocketAddr,
+        /// The domain the client connected with.
+        domain: String,
+        /// The protocol version of the client.
+        protocol_version: i32,
+    },
+    /// A client was routed to a backend.
+    Route {
+        /// The ID of the connection, shared by its events and log lines.
+        connection_id: String,
+        /// The address of the client.
+        peer: SocketAddr,
+        /// The domain the client connected with.
+        domain: String,
+        /// The backend the client was routed to.
+        target: SocketAddr,
+    },
+    /// A client was flagged as suspicious, such as when connecting from a VPN.
+    Flag {
+        /// The ID of the connection, shared by its events and log lines.
+        connection_id: String,
+        /// The address of the client.
+        peer: SocketAddr,
+        /// The domain the client connected with.
+        domain: String,
+        /// Why the client was flagged.
+        reason: String,
+    },
+    /// A client's connection closed.
+    Leave {
+        /// The ID of the connection, shared by its events and log lines.
+        connection_id: String,
+        /// The address of the client.
+        peer: SocketAddr,
+        /// The domain the client connected with.
+        domain: String,
+        /// The backend the client was routed to.
+        target: SocketAddr,
+        /// The username the client logged in with, if it logged in.
+        username: Option<String>,
+        /// How long the connection lasted, in milliseconds.
+        duration_ms: u64,
+        /// The bytes of packet data sent by the client.
+        bytes_upstream: u64,
+        /// The bytes of packet data sent by the backend.
+        bytes_downstream: u64,
+        /// Why the connection closed.
+        close_reason: CloseReason,
+        /// A description of why the connection closed, such as the error it failed with.
+        reason: String,
+    },
+}
+
+/// An event, stamped with the time it was emitted.
+#[derive(Debug, Clone, Serialize)]
+pub struct Envelope {
+    /// When the event was emitted, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The event.
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Envelope {
+    /// The name of the event type, used to build subjects and topics.
+    pub fn kind(&self) -> &'static str {
+        match self.event {
+            Event::Join { .. } => "join",
+            Event::Route { .. } => "route",
+            Event::Flag { .. } => "flag",
+            Event::Leave { .. } => "leave",
+        }
+    }
+}
+
+/// The process-wide event bus.
+fn bus() -> &'static broadcast::Sender<Envelope> {
+    static BUS: OnceLock<broadcast::Sender<Envelope>> = OnceLock::new();
+    BUS.get_or_init(|| broadcast::channel(BUS_CAPACITY).0)
+}
+
+/// The number of events buffered for the slowest subscriber.
+pub fn queued() -> usize {
+    bus().len()
+}
+
+/// Emit an event to every subscriber.
+pub fn emit(event: Event) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    // an error only means nothing is subscribed
+    let _ = bus().send(Envelope { timestamp, event });
+}
+
+/// Subscribe to emitted events.
+pub fn subscribe() -> broadcast::Receiver<Envelope> {
+    bus().subscribe()
+}
+
+/// Spawns an exporter publishing events to the given sink, and returns a handle to the task.
+pub fn spawn(sink: EventSink) -> JoinHandle<Result<()>> {
+    // subscribe before spawning, so no events are missed while the exporter starts
+    let events = subscribe();
+    tokio::task::spawn(async move {
+        match sink {
+            #[cfg(feature = "nats")]
+            EventSink::Nats { url, subject } => nats::export(events, url, subject).await,
+            #[cfg(feature = "kafka")]
+            EventSink::Kafka { brokers, topic } => kafka::export(events, brokers, topic).await,
+            #[allow(unreachable_patterns)]
+            sink => {
+                drop(events);
+                anyhow::bail!(
+                    "Magma was built without support for the {:?} event sink",
+                    sink
+                )
+            }
+        }
+    })
+}
@@ -0,0 +1,38 @@
+//! Publishes events to NATS.
+
+use anyhow::{Context, Result};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use super::Envelope;
+
+/// Publish events to `<subject>.<event type>` on the given NATS server.
+#[tracing::instrument(name = "nats", skip_all, fields(url = %url))]
+pub async fn export(
+    mut events: broadcast::Receiver<Envelope>,
+    url: String,
+    subject: String,
+) -> Result<()> {
+    let client = async_nats::connect(&url)
+        .await
+        .context("failed to connect to NATS")?;
+    info!("Publishing events to NATS subject {}", subject);
+
+    loop {
+        let envelope = match events.recv().await {
+            Ok(envelope) => envelope,
+            Err(RecvError::Lagged(n)) => {
+                warn!("Event exporter fell behind - dropped {} event(s)", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let payload = serde_json::to_vec(&envelope)?;
+        if let Err(err) = client
+            .publish(format!("{}.{}", subject, envelope.kind()), payload.into())
+            .await
+        {
+            warn!("Failed to publish event: {}", err);
+        }
+    }
+}
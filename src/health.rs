@@ -0,0 +1,193 @@
+//! Defines active health checks for backends.
+//!
+//! Backends are probed on an interval, and connections are only routed to backends whose most
+//! recent probe succeeded - unless every backend of a route is failing, in which case all of them
+//! are tried. Backends which have not been probed are assumed healthy.
+//!
+//! Routes with backup targets only use them while none of their primary targets are healthy, and
+//! either return to the primaries as soon as one recovers, or stay on the backups while they last.
+//!
+//! A TCP connect is the simplest probe, but some servers accept connections long before they are
+//! ready for players. The RCON probe instead logs in to the server's console, and can optionally
+//! run a command and check its output.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+use futures::future::join_all;
+use tokio::{
+    net::TcpStream,
+    task::JoinHandle,
+    time::{interval, timeout},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    bridge::ProtocolState,
+    config::{HealthCheckConfig, Probe, Route},
+    protocol::{packets::Handshake, version::ProtocolVersion},
+    rcon, registry, status,
+};
+
+/// The result of the most recent probe of each backend.
+fn probed() -> &'static Mutex<HashMap<SocketAddr, bool>> {
+    static PROBED: OnceLock<Mutex<HashMap<SocketAddr, bool>>> = OnceLock::new();
+    PROBED.get_or_init(Default::default)
+}
+
+/// Test whether a backend passed its most recent probe. Backends which have not been probed are
+/// assumed healthy.
+pub fn is_healthy(target: SocketAddr) -> bool {
+    probed()
+        .lock()
+        .unwrap()
+        .get(&target)
+        .copied()
+        .unwrap_or(true)
+}
+
+/// Filter targets down to the healthy ones, or all of them if none are healthy.
+pub fn healthy_targets(targets: &[SocketAddr]) -> Vec<SocketAddr> {
+    let healthy: Vec<_> = targets
+        .iter()
+        .copied()
+        .filter(|&target| is_healthy(target))
+        .collect();
+    match healthy.is_empty() {
+        true => targets.to_vec(),
+        false => healthy,
+    }
+}
+
+/// The routes which have failed over to their backup targets.
+fn failed_over() -> &'static Mutex<HashSet<String>> {
+    static FAILED_OVER: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    FAILED_OVER.get_or_init(Default::default)
+}
+
+/// Filter a route's targets down to the healthy ones of its primary targets, or of its backups if
+/// none of the primaries are healthy. If no target is healthy at all, the primaries are all tried.
+pub fn tiered_targets(route: &Route) -> Vec<SocketAddr> {
+    if route.backups.is_empty() {
+        return healthy_targets(&route.to);
+    }
+    let healthy = |targets: &[SocketAddr]| -> Vec<SocketAddr> {
+        targets
+            .iter()
+            .copied()
+            .filter(|&target| is_healthy(target))
+            .collect()
+    };
+    let mut failed_over = failed_over().lock().unwrap();
+    if route.fail_back || !failed_over.contains(&route.from) {
+        let primaries = healthy(&route.to);
+        if !primaries.is_empty() {
+            if failed_over.remove(&route.from) {
+                info!("Route {} failed back to its primary targets", route.from);
+            }
+            return primaries;
+        }
+    }
+    let backups = healthy(&route.backups);
+    if backups.is_empty() {
+        // routes which stayed on their backups return to the primaries once they are all down
+        failed_over.remove(&route.from);
+        return route.to.clone();
+    }
+    if failed_over.insert(route.from.clone()) {
+        warn!("Route {} failed over to its backup targets", route.from);
+    }
+    backups
+}
+
+/// Spawns a task probing the configured backends, and returns a handle to the task.
+pub fn spawn(config: HealthCheckConfig) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let mut ticks = interval(config.interval);
+        loop {
+            ticks.tick().await;
+            join_all(config.targets.iter().map(|&target| check(&config, target))).await;
+        }
+    })
+}
+
+/// Probe a backend, recording the result.
+async fn check(config: &HealthCheckConfig, target: SocketAddr) {
+    let result = timeout(config.timeout, probe(&config.probe, target))
+        .await
+        .context("probe timed out")
+        .and_then(|result| result);
+    let healthy = result.is_ok();
+    let previous = probed().lock().unwrap().insert(target, healthy);
+    match (&result, previous) {
+        (Err(err), Some(true) | None) => warn!("Backend {} is unhealthy: {:#}", target, err),
+        (Ok(()), Some(false)) => info!("Backend {} is healthy again", target),
+        _ => {}
+    }
+    registry::record_backend(target, result.err().map(|err| format!("{:#}", err)));
+
+    if healthy && config.fetch_favicon {
+        if let Err(err) = fetch_favicon(target).await {
+            debug!("Failed to fetch the favicon of {}: {:#}", target, err);
+        }
+    }
+}
+
+/// Fetch the favicon of a backend, keeping it to show while the backend is down.
+async fn fetch_favicon(target: SocketAddr) -> Result<()> {
+    let handshake = Handshake {
+        protocol_version: ProtocolVersion::DEFAULT.0,
+        server_address: target.ip().to_string(),
+        server_port: target.port(),
+        next_state: ProtocolState::Status,
+    };
+    let json = status::fetch(TcpStream::connect(target).await?, &handshake).await?;
+    status::store_favicon(target, &json)
+}
+
+/// Run a single probe against a backend.
+async fn probe(probe: &Probe, target: SocketAddr) -> Result<()> {
+    match probe {
+        Probe::Tcp => {
+            TcpStream::connect(target).await?;
+        }
+        Probe::Rcon {
+            port,
+            password,
+            command,
+            expect,
+        } => {
+            let mut stream = TcpStream::connect(SocketAddr::new(target.ip(), *port)).await?;
+            if !rcon::login(&mut stream, password).await? {
+                bail!("RCON password was rejected");
+            }
+            if let Some(command) = command {
+                rcon::write_packet(
+                    &mut stream,
+                    &rcon::Packet {
+                        id: 1,
+                        kind: rcon::COMMAND,
+                        payload: command.clone(),
+                    },
+                )
+                .await?;
+                let response = rcon::read_packet(&mut stream).await?;
+                if let Some(expect) = expect {
+                    if !response.payload.contains(expect.as_str()) {
+                        bail!(
+                            "Response to {:?} did not contain {:?}: {:?}",
+                            command,
+                            expect,
+                            response.payload
+                        );
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,275 @@
+//! Defines the connection history store, an embedded SQLite database of completed sessions.
+//!
+//! The store subscribes to the [event bus](crate::events), recording every session as it ends,
+//! and prunes sessions older than the configured retention. It is intended for lightweight
+//! forensics - who connected, from where, to which backend, and why they left - without
+//! standing up external infrastructure.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::broadcast::error::RecvError,
+    task::{spawn_blocking, JoinHandle},
+    time::interval,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::HistoryConfig,
+    events::{self, Event},
+};
+
+/// How often old sessions are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The maximum number of sessions returned by a query.
+const MAX_QUERY_LIMIT: u32 = 1000;
+
+/// A completed session.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionRecord {
+    /// The ID of the connection, shared by its events and log lines.
+    pub connection_id: Option<String>,
+    /// The username the client logged in with, if it logged in.
+    pub username: Option<String>,
+    /// The IP address of the client.
+    pub ip: String,
+    /// The domain the client connected with.
+    pub domain: String,
+    /// The backend the client was routed to.
+    pub target: String,
+    /// When the session ended, in milliseconds since the Unix epoch.
+    pub ended_at: i64,
+    /// How long the session lasted, in milliseconds.
+    pub duration_ms: i64,
+    /// The bytes of packet data sent by the client.
+    pub bytes_upstream: i64,
+    /// The bytes of packet data sent by the backend.
+    pub bytes_downstream: i64,
+    /// Why the session ended, such as `client_eof` - unset for sessions recorded by older versions.
+    pub close_reason: Option<String>,
+    /// A description of why the session ended, such as the error it failed with.
+    pub reason: String,
+}
+
+/// Filters for querying sessions. Unset filters match every session.
+#[derive(Debug, Default, Deserialize)]
+pub struct SessionQuery {
+    /// Only return sessions of this username.
+    pub username: Option<String>,
+    /// Only return sessions from this IP address.
+    pub ip: Option<String>,
+    /// Only return sessions for this domain.
+    pub domain: Option<String>,
+    /// Only return the session of this connection.
+    pub connection_id: Option<String>,
+    /// The maximum number of sessions to return, most recent first.
+    pub limit: Option<u32>,
+}
+
+/// The connection history store.
+pub struct History {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl History {
+    /// Open the store at the given path, creating it if necessary.
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("failed to open history database {:?}", path))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id INTEGER PRIMARY KEY,
+                username TEXT,
+                ip TEXT NOT NULL,
+                domain TEXT NOT NULL,
+                target TEXT NOT NULL,
+                ended_at INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                bytes_upstream INTEGER NOT NULL,
+                bytes_downstream INTEGER NOT NULL,
+                reason TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS sessions_ended_at ON sessions (ended_at);
+            CREATE INDEX IF NOT EXISTS sessions_username ON sessions (username);
+            CREATE INDEX IF NOT EXISTS sessions_ip ON sessions (ip);",
+        )?;
+        // stores created before connection IDs lack the column
+        if connection
+            .prepare("SELECT connection_id FROM sessions LIMIT 0")
+            .is_err()
+        {
+            connection.execute_batch("ALTER TABLE sessions ADD COLUMN connection_id TEXT;")?;
+        }
+        // as do stores created before close reasons were classified
+        if connection
+            .prepare("SELECT close_reason FROM sessions LIMIT 0")
+            .is_err()
+        {
+            connection.execute_batch("ALTER TABLE sessions ADD COLUMN close_reason TEXT;")?;
+        }
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Query recorded sessions, most recent first.
+    pub async fn query(&self, query: SessionQuery) -> Result<Vec<SessionRecord>> {
+        let limit = query.limit.unwrap_or(100).min(MAX_QUERY_LIMIT);
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT username, ip, domain, target, ended_at, duration_ms, bytes_upstream,
+                    bytes_downstream, reason, connection_id, close_reason
+                FROM sessions
+                WHERE (?1 IS NULL OR username = ?1)
+                    AND (?2 IS NULL OR ip = ?2)
+                    AND (?3 IS NULL OR domain = ?3)
+                    AND (?4 IS NULL OR connection_id = ?4)
+                ORDER BY ended_at DESC
+                LIMIT ?5",
+            )?;
+            let records = statement
+                .query_map(
+                    params![
+                        query.username,
+                        query.ip,
+                        query.domain,
+                        query.connection_id,
+                        limit
+                    ],
+                    |row| {
+                        Ok(SessionRecord {
+                            connection_id: row.get(9)?,
+                            username: row.get(0)?,
+                            ip: row.get(1)?,
+                            domain: row.get(2)?,
+                            target: row.get(3)?,
+                            ended_at: row.get(4)?,
+                            duration_ms: row.get(5)?,
+                            bytes_upstream: row.get(6)?,
+                            bytes_downstream: row.get(7)?,
+                            close_reason: row.get(10)?,
+                            reason: row.get(8)?,
+                        })
+                    },
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(records)
+        })
+        .await
+    }
+
+    /// Record a completed session.
+    async fn insert(&self, record: SessionRecord) -> Result<()> {
+        self.with_connection(move |connection| {
+            connection
+                .prepare_cached(
+                    "INSERT INTO sessions (username, ip, domain, target, ended_at, duration_ms,
+                        bytes_upstream, bytes_downstream, reason, connection_id, close_reason)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                )?
+                .execute(params![
+                    record.username,
+                    record.ip,
+                    record.domain,
+                    record.target,
+                    record.ended_at,
+                    record.duration_ms,
+                    record.bytes_upstream,
+                    record.bytes_downstream,
+                    record.reason,
+                    record.connection_id,
+                    record.close_reason,
+                ])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delete sessions which ended before the given time, returning how many were deleted.
+    async fn prune(&self, before: i64) -> Result<usize> {
+        self.with_connection(move |connection| {
+            Ok(connection.execute("DELETE FROM sessions WHERE ended_at < ?1", params![before])?)
+        })
+        .await
+    }
+
+    /// Run a blocking operation against the database, off the async runtime.
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        spawn_blocking(move || f(&connection.lock().unwrap())).await?
+    }
+}
+
+/// Spawns a task recording completed sessions into the store, and returns a handle to the task.
+pub fn spawn(history: Arc<History>, config: HistoryConfig) -> JoinHandle<Result<()>> {
+    // subscribe before spawning, so no sessions are missed while the task starts
+    let mut events = events::subscribe();
+    tokio::task::spawn(async move {
+        info!("Recording connection history to {:?}", config.path);
+        let mut prune = interval(PRUNE_INTERVAL);
+        loop {
+            select! {
+                event = events.recv() => match event {
+                    Ok(envelope) => {
+                        if let Event::Leave {
+                            connection_id,
+                            peer,
+                            domain,
+                            target,
+                            username,
+                            duration_ms,
+                            bytes_upstream,
+                            bytes_downstream,
+                            close_reason,
+                            reason,
+                        } = envelope.event
+                        {
+                            let record = SessionRecord {
+                                connection_id: Some(connection_id),
+                                username,
+                                ip: peer.ip().to_string(),
+                                domain,
+                                target: target.to_string(),
+                                ended_at: envelope.timestamp as i64,
+                                duration_ms: duration_ms as i64,
+                                bytes_upstream: bytes_upstream as i64,
+                                bytes_downstream: bytes_downstream as i64,
+                                close_reason: Some(close_reason.label().to_string()),
+                                reason,
+                            };
+                            if let Err(err) = history.insert(record).await {
+                                warn!("Failed to record session: {:#}", err);
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Connection history fell behind - dropped {} event(s)", n);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = prune.tick() => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    let before = now.saturating_sub(config.retention).as_millis() as i64;
+                    match history.prune(before).await {
+                        Ok(pruned) => debug!("Pruned {} session(s) from connection history", pruned),
+                        Err(err) => warn!("Failed to prune connection history: {:#}", err),
+                    }
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,101 @@
+//! Defines hooks - external actions Magma triggers to manage backends, such as starting a
+//! stopped server.
+//!
+//! A hook runs a shell command, makes an HTTP request, starts or stops a container through the
+//! Docker Engine API, or sends a power signal to a Pterodactyl or Pelican panel server.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{bail, Result};
+use tokio::process::Command;
+
+use crate::panel::Panel;
+
+/// How long an HTTP hook may take.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An external action.
+#[derive(Debug, Clone)]
+pub enum Hook {
+    /// Run a shell command. The route and target are passed in the `MAGMA_ROUTE` and
+    /// `MAGMA_TARGET` environment variables.
+    Command(String),
+    /// Make an HTTP request.
+    Http {
+        /// The request method.
+        method: reqwest::Method,
+        /// The request URL.
+        url: String,
+    },
+    /// Start or stop a container through the Docker Engine API.
+    Docker {
+        /// The URL of the Docker Engine API, such as `http://localhost:2375`.
+        url: String,
+        /// The name or id of the container.
+        container: String,
+        /// The container action - `start` or `stop`.
+        action: &'static str,
+    },
+    /// Send a power signal to a panel server.
+    Panel {
+        /// The panel server.
+        panel: Panel,
+        /// The power signal - `start` or `stop`.
+        signal: &'static str,
+    },
+}
+
+impl Hook {
+    /// Run the hook for the given route and target.
+    pub async fn run(&self, route: &str, target: SocketAddr) -> Result<()> {
+        match self {
+            Hook::Command(command) => {
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .env("MAGMA_ROUTE", route)
+                    .env("MAGMA_TARGET", target.to_string())
+                    .status()
+                    .await?;
+                if !status.success() {
+                    bail!("Command {:?} exited with {}", command, status);
+                }
+            }
+            Hook::Http { method, url } => {
+                reqwest::Client::new()
+                    .request(method.clone(), url)
+                    .timeout(HTTP_TIMEOUT)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Hook::Docker {
+                url,
+                container,
+                action,
+            } => {
+                let response = reqwest::Client::new()
+                    .post(format!(
+                        "{}/containers/{}/{}",
+                        url.trim_end_matches('/'),
+                        container,
+                        action
+                    ))
+                    .timeout(HTTP_TIMEOUT)
+                    .send()
+                    .await?;
+                // 304 means the container was already in the requested state
+                if !response.status().is_success() && response.status().as_u16() != 304 {
+                    bail!(
+                        "Docker refused to {} container {}: {}",
+                        action,
+                        container,
+                        response.text().await.unwrap_or_default()
+                    );
+                }
+            }
+            Hook::Panel { panel, signal } => panel.power(signal).await?,
+        }
+        Ok(())
+    }
+}
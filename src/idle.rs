@@ -0,0 +1,65 @@
+//! Defines scale-to-zero for idle backends.
+//!
+//! Complementing [wake-on-connect](crate::wake), Magma tracks the players logged in through an
+//! entry's routes, and triggers a stop hook once none have been online for a while. Backends
+//! woken since are given the same grace period before being stopped again.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::{task::JoinHandle, time::interval};
+use tracing::{info, warn};
+
+use crate::{config::IdleConfig, registry, wake};
+
+/// How often player counts are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Spawns a task stopping the configured backends while idle, and returns a handle to the task.
+pub fn spawn(config: IdleConfig) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let mut ticks = interval(CHECK_INTERVAL.min(config.after));
+        let mut active_at = Instant::now();
+        let mut stopped = false;
+        loop {
+            ticks.tick().await;
+            let online: usize = config
+                .domains
+                .iter()
+                .map(|domain| registry::logins(domain))
+                .sum();
+            if online > 0 {
+                active_at = Instant::now();
+                stopped = false;
+                continue;
+            }
+            // waking a backend counts as activity, so it isn't stopped before anyone logs in
+            if let Some(woken_at) = config
+                .targets
+                .iter()
+                .filter_map(|&t| wake::woken_at(t))
+                .max()
+            {
+                if woken_at > active_at {
+                    active_at = woken_at;
+                    stopped = false;
+                }
+            }
+            if stopped || active_at.elapsed() < config.after {
+                continue;
+            }
+            stopped = true;
+            for &target in &config.targets {
+                info!(
+                    "No players on {} for {:?} - stopping backend {}",
+                    config.domains.join(", "),
+                    config.after,
+                    target
+                );
+                if let Err(err) = config.hook.run(&config.domains.join(","), target).await {
+                    warn!("Failed to stop backend {}: {:#}", target, err);
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,120 @@
+//! Defines the InfluxDB exporter, which periodically pushes metrics in the line protocol.
+//!
+//! Every interval, each histogram is written as a point with its count, sum and cumulative
+//! bucket counts as fields, and each gauge as a point with a single `value` field. Points are
+//! written to any endpoint accepting the line protocol, such as InfluxDB's `/api/v2/write`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use tokio::{task::JoinHandle, time::interval};
+use tracing::warn;
+
+use crate::{config::InfluxConfig, metrics, secrets};
+
+/// Escape a measurement name, tag key or tag value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+        .replace('\n', "\\n")
+}
+
+/// Format a measurement and its tags, including the configured tags.
+fn series(measurement: &str, tags: &[(&str, &str)], config: &InfluxConfig) -> String {
+    let mut series = escape(measurement);
+    for (key, value) in tags
+        .iter()
+        .copied()
+        .chain(config.tags.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+    {
+        series.push_str(&format!(",{}={}", escape(key), escape(value)));
+    }
+    series
+}
+
+/// Render every metric as lines, timestamped with the given nanoseconds since the Unix epoch.
+fn render(config: &InfluxConfig, timestamp: u128) -> String {
+    let mut lines = vec![];
+    for histogram in metrics::samples() {
+        let measurement = format!("{}_{}_seconds", config.prefix, histogram.timing.stat());
+        let mut fields = vec![
+            format!("count={}i", histogram.count),
+            format!("sum={}", histogram.sum),
+        ];
+        for (bound, count) in histogram.buckets {
+            fields.push(format!("le_{}={}i", bound, count));
+        }
+        lines.push(format!(
+            "{} {} {}",
+            series(&measurement, &[("route", &histogram.route)], config),
+            fields.join(","),
+            timestamp
+        ));
+    }
+    for gauge in metrics::gauges() {
+        let measurement = format!("{}_{}", config.prefix, gauge.metric());
+        let tags: Vec<_> = gauge
+            .labels
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        lines.push(format!(
+            "{} value={} {}",
+            series(&measurement, &tags, config),
+            gauge.value,
+            timestamp
+        ));
+    }
+    for close in metrics::close_counts() {
+        let measurement = format!("{}_connections_closed", config.prefix);
+        lines.push(format!(
+            "{} count={}i {}",
+            series(
+                &measurement,
+                &[("route", &close.route), ("reason", close.reason.label())],
+                config
+            ),
+            close.count,
+            timestamp
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Spawns the exporter, and returns a handle to the task.
+pub fn spawn(config: InfluxConfig) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(config.interval)
+            .build()?;
+        let mut ticks = interval(config.interval);
+        // the first tick completes immediately, before anything has been recorded
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            let body = render(&config, timestamp);
+            if body.is_empty() {
+                continue;
+            }
+            let mut request = client.post(&config.url).body(body);
+            if let Some(token) = &secrets::current().influx_token {
+                request =
+                    request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token));
+            }
+            let result = request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = result {
+                warn!("Failed to write metrics to {}: {}", config.url, err);
+            }
+        }
+    })
+}
@@ -3,15 +3,24 @@
 
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
 use uuid::Uuid;
 
-use std::fmt::Debug;
+use std::{fmt::Debug, future::Future, time::Duration};
 
 use super::{
-    var_int_length, CompressedPacket, Packet, UncompressedPacket, VARINT_CONTINUE_BIT,
+    checked_length, var_int_length, CompressedPacket, Packet, UncompressedPacket,
+    MAX_PACKET_LENGTH, MAX_STRING_LENGTH, MAX_UNCOMPRESSED_LENGTH, VARINT_CONTINUE_BIT,
     VARINT_SEGMENT_BITS,
 };
+use crate::error::ProtocolError;
+
+/// The time allowed for any frame read at a minimum rate, on top of the time its length needs.
+const FRAME_GRACE: Duration = Duration::from_secs(1);
 
 /// Extension trait for reading Minecraft packets from a stream.
 #[async_trait]
@@ -32,7 +41,7 @@ pub trait ProtocolAsyncReadExt: AsyncRead {
             num_read += 1;
 
             if num_read > 5 {
-                bail!("VarInt too long (max length: 5)");
+                bail!(ProtocolError::VarIntTooLong);
             }
             if read & 0b1000_0000 == 0 {
                 break;
@@ -62,7 +71,7 @@ pub trait ProtocolAsyncReadExt: AsyncRead {
             position += 7;
 
             if position >= 64 {
-                bail!("VarInt exceeded maximum length");
+                bail!(ProtocolError::VarIntTooLong);
             }
         }
     }
@@ -72,7 +81,7 @@ pub trait ProtocolAsyncReadExt: AsyncRead {
     where
         Self: Unpin,
     {
-        let len = self.read_var_int().await? as usize;
+        let len = checked_length(self.read_var_int().await?, MAX_STRING_LENGTH)?;
         let mut buf = vec![0u8; len];
         self.read_exact(&mut buf)
             .await
@@ -91,25 +100,115 @@ pub trait ProtocolAsyncReadExt: AsyncRead {
         Ok(uuid)
     }
 
+    /// Read a raw frame from the stream - the length-prefixed body of a packet, which may or may
+    /// not be compressed. Use [Packet::from_frame] to interpret it.
+    async fn read_frame(&mut self) -> Result<Bytes>
+    where
+        Self: Unpin,
+    {
+        let length = checked_length(self.read_var_int().await?, MAX_PACKET_LENGTH)?;
+        if length == 0 {
+            bail!(ProtocolError::EmptyPacket)
+        }
+        let mut frame = vec![0u8; length];
+        self.read_exact(&mut frame).await?;
+        Ok(frame.into())
+    }
+
+    /// Read a raw frame from the stream, like [read_frame](Self::read_frame), but fail with
+    /// [ProtocolError::TooSlow] if the body arrives slower than the given rate in bytes per second
+    /// once the length has been read.
+    async fn read_frame_at_rate(&mut self, min_rate: u32) -> Result<Bytes>
+    where
+        Self: Unpin,
+    {
+        let length = checked_length(self.read_var_int().await?, MAX_PACKET_LENGTH)?;
+        if length == 0 {
+            bail!(ProtocolError::EmptyPacket)
+        }
+        let deadline = FRAME_GRACE + Duration::from_secs_f64(length as f64 / min_rate as f64);
+        let mut frame = vec![0u8; length];
+        match timeout(deadline, self.read_exact(&mut frame)).await {
+            Ok(read) => read?,
+            Err(_) => bail!(ProtocolError::TooSlow { length, min_rate }),
+        };
+        Ok(frame.into())
+    }
+
+    /// Read a raw frame from the stream, like [read_frame](Self::read_frame), but fail with
+    /// [ProtocolError::Timeout] if the whole frame doesn't arrive within the given duration.
+    async fn read_frame_timeout(&mut self, duration: Duration) -> Result<Bytes>
+    where
+        Self: Unpin,
+    {
+        within(duration, self.read_frame()).await
+    }
+
     /// Read an [UncompressedPacket] from the stream.
     async fn read_uncompressed_packet(&mut self) -> Result<UncompressedPacket>
     where
         Self: Unpin,
     {
-        let length = self.read_var_int().await? as usize;
+        let length = checked_length(self.read_var_int().await?, MAX_PACKET_LENGTH)?;
         if length == 0 {
-            bail!("Attempted to read empty packet")
+            bail!(ProtocolError::EmptyPacket)
         }
 
         // read packet id and compute data length
         let id = self.read_var_int().await?;
-        let data_length = length - var_int_length(id);
+        let data_length = length
+            .checked_sub(var_int_length(id))
+            .context("packet length is shorter than its id")?;
 
         // read data
         let mut data = vec![0u8; data_length];
         self.read_exact(&mut data).await?;
 
-        Ok(UncompressedPacket { id, data })
+        Ok(UncompressedPacket {
+            id,
+            data: data.into(),
+        })
+    }
+
+    /// Read an [UncompressedPacket] from the stream, like
+    /// [read_uncompressed_packet](Self::read_uncompressed_packet), but fail with
+    /// [ProtocolError::Timeout] if the whole packet doesn't arrive within the given duration.
+    async fn read_uncompressed_packet_timeout(
+        &mut self,
+        duration: Duration,
+    ) -> Result<UncompressedPacket>
+    where
+        Self: Unpin,
+    {
+        within(duration, self.read_uncompressed_packet()).await
+    }
+
+    /// Read a packet from the stream, decompressing it if the stream is compressed.
+    async fn read_framed_packet(
+        &mut self,
+        compression_threshold: Option<i32>,
+    ) -> Result<UncompressedPacket>
+    where
+        Self: Unpin,
+    {
+        match compression_threshold {
+            Some(_) => self.read_compressed_packet().await?.decompress(),
+            None => self.read_uncompressed_packet().await,
+        }
+    }
+
+    /// Read a packet from the stream, like [read_framed_packet](Self::read_framed_packet), but
+    /// fail with [ProtocolError::Timeout] if the whole packet doesn't arrive within the given
+    /// duration.
+    async fn read_framed_packet_timeout(
+        &mut self,
+        compression_threshold: Option<i32>,
+        duration: Duration,
+    ) -> Result<UncompressedPacket>
+    where
+        Self: Unpin,
+    {
+        within(duration, self.read_framed_packet(compression_threshold)).await
     }
 
     /// Read a compressed packet from the stream. This does not decompress the packet.
@@ -119,19 +218,31 @@ pub trait ProtocolAsyncReadExt: AsyncRead {
     {
         let packet_length = self.read_var_int().await?;
         let data_length = self.read_var_int().await?;
+        checked_length(data_length, MAX_UNCOMPRESSED_LENGTH)?;
 
-        // read compressed data
-        let mut compressed_data = vec![0u8; data_length as usize];
+        // read compressed data - the packet length includes the data length field
+        let compressed_length = checked_length(packet_length, MAX_PACKET_LENGTH)?
+            .checked_sub(var_int_length(data_length))
+            .context("packet length is shorter than its data length")?;
+        let mut compressed_data = vec![0u8; compressed_length];
         self.read_exact(&mut compressed_data).await?;
 
         Ok(CompressedPacket {
             packet_length,
             data_length,
-            compressed_data,
+            compressed_data: compressed_data.into(),
         })
     }
 }
 
+/// Run a read, failing with [ProtocolError::Timeout] if it doesn't finish within the given duration.
+async fn within<T>(duration: Duration, read: impl Future<Output = Result<T>>) -> Result<T> {
+    match timeout(duration, read).await {
+        Ok(read) => read,
+        Err(_) => bail!(ProtocolError::Timeout(duration)),
+    }
+}
+
 /// Extension trait for writing Minecraft packets to a stream.
 #[async_trait]
 pub trait ProcotolAsyncWriteExt: AsyncWrite {
@@ -195,10 +306,9 @@ pub trait ProcotolAsyncWriteExt: AsyncWrite {
     where
         Self: Unpin,
     {
-        let id_length = var_int_length(packet.id);
-        self.write_var_int((packet.data.len() + id_length) as i32)
-            .await?;
-        self.write_var_int(packet.id).await?;
+        // the header is written separately, so the data is never copied
+        self.write_all(packet.header().as_bytes()).await?;
+        self.write_all(&packet.data).await?;
         Ok(())
     }
 
@@ -207,12 +317,29 @@ pub trait ProcotolAsyncWriteExt: AsyncWrite {
     where
         Self: Unpin,
     {
-        self.write_var_int(packet.packet_length).await?;
-        self.write_var_int(packet.data_length).await?;
+        self.write_all(packet.header().as_bytes()).await?;
         self.write_all(&packet.compressed_data).await?;
         Ok(())
     }
 
+    /// Write a packet to the stream, compressing it if the stream is compressed.
+    async fn write_framed_packet(
+        &mut self,
+        packet: &UncompressedPacket,
+        compression_threshold: Option<i32>,
+    ) -> Result<()>
+    where
+        Self: Unpin,
+    {
+        match compression_threshold {
+            Some(threshold) => {
+                self.write_compressed_packet(&packet.compress(threshold)?)
+                    .await
+            }
+            None => self.write_uncompressed_packet(packet).await,
+        }
+    }
+
     /// Write a [Packet] to the stream.
     async fn write_packet(&mut self, packet: &Packet) -> Result<()>
     where
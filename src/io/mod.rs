@@ -4,13 +4,26 @@
 //! Ideally, Magma should do as little processing as possible on packets, and should only
 //! decompress packets when it needs to read the data inside.
 //!
+//! Packet data is held in [Bytes], so a frame read from one connection can be split into its
+//! fields and written to another without copying its body.
+//!
 //! Refer to the [wiki.vg](https://wiki.vg/Protocol#Packet_format) for more information on
 //! Minecraft packet formats.
 
-use std::io::{Cursor, Write};
+use std::io::Cursor;
+
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use miniz_oxide::{
+    deflate::compress_to_vec_zlib,
+    inflate::{
+        decompress_to_vec_zlib_with_limit,
+        stream::{inflate, InflateState},
+    },
+    DataFormat, MZFlush,
+};
 
-use anyhow::{anyhow, Result};
-use miniz_oxide::inflate::decompress_to_vec_zlib;
+use crate::error::ProtocolError;
 
 mod r#async;
 mod sync;
@@ -19,42 +32,66 @@ pub use r#async::{ProcotolAsyncWriteExt, ProtocolAsyncReadExt};
 pub use sync::{ProtocolReadExt, ProtocolWriteExt};
 
 /// An uncompressed packet.
+#[derive(Clone)]
 pub struct UncompressedPacket {
     /// The packet id.
     pub id: i32,
     /// The packet data.
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 impl UncompressedPacket {
     /// Returns a cursor over the packet data.
-    pub fn as_cursor(&self) -> Cursor<&Vec<u8>> {
-        Cursor::new(&self.data)
+    pub fn as_cursor(&self) -> Cursor<&[u8]> {
+        Cursor::new(&self.data[..])
     }
 
-    /// Returns a mutable cursor over the packet data.
-    pub fn as_cursor_mut(&mut self) -> Cursor<&mut Vec<u8>> {
-        Cursor::new(&mut self.data)
+    /// The fields written ahead of the packet data - its length and id.
+    fn header(&self) -> Header {
+        Header::default()
+            .var_int((self.data.len() + var_int_length(self.id)) as i32)
+            .var_int(self.id)
     }
 
-    /// Consumes the packet and returns its raw bytes.
-    pub fn into_raw(self) -> Result<Vec<u8>> {
-        let buf = vec![0u8; self.data.len() + var_int_length(self.id)];
-        let mut cursor = Cursor::new(buf);
-        ProtocolWriteExt::write_var_int(&mut cursor, self.id)?;
-        cursor.write_all(&self.data)?;
-        Ok(cursor.into_inner())
+    /// Compresses the packet for a connection using the given compression threshold.
+    ///
+    /// Packets smaller than the threshold are left uncompressed, as required by the protocol.
+    pub fn compress(&self, threshold: i32) -> Result<CompressedPacket> {
+        self.compress_with_level(threshold, 6)
+    }
+
+    /// Compresses the packet using the given compression threshold and zlib level (0 - 10).
+    pub fn compress_with_level(&self, threshold: i32, level: u8) -> Result<CompressedPacket> {
+        let mut data = Vec::with_capacity(self.data.len() + var_int_length(self.id));
+        ProtocolWriteExt::write_var_int(&mut data, self.id)?;
+        data.extend_from_slice(&self.data);
+        // only compress packets that meet the threshold
+        let (data_length, compressed_data) = match data.len() as i32 >= threshold {
+            true => (data.len() as i32, compress_to_vec_zlib(&data, level)),
+            false => (0, data),
+        };
+        Ok(CompressedPacket {
+            packet_length: (var_int_length(data_length) + compressed_data.len()) as i32,
+            data_length,
+            compressed_data: compressed_data.into(),
+        })
+    }
+
+    /// Consumes the packet and returns its raw bytes, as sent on an uncompressed connection.
+    pub fn into_raw(self) -> Bytes {
+        self.header().prepend(&self.data)
     }
 }
 
 /// A compressed packet.
+#[derive(Clone)]
 pub struct CompressedPacket {
     /// The length of the packet.
     pub packet_length: i32,
     /// The length of the uncompressed data.
     pub data_length: i32,
     /// The compressed data.
-    pub compressed_data: Vec<u8>,
+    pub compressed_data: Bytes,
 }
 
 impl CompressedPacket {
@@ -67,35 +104,61 @@ impl CompressedPacket {
     /// read the data inside the packet.
     pub fn decompress(self) -> Result<UncompressedPacket> {
         // if packet does not meet the threshold, simply spit it back out
-        let mut data = match self.data_length {
+        let data = match self.data_length {
             0 => self.compressed_data,
-            _ => decompress_to_vec_zlib(&self.compressed_data)
-                .map_err(|_| anyhow!("failed to decompress packet"))?,
+            _ => {
+                // never inflate past the declared length, to guard against compression bombs
+                let data_length = checked_length(self.data_length, MAX_UNCOMPRESSED_LENGTH)?;
+                let data = decompress_to_vec_zlib_with_limit(&self.compressed_data, data_length)
+                    .map_err(|_| ProtocolError::Decompression)?;
+                if data.len() != data_length {
+                    bail!(ProtocolError::Decompression);
+                }
+                data.into()
+            }
         };
-        let mut cursor = Cursor::new(&data);
-        // read and remove packet id from data
+        // read the packet id, and slice it off the data
+        let mut cursor = Cursor::new(&data[..]);
         let id = ProtocolReadExt::read_var_int(&mut cursor)?;
-        data.drain(..var_int_length(id));
-        Ok(UncompressedPacket { id, data })
+        let id_length = cursor.position() as usize;
+        Ok(UncompressedPacket {
+            id,
+            data: data.slice(id_length..),
+        })
     }
 
-    /// Consumes the packet and returns its raw bytes.
-    pub fn into_raw(self) -> Result<Vec<u8>> {
-        let buf = vec![
-            0u8;
-            self.compressed_data.len()
-                + var_int_length(self.packet_length)
-                + var_int_length(self.data_length)
-        ];
-        let mut cursor = Cursor::new(buf);
-        ProtocolWriteExt::write_var_int(&mut cursor, self.packet_length)?;
-        ProtocolWriteExt::write_var_int(&mut cursor, self.data_length)?;
-        cursor.write_all(&self.compressed_data)?;
-        Ok(cursor.into_inner())
+    /// Reads the packet id without decompressing the whole packet.
+    ///
+    /// Only the first few bytes of the packet are inflated, which is far cheaper than
+    /// [CompressedPacket::decompress] for large packets that Magma does not need to read.
+    pub fn peek_id(&self) -> Result<i32> {
+        // packets below the compression threshold are sent as-is
+        if self.data_length == 0 {
+            return ProtocolReadExt::read_var_int(&mut Cursor::new(&self.compressed_data));
+        }
+        // a var int is at most 5 bytes long
+        let mut state = InflateState::new_boxed(DataFormat::Zlib);
+        let mut head = [0u8; 5];
+        let result = inflate(&mut state, &self.compressed_data, &mut head, MZFlush::None);
+        result.status.map_err(|_| ProtocolError::Decompression)?;
+        ProtocolReadExt::read_var_int(&mut Cursor::new(&head[..result.bytes_written]))
+    }
+
+    /// The fields written ahead of the compressed data - the packet and data lengths.
+    fn header(&self) -> Header {
+        Header::default()
+            .var_int(self.packet_length)
+            .var_int(self.data_length)
+    }
+
+    /// Consumes the packet and returns its raw bytes, as sent on a compressed connection.
+    pub fn into_raw(self) -> Bytes {
+        self.header().prepend(&self.compressed_data)
     }
 }
 
 /// A packet, which may be compressed or uncompressed.
+#[derive(Clone)]
 pub enum Packet {
     /// An uncompressed packet.
     Uncompressed(UncompressedPacket),
@@ -104,6 +167,49 @@ pub enum Packet {
 }
 
 impl Packet {
+    /// Interprets a raw frame read from a stream, according to whether the stream is compressed.
+    ///
+    /// The packet shares the frame's buffer rather than copying it.
+    pub fn from_frame(frame: Bytes, compressed: bool) -> Result<Self> {
+        // both formats begin with a var int - the data length or packet id respectively
+        let (value, offset) = {
+            let mut cursor = Cursor::new(&frame[..]);
+            let value = ProtocolReadExt::read_var_int(&mut cursor)?;
+            (value, cursor.position() as usize)
+        };
+        match compressed {
+            true => {
+                checked_length(value, MAX_UNCOMPRESSED_LENGTH)?;
+                Ok(Packet::Compressed(CompressedPacket {
+                    packet_length: frame.len() as i32,
+                    data_length: value,
+                    compressed_data: frame.slice(offset..),
+                }))
+            }
+            false => Ok(Packet::Uncompressed(UncompressedPacket {
+                id: value,
+                data: frame.slice(offset..),
+            })),
+        }
+    }
+
+    /// Returns the packet id. Compressed packets are only inflated far enough to read the id,
+    /// so they can be relayed verbatim unless their contents are needed.
+    pub fn id(&self) -> Result<i32> {
+        match self {
+            Packet::Uncompressed(packet) => Ok(packet.id),
+            Packet::Compressed(packet) => packet.peek_id(),
+        }
+    }
+
+    /// The number of bytes the packet takes on the wire.
+    pub fn size(&self) -> usize {
+        match self {
+            Packet::Uncompressed(packet) => packet.header().len + packet.data.len(),
+            Packet::Compressed(packet) => packet.header().len + packet.compressed_data.len(),
+        }
+    }
+
     /// Decompresses the packet if it is compressed.
     pub fn decompress(self) -> Result<UncompressedPacket> {
         match self {
@@ -113,7 +219,7 @@ impl Packet {
     }
 
     /// Consumes the packet and returns its raw bytes.
-    pub fn into_raw(self) -> Result<Vec<u8>> {
+    pub fn into_raw(self) -> Bytes {
         match self {
             Packet::Uncompressed(packet) => packet.into_raw(),
             Packet::Compressed(packet) => packet.into_raw(),
@@ -121,8 +227,52 @@ impl Packet {
     }
 }
 
+/// The var int fields ahead of a packet's data, encoded on the stack so they can be written
+/// separately from the data without copying it.
+#[derive(Default)]
+struct Header {
+    /// The encoded fields - at most two var ints of 5 bytes each.
+    buf: [u8; 10],
+    /// The number of bytes encoded.
+    len: usize,
+}
+
+impl Header {
+    /// Append a var int.
+    fn var_int(mut self, value: i32) -> Self {
+        let mut x = value as u32;
+        loop {
+            let mut temp = (x & 0b0111_1111) as u8;
+            x >>= 7;
+            if x != 0 {
+                temp |= 0b1000_0000;
+            }
+            self.buf[self.len] = temp;
+            self.len += 1;
+            if x == 0 {
+                break self;
+            }
+        }
+    }
+
+    /// The encoded fields.
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Copy the fields and the given data into a single buffer of exactly the right size.
+    fn prepend(&self, data: &[u8]) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.len + data.len());
+        buf.put_slice(self.as_bytes());
+        buf.put_slice(data);
+        buf.freeze()
+    }
+}
+
 /// Calculates the length of a var int.
-fn var_int_length(mut x: i32) -> usize {
+fn var_int_length(x: i32) -> usize {
+    // var ints are encoded as unsigned - shifting a negative i32 would never reach zero
+    let mut x = x as u32;
     let mut size = 1; // all var ints are at least 1 byte big
     loop {
         x >>= 7;
@@ -137,6 +287,22 @@ fn var_int_length(mut x: i32) -> usize {
     size
 }
 
+/// Validates a length prefix read from a stream, rejecting negative or oversized values before
+/// anything is allocated.
+fn checked_length(length: i32, max: i32) -> Result<usize> {
+    if !(0..=max).contains(&length) {
+        bail!(ProtocolError::LengthOutOfBounds { length, max });
+    }
+    Ok(length as usize)
+}
+
+/// The maximum length of a packet - the largest value representable by a 3-byte var int.
+const MAX_PACKET_LENGTH: i32 = 2097151;
+/// The maximum length of a decompressed packet, as enforced by the vanilla server.
+const MAX_UNCOMPRESSED_LENGTH: i32 = 8388608;
+/// The maximum length of a string in bytes - 32767 UTF-16 code units of up to 3 bytes each.
+const MAX_STRING_LENGTH: i32 = 32767 * 3;
+
 /// Used to extract the value from a segment.
 const VARINT_SEGMENT_BITS: u8 = 0x7F;
 /// Used to indicate whether there are more bytes to read.
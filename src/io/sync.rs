@@ -2,14 +2,17 @@
 //! packets to a type implementing [Read].
 
 use anyhow::{bail, Context, Result};
+use bytes::Bytes;
 use uuid::Uuid;
 
 use std::io::{Read, Write};
 
 use super::{
-    var_int_length, CompressedPacket, Packet, UncompressedPacket, VARINT_CONTINUE_BIT,
+    checked_length, var_int_length, CompressedPacket, Packet, UncompressedPacket,
+    MAX_PACKET_LENGTH, MAX_STRING_LENGTH, MAX_UNCOMPRESSED_LENGTH, VARINT_CONTINUE_BIT,
     VARINT_SEGMENT_BITS,
 };
+use crate::error::ProtocolError;
 
 /// Extension trait for reading Minecraft packets from a stream.
 pub trait ProtocolReadExt: Read {
@@ -20,6 +23,29 @@ pub trait ProtocolReadExt: Read {
         Ok(buf[0])
     }
 
+    /// Read a [u16] from the stream.
+    fn read_u16(&mut self) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        self.read_exact(&mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Read an [i64] from the stream.
+    fn read_i64(&mut self) -> Result<i64> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(i64::from_be_bytes(buf))
+    }
+
+    /// Read a [bool] from the stream.
+    fn read_bool(&mut self) -> Result<bool> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => bail!(ProtocolError::InvalidBool(value)),
+        }
+    }
+
     /// Read a var int from the stream.
     fn read_var_int(&mut self) -> Result<i32> {
         let mut num_read = 0;
@@ -33,7 +59,7 @@ pub trait ProtocolReadExt: Read {
             num_read += 1;
 
             if num_read > 5 {
-                bail!("VarInt too long (max length: 5)");
+                bail!(ProtocolError::VarIntTooLong);
             }
             if read & 0b1000_0000 == 0 {
                 break;
@@ -60,20 +86,29 @@ pub trait ProtocolReadExt: Read {
             position += 7;
 
             if position >= 64 {
-                bail!("VarInt exceeded maximum length");
+                bail!(ProtocolError::VarIntTooLong);
             }
         }
     }
 
     /// Read a string from the stream.
     fn read_string(&mut self) -> Result<String> {
-        let len = self.read_var_int()? as usize;
+        let len = checked_length(self.read_var_int()?, MAX_STRING_LENGTH)?;
         let mut buf = vec![0u8; len];
         self.read_exact(&mut buf)
             .context("failed to read string bytes")?;
         String::from_utf8(buf).context("failed to decode string bytes")
     }
 
+    /// Read a length-prefixed byte array from the stream.
+    fn read_byte_array(&mut self) -> Result<Vec<u8>> {
+        let len = checked_length(self.read_var_int()?, MAX_PACKET_LENGTH)?;
+        let mut buf = vec![0u8; len];
+        self.read_exact(&mut buf)
+            .context("failed to read byte array")?;
+        Ok(buf)
+    }
+
     /// Read a [Uuid] from the stream.
     fn read_uuid(&mut self) -> Result<Uuid> {
         let mut buf = [0u8; 16];
@@ -82,37 +117,58 @@ pub trait ProtocolReadExt: Read {
         Ok(uuid)
     }
 
+    /// Read a raw frame from the stream - the length-prefixed body of a packet, which may or may
+    /// not be compressed. Use [Packet::from_frame] to interpret it.
+    fn read_frame(&mut self) -> Result<Bytes> {
+        let length = checked_length(self.read_var_int()?, MAX_PACKET_LENGTH)?;
+        if length == 0 {
+            bail!(ProtocolError::EmptyPacket)
+        }
+        let mut frame = vec![0u8; length];
+        self.read_exact(&mut frame)?;
+        Ok(frame.into())
+    }
+
     /// Read an [UncompressedPacket] from the stream.
     fn read_uncompressed_packet(&mut self) -> Result<UncompressedPacket> {
-        let length = self.read_var_int()? as usize;
+        let length = checked_length(self.read_var_int()?, MAX_PACKET_LENGTH)?;
         if length == 0 {
-            bail!("Attempted to read empty packet")
+            bail!(ProtocolError::EmptyPacket)
         }
 
         // read packet id and compute data length
         let id = self.read_var_int()?;
-        let data_length = length - var_int_length(id);
+        let data_length = length
+            .checked_sub(var_int_length(id))
+            .context("packet length is shorter than its id")?;
 
         // read data
         let mut data = vec![0u8; data_length];
         self.read_exact(&mut data)?;
 
-        Ok(UncompressedPacket { id, data })
+        Ok(UncompressedPacket {
+            id,
+            data: data.into(),
+        })
     }
 
     /// Read a compressed packet from the stream. This does not decompress the packet.
     fn read_compressed_packet(&mut self) -> Result<CompressedPacket> {
         let packet_length = self.read_var_int()?;
         let data_length = self.read_var_int()?;
+        checked_length(data_length, MAX_UNCOMPRESSED_LENGTH)?;
 
-        // read compressed data
-        let mut compressed_data = vec![0u8; data_length as usize];
+        // read compressed data - the packet length includes the data length field
+        let compressed_length = checked_length(packet_length, MAX_PACKET_LENGTH)?
+            .checked_sub(var_int_length(data_length))
+            .context("packet length is shorter than its data length")?;
+        let mut compressed_data = vec![0u8; compressed_length];
         self.read_exact(&mut compressed_data)?;
 
         Ok(CompressedPacket {
             packet_length,
             data_length,
-            compressed_data,
+            compressed_data: compressed_data.into(),
         })
     }
 }
@@ -125,6 +181,23 @@ pub trait ProtocolWriteExt: Write {
         Ok(())
     }
 
+    /// Write a [u16] to the stream.
+    fn write_u16(&mut self, value: u16) -> Result<()> {
+        self.write_all(&value.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Write an [i64] to the stream.
+    fn write_i64(&mut self, value: i64) -> Result<()> {
+        self.write_all(&value.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Write a [bool] to the stream.
+    fn write_bool(&mut self, value: bool) -> Result<()> {
+        self.write_u8(value as u8)
+    }
+
     /// Write a var int to the stream.
     fn write_var_int(&mut self, value: i32) -> Result<()> {
         let mut x = value as u32;
@@ -170,18 +243,29 @@ pub trait ProtocolWriteExt: Write {
         self.write_all(buf).context("failed to write string")
     }
 
+    /// Write a length-prefixed byte array to the stream.
+    fn write_byte_array(&mut self, value: &[u8]) -> Result<()> {
+        self.write_var_int(value.len() as i32)
+            .context("failed to write byte array length")?;
+        self.write_all(value).context("failed to write byte array")
+    }
+
+    /// Write a [Uuid] to the stream.
+    fn write_uuid(&mut self, value: &Uuid) -> Result<()> {
+        self.write_all(value.as_bytes())?;
+        Ok(())
+    }
+
     /// Write an [UncompressedPacket] to the stream.
     fn write_uncompressed_packet(&mut self, packet: &UncompressedPacket) -> Result<()> {
-        let id_length = var_int_length(packet.id);
-        self.write_var_int((packet.data.len() + id_length) as i32)?;
-        self.write_var_int(packet.id)?;
+        self.write_all(packet.header().as_bytes())?;
+        self.write_all(&packet.data)?;
         Ok(())
     }
 
     /// Write a [CompressedPacket] to the stream.
     fn write_compressed_packet(&mut self, packet: &CompressedPacket) -> Result<()> {
-        self.write_var_int(packet.packet_length)?;
-        self.write_var_int(packet.data_length)?;
+        self.write_all(packet.header().as_bytes())?;
         self.write_all(&packet.compressed_data)?;
         Ok(())
     }
@@ -0,0 +1,61 @@
+//! The Magma library crate.
+//!
+//! Magma's internals are exposed as a library so that tooling such as the fuzz targets can drive
+//! the protocol decoders directly. The `magma` binary is a thin wrapper around these modules.
+
+pub mod admin;
+pub mod agones;
+pub mod announce;
+pub mod auth;
+pub mod bench;
+pub mod bridge;
+pub mod client;
+pub mod config;
+pub mod crash;
+pub mod cryptor;
+pub mod dns;
+pub mod drain;
+pub mod dump;
+pub mod error;
+pub mod events;
+pub mod health;
+pub mod history;
+pub mod hook;
+pub mod idle;
+pub mod influx;
+pub mod io;
+pub mod limits;
+pub mod link;
+pub mod memory;
+pub mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod motd;
+pub mod panel;
+pub mod pool;
+pub mod protocol;
+pub mod proxy;
+pub mod query;
+pub mod ratelimit;
+pub mod rcon;
+pub mod realip;
+pub mod registry;
+pub mod reload;
+pub mod reply;
+pub mod reputation;
+pub mod resume;
+pub mod schedule;
+pub mod secrets;
+pub mod security;
+pub mod signals;
+pub mod statsd;
+pub mod status;
+pub mod tail;
+pub mod tarpit;
+pub mod template;
+pub mod tls;
+pub mod transfer;
+pub mod tunnel;
+pub mod username;
+pub mod wake;
+pub mod websocket;
@@ -0,0 +1,335 @@
+//! Inspects process resource limits, so Magma can degrade gracefully as it approaches them.
+//!
+//! File descriptors are the limit a proxy hits first - every connection costs two. At startup, the
+//! limit can be raised, and is reported along with roughly how many players it supports. Counts
+//! are read from `/proc`, and so are only available on Linux. Elsewhere, checks never report low
+//! headroom. Counting takes a descriptor itself, so a count which fails for want of one means
+//! there is no headroom left.
+//!
+//! Once accepting or connecting fails for want of descriptors or socket buffers anyway, Magma
+//! sheds load for a short while - status pings are turned away, so what's left goes to logins.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::config::MagmaConfig;
+
+/// How long a headroom check is trusted for while headroom is healthy.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long load is shed for after resources last ran out.
+const SHED_DURATION: Duration = Duration::from_secs(5);
+/// How often running out of resources is warned about, at most.
+const WARN_INTERVAL: Duration = Duration::from_secs(10);
+/// The file descriptors each player costs - their connection, and the one to their backend.
+const FILES_PER_PLAYER: u64 = 2;
+/// The file descriptors set aside for everything but players - listeners, logs, the history
+/// database, and lookups.
+const RESERVED_FILES: u64 = 64;
+
+/// `OPEN_MAX` - the most file descriptors macOS lets a process open, when `kern.maxfilesperproc`
+/// can't be read.
+#[cfg(target_os = "macos")]
+const OPEN_MAX: libc::rlim_t = 10240;
+
+/// Test whether an error was caused by running out of file descriptors.
+#[cfg(unix)]
+pub fn is_fd_exhaustion(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+/// Test whether an error was caused by running out of file descriptors.
+#[cfg(not(unix))]
+pub fn is_fd_exhaustion(_err: &io::Error) -> bool {
+    false
+}
+
+/// Test whether an error was caused by running out of a resource sockets need - file descriptors,
+/// socket buffers or kernel memory - rather than by the peer.
+#[cfg(unix)]
+pub fn is_resource_exhaustion(err: &io::Error) -> bool {
+    is_fd_exhaustion(err) || matches!(err.raw_os_error(), Some(libc::ENOBUFS | libc::ENOMEM))
+}
+
+/// Test whether an error was caused by running out of a resource sockets need - file descriptors,
+/// socket buffers or kernel memory - rather than by the peer.
+#[cfg(not(unix))]
+pub fn is_resource_exhaustion(_err: &io::Error) -> bool {
+    false
+}
+
+/// What was being done when resources ran out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    /// Accepting a client's connection.
+    Accept,
+    /// Connecting to a backend.
+    Connect,
+}
+
+impl Operation {
+    /// The label of the operation in metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            Operation::Accept => "accept",
+            Operation::Connect => "connect",
+        }
+    }
+}
+
+/// Tracks running out of resources, and whether load is being shed because of it.
+#[derive(Default)]
+struct Shedding {
+    /// When load stops being shed, if it was.
+    until: Option<Instant>,
+    /// When running out was last warned about.
+    warned: Option<Instant>,
+    /// How many times resources ran out since the last warning.
+    unwarned: u64,
+    /// How many times resources ran out, by operation, since startup.
+    counts: BTreeMap<Operation, u64>,
+}
+
+fn shedding() -> &'static Mutex<Shedding> {
+    static SHEDDING: OnceLock<Mutex<Shedding>> = OnceLock::new();
+    SHEDDING.get_or_init(Mutex::default)
+}
+
+/// Record that resources ran out, shedding load for a while. The first time in a while is warned
+/// about, along with how many times it happened since - an accept loop failing on every attempt
+/// would otherwise flood the log.
+pub fn exhausted(operation: Operation, err: &io::Error) {
+    let mut shedding = shedding().lock().unwrap();
+    let now = Instant::now();
+    shedding.until = Some(now + SHED_DURATION);
+    *shedding.counts.entry(operation).or_default() += 1;
+    shedding.unwarned += 1;
+    if shedding
+        .warned
+        .is_none_or(|warned| warned.elapsed() >= WARN_INTERVAL)
+    {
+        warn!(
+            "Out of resources to {} ({}, {} time(s) since last warned) - shedding status pings for {:?}",
+            operation.label(),
+            err,
+            shedding.unwarned,
+            SHED_DURATION
+        );
+        shedding.warned = Some(now);
+        shedding.unwarned = 0;
+    }
+}
+
+/// Test whether load is being shed, as resources ran out recently.
+pub fn is_shedding() -> bool {
+    shedding()
+        .lock()
+        .unwrap()
+        .until
+        .is_some_and(|until| Instant::now() < until)
+}
+
+/// How many times resources ran out, by operation, since startup.
+pub fn exhaustion_counts() -> Vec<(Operation, u64)> {
+    let shedding = shedding().lock().unwrap();
+    shedding
+        .counts
+        .iter()
+        .map(|(operation, count)| (*operation, *count))
+        .collect()
+}
+
+/// Count the file descriptors the process has open, or `None` on platforms without `/proc`.
+#[cfg(target_os = "linux")]
+pub fn open_files() -> Option<io::Result<u64>> {
+    Some(std::fs::read_dir("/proc/self/fd").map(|entries| entries.count() as u64))
+}
+
+/// Count the file descriptors the process has open, or `None` on platforms without `/proc`.
+#[cfg(not(target_os = "linux"))]
+pub fn open_files() -> Option<io::Result<u64>> {
+    None
+}
+
+/// The soft and hard limits on the number of file descriptors the process may open.
+#[cfg(unix)]
+fn nofile_limit() -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: the pointer is to a valid rlimit, which getrlimit only writes to
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+/// The soft limit on the number of file descriptors the process may open, if limited.
+#[cfg(unix)]
+pub fn max_open_files() -> Option<u64> {
+    let limit = nofile_limit().ok()?;
+    (limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur as u64)
+}
+
+/// The soft limit on the number of file descriptors the process may open, if limited.
+#[cfg(not(unix))]
+pub fn max_open_files() -> Option<u64> {
+    None
+}
+
+/// The most file descriptors macOS lets a process open. Its hard limit is usually unlimited, but
+/// soft limits above `kern.maxfilesperproc` are refused.
+#[cfg(target_os = "macos")]
+fn max_files_per_proc() -> libc::rlim_t {
+    let mut max: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>();
+    // SAFETY: the name is NUL-terminated, and sysctlbyname only writes up to size bytes to max
+    let result = unsafe {
+        libc::sysctlbyname(
+            b"kern.maxfilesperproc\0".as_ptr().cast(),
+            (&mut max as *mut libc::c_int).cast(),
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    match result == 0 && max > 0 {
+        true => max as libc::rlim_t,
+        false => OPEN_MAX,
+    }
+}
+
+/// Raise the soft limit on the number of file descriptors the process may open to a target,
+/// capped at the hard limit and, on macOS, at `kern.maxfilesperproc`, returning the soft limit in
+/// effect. A limit already above the target is left alone.
+#[cfg(unix)]
+pub fn raise_open_files(target: u64) -> io::Result<u64> {
+    let mut limit = nofile_limit()?;
+    let target = (target as libc::rlim_t).min(limit.rlim_max);
+    #[cfg(target_os = "macos")]
+    let target = target.min(max_files_per_proc());
+    if limit.rlim_cur >= target {
+        return Ok(limit.rlim_cur as u64);
+    }
+    limit.rlim_cur = target;
+    // SAFETY: the pointer is to a valid rlimit, which setrlimit only reads
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(target as u64)
+}
+
+/// Raise the soft limit on the number of file descriptors the process may open to a target,
+/// capped at the hard limit and, on macOS, at `kern.maxfilesperproc`, returning the soft limit in
+/// effect. A limit already above the target is left alone.
+#[cfg(not(unix))]
+pub fn raise_open_files(_target: u64) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "file descriptor limits are only supported on unix",
+    ))
+}
+
+/// Raise the limit on open file descriptors if configured, then log it along with roughly how
+/// many players it supports, warning when routes allow more players than that.
+pub fn report_capacity(config: &MagmaConfig) {
+    if let Some(target) = config.max_open_files {
+        match raise_open_files(target) {
+            Ok(limit) if limit < target => warn!(
+                "Open file limit could only be raised to {} of {} - the hard limit is lower",
+                limit, target
+            ),
+            Ok(_) => {}
+            Err(err) => warn!("Failed to raise the open file limit to {}: {}", target, err),
+        }
+    }
+    let Some(limit) = max_open_files() else {
+        info!("Open file limit is unlimited or unknown");
+        return;
+    };
+
+    // connections held in the tarpit, and the headroom kept free, cost descriptors too
+    let tarpit = config
+        .tarpit
+        .as_ref()
+        .map_or(0, |tarpit| tarpit.max_connections as u64);
+    let headroom = config
+        .proxies
+        .iter()
+        .filter_map(|proxy| proxy.min_fd_headroom)
+        .max()
+        .unwrap_or(0);
+    let capacity = limit.saturating_sub(RESERVED_FILES + tarpit + headroom) / FILES_PER_PLAYER;
+    info!(
+        "Open file limit is {}, enough for about {} concurrent player(s)",
+        limit, capacity
+    );
+
+    let max_players: u64 = config
+        .proxies
+        .iter()
+        .flat_map(|proxy| &proxy.routes)
+        .filter_map(|route| route.max_players)
+        .map(|max| max as u64)
+        .sum();
+    if max_players > capacity {
+        warn!(
+            "Routes allow up to {} player(s), but the open file limit only supports about {} - raise it with max_open_files",
+            max_players, capacity
+        );
+    }
+}
+
+/// The number of file descriptors the process may still open, if known. None are left if they
+/// couldn't be counted for want of one.
+pub fn fd_headroom() -> Option<u64> {
+    let open = match open_files()? {
+        Ok(open) => open,
+        Err(err) if is_fd_exhaustion(&err) => return Some(0),
+        Err(_) => return None,
+    };
+    Some(max_open_files()?.saturating_sub(open))
+}
+
+/// Checks whether file descriptor headroom has fallen below a minimum.
+///
+/// Counting open descriptors is not free, so while headroom is healthy the result is reused for a
+/// short interval. Once headroom is low, every check counts again, so accepting resumes as soon
+/// as descriptors are freed.
+pub struct HeadroomCheck {
+    /// The minimum headroom.
+    min: u64,
+    /// When headroom was last counted.
+    checked: Option<Instant>,
+    /// Whether headroom was low when last counted.
+    low: bool,
+}
+
+impl HeadroomCheck {
+    /// Create a check against the given minimum headroom.
+    pub fn new(min: u64) -> Self {
+        Self {
+            min,
+            checked: None,
+            low: false,
+        }
+    }
+
+    /// Test whether headroom is below the minimum.
+    pub fn is_low(&mut self) -> bool {
+        let stale = self
+            .checked
+            .is_none_or(|checked| checked.elapsed() >= CHECK_INTERVAL);
+        if self.low || stale {
+            self.low = fd_headroom().is_some_and(|headroom| headroom < self.min);
+            self.checked = Some(Instant::now());
+        }
+        self.low
+    }
+}
@@ -0,0 +1,221 @@
+//! Defines links - framed connections between Magma instances, such as edge to hub tunnels.
+//!
+//! A link carries opaque frames, which may be compressed with zstd to cut WAN bandwidth. Whether
+//! compression is used is negotiated when the link is established, so both ends always agree, and
+//! the traffic of clients and backends passing through the link is unaffected.
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{
+    self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind, ReadHalf, WriteHalf,
+};
+use tracing::debug;
+
+/// The magic bytes sent at the start of a link.
+const MAGIC: &[u8; 4] = b"MGLK";
+/// The link protocol version.
+const VERSION: u8 = 1;
+/// The maximum length of an uncompressed frame.
+const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+/// Frames shorter than this are never compressed, as the overhead outweighs the savings.
+const MIN_COMPRESSED_LENGTH: usize = 128;
+/// Set in the frame flags when the frame is compressed.
+const FLAG_COMPRESSED: u8 = 0x01;
+
+/// The compression used on a link.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkCompression {
+    /// Frames are sent as-is.
+    #[default]
+    None,
+    /// Frames are compressed with zstd at the given level.
+    Zstd(i32),
+}
+
+impl LinkCompression {
+    /// The id of this compression method on the wire.
+    fn id(self) -> u8 {
+        match self {
+            LinkCompression::None => 0,
+            LinkCompression::Zstd(_) => 1,
+        }
+    }
+}
+
+/// An established link to another Magma instance.
+pub struct Link<S> {
+    stream: S,
+    compression: LinkCompression,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Link<S> {
+    /// Establish a link over the given stream, offering the given compression to the peer.
+    pub async fn connect(mut stream: S, offer: LinkCompression) -> Result<Self> {
+        write_hello(&mut stream, offer.id()).await?;
+        let chosen = read_hello(&mut stream).await?;
+        let compression = match (chosen, offer) {
+            (0, _) => LinkCompression::None,
+            (1, LinkCompression::Zstd(level)) => LinkCompression::Zstd(level),
+            (id, _) => bail!("Peer chose unsupported link compression {}", id),
+        };
+        debug!("Established link with compression {:?}", compression);
+        Ok(Self {
+            stream,
+            compression,
+        })
+    }
+
+    /// Accept a link over the given stream, using compression if the peer offers it and it is
+    /// allowed.
+    pub async fn accept(mut stream: S, allow: LinkCompression) -> Result<Self> {
+        let offered = read_hello(&mut stream).await?;
+        let compression = match (offered, allow) {
+            (1, LinkCompression::Zstd(level)) => LinkCompression::Zstd(level),
+            _ => LinkCompression::None,
+        };
+        write_hello(&mut stream, compression.id()).await?;
+        debug!("Accepted link with compression {:?}", compression);
+        Ok(Self {
+            stream,
+            compression,
+        })
+    }
+
+    /// The compression negotiated for this link.
+    pub fn compression(&self) -> LinkCompression {
+        self.compression
+    }
+
+    /// Split the link into a reader and a writer.
+    pub fn into_split(self) -> (LinkReader<ReadHalf<S>>, LinkWriter<WriteHalf<S>>) {
+        let (rx, tx) = io::split(self.stream);
+        (
+            LinkReader {
+                inner: rx,
+                compression: self.compression,
+            },
+            LinkWriter {
+                inner: tx,
+                compression: self.compression,
+            },
+        )
+    }
+}
+
+/// Reads frames from a link.
+pub struct LinkReader<R> {
+    inner: R,
+    compression: LinkCompression,
+}
+
+impl<R: AsyncRead + Unpin> LinkReader<R> {
+    /// Read the next frame, returning [None] if the link was closed.
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let length = match self.inner.read_u32().await {
+            Ok(length) => length as usize,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if length > MAX_FRAME_LENGTH {
+            bail!(
+                "Link frame length {} is out of bounds (max: {})",
+                length,
+                MAX_FRAME_LENGTH
+            );
+        }
+        let flags = self.inner.read_u8().await?;
+        let mut frame = vec![0u8; length];
+        self.inner.read_exact(&mut frame).await?;
+
+        if flags & FLAG_COMPRESSED == 0 {
+            return Ok(Some(frame));
+        }
+        if self.compression == LinkCompression::None {
+            bail!("Received a compressed frame on an uncompressed link");
+        }
+        let frame = zstd::bulk::decompress(&frame, MAX_FRAME_LENGTH)
+            .context("failed to decompress link frame")?;
+        Ok(Some(frame))
+    }
+}
+
+/// Writes frames to a link.
+pub struct LinkWriter<W> {
+    inner: W,
+    compression: LinkCompression,
+}
+
+impl<W: AsyncWrite + Unpin> LinkWriter<W> {
+    /// Write a frame, compressing it if worthwhile.
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.len() > MAX_FRAME_LENGTH {
+            bail!(
+                "Link frame length {} is out of bounds (max: {})",
+                frame.len(),
+                MAX_FRAME_LENGTH
+            );
+        }
+        let compressed = match self.compression {
+            LinkCompression::Zstd(level) if frame.len() >= MIN_COMPRESSED_LENGTH => {
+                let compressed = zstd::bulk::compress(frame, level)?;
+                // incompressible frames are sent as-is
+                (compressed.len() < frame.len()).then_some(compressed)
+            }
+            _ => None,
+        };
+        match &compressed {
+            Some(compressed) => {
+                self.inner.write_u32(compressed.len() as u32).await?;
+                self.inner.write_u8(FLAG_COMPRESSED).await?;
+                self.inner.write_all(compressed).await?;
+            }
+            None => {
+                self.inner.write_u32(frame.len() as u32).await?;
+                self.inner.write_u8(0).await?;
+                self.inner.write_all(frame).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush buffered frames to the peer.
+    pub async fn flush(&mut self) -> Result<()> {
+        self.inner.flush().await?;
+        Ok(())
+    }
+
+    /// Shut down the link.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.inner.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Write the link hello - the magic, protocol version, and a compression method.
+async fn write_hello<S: AsyncWrite + Unpin>(stream: &mut S, compression: u8) -> Result<()> {
+    stream.write_all(MAGIC).await?;
+    stream.write_u8(VERSION).await?;
+    stream.write_u8(compression).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read the link hello, returning the compression method.
+async fn read_hello<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u8> {
+    let mut magic = [0u8; 4];
+    stream
+        .read_exact(&mut magic)
+        .await
+        .context("failed to read link hello")?;
+    if &magic != MAGIC {
+        bail!("Peer is not a Magma link");
+    }
+    let version = stream.read_u8().await?;
+    if version != VERSION {
+        bail!(
+            "Unsupported link version {} (expected {})",
+            version,
+            VERSION
+        );
+    }
+    Ok(stream.read_u8().await?)
+}
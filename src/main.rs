@@ -8,83 +8,218 @@
 //! - **Flexible**: Magma supports multiple routing algorithms, and can be configured to use any of them.
 //! - **Easy to use**: Magma is easy to use, and can be configured using a simple TOML configuration file.
 
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    io::{stdout, IsTerminal},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use ansi_term::{Color, Style};
-use anyhow::{Context, Result};
-use clap::Parser;
+use ansi_term::Style;
+use anyhow::{bail, Context, Result};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use futures::future::try_join_all;
 use time::macros::format_description;
-use tokio::fs::write;
+use tokio::{fs::write, runtime::Builder};
 use tracing::{debug, error, info};
 use tracing_subscriber::{
     fmt::{self, time::UtcTime},
     prelude::*,
     util::SubscriberInitExt,
-    EnvFilter,
+    EnvFilter, Layer, Registry,
 };
 
-mod bridge;
-mod config;
-mod cryptor;
-mod io;
-mod proxy;
-
-use config::Config;
+use magma::{
+    admin::{self, AdminState},
+    announce,
+    auth::Authenticator,
+    bench,
+    config::{self, Config, Overrides, RouteOverride, TunnelConfig},
+    crash, dump, events, health,
+    history::{self, History},
+    idle, influx, limits, memory,
+    proxy::Services,
+    rcon,
+    reload::Reloader,
+    reputation::Reputation,
+    secrets, security, statsd,
+    tail::TailLayer,
+    tarpit::Tarpit,
+    tunnel::{edge::Edge, hub},
+};
 
 /// Magam is a light-weight domain-switching reverse proxy for Minecraft servers.
 #[derive(Parser)]
 struct Args {
-    /// The path to the configuration file.
+    /// The path to the configuration file. May be given more than once, to merge overlays onto a
+    /// base configuration in order.
     #[clap(long, default_value = "config.toml")]
-    config: PathBuf,
+    config: Vec<PathBuf>,
+    /// The address every proxy entry listens on, in place of its own.
+    #[clap(long)]
+    listen: Option<String>,
+    /// A route to add to the configuration, such as `from=play.example.com,to=127.0.0.1:25570`.
+    /// May be given more than once. Without a configuration file, Magma runs on these routes alone.
+    #[clap(long = "route")]
+    routes: Vec<RouteOverride>,
+    /// The level Magma logs at, such as `debug`, in place of `RUST_LOG`.
+    #[clap(long)]
+    log_level: Option<String>,
+    /// Disable colored output. Also disabled by setting `NO_COLOR`, or when output isn't a terminal.
+    #[clap(long)]
+    no_color: bool,
+    /// How log lines are timestamped.
+    #[clap(long, value_enum, default_value_t = LogTime::Time)]
+    log_time: LogTime,
+    /// Whether log lines show the module they were logged from.
+    #[clap(long, action = ArgAction::Set, default_value_t = true)]
+    log_targets: bool,
+    /// The number of threads running connections. Defaults to one per CPU core.
+    #[clap(long)]
+    worker_threads: Option<usize>,
+    /// The maximum number of threads for blocking work, such as file IO and DNS lookups.
+    #[clap(long)]
+    max_blocking_threads: Option<usize>,
+    /// The number of tasks a thread runs between checks for new IO and timer events.
+    #[clap(long)]
+    event_interval: Option<u32>,
+    /// The command to run. Defaults to running the proxy.
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// How log lines are timestamped.
+#[derive(Clone, Copy, ValueEnum)]
+enum LogTime {
+    /// The time of day in UTC, such as `12:00:00`.
+    Time,
+    /// The full date and time in RFC 3339 format, such as `2024-01-01T12:00:00Z`.
+    Rfc3339,
+    /// No timestamp, for log collectors which add their own, such as journald.
+    None,
+}
+
+/// Magma subcommands.
+#[derive(Subcommand)]
+enum Command {
+    /// Load-test a proxy or server using synthetic clients.
+    Bench(bench::BenchArgs),
+    /// Inspect the configuration.
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
+
+/// Configuration subcommands.
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration, after merging the files and applying the overrides.
+    Render,
+}
+
+fn main() -> Result<()> {
     // parse arguments
     let args = Args::parse();
+    // build the runtime - small hosts may want fewer threads than cores
+    let mut runtime = Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = args.worker_threads {
+        if threads == 0 {
+            bail!("--worker-threads must be at least 1");
+        }
+        runtime.worker_threads(threads);
+    }
+    if let Some(threads) = args.max_blocking_threads {
+        if threads == 0 {
+            bail!("--max-blocking-threads must be at least 1");
+        }
+        runtime.max_blocking_threads(threads);
+    }
+    if let Some(interval) = args.event_interval {
+        if interval == 0 {
+            bail!("--event-interval must be at least 1");
+        }
+        runtime.event_interval(interval);
+    }
+    runtime
+        .build()
+        .context("Failed to start the runtime")?
+        .block_on(run(args))
+}
+
+/// Run Magma with the given arguments.
+async fn run(args: Args) -> Result<()> {
+    let current_dir = env::current_dir().context("failed to locate current directory")?;
+    let paths: Vec<_> = args
+        .config
+        .iter()
+        .map(|path| current_dir.join(path))
+        .collect();
+    let overrides = Overrides {
+        listen: args.listen,
+        routes: args.routes,
+    };
+    // rendered before logging starts, so the output is only the configuration
+    if let Some(Command::Config(ConfigCommand::Render)) = args.command {
+        let config = config::merge_files(&paths, &overrides).await?;
+        print!(
+            "{}",
+            toml::to_string_pretty(&config).context("failed to render configuration")?
+        );
+        return Ok(());
+    }
+
     // initialize logging
+    let color = use_color(args.no_color);
     tracing_subscriber::registry()
-        .with(fmt::layer().with_timer(UtcTime::new(format_description!(
-            "[hour]:[minute]:[second]"
-        ))))
-        .with(
-            EnvFilter::builder()
+        .with(log_layer(color, args.log_time, args.log_targets))
+        .with(match &args.log_level {
+            Some(level) => EnvFilter::try_new(format!("magma={}", level))
+                .context("Failed to parse --log-level")?,
+            None => EnvFilter::builder()
                 .with_default_directive("magma=info".parse().unwrap())
                 .from_env()
                 .context("Failed to parse RUST_LOG environment variable")
                 .unwrap(),
-        )
+        })
+        .with(TailLayer)
         .init();
     // splash!
+    let (bold, dimmed) = match color {
+        true => (Style::new().bold(), Style::new().dimmed()),
+        false => (Style::new(), Style::new()),
+    };
     println!(
         "\n{} v{} ({})",
-        Style::new().bold().paint("magma"),
+        bold.paint("magma"),
         env!("CARGO_PKG_VERSION"),
         env!("VERGEN_GIT_SHA")
     );
-    println!("{}\n", Color::Black.paint("made with 💜 by kaylen"));
-
-    let config = env::current_dir()
-        .context("failed to locate current directory")
-        .unwrap()
-        .join(args.config);
-    // ensure config exists
-    if !config.exists() {
-        debug!("Failed to locate config file - copying defaults...");
-        write(config.clone(), include_str!("../assets/config.v1.toml"))
-            .await
-            .context("Failed to write default config file")?;
+    println!("{}\n", dimmed.paint("made with 💜 by kaylen"));
+
+    if let Some(Command::Bench(args)) = args.command {
+        return bench::run(args).await;
+    }
+
+    // ensure config exists, unless the routes were given on the command line
+    if let [path] = &paths[..] {
+        if !path.exists() && overrides.routes.is_empty() {
+            debug!("Failed to locate config file - copying defaults...");
+            write(path, include_str!("../assets/config.v1.toml"))
+                .await
+                .context("Failed to write default config file")?;
+        }
     }
     // load config
-    info!("Loading configuration from {:?}...", config);
-    let config = config::from_path(&config).await?;
+    info!("Loading configuration from {:?}...", paths);
+    let config = config::load(&paths, &overrides).await?;
     // check config is latest version
     if !config.is_latest() {
         todo!("config migration");
     }
     let config = config.build().context("failed to build configuration")?;
+    secrets::publish(&config)?;
+    crash::install(config.crash_reports.clone());
+    limits::report_capacity(&config);
 
     let route_count = config
         .proxies
@@ -100,8 +235,67 @@ async fn main() -> Result<()> {
     );
 
     let mut handles = vec![];
-    for config in config.proxies {
-        handles.push(proxy::spawn(config));
+    let edge = match config.tunnel {
+        Some(TunnelConfig::Edge(config)) => Some(Edge::spawn(config)?),
+        Some(TunnelConfig::Hub(config)) => {
+            handles.push(hub::spawn(config));
+            None
+        }
+        None => None,
+    };
+    if let Some(path) = config.security_log {
+        handles.push(security::spawn(path));
+    }
+    for config in config.idle_shutdowns {
+        handles.push(idle::spawn(config));
+    }
+    for config in config.health_checks {
+        handles.push(health::spawn(config));
+    }
+    for config in config.rcon {
+        handles.push(rcon::spawn(config));
+    }
+    for sink in config.events {
+        handles.push(events::spawn(sink));
+    }
+    let mut admin_state = AdminState::default();
+    if let Some(config) = config.memory {
+        handles.push(memory::spawn(config));
+    }
+    if let Some(config) = config.statsd {
+        handles.push(statsd::spawn(config));
+    }
+    if let Some(config) = config.influx {
+        handles.push(influx::spawn(config));
+    }
+    if !config.announcements.is_empty() {
+        handles.push(announce::spawn(config.announcements));
+    }
+    if let Some(config) = config.history {
+        let history = Arc::new(History::open(&config.path)?);
+        handles.push(history::spawn(history.clone(), config));
+        admin_state.history = Some(history);
+    }
+    // the key clients encrypt with is only generated if some route authenticates logins
+    let authenticates = config
+        .proxies
+        .iter()
+        .flat_map(|proxy| &proxy.routes)
+        .any(|route| route.authenticate);
+    let services = Services {
+        authenticator: authenticates
+            .then(|| Authenticator::new(config.authentication))
+            .transpose()?,
+        edge,
+        reputation: config.reputation.map(Reputation::load).transpose()?,
+        tarpit: config.tarpit.map(Tarpit::new),
+    };
+    let reloader = Reloader::start(paths, overrides, config.proxies, services);
+    handles.push(reloader.spawn());
+    handles.push(dump::spawn(reloader.clone()));
+    admin_state.reloader = Some(reloader);
+    if let Some(config) = config.admin {
+        handles.push(admin::spawn(config, admin_state));
     }
 
     match try_join_all(handles).await {
@@ -118,3 +312,24 @@ async fn main() -> Result<()> {
         }
     }
 }
+
+/// Decide whether output is colored - not if disabled with `--no-color` or `NO_COLOR`, or if
+/// output is piped to a file or a log collector.
+fn use_color(no_color: bool) -> bool {
+    let no_color_env = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && !no_color_env && stdout().is_terminal()
+}
+
+/// Build the layer writing log lines to the console.
+fn log_layer(color: bool, time: LogTime, targets: bool) -> Box<dyn Layer<Registry> + Send + Sync> {
+    let layer = fmt::layer().with_ansi(color).with_target(targets);
+    match time {
+        LogTime::Time => layer
+            .with_timer(UtcTime::new(format_description!(
+                "[hour]:[minute]:[second]"
+            )))
+            .boxed(),
+        LogTime::Rfc3339 => layer.with_timer(UtcTime::rfc_3339()).boxed(),
+        LogTime::None => layer.without_time().boxed(),
+    }
+}
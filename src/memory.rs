@@ -0,0 +1,105 @@
+//! Defines the memory watchdog, which sheds load as the process approaches its memory limit.
+//!
+//! The watchdog samples the resident set size of the process, which is read from `/proc` and so
+//! is only available on Linux. Past [SHED_RATIO] of the limit, status connections - which are
+//! cheap to retry - are refused and closed. Past the limit itself, new logins are refused too.
+//! Players already logged in are never disconnected.
+
+use std::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{task::JoinHandle, time::interval};
+use tracing::{info, warn};
+
+use crate::{config::MemoryConfig, registry};
+
+/// How often the resident set size is sampled.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The share of the limit past which status connections are shed.
+const SHED_RATIO: f64 = 0.9;
+
+/// How close the process is to its memory limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pressure {
+    /// Well below the limit.
+    Normal = 0,
+    /// Close to the limit - status connections are shed.
+    High = 1,
+    /// At or over the limit - logins are refused too.
+    Critical = 2,
+}
+
+/// The pressure seen by the most recent sample.
+static PRESSURE: AtomicU8 = AtomicU8::new(Pressure::Normal as u8);
+
+/// The pressure seen by the most recent sample. Always [Pressure::Normal] without a watchdog.
+pub fn pressure() -> Pressure {
+    match PRESSURE.load(Ordering::Relaxed) {
+        0 => Pressure::Normal,
+        1 => Pressure::High,
+        _ => Pressure::Critical,
+    }
+}
+
+/// The resident set size of the process in bytes, if known.
+#[cfg(target_os = "linux")]
+pub fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    // VmRSS:      123456 kB
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// The resident set size of the process in bytes, if known.
+#[cfg(not(target_os = "linux"))]
+pub fn rss() -> Option<u64> {
+    None
+}
+
+/// Spawns the watchdog, and returns a handle to the task.
+pub fn spawn(config: MemoryConfig) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        if rss().is_none() {
+            warn!("Memory usage can't be read on this platform - the memory limit is ignored");
+            return Ok(());
+        }
+        let mut ticks = interval(CHECK_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let Some(rss) = rss() else {
+                continue;
+            };
+            let current = match rss {
+                rss if rss >= config.rss_limit => Pressure::Critical,
+                rss if rss as f64 >= config.rss_limit as f64 * SHED_RATIO => Pressure::High,
+                _ => Pressure::Normal,
+            };
+            let previous = pressure();
+            PRESSURE.store(current as u8, Ordering::Relaxed);
+            if current >= Pressure::High {
+                // status connections opened before the pressure was seen are closed too
+                let shed = registry::kick_status();
+                if shed > 0 {
+                    info!("Closed {} status connection(s) to free memory", shed);
+                }
+            }
+            if current != previous {
+                let mb = rss / 1024 / 1024;
+                match current {
+                    Pressure::Normal => info!("Memory usage is back to {} MB", mb),
+                    Pressure::High => {
+                        warn!("Memory usage is at {} MB - shedding status connections", mb)
+                    }
+                    Pressure::Critical => warn!(
+                        "Memory usage is at {} MB, over the limit - refusing logins",
+                        mb
+                    ),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,343 @@
+//! Defines Prometheus metrics, served in the text exposition format by the admin API at
+//! `/metrics`, and also pushed to statsd or InfluxDB if configured.
+//!
+//! Latencies are recorded as histograms labelled by route, so tail latencies can be seen rather
+//! than just averages. Each route's live connections are sampled as a gauge, and closed
+//! connections are counted by why they closed, so organic leaves can be told apart from problems
+//! on the proxy's side. Connections to unknown domains are not recorded, so clients can't create
+//! labels at will.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::{bridge::CloseReason, limits, registry, statsd, status};
+
+/// The upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 14] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// A latency recorded per route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Timing {
+    /// From accepting a connection to reading its handshake.
+    Handshake,
+    /// Finding the route matching a handshake.
+    RouteLookup,
+    /// Connecting to a backend.
+    BackendConnect,
+    /// From relaying a login to the backend to the backend accepting it.
+    Login,
+}
+
+impl Timing {
+    /// The name of the metric, without a prefix or unit.
+    pub fn stat(self) -> &'static str {
+        match self {
+            Timing::Handshake => "handshake",
+            Timing::RouteLookup => "route_lookup",
+            Timing::BackendConnect => "backend_connect",
+            Timing::Login => "login",
+        }
+    }
+
+    /// The Prometheus name of the metric.
+    fn name(self) -> String {
+        format!("magma_{}_seconds", self.stat())
+    }
+
+    /// The description of the metric.
+    fn help(self) -> &'static str {
+        match self {
+            Timing::Handshake => "Time from accepting a connection to reading its handshake.",
+            Timing::RouteLookup => "Time taken to find the route matching a handshake.",
+            Timing::BackendConnect => "Time taken to connect to a backend.",
+            Timing::Login => "Time from relaying a login to the backend accepting it.",
+        }
+    }
+}
+
+/// A histogram of durations.
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each bucket - not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    /// Record a duration.
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// The histograms of every timing and route.
+fn histograms() -> &'static Mutex<BTreeMap<(Timing, String), Histogram>> {
+    static HISTOGRAMS: OnceLock<Mutex<BTreeMap<(Timing, String), Histogram>>> = OnceLock::new();
+    HISTOGRAMS.get_or_init(Default::default)
+}
+
+/// A sample of a histogram, when metrics are exported.
+pub struct HistogramSample {
+    /// The latency recorded.
+    pub timing: Timing,
+    /// The route it was recorded for.
+    pub route: String,
+    /// The number of observations at or below each bucket's upper bound, in seconds.
+    pub buckets: Vec<(f64, u64)>,
+    /// The number of observations.
+    pub count: u64,
+    /// The sum of every observation, in seconds.
+    pub sum: f64,
+}
+
+/// Sample every histogram.
+pub fn samples() -> Vec<HistogramSample> {
+    histograms()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((timing, route), histogram)| HistogramSample {
+            timing: *timing,
+            route: route.clone(),
+            buckets: BUCKETS
+                .iter()
+                .zip(histogram.buckets)
+                .scan(0, |cumulative, (bound, count)| {
+                    *cumulative += count;
+                    Some((*bound, *cumulative))
+                })
+                .collect(),
+            count: histogram.count,
+            sum: histogram.sum,
+        })
+        .collect()
+}
+
+/// A gauge, sampled when metrics are exported.
+pub struct Gauge {
+    /// The name of the metric, without a prefix or unit.
+    pub stat: &'static str,
+    /// The unit of the metric, if it has one.
+    pub unit: Option<&'static str>,
+    /// The description of the metric.
+    pub help: &'static str,
+    /// The labels of the sample.
+    pub labels: Vec<(&'static str, String)>,
+    /// The value of the sample.
+    pub value: f64,
+}
+
+impl Gauge {
+    /// The name of the metric with its unit, without a prefix.
+    pub fn metric(&self) -> String {
+        match self.unit {
+            Some(unit) => format!("{}_{}", self.stat, unit),
+            None => self.stat.to_string(),
+        }
+    }
+}
+
+/// Sample every gauge.
+pub fn gauges() -> Vec<Gauge> {
+    let status_cache = status::snapshot().into_iter().map(|entry| Gauge {
+        stat: "status_cache_age",
+        unit: Some("seconds"),
+        help: "Age of each cached status response.",
+        labels: vec![
+            ("route", entry.domain),
+            ("protocol_version", entry.protocol_version.to_string()),
+        ],
+        value: entry.age_ms as f64 / 1000.0,
+    });
+    let connections = registry::routes().into_iter().map(|route| Gauge {
+        stat: "active_connections",
+        unit: None,
+        help: "Live connections to each route.",
+        labels: vec![("route", route.domain)],
+        value: route.connections as f64,
+    });
+    let shedding = Gauge {
+        stat: "shedding",
+        unit: None,
+        help: "Whether status pings are shed, as resources ran out recently.",
+        labels: vec![],
+        value: if limits::is_shedding() { 1.0 } else { 0.0 },
+    };
+    status_cache.chain(connections).chain([shedding]).collect()
+}
+
+/// The number of closed connections of every route and close reason.
+fn closes() -> &'static Mutex<BTreeMap<(String, CloseReason), u64>> {
+    static CLOSES: OnceLock<Mutex<BTreeMap<(String, CloseReason), u64>>> = OnceLock::new();
+    CLOSES.get_or_init(Default::default)
+}
+
+/// A count of connections to a route which closed for the same reason.
+pub struct CloseCount {
+    /// The route the connections were to.
+    pub route: String,
+    /// Why they closed.
+    pub reason: CloseReason,
+    /// The number of connections, since startup.
+    pub count: u64,
+}
+
+/// Sample the count of closed connections.
+pub fn close_counts() -> Vec<CloseCount> {
+    closes()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((route, reason), count)| CloseCount {
+            route: route.clone(),
+            reason: *reason,
+            count: *count,
+        })
+        .collect()
+}
+
+/// Count a closed connection to a route.
+pub fn close(route: &str, reason: CloseReason) {
+    statsd::close(route, reason);
+    *closes()
+        .lock()
+        .unwrap()
+        .entry((route.to_string(), reason))
+        .or_default() += 1;
+}
+
+/// Record a latency for a route.
+pub fn observe(timing: Timing, route: &str, duration: Duration) {
+    statsd::timing(timing, route, duration);
+    histograms()
+        .lock()
+        .unwrap()
+        .entry((timing, route.to_string()))
+        .or_default()
+        .observe(duration);
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    // writing to a string can't fail
+    let mut out = String::new();
+    let mut last = None;
+    for histogram in samples() {
+        let timing = histogram.timing;
+        if last != Some(timing) {
+            let _ = writeln!(out, "# HELP {} {}", timing.name(), timing.help());
+            let _ = writeln!(out, "# TYPE {} histogram", timing.name());
+            last = Some(timing);
+        }
+        let route = escape(&histogram.route);
+        for (bound, cumulative) in histogram.buckets {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                timing.name(),
+                route,
+                bound,
+                cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+            timing.name(),
+            route,
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{route=\"{}\"}} {}",
+            timing.name(),
+            route,
+            histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{route=\"{}\"}} {}",
+            timing.name(),
+            route,
+            histogram.count
+        );
+    }
+
+    let mut last = None;
+    for gauge in gauges() {
+        let metric = gauge.metric();
+        if last != Some(gauge.stat) {
+            let _ = writeln!(out, "# HELP magma_{} {}", metric, gauge.help);
+            let _ = writeln!(out, "# TYPE magma_{} gauge", metric);
+            last = Some(gauge.stat);
+        }
+        let labels: Vec<_> = gauge
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        let _ = writeln!(
+            out,
+            "magma_{}{{{}}} {}",
+            metric,
+            labels.join(","),
+            gauge.value
+        );
+    }
+
+    let closes = close_counts();
+    if !closes.is_empty() {
+        let _ = writeln!(
+            out,
+            "# HELP magma_connections_closed_total Closed connections to each route, by why they closed."
+        );
+        let _ = writeln!(out, "# TYPE magma_connections_closed_total counter");
+    }
+    for close in closes {
+        let _ = writeln!(
+            out,
+            "magma_connections_closed_total{{route=\"{}\",reason=\"{}\"}} {}",
+            escape(&close.route),
+            close.reason.label(),
+            close.count
+        );
+    }
+
+    let exhaustions = limits::exhaustion_counts();
+    if !exhaustions.is_empty() {
+        let _ = writeln!(
+            out,
+            "# HELP magma_resource_exhaustion_total Times accepting or connecting failed for want of file descriptors or socket buffers."
+        );
+        let _ = writeln!(out, "# TYPE magma_resource_exhaustion_total counter");
+    }
+    for (operation, count) in exhaustions {
+        let _ = writeln!(
+            out,
+            "magma_resource_exhaustion_total{{operation=\"{}\"}} {}",
+            operation.label(),
+            count
+        );
+    }
+    out
+}
@@ -0,0 +1,501 @@
+//! A mock Minecraft server, for integration tests.
+//!
+//! The server speaks just enough of the vanilla protocol for a client to ping it and log in
+//! offline - it answers status pings with a configurable MOTD, optionally enables compression
+//! during login, completes configuration on 1.20.2+, and then sends keep-alives until the client
+//! disconnects. It records what it sees, so tests can assert on what Magma forwarded.
+//!
+//! In online mode, the server also encrypts logins, and runs a session server of its own which
+//! clients join through - so encrypted logins can be tested without Mojang's. The session server
+//! can also be run on its own, for Magma to authenticate players with.
+//!
+//! This module is only available with the `mock` feature.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use bytes::Bytes;
+use rand::RngCore;
+use rsa::{pkcs8::EncodePublicKey, Pkcs1v15Encrypt, RsaPrivateKey};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::interval,
+};
+use tracing::debug;
+
+use crate::{
+    bridge::ProtocolState,
+    cryptor::{server_hash, CipherStream},
+    io::{ProcotolAsyncWriteExt, ProtocolAsyncReadExt, ProtocolWriteExt, UncompressedPacket},
+    protocol::{
+        packets::{
+            EncryptionRequest, EncryptionResponse, Handshake, LoginStart, LoginSuccess,
+            PacketCodec, PingRequest, PongResponse, SetCompression, StatusRequest, StatusResponse,
+        },
+        version::{LogicalPacket, ProtocolVersion, VersionedPacket},
+    },
+};
+
+/// The configuration of a mock server.
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    /// The MOTD shown to status pings.
+    pub motd: String,
+    /// The compression threshold to enable during login, if any.
+    pub compression_threshold: Option<i32>,
+    /// How often keep-alives are sent during play.
+    pub keep_alive_interval: Duration,
+    /// Whether logins are encrypted, and checked against the server's own session server.
+    pub online_mode: bool,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            motd: "A Mock Server".to_string(),
+            compression_threshold: None,
+            keep_alive_interval: Duration::from_secs(1),
+            online_mode: false,
+        }
+    }
+}
+
+/// What a mock server has seen.
+#[derive(Debug, Default)]
+struct Seen {
+    handshakes: Vec<Handshake>,
+    usernames: Vec<String>,
+    keep_alives: usize,
+}
+
+/// The key and session server of a server in online mode.
+struct Online {
+    key: RsaPrivateKey,
+    session_server: MockSessionServer,
+}
+
+impl Online {
+    /// Generate a key, and start a session server recording joins.
+    fn start() -> Result<Self> {
+        Ok(Self {
+            key: RsaPrivateKey::new(&mut rand::thread_rng(), 1024)?,
+            session_server: MockSessionServer::start()?,
+        })
+    }
+}
+
+/// The server hashes clients have joined with, and the profile each joined as.
+type Joins = Arc<Mutex<Vec<(String, String)>>>;
+
+/// A running mock session server, which accepts every join. The server stops when this is
+/// dropped.
+pub struct MockSessionServer {
+    addr: SocketAddr,
+    joins: Joins,
+    handle: JoinHandle<()>,
+}
+
+impl MockSessionServer {
+    /// Start a session server on an ephemeral loopback port.
+    pub fn start() -> Result<Self> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let joins = Joins::default();
+        let app = Router::new()
+            .route("/session/minecraft/join", post(join))
+            .route("/session/minecraft/hasJoined", get(has_joined))
+            .with_state(joins.clone());
+        let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+        let handle = tokio::task::spawn(async move {
+            if let Err(err) = server.await {
+                debug!("Mock session server stopped: {:#}", err);
+            }
+        });
+        Ok(Self {
+            addr,
+            joins,
+            handle,
+        })
+    }
+
+    /// The base URL of the session server.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The server hashes clients have joined with, oldest first.
+    pub fn joins(&self) -> Vec<String> {
+        let joins = self.joins.lock().unwrap();
+        joins.iter().map(|(hash, _)| hash.clone()).collect()
+    }
+}
+
+impl Drop for MockSessionServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Record a join to the session server.
+async fn join(State(joins): State<Joins>, Json(body): Json<Value>) -> StatusCode {
+    match (body["serverId"].as_str(), body["selectedProfile"].as_str()) {
+        (Some(hash), Some(profile)) => {
+            let join = (hash.to_string(), profile.to_string());
+            joins.lock().unwrap().push(join);
+            StatusCode::NO_CONTENT
+        }
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Answer whether a player has joined with the given hash, with their profile if they have.
+async fn has_joined(
+    State(joins): State<Joins>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let (Some(username), Some(hash)) = (query.get("username"), query.get("serverId")) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let joins = joins.lock().unwrap();
+    match joins.iter().find(|(joined, _)| joined == hash) {
+        Some((_, profile)) => Json(json!({
+            "id": profile,
+            "name": username,
+            "properties": [],
+        }))
+        .into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// A running mock server. The server stops when this is dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    seen: Arc<Mutex<Seen>>,
+    online: Option<Arc<Online>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Start a mock server on an ephemeral loopback port.
+    pub async fn start(config: MockConfig) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let online = match config.online_mode {
+            true => Some(Arc::new(Online::start()?)),
+            false => None,
+        };
+        let handle = {
+            let (seen, online) = (seen.clone(), online.clone());
+            let config = Arc::new(config);
+            tokio::task::spawn(async move {
+                while let Ok((stream, peer)) = listener.accept().await {
+                    let (seen, online, config) = (seen.clone(), online.clone(), config.clone());
+                    tokio::task::spawn(async move {
+                        let online = online.as_deref();
+                        if let Err(err) = handle(&config, &seen, online, stream).await {
+                            debug!("Mock session from {} ended: {:#}", peer, err);
+                        }
+                    });
+                }
+            })
+        };
+        Ok(Self {
+            addr,
+            seen,
+            online,
+            handle,
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The handshakes the server has received, oldest first.
+    pub fn handshakes(&self) -> Vec<Handshake> {
+        self.seen.lock().unwrap().handshakes.clone()
+    }
+
+    /// The usernames which have logged in, oldest first.
+    pub fn usernames(&self) -> Vec<String> {
+        self.seen.lock().unwrap().usernames.clone()
+    }
+
+    /// The number of keep-alives clients have answered.
+    pub fn keep_alives(&self) -> usize {
+        self.seen.lock().unwrap().keep_alives
+    }
+
+    /// The base URL of the session server clients should join through, in online mode.
+    pub fn session_server(&self) -> Option<String> {
+        self.online
+            .as_ref()
+            .map(|online| online.session_server.url())
+    }
+
+    /// The server hashes clients have joined with, oldest first.
+    pub fn joins(&self) -> Vec<String> {
+        self.online
+            .as_ref()
+            .map_or_else(Vec::new, |online| online.session_server.joins())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+        if let Some(online) = &self.online {
+            online.session_server.handle.abort();
+        }
+    }
+}
+
+/// A connection to a client, tracking compression.
+struct Connection {
+    stream: CipherStream<TcpStream>,
+    compression_threshold: Option<i32>,
+}
+
+impl Connection {
+    async fn send(&mut self, packet: &UncompressedPacket) -> Result<()> {
+        self.stream
+            .write_framed_packet(packet, self.compression_threshold)
+            .await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<UncompressedPacket> {
+        self.stream
+            .read_framed_packet(self.compression_threshold)
+            .await
+    }
+
+    /// Receive the next packet, failing unless it is the given logical packet.
+    async fn expect(&mut self, version: ProtocolVersion, expected: LogicalPacket) -> Result<()> {
+        let packet = self.recv().await?;
+        if version.packet_id(expected) != Some(packet.id) {
+            bail!("Expected {:?}, got {:#04x}", expected, packet.id);
+        }
+        Ok(())
+    }
+}
+
+/// Handle a client connection.
+async fn handle(
+    config: &MockConfig,
+    seen: &Mutex<Seen>,
+    online: Option<&Online>,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut connection = Connection {
+        stream: CipherStream::new(stream),
+        compression_threshold: None,
+    };
+    let handshake = Handshake::decode(&connection.recv().await?)?;
+    seen.lock().unwrap().handshakes.push(handshake.clone());
+    let version = ProtocolVersion(handshake.protocol_version);
+    match handshake.next_state {
+        ProtocolState::Status => status(config, &mut connection, version).await,
+        ProtocolState::Login => login(config, seen, online, connection, version).await,
+        state => bail!("Unexpected next state {:?}", state),
+    }
+}
+
+/// Answer a status ping.
+async fn status(
+    config: &MockConfig,
+    connection: &mut Connection,
+    version: ProtocolVersion,
+) -> Result<()> {
+    StatusRequest::decode(&connection.recv().await?)?;
+    let json = json!({
+        "version": { "name": "Mock", "protocol": version.0 },
+        "players": { "online": 0, "max": 20 },
+        "description": { "text": config.motd },
+    });
+    connection
+        .send(
+            &StatusResponse {
+                json: json.to_string(),
+            }
+            .encode()?,
+        )
+        .await?;
+    let ping = PingRequest::decode(&connection.recv().await?)?;
+    connection
+        .send(
+            &PongResponse {
+                payload: ping.payload,
+            }
+            .encode()?,
+        )
+        .await
+}
+
+/// Log a client in, then keep it alive until it disconnects.
+async fn login(
+    config: &MockConfig,
+    seen: &Mutex<Seen>,
+    online: Option<&Online>,
+    mut connection: Connection,
+    version: ProtocolVersion,
+) -> Result<()> {
+    let login_start = LoginStart::decode_versioned(&connection.recv().await?, version)?;
+    seen.lock()
+        .unwrap()
+        .usernames
+        .push(login_start.username.clone());
+
+    if let Some(online) = online {
+        encrypt(online, &mut connection, version).await?;
+    }
+    if let Some(threshold) = config.compression_threshold {
+        connection
+            .send(&SetCompression { threshold }.encode()?)
+            .await?;
+        connection.compression_threshold = Some(threshold);
+    }
+    let mut success = LoginSuccess {
+        uuid: login_start.uuid.unwrap_or_default(),
+        username: login_start.username,
+        properties: vec![],
+    }
+    .encode()?;
+    // 1.20.5 - 1.21.1 end the packet with a strict error handling flag
+    if version >= ProtocolVersion::V1_20_5 {
+        let mut data = success.data.to_vec();
+        data.write_bool(true)?;
+        success.data = data.into();
+    }
+    connection.send(&success).await?;
+
+    // 1.20.2+ clients acknowledge the login, and are configured before play
+    if let Some(finish) = version.packet_id(LogicalPacket::FinishConfiguration) {
+        connection
+            .expect(version, LogicalPacket::LoginAcknowledged)
+            .await?;
+        connection
+            .send(&UncompressedPacket {
+                id: finish,
+                data: Bytes::new(),
+            })
+            .await?;
+        connection
+            .expect(version, LogicalPacket::AcknowledgeFinishConfiguration)
+            .await?;
+    }
+
+    // read and write independently, so a keep-alive never interrupts a partially read packet
+    let threshold = connection.compression_threshold;
+    let (reader, writer) = tokio::io::split(connection.stream);
+    tokio::try_join!(
+        send_keep_alives(config, writer, threshold, version),
+        count_keep_alives(seen, reader, threshold, version),
+    )?;
+    Ok(())
+}
+
+/// Encrypt a login, checking the client joined through the session server.
+async fn encrypt(
+    online: &Online,
+    connection: &mut Connection,
+    version: ProtocolVersion,
+) -> Result<()> {
+    let public_key = online
+        .key
+        .to_public_key()
+        .to_public_key_der()?
+        .as_bytes()
+        .to_vec();
+    let mut verify_token = vec![0u8; 4];
+    rand::thread_rng().fill_bytes(&mut verify_token);
+    let mut request = EncryptionRequest {
+        server_id: String::new(),
+        public_key: public_key.clone(),
+        verify_token: verify_token.clone(),
+    }
+    .encode()?;
+    // 1.20.5+ end the packet with whether the client should authenticate
+    if version >= ProtocolVersion::V1_20_5 {
+        let mut data = request.data.to_vec();
+        data.write_bool(true)?;
+        request.data = data.into();
+    }
+    connection.send(&request).await?;
+
+    let response = EncryptionResponse::decode(&connection.recv().await?)?;
+    let secret = online
+        .key
+        .decrypt(Pkcs1v15Encrypt, &response.shared_secret)
+        .context("failed to decrypt shared secret")?;
+    let token = online
+        .key
+        .decrypt(Pkcs1v15Encrypt, &response.verify_token)
+        .context("failed to decrypt verify token")?;
+    if token != verify_token {
+        bail!("Client sent the wrong verify token");
+    }
+    let hash = server_hash("", &secret, &public_key);
+    if !online.session_server.joins().contains(&hash) {
+        bail!("Client did not join with {}", hash);
+    }
+    connection.stream.enable(&secret);
+    Ok(())
+}
+
+/// Send keep-alives on an interval.
+async fn send_keep_alives(
+    config: &MockConfig,
+    mut writer: WriteHalf<CipherStream<TcpStream>>,
+    threshold: Option<i32>,
+    version: ProtocolVersion,
+) -> Result<()> {
+    let id = version
+        .packet_id(LogicalPacket::KeepAliveClientbound)
+        .context("keep-alives are unknown in this version")?;
+    let mut ticks = interval(config.keep_alive_interval);
+    for payload in 1i64.. {
+        ticks.tick().await;
+        let packet = UncompressedPacket {
+            id,
+            data: Bytes::copy_from_slice(&payload.to_be_bytes()),
+        };
+        writer.write_framed_packet(&packet, threshold).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Count the keep-alives answered by the client, until it disconnects.
+async fn count_keep_alives(
+    seen: &Mutex<Seen>,
+    mut reader: ReadHalf<CipherStream<TcpStream>>,
+    threshold: Option<i32>,
+    version: ProtocolVersion,
+) -> Result<()> {
+    loop {
+        let packet = reader.read_framed_packet(threshold).await?;
+        if version.packet_id(LogicalPacket::KeepAliveServerbound) == Some(packet.id) {
+            seen.lock().unwrap().keep_alives += 1;
+        }
+    }
+}
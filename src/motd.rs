@@ -0,0 +1,44 @@
+//! Defines rotating MOTDs, which keep a route's entry in the server list fresh without a backend
+//! plugin.
+//!
+//! A route with MOTDs replaces the description of its status responses - whether relayed from the
+//! backend or answered from the status cache - with one of them. MOTDs are shown in turn with
+//! each ping, at random, or each for a slice of time.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::Rng;
+use serde_json::Value;
+
+use crate::config::{MotdRotation, Motds};
+
+/// The number of pings answered by each route with sequential MOTDs.
+fn pings() -> &'static Mutex<HashMap<String, usize>> {
+    static PINGS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+    PINGS.get_or_init(Default::default)
+}
+
+/// Choose the MOTD shown by the next status response of a route.
+pub fn pick<'a>(domain: &str, motds: &'a Motds) -> &'a Value {
+    let index = match motds.rotation {
+        MotdRotation::Sequential => {
+            let mut pings = pings().lock().unwrap();
+            let count = pings.entry(domain.to_string()).or_default();
+            *count = count.wrapping_add(1);
+            *count - 1
+        }
+        MotdRotation::Random => rand::thread_rng().gen_range(0..motds.motds.len()),
+        MotdRotation::Timed(slice) => {
+            let elapsed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            // every instance shows the same MOTD at the same time
+            (elapsed.as_secs() / slice.as_secs().max(1)) as usize
+        }
+    };
+    &motds.motds[index % motds.motds.len()]
+}
@@ -0,0 +1,122 @@
+//! Defines an integration with the Pterodactyl and Pelican panel client API.
+//!
+//! A panel server can be used as a hook to start and stop it for wake-on-connect and idle
+//! shutdowns, and its default allocation can stand in for an entry's hardcoded targets. Allocations
+//! are resolved when the configuration is loaded.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+
+/// How long a panel request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The media type of panel API responses.
+const ACCEPT: &str = "application/vnd.pterodactyl.v1+json";
+
+/// A server on a panel.
+#[derive(Debug, Clone)]
+pub struct Panel {
+    /// The base URL of the panel, such as `https://panel.example.com`.
+    pub url: String,
+    /// A client API key with access to the server.
+    pub key: String,
+    /// The short identifier of the server.
+    pub server: String,
+}
+
+#[derive(Deserialize)]
+struct ServerResponse {
+    attributes: ServerAttributes,
+}
+
+#[derive(Deserialize)]
+struct ServerAttributes {
+    relationships: Relationships,
+}
+
+#[derive(Deserialize)]
+struct Relationships {
+    allocations: Allocations,
+}
+
+#[derive(Deserialize)]
+struct Allocations {
+    data: Vec<AllocationObject>,
+}
+
+#[derive(Deserialize)]
+struct AllocationObject {
+    attributes: Allocation,
+}
+
+#[derive(Deserialize)]
+struct Allocation {
+    ip: IpAddr,
+    port: u16,
+    is_default: bool,
+}
+
+impl Panel {
+    /// Build a request to a client API endpoint of the server.
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        reqwest::Client::new()
+            .request(
+                method,
+                format!(
+                    "{}/api/client/servers/{}{}",
+                    self.url.trim_end_matches('/'),
+                    self.server,
+                    path
+                ),
+            )
+            .bearer_auth(&self.key)
+            .header("Accept", ACCEPT)
+            .timeout(REQUEST_TIMEOUT)
+    }
+
+    /// Send a power signal to the server - `start`, `stop`, `restart` or `kill`.
+    pub async fn power(&self, signal: &str) -> Result<()> {
+        self.request(reqwest::Method::POST, "/power")
+            .json(&json!({ "signal": signal }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Resolve the default allocation of the server. Allocations bound to the unspecified
+    /// address are reached through `host`, which must then be given.
+    pub async fn allocation(&self, host: Option<IpAddr>) -> Result<SocketAddr> {
+        let response: ServerResponse = self
+            .request(reqwest::Method::GET, "")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let allocation = response
+            .attributes
+            .relationships
+            .allocations
+            .data
+            .into_iter()
+            .map(|object| object.attributes)
+            .find(|allocation| allocation.is_default)
+            .with_context(|| format!("Server {} has no default allocation", self.server))?;
+        let ip = match (host, allocation.ip.is_unspecified()) {
+            (Some(host), _) => host,
+            (None, false) => allocation.ip,
+            (None, true) => bail!(
+                "Server {} is bound to {}, so the panel host must be given",
+                self.server,
+                allocation.ip
+            ),
+        };
+        Ok(SocketAddr::new(ip, allocation.port))
+    }
+}
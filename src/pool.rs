@@ -0,0 +1,105 @@
+//! Defines pools of pre-connected backend sockets for status connections.
+//!
+//! Server list refreshes open many short-lived status connections at once, each of which would
+//! otherwise wait on a TCP handshake with the backend. Status connections take a socket which
+//! was connected ahead of time instead, and the pool is topped back up in the background.
+//! Servers close connections which never send a handshake, so sockets are only kept for
+//! [IDLE_TIMEOUT].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// How long a pre-connected socket is kept before it is discarded.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A socket connected ahead of time.
+struct Warm {
+    stream: TcpStream,
+    connected_at: Instant,
+}
+
+impl Warm {
+    /// Test whether the socket can still be used - it is young, and the backend hasn't closed it.
+    fn is_usable(&self) -> bool {
+        if self.connected_at.elapsed() >= IDLE_TIMEOUT {
+            return false;
+        }
+        // backends send nothing before the handshake, so a read only succeeds once closed
+        match self.stream.try_read(&mut [0u8; 1]) {
+            Ok(_) => false,
+            Err(err) => err.kind() == ErrorKind::WouldBlock,
+        }
+    }
+}
+
+/// The pre-connected sockets to a backend.
+#[derive(Default)]
+struct Pool {
+    warm: VecDeque<Warm>,
+    /// Whether a task is topping the pool up.
+    filling: bool,
+}
+
+/// The pools of every backend.
+fn pools() -> &'static Mutex<HashMap<SocketAddr, Pool>> {
+    static POOLS: OnceLock<Mutex<HashMap<SocketAddr, Pool>>> = OnceLock::new();
+    POOLS.get_or_init(Default::default)
+}
+
+/// Take a pre-connected socket to the target, or connect one if none are ready, then top the
+/// pool back up to the given size in the background.
+pub async fn connect(target: SocketAddr, size: usize) -> std::io::Result<TcpStream> {
+    let warm = {
+        let mut pools = pools().lock().unwrap();
+        let pool = pools.entry(target).or_default();
+        std::iter::from_fn(|| pool.warm.pop_front()).find(Warm::is_usable)
+    };
+    fill(target, size);
+    match warm {
+        Some(warm) => Ok(warm.stream),
+        None => TcpStream::connect(target).await,
+    }
+}
+
+/// Top the pool of the target up to the given size in the background, unless it is already
+/// being topped up.
+fn fill(target: SocketAddr, size: usize) {
+    let missing = {
+        let mut pools = pools().lock().unwrap();
+        let pool = pools.entry(target).or_default();
+        // drop sockets which have aged out while waiting
+        pool.warm.retain(Warm::is_usable);
+        let missing = size.saturating_sub(pool.warm.len());
+        if pool.filling || missing == 0 {
+            return;
+        }
+        pool.filling = true;
+        missing
+    };
+    tokio::task::spawn(async move {
+        for _ in 0..missing {
+            match TcpStream::connect(target).await {
+                Ok(stream) => {
+                    let mut pools = pools().lock().unwrap();
+                    pools.entry(target).or_default().warm.push_back(Warm {
+                        stream,
+                        connected_at: Instant::now(),
+                    });
+                }
+                Err(err) => {
+                    debug!("Failed to pre-connect to {}: {}", target, err);
+                    break;
+                }
+            }
+        }
+        pools().lock().unwrap().entry(target).or_default().filling = false;
+    });
+}
@@ -0,0 +1,142 @@
+//! Defines the encoding of chat components, which changes between protocol versions.
+//!
+//! Chat components are sent as JSON strings during login, and in every state before 1.20.3.
+//! From 1.20.3, components sent in the configuration and play states are encoded as network NBT
+//! instead - an unnamed root tag, converted here from the component's JSON.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use super::version::ProtocolVersion;
+use crate::{bridge::ProtocolState, io::ProtocolWriteExt};
+
+const TAG_END: u8 = 0;
+const TAG_BYTE: u8 = 1;
+const TAG_INT: u8 = 3;
+const TAG_LONG: u8 = 4;
+const TAG_DOUBLE: u8 = 6;
+const TAG_STRING: u8 = 8;
+const TAG_LIST: u8 = 9;
+const TAG_COMPOUND: u8 = 10;
+
+/// Write a chat component as the given protocol version expects it in the given state.
+pub fn write_component<W: Write>(
+    buf: &mut W,
+    component: &Value,
+    version: ProtocolVersion,
+    state: ProtocolState,
+) -> Result<()> {
+    match state {
+        ProtocolState::Configuration | ProtocolState::Play
+            if version >= ProtocolVersion::V1_20_3 =>
+        {
+            let component = normalize(component);
+            buf.write_u8(tag(&component))?;
+            write_payload(buf, &component)
+        }
+        _ => buf.write_string(component.to_string()),
+    }
+}
+
+/// Replace values NBT can't represent - nulls are dropped from objects, and lists mixing types
+/// become lists of components.
+fn normalize(value: &Value) -> Value {
+    match value {
+        Value::Null => Value::String(String::new()),
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key.clone(), normalize(value)))
+                .collect(),
+        ),
+        Value::Array(array) => {
+            let array: Vec<_> = array.iter().map(normalize).collect();
+            match array.windows(2).all(|pair| tag(&pair[0]) == tag(&pair[1])) {
+                true => Value::Array(array),
+                false => Value::Array(
+                    array
+                        .into_iter()
+                        .map(|value| match value {
+                            Value::Object(_) => value,
+                            Value::String(text) => serde_json::json!({ "text": text }),
+                            value => serde_json::json!({ "text": value.to_string() }),
+                        })
+                        .collect(),
+                ),
+            }
+        }
+        value => value.clone(),
+    }
+}
+
+/// The NBT tag a normalized value is encoded as.
+fn tag(value: &Value) -> u8 {
+    match value {
+        Value::Null | Value::String(_) => TAG_STRING,
+        Value::Bool(_) => TAG_BYTE,
+        Value::Number(number) => match number.as_i64() {
+            Some(n) if i32::try_from(n).is_ok() => TAG_INT,
+            Some(_) => TAG_LONG,
+            None => TAG_DOUBLE,
+        },
+        Value::Array(_) => TAG_LIST,
+        Value::Object(_) => TAG_COMPOUND,
+    }
+}
+
+/// Write the payload of a normalized value's tag.
+fn write_payload<W: Write>(buf: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::Null => write_nbt_string(buf, ""),
+        Value::String(text) => write_nbt_string(buf, text),
+        Value::Bool(flag) => buf.write_u8(*flag as u8),
+        Value::Number(number) => match (tag(value), number.as_i64()) {
+            (TAG_INT, Some(n)) => Ok(buf.write_all(&(n as i32).to_be_bytes())?),
+            (TAG_LONG, Some(n)) => Ok(buf.write_all(&n.to_be_bytes())?),
+            _ => Ok(buf.write_all(&number.as_f64().unwrap_or_default().to_be_bytes())?),
+        },
+        Value::Array(array) => {
+            buf.write_u8(array.first().map_or(TAG_END, tag))?;
+            buf.write_all(&(array.len() as i32).to_be_bytes())?;
+            for value in array {
+                write_payload(buf, value)?;
+            }
+            Ok(())
+        }
+        Value::Object(object) => {
+            for (key, value) in object {
+                buf.write_u8(tag(value))?;
+                write_nbt_string(buf, key)?;
+                write_payload(buf, value)?;
+            }
+            buf.write_u8(TAG_END)
+        }
+    }
+}
+
+/// Write a string in the modified UTF-8 NBT uses - nulls take two bytes, and characters outside
+/// the basic multilingual plane are written as surrogate pairs.
+fn write_nbt_string<W: Write>(buf: &mut W, text: &str) -> Result<()> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for unit in text.encode_utf16() {
+        match unit {
+            0x0001..=0x007F => bytes.push(unit as u8),
+            0x0000 | 0x0080..=0x07FF => {
+                bytes.push(0xC0 | (unit >> 6) as u8);
+                bytes.push(0x80 | (unit & 0x3F) as u8);
+            }
+            _ => {
+                bytes.push(0xE0 | (unit >> 12) as u8);
+                bytes.push(0x80 | ((unit >> 6) & 0x3F) as u8);
+                bytes.push(0x80 | (unit & 0x3F) as u8);
+            }
+        }
+    }
+    let len = u16::try_from(bytes.len()).context("string is too long for NBT")?;
+    buf.write_u16(len)?;
+    buf.write_all(&bytes)?;
+    Ok(())
+}
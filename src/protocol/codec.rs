@@ -0,0 +1,300 @@
+//! Defines field codecs, and the [packet] macro for declaring packets in terms of them.
+//!
+//! Rather than hand-writing cursor code for each packet, packets are declared as a table of fields
+//! and the codec used to encode each one:
+//!
+//! ```ignore
+//! packet! {
+//!     /// Sent by the server to enable compression.
+//!     pub struct SetCompression = 0x03 {
+//!         /// The compression threshold.
+//!         threshold: i32 as codec::VarInt,
+//!     }
+//! }
+//! ```
+//!
+//! Omitting the packet id declares a compound type, which can itself be used as a codec for
+//! fields of other packets.
+
+use std::{
+    io::{Read, Write},
+    marker::PhantomData,
+};
+
+use anyhow::Result;
+
+use crate::{
+    bridge::ProtocolState,
+    io::{ProtocolReadExt, ProtocolWriteExt},
+};
+
+/// Encodes and decodes a single field type.
+pub trait Codec {
+    /// The type of value this codec reads and writes.
+    type Value;
+
+    /// Read a value from the given buffer.
+    fn read<R: Read>(buf: &mut R) -> Result<Self::Value>;
+
+    /// Write a value to the given buffer.
+    fn write<W: Write>(value: &Self::Value, buf: &mut W) -> Result<()>;
+}
+
+/// A variable-length [i32].
+pub struct VarInt;
+
+impl Codec for VarInt {
+    type Value = i32;
+
+    fn read<R: Read>(buf: &mut R) -> Result<i32> {
+        buf.read_var_int()
+    }
+
+    fn write<W: Write>(value: &i32, buf: &mut W) -> Result<()> {
+        buf.write_var_int(*value)
+    }
+}
+
+/// A var int prefixed UTF-8 string.
+pub struct Str;
+
+impl Codec for Str {
+    type Value = String;
+
+    fn read<R: Read>(buf: &mut R) -> Result<String> {
+        buf.read_string()
+    }
+
+    fn write<W: Write>(value: &String, buf: &mut W) -> Result<()> {
+        buf.write_string(value.clone())
+    }
+}
+
+/// A big-endian [u16].
+pub struct UnsignedShort;
+
+impl Codec for UnsignedShort {
+    type Value = u16;
+
+    fn read<R: Read>(buf: &mut R) -> Result<u16> {
+        buf.read_u16()
+    }
+
+    fn write<W: Write>(value: &u16, buf: &mut W) -> Result<()> {
+        buf.write_u16(*value)
+    }
+}
+
+/// A big-endian [i64].
+pub struct Long;
+
+impl Codec for Long {
+    type Value = i64;
+
+    fn read<R: Read>(buf: &mut R) -> Result<i64> {
+        buf.read_i64()
+    }
+
+    fn write<W: Write>(value: &i64, buf: &mut W) -> Result<()> {
+        buf.write_i64(*value)
+    }
+}
+
+/// A single-byte [bool].
+pub struct Boolean;
+
+impl Codec for Boolean {
+    type Value = bool;
+
+    fn read<R: Read>(buf: &mut R) -> Result<bool> {
+        buf.read_bool()
+    }
+
+    fn write<W: Write>(value: &bool, buf: &mut W) -> Result<()> {
+        buf.write_bool(*value)
+    }
+}
+
+/// A 128-bit UUID.
+pub struct Uuid;
+
+impl Codec for Uuid {
+    type Value = uuid::Uuid;
+
+    fn read<R: Read>(buf: &mut R) -> Result<uuid::Uuid> {
+        buf.read_uuid()
+    }
+
+    fn write<W: Write>(value: &uuid::Uuid, buf: &mut W) -> Result<()> {
+        buf.write_uuid(value)
+    }
+}
+
+/// A var int prefixed array of bytes.
+pub struct ByteArray;
+
+impl Codec for ByteArray {
+    type Value = Vec<u8>;
+
+    fn read<R: Read>(buf: &mut R) -> Result<Vec<u8>> {
+        buf.read_byte_array()
+    }
+
+    fn write<W: Write>(value: &Vec<u8>, buf: &mut W) -> Result<()> {
+        buf.write_byte_array(value)
+    }
+}
+
+/// The remaining bytes of a packet, with no length prefix. Must be the last field.
+pub struct Remaining;
+
+impl Codec for Remaining {
+    type Value = Vec<u8>;
+
+    fn read<R: Read>(buf: &mut R) -> Result<Vec<u8>> {
+        let mut rest = vec![];
+        buf.read_to_end(&mut rest)?;
+        Ok(rest)
+    }
+
+    fn write<W: Write>(value: &Vec<u8>, buf: &mut W) -> Result<()> {
+        buf.write_all(value)?;
+        Ok(())
+    }
+}
+
+/// A [ProtocolState], encoded as a var int.
+pub struct State;
+
+impl Codec for State {
+    type Value = ProtocolState;
+
+    fn read<R: Read>(buf: &mut R) -> Result<ProtocolState> {
+        buf.read_var_int()?.try_into()
+    }
+
+    fn write<W: Write>(value: &ProtocolState, buf: &mut W) -> Result<()> {
+        buf.write_var_int(value.try_into()?)
+    }
+}
+
+/// A value prefixed by a boolean indicating whether it is present.
+pub struct Optional<C>(PhantomData<C>);
+
+impl<C: Codec> Codec for Optional<C> {
+    type Value = Option<C::Value>;
+
+    fn read<R: Read>(buf: &mut R) -> Result<Self::Value> {
+        match buf.read_bool()? {
+            true => Ok(Some(C::read(buf)?)),
+            false => Ok(None),
+        }
+    }
+
+    fn write<W: Write>(value: &Self::Value, buf: &mut W) -> Result<()> {
+        buf.write_bool(value.is_some())?;
+        match value {
+            Some(value) => C::write(value, buf),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A var int prefixed array of values.
+pub struct Array<C>(PhantomData<C>);
+
+impl<C: Codec> Codec for Array<C> {
+    type Value = Vec<C::Value>;
+
+    fn read<R: Read>(buf: &mut R) -> Result<Self::Value> {
+        let len = buf.read_var_int()?;
+        (0..len).map(|_| C::read(buf)).collect()
+    }
+
+    fn write<W: Write>(value: &Self::Value, buf: &mut W) -> Result<()> {
+        buf.write_var_int(value.len() as i32)?;
+        for item in value {
+            C::write(item, buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Declares a packet or compound type from a table of fields and their codecs.
+///
+/// Declaring a packet id implements [PacketCodec](crate::protocol::packets::PacketCodec),
+/// otherwise [Codec] is implemented for the type itself.
+macro_rules! packet {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident = $id:literal {
+            $(
+                $(#[$field_meta:meta])*
+                $field:ident: $ty:ty as $codec:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone)]
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                pub $field: $ty,
+            )*
+        }
+
+        impl $crate::protocol::packets::PacketCodec for $name {
+            const ID: i32 = $id;
+
+            #[allow(unused_variables)]
+            fn read<R: ::std::io::Read>(buf: &mut R) -> ::anyhow::Result<Self> {
+                Ok(Self {
+                    $($field: <$codec as $crate::protocol::codec::Codec>::read(buf)?,)*
+                })
+            }
+
+            #[allow(unused_variables)]
+            fn write<W: ::std::io::Write>(&self, buf: &mut W) -> ::anyhow::Result<()> {
+                $(<$codec as $crate::protocol::codec::Codec>::write(&self.$field, buf)?;)*
+                Ok(())
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field:ident: $ty:ty as $codec:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone)]
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                pub $field: $ty,
+            )*
+        }
+
+        impl $crate::protocol::codec::Codec for $name {
+            type Value = Self;
+
+            #[allow(unused_variables)]
+            fn read<R: ::std::io::Read>(buf: &mut R) -> ::anyhow::Result<Self> {
+                Ok(Self {
+                    $($field: <$codec as $crate::protocol::codec::Codec>::read(buf)?,)*
+                })
+            }
+
+            #[allow(unused_variables)]
+            fn write<W: ::std::io::Write>(value: &Self, buf: &mut W) -> ::anyhow::Result<()> {
+                $(<$codec as $crate::protocol::codec::Codec>::write(&value.$field, buf)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+pub(crate) use packet;
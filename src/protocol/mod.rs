@@ -0,0 +1,10 @@
+//! Defines typed representations of the Minecraft protocol.
+//!
+//! Magma only needs to understand a handful of packets - those exchanged during the handshake,
+//! status, and login phases - in order to route connections. Everything else is forwarded as
+//! opaque bytes. Refer to [wiki.vg](https://wiki.vg/Protocol) for the full protocol.
+
+pub mod chat;
+pub mod codec;
+pub mod packets;
+pub mod version;
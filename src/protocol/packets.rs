@@ -0,0 +1,183 @@
+//! Defines typed packets, along with their encoding and decoding.
+//!
+//! Each packet implements [PacketCodec], which maps between the typed packet and an
+//! [UncompressedPacket]. Packets are declared using the [packet] macro - see [codec] for the
+//! available field codecs. Packet layouts follow protocol version 761 (1.19.3).
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+use uuid::Uuid;
+
+use super::codec::{self, packet};
+use crate::{bridge::ProtocolState, error::ProtocolError, io::UncompressedPacket};
+
+/// A packet that can be encoded to and decoded from an [UncompressedPacket].
+pub trait PacketCodec: Sized {
+    /// The packet id.
+    const ID: i32;
+
+    /// Read the packet fields from the given buffer.
+    fn read<R: Read>(buf: &mut R) -> Result<Self>;
+
+    /// Write the packet fields to the given buffer.
+    fn write<W: Write>(&self, buf: &mut W) -> Result<()>;
+
+    /// Decode the packet from an [UncompressedPacket], checking its id.
+    fn decode(packet: &UncompressedPacket) -> Result<Self> {
+        if packet.id != Self::ID {
+            bail!(ProtocolError::UnexpectedPacket {
+                expected: Self::ID,
+                actual: packet.id,
+            });
+        }
+        Self::read(&mut packet.as_cursor())
+    }
+
+    /// Encode the packet into an [UncompressedPacket].
+    fn encode(&self) -> Result<UncompressedPacket> {
+        let mut data = vec![];
+        self.write(&mut data)?;
+        Ok(UncompressedPacket {
+            id: Self::ID,
+            data: data.into(),
+        })
+    }
+}
+
+packet! {
+    /// Sent by the client to initiate a connection.
+    pub struct Handshake = 0x00 {
+        /// The protocol version of the client.
+        protocol_version: i32 as codec::VarInt,
+        /// The address the client used to connect.
+        server_address: String as codec::Str,
+        /// The port the client used to connect.
+        server_port: u16 as codec::UnsignedShort,
+        /// The state the client wishes to switch to.
+        next_state: ProtocolState as codec::State,
+    }
+}
+
+packet! {
+    /// Sent by the client to request the server status.
+    pub struct StatusRequest = 0x00 {}
+}
+
+packet! {
+    /// Sent by the server in response to a [StatusRequest].
+    pub struct StatusResponse = 0x00 {
+        /// The JSON-encoded server status.
+        json: String as codec::Str,
+    }
+}
+
+packet! {
+    /// Sent by the client to measure latency during the status phase.
+    pub struct PingRequest = 0x01 {
+        /// An arbitrary payload, echoed back by the server.
+        payload: i64 as codec::Long,
+    }
+}
+
+packet! {
+    /// Sent by the server in response to a [PingRequest].
+    pub struct PongResponse = 0x01 {
+        /// The payload of the corresponding [PingRequest].
+        payload: i64 as codec::Long,
+    }
+}
+
+packet! {
+    /// Sent by the server to disconnect the client during login.
+    pub struct Disconnect = 0x00 {
+        /// The JSON-encoded chat component explaining the disconnect.
+        reason: String as codec::Str,
+    }
+}
+
+packet! {
+    /// Sent by the client to begin logging in.
+    pub struct LoginStart = 0x00 {
+        /// The username of the player.
+        username: String as codec::Str,
+        /// The UUID of the player, if the client provided one.
+        uuid: Option<Uuid> as codec::Optional<codec::Uuid>,
+    }
+}
+
+packet! {
+    /// Sent by the server to begin encryption.
+    pub struct EncryptionRequest = 0x01 {
+        /// The server id - empty on modern servers.
+        server_id: String as codec::Str,
+        /// The DER-encoded public key of the server.
+        public_key: Vec<u8> as codec::ByteArray,
+        /// A random token the client must encrypt and return.
+        verify_token: Vec<u8> as codec::ByteArray,
+    }
+}
+
+packet! {
+    /// Sent by the client in response to an [EncryptionRequest].
+    pub struct EncryptionResponse = 0x01 {
+        /// The shared secret, encrypted with the server's public key.
+        shared_secret: Vec<u8> as codec::ByteArray,
+        /// The verify token, encrypted with the server's public key.
+        verify_token: Vec<u8> as codec::ByteArray,
+    }
+}
+
+packet! {
+    /// A property of a player profile, such as their skin.
+    pub struct ProfileProperty {
+        /// The property name.
+        name: String as codec::Str,
+        /// The property value.
+        value: String as codec::Str,
+        /// The Yggdrasil signature of the value, if signed.
+        signature: Option<String> as codec::Optional<codec::Str>,
+    }
+}
+
+packet! {
+    /// Sent by the server once login has completed.
+    pub struct LoginSuccess = 0x02 {
+        /// The UUID of the player.
+        uuid: Uuid as codec::Uuid,
+        /// The username of the player.
+        username: String as codec::Str,
+        /// The properties of the player profile.
+        properties: Vec<ProfileProperty> as codec::Array<ProfileProperty>,
+    }
+}
+
+packet! {
+    /// Sent by the server to query the client during login, such as for Velocity forwarding.
+    pub struct LoginPluginRequest = 0x04 {
+        /// The id of this query, echoed back in the response.
+        message_id: i32 as codec::VarInt,
+        /// The plugin channel of the query.
+        channel: String as codec::Str,
+        /// The channel-specific payload.
+        data: Vec<u8> as codec::Remaining,
+    }
+}
+
+packet! {
+    /// Sent by the client in response to a [LoginPluginRequest].
+    pub struct LoginPluginResponse = 0x02 {
+        /// The id of the query being answered.
+        message_id: i32 as codec::VarInt,
+        /// The channel-specific payload, or [None] if the client did not understand the query.
+        data: Option<Vec<u8>> as codec::Optional<codec::Remaining>,
+    }
+}
+
+packet! {
+    /// Sent by the server to enable compression.
+    pub struct SetCompression = 0x03 {
+        /// The compression threshold - negative values disable compression.
+        threshold: i32 as codec::VarInt,
+    }
+}
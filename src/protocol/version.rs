@@ -0,0 +1,421 @@
+//! Defines protocol versions, and the registry mapping logical packets to per-version ids.
+//!
+//! Packet ids and field layouts shift between Minecraft versions. Magma only inspects a handful
+//! of packets, so rather than tracking the entire protocol, this module records the ids of those
+//! packets for each supported version. Lookups for versions Magma does not know about return
+//! [None], in which case the packet should be treated as opaque.
+
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+
+use super::packets::{self, PacketCodec};
+use crate::{
+    bridge::ProtocolState,
+    error::ProtocolError,
+    io::{ProtocolReadExt, ProtocolWriteExt, UncompressedPacket},
+};
+
+/// A Minecraft protocol version number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion(pub i32);
+
+impl ProtocolVersion {
+    /// 1.19
+    pub const V1_19: Self = Self(759);
+    /// 1.19.1 - 1.19.2
+    pub const V1_19_1: Self = Self(760);
+    /// 1.19.3
+    pub const V1_19_3: Self = Self(761);
+    /// 1.19.4
+    pub const V1_19_4: Self = Self(762);
+    /// 1.20 - 1.20.1
+    pub const V1_20: Self = Self(763);
+    /// 1.20.2
+    pub const V1_20_2: Self = Self(764);
+    /// 1.20.3 - 1.20.4
+    pub const V1_20_3: Self = Self(765);
+    /// 1.20.5 - 1.20.6
+    pub const V1_20_5: Self = Self(766);
+    /// 1.21 - 1.21.1
+    pub const V1_21: Self = Self(767);
+
+    /// The protocol version Magma broadcasts by default.
+    pub const DEFAULT: Self = Self::V1_19_3;
+    /// The oldest protocol version with a known packet registry.
+    pub const OLDEST: Self = Self::V1_19;
+    /// The newest protocol version with a known packet registry.
+    pub const LATEST: Self = Self::V1_21;
+
+    /// Test whether Magma knows the packet layout of this version.
+    pub fn is_known(self) -> bool {
+        (Self::OLDEST..=Self::LATEST).contains(&self)
+    }
+
+    /// Look up the id of a logical packet in this version.
+    ///
+    /// Returns [None] if the packet does not exist in this version, or the version is unknown.
+    pub fn packet_id(self, packet: LogicalPacket) -> Option<i32> {
+        use LogicalPacket::*;
+
+        if !self.is_known() {
+            return None;
+        }
+        let id = match packet {
+            // handshaking and status are stable across all versions
+            Handshake | StatusRequest | StatusResponse => 0x00,
+            PingRequest | PongResponse => 0x01,
+            // login
+            LoginDisconnect | LoginStart => 0x00,
+            EncryptionRequest | EncryptionResponse => 0x01,
+            LoginSuccess | LoginPluginResponse => 0x02,
+            SetCompression => 0x03,
+            LoginPluginRequest => 0x04,
+            LoginAcknowledged if self >= Self::V1_20_2 => 0x03,
+            // configuration
+            FinishConfiguration | AcknowledgeFinishConfiguration if self >= Self::V1_20_5 => 0x03,
+            FinishConfiguration | AcknowledgeFinishConfiguration if self >= Self::V1_20_2 => 0x02,
+            StartConfiguration if self >= Self::V1_20_5 => 0x69,
+            StartConfiguration if self >= Self::V1_20_3 => 0x67,
+            StartConfiguration if self >= Self::V1_20_2 => 0x65,
+            ConfigurationAcknowledged if self >= Self::V1_20_5 => 0x0C,
+            ConfigurationAcknowledged if self >= Self::V1_20_2 => 0x0B,
+            // disconnects
+            ConfigurationDisconnect if self >= Self::V1_20_5 => 0x02,
+            ConfigurationDisconnect if self >= Self::V1_20_2 => 0x01,
+            PlayDisconnect if self >= Self::V1_20_5 => 0x1D,
+            PlayDisconnect if self >= Self::V1_20_2 => 0x1B,
+            PlayDisconnect if self >= Self::V1_19_4 => 0x1A,
+            PlayDisconnect if self >= Self::V1_19_3 => 0x17,
+            PlayDisconnect if self >= Self::V1_19_1 => 0x19,
+            PlayDisconnect => 0x17,
+            // chat
+            SystemChat if self >= Self::V1_20_5 => 0x6C,
+            SystemChat if self >= Self::V1_20_3 => 0x69,
+            SystemChat if self >= Self::V1_20_2 => 0x67,
+            SystemChat if self >= Self::V1_19_4 => 0x64,
+            SystemChat if self >= Self::V1_19_3 => 0x60,
+            SystemChat if self >= Self::V1_19_1 => 0x62,
+            SystemChat => 0x5F,
+            ChatCommand if self >= Self::V1_19_1 => 0x04,
+            ChatCommand => 0x03,
+            ChatCommandSigned if self >= Self::V1_20_5 => 0x05,
+            MessageAcknowledgment if self >= Self::V1_19_1 => 0x03,
+            // plugin messages
+            PluginMessageClientbound if self >= Self::V1_20_5 => 0x19,
+            PluginMessageClientbound if self >= Self::V1_20_2 => 0x18,
+            PluginMessageClientbound if self >= Self::V1_19_4 => 0x17,
+            PluginMessageClientbound if self >= Self::V1_19_3 => 0x15,
+            PluginMessageClientbound if self >= Self::V1_19_1 => 0x16,
+            PluginMessageClientbound => 0x15,
+            PluginMessageServerbound if self >= Self::V1_20_5 => 0x12,
+            PluginMessageServerbound if self >= Self::V1_20_3 => 0x10,
+            PluginMessageServerbound if self >= Self::V1_20_2 => 0x0F,
+            PluginMessageServerbound if self >= Self::V1_19_4 => 0x0D,
+            PluginMessageServerbound if self >= Self::V1_19_3 => 0x0C,
+            PluginMessageServerbound if self >= Self::V1_19_1 => 0x0D,
+            PluginMessageServerbound => 0x0C,
+            // chat sessions
+            PlayerSession if self >= Self::V1_20_5 => 0x07,
+            PlayerSession if self >= Self::V1_19_4 => 0x06,
+            PlayerSession if self >= Self::V1_19_3 => 0x20,
+            // client information
+            ConfigurationClientInformation if self >= Self::V1_20_2 => 0x00,
+            PlayClientInformation if self >= Self::V1_20_5 => 0x0A,
+            PlayClientInformation if self >= Self::V1_20_2 => 0x09,
+            PlayClientInformation if self >= Self::V1_19_4 => 0x08,
+            PlayClientInformation if self >= Self::V1_19_3 => 0x07,
+            PlayClientInformation if self >= Self::V1_19_1 => 0x08,
+            PlayClientInformation => 0x07,
+            // transfer
+            ConfigurationTransfer if self >= Self::V1_20_5 => 0x0B,
+            PlayTransfer if self >= Self::V1_20_5 => 0x73,
+            // keep-alives
+            KeepAliveClientbound if self >= Self::V1_20_5 => 0x26,
+            KeepAliveClientbound if self >= Self::V1_20_2 => 0x24,
+            KeepAliveClientbound if self >= Self::V1_19_4 => 0x23,
+            KeepAliveClientbound if self >= Self::V1_19_3 => 0x1F,
+            KeepAliveClientbound if self >= Self::V1_19_1 => 0x20,
+            KeepAliveClientbound => 0x1E,
+            KeepAliveServerbound if self >= Self::V1_20_5 => 0x18,
+            KeepAliveServerbound if self >= Self::V1_20_3 => 0x15,
+            KeepAliveServerbound if self >= Self::V1_20_2 => 0x14,
+            KeepAliveServerbound if self >= Self::V1_19_4 => 0x12,
+            KeepAliveServerbound if self >= Self::V1_19_3 => 0x11,
+            KeepAliveServerbound if self >= Self::V1_19_1 => 0x12,
+            KeepAliveServerbound => 0x11,
+            _ => return None,
+        };
+        Some(id)
+    }
+
+    /// Find the logical packet with the given id in the given state and direction.
+    pub fn logical_packet(
+        self,
+        state: ProtocolState,
+        direction: Direction,
+        id: i32,
+    ) -> Option<LogicalPacket> {
+        LogicalPacket::ALL
+            .iter()
+            .copied()
+            .filter(|packet| packet.state() == state && packet.direction() == direction)
+            .find(|packet| self.packet_id(*packet) == Some(id))
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<i32> for ProtocolVersion {
+    fn from(version: i32) -> Self {
+        Self(version)
+    }
+}
+
+/// The direction a packet travels in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to the server.
+    Serverbound,
+    /// From the server to the client.
+    Clientbound,
+}
+
+/// A packet Magma inspects, independent of its per-version id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogicalPacket {
+    Handshake,
+    StatusRequest,
+    StatusResponse,
+    PingRequest,
+    PongResponse,
+    LoginDisconnect,
+    EncryptionRequest,
+    LoginSuccess,
+    SetCompression,
+    LoginPluginRequest,
+    LoginStart,
+    EncryptionResponse,
+    LoginPluginResponse,
+    LoginAcknowledged,
+    FinishConfiguration,
+    AcknowledgeFinishConfiguration,
+    ConfigurationTransfer,
+    ConfigurationDisconnect,
+    StartConfiguration,
+    ConfigurationAcknowledged,
+    PlayTransfer,
+    PlayDisconnect,
+    SystemChat,
+    ChatCommand,
+    ChatCommandSigned,
+    MessageAcknowledgment,
+    PluginMessageClientbound,
+    PluginMessageServerbound,
+    KeepAliveClientbound,
+    KeepAliveServerbound,
+    ConfigurationClientInformation,
+    PlayClientInformation,
+    PlayerSession,
+}
+
+impl LogicalPacket {
+    /// Every logical packet.
+    pub const ALL: &'static [LogicalPacket] = &[
+        Self::Handshake,
+        Self::StatusRequest,
+        Self::StatusResponse,
+        Self::PingRequest,
+        Self::PongResponse,
+        Self::LoginDisconnect,
+        Self::EncryptionRequest,
+        Self::LoginSuccess,
+        Self::SetCompression,
+        Self::LoginPluginRequest,
+        Self::LoginStart,
+        Self::EncryptionResponse,
+        Self::LoginPluginResponse,
+        Self::LoginAcknowledged,
+        Self::FinishConfiguration,
+        Self::AcknowledgeFinishConfiguration,
+        Self::ConfigurationTransfer,
+        Self::ConfigurationDisconnect,
+        Self::StartConfiguration,
+        Self::ConfigurationAcknowledged,
+        Self::PlayTransfer,
+        Self::PlayDisconnect,
+        Self::SystemChat,
+        Self::ChatCommand,
+        Self::ChatCommandSigned,
+        Self::MessageAcknowledgment,
+        Self::PluginMessageClientbound,
+        Self::PluginMessageServerbound,
+        Self::KeepAliveClientbound,
+        Self::KeepAliveServerbound,
+        Self::ConfigurationClientInformation,
+        Self::PlayClientInformation,
+        Self::PlayerSession,
+    ];
+
+    /// The protocol state this packet is sent in.
+    pub fn state(self) -> ProtocolState {
+        use LogicalPacket::*;
+
+        match self {
+            Handshake => ProtocolState::Handshaking,
+            StatusRequest | StatusResponse | PingRequest | PongResponse => ProtocolState::Status,
+            LoginDisconnect | EncryptionRequest | LoginSuccess | SetCompression
+            | LoginPluginRequest | LoginStart | EncryptionResponse | LoginPluginResponse
+            | LoginAcknowledged => ProtocolState::Login,
+            FinishConfiguration
+            | AcknowledgeFinishConfiguration
+            | ConfigurationTransfer
+            | ConfigurationDisconnect
+            | ConfigurationClientInformation => ProtocolState::Configuration,
+            StartConfiguration
+            | ConfigurationAcknowledged
+            | PlayTransfer
+            | PlayDisconnect
+            | SystemChat
+            | ChatCommand
+            | ChatCommandSigned
+            | MessageAcknowledgment
+            | PluginMessageClientbound
+            | PluginMessageServerbound
+            | KeepAliveClientbound
+            | KeepAliveServerbound
+            | PlayClientInformation
+            | PlayerSession => ProtocolState::Play,
+        }
+    }
+
+    /// The direction this packet travels in.
+    pub fn direction(self) -> Direction {
+        use LogicalPacket::*;
+
+        match self {
+            Handshake
+            | StatusRequest
+            | PingRequest
+            | LoginStart
+            | EncryptionResponse
+            | LoginPluginResponse
+            | LoginAcknowledged
+            | AcknowledgeFinishConfiguration
+            | ConfigurationAcknowledged
+            | ChatCommand
+            | ChatCommandSigned
+            | MessageAcknowledgment
+            | PluginMessageServerbound
+            | KeepAliveServerbound
+            | ConfigurationClientInformation
+            | PlayClientInformation
+            | PlayerSession => Direction::Serverbound,
+            StatusResponse
+            | PongResponse
+            | LoginDisconnect
+            | EncryptionRequest
+            | LoginSuccess
+            | SetCompression
+            | LoginPluginRequest
+            | FinishConfiguration
+            | ConfigurationTransfer
+            | ConfigurationDisconnect
+            | StartConfiguration
+            | PlayTransfer
+            | PlayDisconnect
+            | SystemChat
+            | PluginMessageClientbound
+            | KeepAliveClientbound => Direction::Clientbound,
+        }
+    }
+}
+
+/// A packet whose layout depends on the protocol version.
+pub trait VersionedPacket: Sized {
+    /// Decode the packet as laid out in the given protocol version.
+    fn decode_versioned(packet: &UncompressedPacket, version: ProtocolVersion) -> Result<Self>;
+
+    /// Encode the packet as laid out in the given protocol version.
+    fn encode_versioned(&self, version: ProtocolVersion) -> Result<UncompressedPacket>;
+}
+
+impl VersionedPacket for packets::LoginStart {
+    fn decode_versioned(packet: &UncompressedPacket, version: ProtocolVersion) -> Result<Self> {
+        if packet.id != Self::ID {
+            bail!(ProtocolError::UnexpectedPacket {
+                expected: Self::ID,
+                actual: packet.id,
+            });
+        }
+        let mut buf = packet.as_cursor();
+        let username = buf.read_string()?;
+        let uuid = match version {
+            // every version begins with the username, which is all older versions send - and all
+            // that can be trusted of newer ones
+            v if !v.is_known() => None,
+            // 1.19 - 1.19.2 carry optional signature data, which we skip
+            v if v < ProtocolVersion::V1_19_3 => {
+                if buf.read_bool()? {
+                    buf.read_i64()?;
+                    buf.read_byte_array()?;
+                    buf.read_byte_array()?;
+                }
+                match version >= ProtocolVersion::V1_19_1 && buf.read_bool()? {
+                    true => Some(buf.read_uuid()?),
+                    false => None,
+                }
+            }
+            // 1.19.3 - 1.20.1 prefix the uuid with a presence flag
+            v if v < ProtocolVersion::V1_20_2 => match buf.read_bool()? {
+                true => Some(buf.read_uuid()?),
+                false => None,
+            },
+            // 1.20.2+ always send a uuid
+            _ => Some(buf.read_uuid()?),
+        };
+        Ok(Self { username, uuid })
+    }
+
+    fn encode_versioned(&self, version: ProtocolVersion) -> Result<UncompressedPacket> {
+        let mut data = vec![];
+        data.write_string(self.username.clone())?;
+        match version {
+            v if v > ProtocolVersion::LATEST => {
+                bail!("the login start of protocol version {} is unknown", version)
+            }
+            v if v < ProtocolVersion::OLDEST => {}
+            v if v < ProtocolVersion::V1_19_3 => {
+                // no signature data
+                data.write_bool(false)?;
+                if version >= ProtocolVersion::V1_19_1 {
+                    data.write_bool(self.uuid.is_some())?;
+                    if let Some(uuid) = &self.uuid {
+                        data.write_uuid(uuid)?;
+                    }
+                }
+            }
+            v if v < ProtocolVersion::V1_20_2 => {
+                data.write_bool(self.uuid.is_some())?;
+                if let Some(uuid) = &self.uuid {
+                    data.write_uuid(uuid)?;
+                }
+            }
+            _ => data.write_uuid(&self.uuid.context("a uuid is required in 1.20.2+")?)?,
+        }
+        Ok(UncompressedPacket {
+            id: Self::ID,
+            data: data.into(),
+        })
+    }
+}
@@ -4,24 +4,80 @@
 //! listening address. Each proxy server can have multiple routes, which define where the proxy server
 //! should route connections to.
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 
 use rand::{thread_rng, Rng};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::{watch, Notify},
     task::JoinHandle,
+    time::{sleep, timeout},
 };
-use tracing::{error, info, trace, warn};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
 
 use crate::{
-    bridge::{self, ProtocolState},
-    config::{Proxy, SelectionAlgorithmKind},
+    agones,
+    auth::Authenticator,
+    bridge::{self, BridgeOptions, CloseReason, ProtocolState, Session, Stream},
+    config::{
+        FallbackMethod, HostnameRewrite, PlayerSample, Proxy, Route, SelectionAlgorithmKind,
+        Transport, VpnPolicy,
+    },
+    crash::{self, ConnectionContext},
+    dns, drain,
+    error::{MagmaError, ProtocolError},
+    events::{self, Event},
+    health,
     io::{ProcotolAsyncWriteExt, ProtocolAsyncReadExt},
+    limits::{self, HeadroomCheck, Operation},
+    memory::{self, Pressure},
+    metrics::{self, Timing},
+    motd, pool,
+    protocol::{
+        packets::{Handshake, PacketCodec},
+        version::ProtocolVersion,
+    },
+    query,
+    ratelimit::{self, Throttled},
+    realip,
+    registry::{self, ListenerState, Rejection},
+    reply::{self, Players},
+    reputation::Reputation,
+    resume,
+    security::{self, SecurityEvent},
+    status::{self, Lookup},
+    tarpit::Tarpit,
+    transfer,
+    tunnel::edge::Edge,
+    websocket,
 };
 
+/// The initial delay before accepting again after an accept error.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+/// The maximum delay before accepting again after an accept error.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// The first byte of a server list ping from clients older than 1.7.
+const LEGACY_PING: u8 = 0xFE;
+/// The initial delay before restarting a failed listener.
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+/// The maximum delay before restarting a failed listener.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+/// How many times in a row a listener is restarted before it is given up on.
+const MAX_RESTARTS: u32 = 5;
+/// How long a listener must run for its failures to no longer count as in a row.
+const STABLE_AFTER: Duration = Duration::from_secs(300);
+
 /// A selection algorithm for routing new connections to upstream servers.
 ///
 /// Once a connection is established, Magma has to decide which upstream server to route the connection to.
@@ -81,71 +137,1100 @@ impl SelectionAlgorithm for RandomSelector {
     }
 }
 
-/// Spawns a new proxy server, and returns a handle to the task.
-pub fn spawn(proxy: Proxy) -> JoinHandle<Result<()>> {
-    tokio::task::spawn(async move { listen(proxy).await })
+/// Services shared by every proxy server.
+#[derive(Clone, Default)]
+pub struct Services {
+    /// The authenticator of routes which authenticate logins themselves, if any route did at
+    /// startup.
+    pub authenticator: Option<Arc<Authenticator>>,
+    /// The edge tunneled routes are forwarded through, if any.
+    pub edge: Option<Arc<Edge>>,
+    /// The IP reputation checker, if enabled.
+    pub reputation: Option<Arc<Reputation>>,
+    /// The tarpit denied connections are held in, if enabled.
+    pub tarpit: Option<Arc<Tarpit>>,
 }
 
-/// Listen for new connections.
+/// Spawns a new proxy server, and returns a handle to the task. The routes of the proxy are
+/// replaced whenever a new proxy is sent through the channel.
 ///
-/// This function will listen for new connections, and invoke [handle_connection] for each new connection.
-#[tracing::instrument(name="proxy", skip_all, fields(addr=%proxy.listen_addr))]
-async fn listen(proxy: Proxy) -> Result<()> {
+/// The listener is supervised - if it fails, such as when its address is still in use, it is
+/// restarted with a backoff. The task fails once the listener has failed [MAX_RESTARTS] times in
+/// a row, or with an error which retrying can't fix.
+pub fn spawn(proxy: watch::Receiver<Arc<Proxy>>, services: Services) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let addr = proxy.borrow().listen_addr;
+        let mut backoff = MIN_RESTART_BACKOFF;
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            let Err(err) = listen(proxy.clone(), services.clone()).await else {
+                return Ok(());
+            };
+            // log the whole chain of causes, such as why binding failed
+            let fatal = err.is_fatal();
+            let err = anyhow::Error::from(err);
+            let error = Some(format!("{:#}", err));
+            if fatal {
+                error!("Proxy server on {} failed: {:#}", addr, err);
+                registry::record_listener(addr, ListenerState::Failed, error);
+                return Err(err);
+            }
+            if started.elapsed() >= STABLE_AFTER {
+                backoff = MIN_RESTART_BACKOFF;
+                failures = 0;
+            }
+            failures += 1;
+            if failures > MAX_RESTARTS {
+                error!(
+                    "Proxy server on {} failed {} times in a row - giving up",
+                    addr, failures
+                );
+                registry::record_listener(addr, ListenerState::Failed, error);
+                return Err(err);
+            }
+            warn!(
+                "Proxy server on {} failed: {:#} - restarting in {:?}",
+                addr, err, backoff
+            );
+            registry::record_listener(addr, ListenerState::Restarting, error);
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+        }
+    })
+}
+
+/// Aborts a task when dropped, so helper tasks stop with their listener.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The addresses Magma is listening on, while it is - once for each listener bound to one.
+fn listening() -> &'static Mutex<Vec<SocketAddr>> {
+    static LISTENING: OnceLock<Mutex<Vec<SocketAddr>>> = OnceLock::new();
+    LISTENING.get_or_init(Mutex::default)
+}
+
+/// Records an address as listened on, until dropped.
+struct Listening(SocketAddr);
+
+impl Listening {
+    fn new(addr: SocketAddr) -> Self {
+        listening().lock().unwrap().push(addr);
+        Self(addr)
+    }
+}
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        let mut listening = listening().lock().unwrap();
+        if let Some(i) = listening.iter().position(|addr| *addr == self.0) {
+            listening.swap_remove(i);
+        }
+    }
+}
+
+/// Fail a backend connection which reached one of Magma's own listeners, as the target resolves
+/// to this proxy. Its connection would be proxied to itself again, and so on until the sockets run
+/// out, so it is turned away before anything is sent.
+fn check_loop(domain: &str, stream: &TcpStream) -> Result<()> {
+    let (local, target) = (stream.local_addr()?, stream.peer_addr()?);
+    // connections to this host are made from the address they reach, unless over loopback
+    if target.ip() != local.ip() && !target.ip().is_loopback() {
+        return Ok(());
+    }
+    let looped = listening().lock().unwrap().iter().any(|addr| {
+        addr.port() == target.port() && (addr.ip().is_unspecified() || addr.ip() == target.ip())
+    });
+    if looped {
+        error!(
+            "Route {} targets {}, which is this proxy's own listener - point it at the backend server instead",
+            domain, target
+        );
+        bail!("backend {} is this proxy's own listener", target);
+    }
+    Ok(())
+}
+
+/// Bind the listener of a proxy, and serve it.
+async fn listen(
+    proxies: watch::Receiver<Arc<Proxy>>,
+    services: Services,
+) -> Result<(), MagmaError> {
+    let addr = proxies.borrow().listen_addr;
+    let backlog = proxies.borrow().backlog;
+    let v6_only = proxies.borrow().v6_only;
     // create tcp listener
-    let listener = TcpListener::bind(proxy.listen_addr).await.map_err(|err| {
-        error!("Error while starting proxy server: {}", err);
-        err
-    })?;
-    let proxy = Arc::new(proxy);
+    let listener =
+        bind(addr, backlog, v6_only).map_err(|source| MagmaError::Bind { addr, source })?;
+    registry::record_listener(addr, ListenerState::Running, None);
+    Ok(serve(listener, proxies, services).await?)
+}
+
+/// Serve a proxy on a bound listener.
+///
+/// This function will listen for new connections, and invoke [handle_connection] for each new connection.
+#[tracing::instrument(name="proxy", skip_all, fields(addr=%proxies.borrow().listen_addr))]
+pub async fn serve(
+    listener: TcpListener,
+    proxies: watch::Receiver<Arc<Proxy>>,
+    services: Services,
+) -> Result<()> {
+    let proxy = proxies.borrow().clone();
+    let _listening = Listening::new(listener.local_addr()?);
+    let mut headroom = proxy.min_fd_headroom.map(HeadroomCheck::new);
+    // the transport is a property of the socket, so it is fixed until restart
+    let transport = proxy.transport;
+    let _query = proxy.query.then(|| {
+        let proxies = proxies.clone();
+        AbortOnDrop(tokio::task::spawn(async move {
+            if let Err(err) = query::serve(proxies).await {
+                error!("Query responder failed: {:#}", err);
+            }
+        }))
+    });
 
     info!("Started proxy server");
 
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    let mut paused = false;
     loop {
+        // stop accepting while file descriptors are scarce, so existing connections keep working
+        if headroom.as_mut().is_some_and(|check| check.is_low()) {
+            if !paused {
+                warn!("File descriptor headroom is low - pausing new connections");
+                paused = true;
+            }
+            let _ = timeout(MAX_ACCEPT_BACKOFF, connection_closed().notified()).await;
+            continue;
+        }
+        if paused {
+            info!("File descriptor headroom recovered - resuming new connections");
+            paused = false;
+        }
+
         // accept new connections, and create a new task for each
-        let (stream, _) = match listener.accept().await {
-            Ok(s) => s,
-            Err(_) => continue,
+        let (stream, peer) = match listener.accept().await {
+            Ok(s) => {
+                backoff = MIN_ACCEPT_BACKOFF;
+                s
+            }
+            Err(err) => {
+                // accept errors are usually persistent, such as running out of file
+                // descriptors - back off rather than spinning, waking early if one is freed
+                if limits::is_resource_exhaustion(&err) {
+                    limits::exhausted(Operation::Accept, &err);
+                    let _ = timeout(backoff, connection_closed().notified()).await;
+                } else {
+                    warn!(
+                        "Failed to accept connection: {} - retrying in {:?}",
+                        err, backoff
+                    );
+                    sleep(backoff).await;
+                }
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        // connections use the routes current when they were accepted
+        let (proxy, services) = (proxies.borrow().clone(), services.clone());
+        let id = connection_id();
+        let span = info_span!("connection", id = %id, %peer, route = field::Empty);
+        let context = ConnectionContext {
+            connection_id: id.clone(),
+            peer,
+            listener: proxy.listen_addr,
         };
-        tokio::task::spawn(handle_connection(proxy.clone(), stream));
+        tokio::task::spawn(
+            crash::scope(context, async move {
+                let result = match transport {
+                    Transport::Tcp => handle_connection(proxy, services, id, peer, stream).await,
+                    Transport::Websocket => match websocket::accept(stream).await {
+                        Ok(stream) => handle_connection(proxy, services, id, peer, stream).await,
+                        Err(err) => Err(err),
+                    },
+                };
+                connection_closed().notify_waiters();
+                result
+            })
+            .instrument(span),
+        );
     }
 }
 
+/// Bind a listener with the given backlog.
+fn bind(addr: SocketAddr, backlog: u32, v6_only: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Connect to a target from the client's address, so the backend sees the client's real IP.
+///
+/// The socket is bound to an address which isn't local, which needs `CAP_NET_ADMIN`, and the
+/// backend's replies to the client's address must be routed back to Magma by policy routing.
+#[cfg(target_os = "linux")]
+async fn connect_transparent(target: SocketAddr, peer: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = Socket::new(
+        Domain::for_address(target),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_ip_transparent(true)?;
+    socket.set_nonblocking(true)?;
+    // the client's own port may already be connected to the backend from an earlier session
+    socket.bind(&SocketAddr::new(peer.ip(), 0).into())?;
+    tokio::net::TcpSocket::from_std_stream(socket.into())
+        .connect(target)
+        .await
+}
+
+/// Transparent connections are rejected by the configuration on other platforms.
+#[cfg(not(target_os = "linux"))]
+async fn connect_transparent(_: SocketAddr, _: SocketAddr) -> std::io::Result<TcpStream> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Assign a connection a short ID, unique within the process, which its log lines and events
+/// carry so its whole lifecycle can be found with one search.
+fn connection_id() -> String {
+    static NEXT_ID: OnceLock<AtomicU32> = OnceLock::new();
+    // start somewhere random, so IDs from different runs rarely collide in aggregated logs
+    let next = NEXT_ID.get_or_init(|| AtomicU32::new(thread_rng().gen()));
+    format!("{:08x}", next.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Notified whenever a client connection closes, freeing its file descriptors.
+fn connection_closed() -> &'static Notify {
+    static CONNECTION_CLOSED: OnceLock<Notify> = OnceLock::new();
+    CONNECTION_CLOSED.get_or_init(Notify::new)
+}
+
 /// Handle a new connection from a client.
-async fn handle_connection(proxy: Arc<Proxy>, mut client_stream: TcpStream) -> Result<()> {
-    // read the first packet from the client - this should be a handshake packet
-    let handshake = client_stream.read_uncompressed_packet().await?;
-    if handshake.id != 0x00 {
+async fn handle_connection<C: Stream>(
+    proxy: Arc<Proxy>,
+    services: Services,
+    connection_id: String,
+    peer: SocketAddr,
+    mut client_stream: C,
+) -> Result<()> {
+    let accepted = Instant::now();
+    // read the first packet from the client - this should be a handshake packet, sent promptly so
+    // clients can't hold sockets open by dripping it a byte at a time
+    let handshake_timeout = proxy.slow_clients.handshake_timeout;
+    let read = async {
+        let first = match timeout(handshake_timeout, client_stream.read_u8()).await {
+            Ok(first) => first?,
+            Err(_) => return Err(ProtocolError::Timeout(handshake_timeout).into()),
+        };
+        // clients older than 1.7 ping with a byte no handshake starts with, and can't be routed
+        if first == LEGACY_PING {
+            return Ok(None);
+        }
+        let remaining = handshake_timeout.saturating_sub(accepted.elapsed());
+        (&[first][..])
+            .chain(&mut client_stream)
+            .read_uncompressed_packet_timeout(remaining)
+            .await
+            .map(Some)
+    };
+    let handshake = match read.await {
+        Ok(Some(handshake)) => handshake,
+        Ok(None) => {
+            trace!("Dropping legacy ping from {}", peer);
+            client_stream.shutdown().await?;
+            return Ok(());
+        }
+        Err(err) => {
+            match err.downcast_ref::<ProtocolError>() {
+                Some(ProtocolError::Timeout(_)) => security::report(
+                    SecurityEvent::SlowClient,
+                    peer.ip(),
+                    format!("no handshake within {:?}", handshake_timeout),
+                ),
+                // clients which simply disconnect are not malicious
+                _ if err.downcast_ref::<std::io::Error>().is_some() => {}
+                _ => security::report(SecurityEvent::MalformedProtocol, peer.ip(), &err),
+            }
+            return Err(err.context("failed to read the handshake"));
+        }
+    };
+    if handshake.id != Handshake::ID {
         trace!("Received unexpected packet from client: {:?}", handshake.id);
+        security::report(
+            SecurityEvent::MalformedProtocol,
+            peer.ip(),
+            format!("unexpected packet {:#04x} during handshake", handshake.id),
+        );
         client_stream.shutdown().await?;
+        return Ok(());
     }
-    // read target server address
-    let mut handshake = handshake.as_cursor();
-    let protocol_version = handshake.read_var_int().await?;
-    let server_address = handshake.read_string().await?;
-    let _ = handshake.read_u16().await?;
-    let next_state: ProtocolState = handshake.read_var_int().await?.try_into()?;
+    let mut handshake = Handshake::decode(&handshake).inspect_err(|err| {
+        security::report(SecurityEvent::MalformedProtocol, peer.ip(), err);
+    })?;
 
-    // lookup target server
-    let target = proxy.routes.iter().find(|r| r.from == server_address);
-    if target.is_none() {
-        warn!("No target server found for address: {}", server_address);
+    // clients behind an anti-DDoS provider are known by the address in its signed payload
+    let mut peer = peer;
+    if let Some(real_ip) = &proxy.real_ip {
+        match realip::extract(&handshake.server_address, real_ip) {
+            Ok(Some((hostname, client))) => {
+                trace!("Client {} is connecting through {}", client, peer);
+                handshake.server_address = hostname;
+                peer = client;
+                Span::current().record("peer", field::display(peer));
+            }
+            Ok(None) if real_ip.required => {
+                security::report(
+                    SecurityEvent::Denied,
+                    peer.ip(),
+                    "connected without a RealIP payload",
+                );
+                client_stream.shutdown().await?;
+                return Ok(());
+            }
+            Ok(None) => {}
+            Err(err) => {
+                security::report(SecurityEvent::AuthFailure, peer.ip(), &err);
+                client_stream.shutdown().await?;
+                return Ok(());
+            }
+        }
+    }
+    let domain = route_domain(&handshake.server_address).to_string();
+    let handshake_time = accepted.elapsed();
+    events::emit(Event::Join {
+        connection_id: connection_id.clone(),
+        peer,
+        listener: proxy.listen_addr,
+        domain: domain.clone(),
+        protocol_version: handshake.protocol_version,
+    });
+
+    // turn away clients connecting faster than the listener allows
+    if !ratelimit::allow(
+        &proxy.rate_limits,
+        proxy.listen_addr,
+        peer.ip(),
+        handshake.next_state,
+    ) {
+        security::report(
+            SecurityEvent::RateLimited,
+            peer.ip(),
+            format!(
+                "{:?} rate limit of {}",
+                handshake.next_state, proxy.listen_addr
+            ),
+        );
+        // only known domains are counted, so clients can't create metric labels at will
+        if let Some(route) = proxy.route(&domain) {
+            metrics::close(&route.from, CloseReason::RateLimited);
+        }
+        return match handshake.next_state {
+            ProtocolState::Status => {
+                client_stream.shutdown().await?;
+                Ok(())
+            }
+            _ => {
+                reply::reject(
+                    &mut client_stream,
+                    &handshake,
+                    "You are connecting too fast - try again shortly!",
+                    "",
+                    Players::default(),
+                    None,
+                )
+                .await
+            }
+        };
+    }
+
+    // throttle logins from each IP, as vanilla servers would
+    let login_permit = match handshake.next_state {
+        ProtocolState::Status => None,
+        _ => match ratelimit::throttle_login(&proxy.rate_limits, proxy.listen_addr, peer.ip()) {
+            Ok(permit) => Some(permit),
+            Err(throttled) => {
+                security::report(
+                    SecurityEvent::RateLimited,
+                    peer.ip(),
+                    format!("login throttle of {} ({:?})", proxy.listen_addr, throttled),
+                );
+                if let Some(route) = proxy.route(&domain) {
+                    metrics::close(&route.from, CloseReason::RateLimited);
+                }
+                let message = match throttled {
+                    Throttled::TooSoon => "Connection throttled! Please wait before reconnecting.",
+                    Throttled::TooManyLogins => {
+                        "Too many players are logging in from your address - try again shortly!"
+                    }
+                };
+                return reply::reject(
+                    &mut client_stream,
+                    &handshake,
+                    message,
+                    "",
+                    Players::default(),
+                    None,
+                )
+                .await;
+            }
+        },
+    };
+
+    // shed status connections while sockets are scarce, so those left go to logins
+    if handshake.next_state == ProtocolState::Status && limits::is_shedding() {
+        trace!("Shedding status connection from {}", peer);
         client_stream.shutdown().await?;
         return Ok(());
     }
-    let target = &target.unwrap().to[rand::thread_rng().gen_range(0..target.unwrap().to.len())];
 
-    // create a new connection to the target server
-    let mut server_stream = TcpStream::connect(target).await?;
+    // shed load close to the memory limit - status connections first, then logins
+    match (memory::pressure(), handshake.next_state) {
+        (Pressure::High | Pressure::Critical, ProtocolState::Status) => {
+            trace!("Shedding status connection from {}", peer);
+            client_stream.shutdown().await?;
+            return Ok(());
+        }
+        (Pressure::Critical, _) => {
+            info!("Memory usage is over the limit - rejecting {}", peer);
+            return reply::reject(
+                &mut client_stream,
+                &handshake,
+                "This server is overloaded right now - try again later!",
+                "",
+                Players::default(),
+                None,
+            )
+            .await;
+        }
+        _ => {}
+    }
+
+    // lookup target server
+    let lookup_started = Instant::now();
+    let target = proxy
+        .find_route(&domain)
+        .or_else(|| match &proxy.fallback_method {
+            // unknown domains may be sent to a designated route instead
+            FallbackMethod::Route(fallback) => proxy.route(fallback).map(|r| (r, None)),
+            _ => None,
+        });
+    if target.is_none() {
+        warn!("No target server found for address: {}", domain);
+        return match &proxy.fallback_method {
+            FallbackMethod::Status(message) => {
+                let message = serde_json::to_value(message)?;
+                reply::reject_component(&mut client_stream, &handshake, &message).await
+            }
+            _ => {
+                client_stream.shutdown().await?;
+                Ok(())
+            }
+        };
+    }
+    let (route, name) = target.unwrap();
+    Span::current().record("route", route.from.as_str());
+    // only matched connections are recorded, so clients can't create metric labels at will
+    metrics::observe(Timing::Handshake, &route.from, handshake_time);
+    metrics::observe(Timing::RouteLookup, &route.from, lookup_started.elapsed());
+    let mut targets = &route.to;
+    let mut in_limbo = false;
+
+    // turn away clients whose hostname doesn't point here, such as scanners guessing domains
+    if let Some(verification) = &route.dns_verification {
+        if !dns::verify(&domain, verification).await {
+            security::report(
+                SecurityEvent::Denied,
+                peer.ip(),
+                format!("{} does not resolve to this proxy", domain),
+            );
+            client_stream.shutdown().await?;
+            return Ok(());
+        }
+    }
+
+    // turn clients away outside of the route's hours
+    if let Some(schedule) = route
+        .schedule
+        .as_ref()
+        .filter(|schedule| !schedule.is_open())
+    {
+        trace!("Route {} is closed - rejecting {}", route.from, peer);
+        return reply::reject(
+            &mut client_stream,
+            &handshake,
+            localized(route, &schedule.message),
+            localized(route, &schedule.motd),
+            Players::default(),
+            status::favicon(&route.to).as_deref(),
+        )
+        .await;
+    }
+
+    // apply the route's VPN policy
+    if let (VpnPolicy::Deny | VpnPolicy::Flag | VpnPolicy::Limbo(_), Some(reputation)) =
+        (&route.vpn_policy, &services.reputation)
+    {
+        if reputation.is_vpn(peer.ip()).await {
+            events::emit(Event::Flag {
+                connection_id: connection_id.clone(),
+                peer,
+                domain: route.from.clone(),
+                reason: "vpn".to_string(),
+            });
+            match &route.vpn_policy {
+                VpnPolicy::Deny => {
+                    info!("Denied VPN client {} for {}", peer, route.from);
+                    security::report(
+                        SecurityEvent::Denied,
+                        peer.ip(),
+                        format!("vpn client for {}", route.from),
+                    );
+                    return deny(&services, client_stream).await;
+                }
+                VpnPolicy::Limbo(limbo) => {
+                    info!("Routing VPN client {} for {} to limbo", peer, route.from);
+                    targets = limbo;
+                    in_limbo = true;
+                }
+                _ => warn!("VPN client {} connected to {}", peer, route.from),
+            }
+        }
+    }
+    // allocate a game server for the connection, if the route is backed by a fleet
+    let allocation = match route.agones.as_ref().filter(|_| !in_limbo) {
+        Some(agones) => match agones::allocate(agones).await {
+            Ok(allocation) => Some(allocation),
+            Err(err) => {
+                warn!("Failed to allocate a game server for {}: {:#}", peer, err);
+                return reply::reject(
+                    &mut client_stream,
+                    &handshake,
+                    "No servers are available right now - try again later!",
+                    "",
+                    Players::default(),
+                    status::favicon(&route.to).as_deref(),
+                )
+                .await;
+            }
+        },
+        None => None,
+    };
+    // players whose backend failed get their place back, if they return within the grace period
+    let resumed = match handshake.next_state {
+        ProtocolState::Status => None,
+        _ => resume::take(peer.ip(), &route.from),
+    };
+    let target = match (&allocation, &name, &route.resolver) {
+        (Some(allocation), _, _) => allocation.address,
+        (None, Some(name), Some(resolver)) if !in_limbo => match resolver.resolve(name).await {
+            Ok(target) => target,
+            Err(err) => {
+                warn!("No target server found for {}: {:#}", name, err);
+                client_stream.shutdown().await?;
+                return Ok(());
+            }
+        },
+        _ => match transfer::take(peer.ip(), &route.from).filter(|_| !in_limbo) {
+            // players transferred by an operator go to the backend they chose
+            Some(target) => target,
+            None => {
+                let targets = drain::exclude(match in_limbo {
+                    true => health::healthy_targets(targets),
+                    false => health::tiered_targets(route),
+                });
+                match resumed.filter(|target| targets.contains(target)) {
+                    Some(target) => target,
+                    None => targets[rand::thread_rng().gen_range(0..targets.len())],
+                }
+            }
+        },
+    };
+    events::emit(Event::Route {
+        connection_id: connection_id.clone(),
+        peer,
+        domain: route.from.clone(),
+        target,
+    });
+
+    let handshake = rewrite_hostname(route, &proxy, name.as_deref(), peer, handshake);
+
+    // answer status pings from the cache, if the route has one
+    if let (ProtocolState::Status, Some(ttl)) = (handshake.next_state, route.status_cache) {
+        return cached_status(route, &services, target, handshake, ttl, client_stream).await;
+    }
+
+    // create a new connection to the target server, through the tunnel if required
+    let started = Instant::now();
+    let session = Arc::new(Session {
+        connection_id: connection_id.clone(),
+        route: route.from.clone(),
+        login_permit: Mutex::new(login_permit),
+        ..Default::default()
+    });
+    let login = handshake.next_state == ProtocolState::Login;
+    let registration = match registry::register(
+        peer,
+        route.from.clone(),
+        target,
+        login,
+        // the slots held for other players can't be taken
+        route
+            .max_players
+            .filter(|_| login)
+            .map(|max| max.saturating_sub(resume::reserved(&route.from))),
+        route.tenant.as_ref(),
+        session.clone(),
+    ) {
+        Ok(registration) => registration,
+        Err(rejection) => {
+            let message = match (rejection, &route.tenant) {
+                (Rejection::RouteFull, _) | (_, None) => {
+                    info!("Route {} is full - rejecting {}", route.from, peer);
+                    &route.full_message
+                }
+                (rejection, Some(tenant)) => {
+                    info!(
+                        "Tenant {} of {} is over its limits ({:?}) - rejecting {}",
+                        tenant.name, route.from, rejection, peer
+                    );
+                    &tenant.message
+                }
+            };
+            return reply::reject(
+                &mut client_stream,
+                &handshake,
+                localized(route, message),
+                "",
+                Players::default(),
+                status::favicon(&route.to).as_deref(),
+            )
+            .await;
+        }
+    };
+    let result = async {
+        match route.tunnel {
+            true => {
+                let edge = services
+                    .edge
+                    .as_ref()
+                    .context("tunneled route without a tunnel edge")?;
+                let connect_started = Instant::now();
+                let server_stream = edge.open(target).await;
+                metrics::observe(
+                    Timing::BackendConnect,
+                    &route.from,
+                    connect_started.elapsed(),
+                );
+                registry::record_backend(
+                    target,
+                    server_stream.as_ref().err().map(|err| format!("{:#}", err)),
+                );
+                let server_stream = server_stream?;
+                connect(
+                    route,
+                    handshake,
+                    proxy.slow_clients.min_receive_rate,
+                    services.authenticator.as_deref(),
+                    session.clone(),
+                    client_stream,
+                    server_stream,
+                )
+                .await
+            }
+            false => {
+                let connect_started = Instant::now();
+                let server_stream = match (login, route.status_pool) {
+                    // pooled sockets are connected before the client is known
+                    _ if route.transparent => connect_transparent(target, peer).await,
+                    (false, size) if size > 0 => pool::connect(target, size).await,
+                    _ => TcpStream::connect(target).await,
+                };
+                metrics::observe(
+                    Timing::BackendConnect,
+                    &route.from,
+                    connect_started.elapsed(),
+                );
+                registry::record_backend(
+                    target,
+                    server_stream.as_ref().err().map(|err| err.to_string()),
+                );
+                if let Err(err) = &server_stream {
+                    if limits::is_resource_exhaustion(err) {
+                        limits::exhausted(Operation::Connect, err);
+                    }
+                }
+                let server_stream = match (server_stream, &route.wake) {
+                    (Err(err), Some(wake)) => {
+                        info!("Backend {} for {} is down: {}", target, route.from, err);
+                        wake.start(&route.from, target);
+                        let server_stream = match login {
+                            true => wake.wait(target).await,
+                            false => Err(anyhow!("backend {} is starting", target)),
+                        };
+                        match server_stream {
+                            Ok(server_stream) => server_stream,
+                            Err(err) => {
+                                reply::reject(
+                                    &mut client_stream,
+                                    &handshake,
+                                    localized(route, &wake.message),
+                                    localized(route, &wake.motd),
+                                    Players::default(),
+                                    status::favicon(&route.to).as_deref(),
+                                )
+                                .await?;
+                                return Err(err);
+                            }
+                        }
+                    }
+                    (server_stream, _) => server_stream?,
+                };
+                check_loop(&route.from, &server_stream)?;
+                connect(
+                    route,
+                    handshake,
+                    proxy.slow_clients.min_receive_rate,
+                    services.authenticator.as_deref(),
+                    session.clone(),
+                    client_stream,
+                    server_stream,
+                )
+                .await
+            }
+        }
+    }
+    .await;
+    drop(registration);
+    if let Some(err @ ProtocolError::TooSlow { .. }) = result
+        .as_ref()
+        .err()
+        .and_then(|err| err.downcast_ref::<ProtocolError>())
+    {
+        security::report(SecurityEvent::SlowClient, peer.ip(), err);
+    }
+    // failures before the bridge is created are the backend's, as the client has only been read
+    let reason = match &result {
+        Ok(reason) => *reason,
+        Err(err) => err
+            .downcast_ref::<CloseReason>()
+            .copied()
+            .unwrap_or(CloseReason::BackendUnreachable),
+    };
+    let detail = match (&result, session.closed_by.get()) {
+        (_, Some((_, closed_by))) => closed_by.clone(),
+        (Ok(reason), None) => reason.to_string(),
+        (Err(err), None) => format!("{:#}", err),
+    };
+    debug!("Connection closed ({}): {}", reason.label(), detail);
+    metrics::close(&route.from, reason);
+    if let (CloseReason::ServerEof | CloseReason::BackendUnreachable, true, Some(grace)) =
+        (reason, session.username.get().is_some(), route.resume_grace)
+    {
+        debug!("Holding {}'s place for {:?}", peer, grace);
+        resume::reserve(peer.ip(), &route.from, target, grace);
+    }
+    if let (Some(agones), Some(allocation)) = (&route.agones, allocation) {
+        agones::release(agones, allocation).await;
+    }
+
+    events::emit(Event::Leave {
+        connection_id,
+        peer,
+        domain: route.from.clone(),
+        target,
+        username: session.username.get().cloned(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        bytes_upstream: session.upstream.load(Ordering::Relaxed),
+        bytes_downstream: session.downstream.load(Ordering::Relaxed),
+        close_reason: reason,
+        reason: detail,
+    });
+    result.map(|_| ())
+}
+
+/// Rewrite the hostname of a handshake before it is sent to a backend of the route.
+fn rewrite_hostname(
+    route: &Route,
+    proxy: &Proxy,
+    name: Option<&str>,
+    peer: SocketAddr,
+    handshake: Handshake,
+) -> Handshake {
+    let hostname = match route.offline_mode {
+        true => strip_forwarding(&handshake.server_address),
+        false => handshake.server_address.clone(),
+    };
+    let server_address = match &route.hostname_rewrite {
+        HostnameRewrite::Listener => proxy.listen_addr.ip().to_string(),
+        HostnameRewrite::Preserve => {
+            return Handshake {
+                server_address: hostname,
+                ..handshake
+            }
+        }
+        HostnameRewrite::Fixed(hostname) => hostname.clone(),
+        HostnameRewrite::Template(template) => template
+            .replace("{host}", &hostname)
+            .replace("{name}", name.unwrap_or_default())
+            .replace("{ip}", &peer.ip().to_string()),
+    };
+    Handshake {
+        server_address,
+        server_port: proxy.listen_addr.port(),
+        ..handshake
+    }
+}
+
+/// The domain a hostname is routed by. Clients may append data to their hostname after a NUL, such
+/// as the markers of Forge clients or the encrypted data of Floodgate, and clients resolving SRV
+/// records may send it fully qualified, with a trailing dot.
+fn route_domain(hostname: &str) -> &str {
+    let domain = hostname.split('\0').next().unwrap_or_default();
+    domain.strip_suffix('.').unwrap_or(domain)
+}
+
+/// Remove the BungeeCord forwarding data a client may have put in its hostname, which offline
+/// backends trust as the player's address, uuid and profile. The markers of Forge clients are kept.
+fn strip_forwarding(hostname: &str) -> String {
+    let mut parts = hostname.split('\0');
+    let mut stripped = parts.next().unwrap_or_default().to_string();
+    for marker in parts.filter(|part| part.starts_with("FML") || part.starts_with("FORGE")) {
+        stripped.push('\0');
+        stripped.push_str(marker);
+        // older markers are terminated, such as `\0FML2\0`
+        if marker.starts_with("FML") {
+            stripped.push('\0');
+        }
+    }
+    stripped
+}
+
+/// Answer a status ping from the route's status cache. The response is fetched from the target
+/// if none is cached, and refreshed in the background once it is older than the TTL.
+async fn cached_status<C: Stream>(
+    route: &Route,
+    services: &Services,
+    target: SocketAddr,
+    handshake: Handshake,
+    ttl: Duration,
+    mut client_stream: C,
+) -> Result<()> {
+    let version = handshake.protocol_version;
+    let json = match status::lookup(&route.from, version, ttl) {
+        Lookup::Fresh(json) | Lookup::Refreshing(json) => json,
+        Lookup::Stale(json) => {
+            // serve the stale response now, and refresh it for the next ping
+            let (domain, tunnel, pool_size) = (route.from.clone(), route.tunnel, route.status_pool);
+            let services = services.clone();
+            tokio::task::spawn(
+                async move {
+                    match fetch_status(&services, &domain, tunnel, pool_size, target, &handshake)
+                        .await
+                    {
+                        Ok(json) => status::store(&domain, version, json),
+                        Err(err) => {
+                            debug!("Failed to refresh the status of {}: {:#}", domain, err);
+                            status::refresh_failed(&domain, version);
+                        }
+                    }
+                }
+                .in_current_span(),
+            );
+            json
+        }
+        Lookup::Missing => {
+            let json = fetch_status(
+                services,
+                &route.from,
+                route.tunnel,
+                route.status_pool,
+                target,
+                &handshake,
+            )
+            .await?;
+            status::store(&route.from, version, json.clone());
+            json
+        }
+    };
+    // status responses show the route's own player limit, rather than the server's
+    let json = match route.max_players {
+        Some(max) => {
+            let online = registry::logins(&route.from);
+            reply::override_players(&json, Players { online, max })?
+        }
+        None => json,
+    };
+    let json = match &route.motds {
+        Some(motds) => reply::override_description(&json, motd::pick(&route.from, motds))?,
+        None => json,
+    };
+    let json = match route.player_sample {
+        PlayerSample::Pass => json,
+        ref sample => reply::override_sample(&json, sample)?,
+    };
+    let json = match route.enforce_secure_chat {
+        Some(enforced) => reply::override_secure_chat(&json, enforced)?,
+        None => json,
+    };
+    let json = match status::favicon(&route.to) {
+        Some(favicon) => reply::default_favicon(&json, &favicon)?,
+        None => json,
+    };
+    reply::answer_status(&mut client_stream, json).await
+}
+
+/// Fetch a status response from a target, through the tunnel if required.
+async fn fetch_status(
+    services: &Services,
+    domain: &str,
+    tunnel: bool,
+    pool_size: usize,
+    target: SocketAddr,
+    handshake: &Handshake,
+) -> Result<String> {
+    let result = match tunnel {
+        true => {
+            let edge = services
+                .edge
+                .as_ref()
+                .context("tunneled route without a tunnel edge")?;
+            match edge.open(target).await {
+                Ok(stream) => status::fetch(stream, handshake).await,
+                Err(err) => Err(err),
+            }
+        }
+        false => {
+            let stream = match pool_size {
+                0 => TcpStream::connect(target).await,
+                size => pool::connect(target, size).await,
+            };
+            if let Err(err) = &stream {
+                if limits::is_resource_exhaustion(err) {
+                    limits::exhausted(Operation::Connect, err);
+                }
+            }
+            match stream {
+                Ok(stream) => match check_loop(domain, &stream) {
+                    Ok(()) => status::fetch(stream, handshake).await,
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err.into()),
+            }
+        }
+    };
+    registry::record_backend(
+        target,
+        result.as_ref().err().map(|err| format!("{:#}", err)),
+    );
+    result
+}
+
+/// Translate a message shown before the client has declared its locale into the route's fallback
+/// locale.
+fn localized<'a>(route: &'a Route, message: &'a str) -> &'a str {
+    route
+        .messages
+        .as_ref()
+        .map_or(message, |messages| messages.translate(None, message))
+}
+
+/// Close a denied connection, holding it in the tarpit first if enabled.
+async fn deny<C: Stream>(services: &Services, mut client_stream: C) -> Result<()> {
+    if let Some(tarpit) = &services.tarpit {
+        tarpit.hold(&mut client_stream).await;
+    }
+    client_stream.shutdown().await?;
+    Ok(())
+}
+
+/// Authenticate the login if the route requires it, then forward the handshake to the server, and
+/// bridge the client and server streams until the bridge closes, returning why.
+async fn connect<C: Stream, S: Stream>(
+    route: &Route,
+    handshake: Handshake,
+    min_receive_rate: u32,
+    authenticator: Option<&Authenticator>,
+    session: Arc<Session>,
+    client_stream: C,
+    server_stream: S,
+) -> Result<CloseReason> {
+    // the backend hears nothing of the client until it is authenticated
+    if route.authenticate && handshake.next_state == ProtocolState::Login {
+        let authenticator =
+            authenticator.context("authenticating route without an authenticator")?;
+        let version = ProtocolVersion(handshake.protocol_version);
+        let client_stream = authenticator.login(route, version, client_stream).await?;
+        return forward(
+            route,
+            handshake,
+            min_receive_rate,
+            session,
+            client_stream,
+            server_stream,
+        )
+        .await;
+    }
+    forward(
+        route,
+        handshake,
+        min_receive_rate,
+        session,
+        client_stream,
+        server_stream,
+    )
+    .await
+}
 
-    // write handshake packet to server
-    server_stream.write_var_int(0x00).await?;
-    server_stream.write_var_int(protocol_version).await?;
+/// Forward the handshake to the server, and bridge the client and server streams until the bridge
+/// closes, returning why.
+async fn forward<C: Stream, S: Stream>(
+    route: &Route,
+    handshake: Handshake,
+    min_receive_rate: u32,
+    session: Arc<Session>,
+    client_stream: C,
+    mut server_stream: S,
+) -> Result<CloseReason> {
     server_stream
-        .write_string(proxy.listen_addr.ip().to_string())
+        .write_uncompressed_packet(&handshake.encode()?)
         .await?;
-    server_stream.write_u16(proxy.listen_addr.port()).await?;
-    server_stream.write_var_int((&next_state).into()).await?;
 
-    // create bridge
-    bridge::create(next_state, client_stream, server_stream).await
+    // status responses show the route's own player limit, rather than the server's
+    let players = route.max_players.map(|max| Players {
+        online: registry::logins(&route.from),
+        max,
+    });
+    // and the favicon last fetched by a health check, if the server has none of its own
+    let favicon = match handshake.next_state {
+        ProtocolState::Status => status::favicon(&route.to),
+        _ => None,
+    };
+
+    let options = BridgeOptions {
+        players,
+        favicon,
+        min_receive_rate,
+        ..BridgeOptions::from(route)
+    };
+    bridge::create(
+        handshake.next_state,
+        ProtocolVersion(handshake.protocol_version),
+        options,
+        session,
+        client_stream,
+        server_stream,
+    )
+    .await
 }
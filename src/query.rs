@@ -0,0 +1,198 @@
+//! Defines a responder for the GameSpy4 (GS4) UDP query protocol.
+//!
+//! Many server lists and hosting panels still probe the query protocol, rather than the status
+//! ping. The responder answers on the same port as its proxy, with player counts aggregated over
+//! the proxy's routes.
+//!
+//! Clients first request a challenge token, which they must echo in their stat requests. Tokens
+//! are derived from the client's address and the current 30 second window with a secret key, so
+//! the responder keeps no per-client state, and spoofed requests can't amplify traffic.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use tokio::{net::UdpSocket, sync::watch};
+use tracing::{info, trace};
+
+use crate::{config::Proxy, registry};
+
+/// The magic bytes prefixing every request.
+const MAGIC: [u8; 2] = [0xFE, 0xFD];
+/// The packet type of a handshake, which requests a challenge token.
+const HANDSHAKE: u8 = 0x09;
+/// The packet type of a stat request.
+const STAT: u8 = 0x00;
+/// How long a challenge token is valid for, in seconds.
+const TOKEN_WINDOW: u64 = 30;
+
+/// Answer queries for the given proxy until the socket fails.
+#[tracing::instrument(name = "query", skip_all, fields(addr = %proxies.borrow().listen_addr))]
+pub async fn serve(proxies: watch::Receiver<Arc<Proxy>>) -> Result<()> {
+    let listen_addr = proxies.borrow().listen_addr;
+    let socket = UdpSocket::bind(listen_addr)
+        .await
+        .context("failed to bind query socket")?;
+    let tokens = Tokens::default();
+    info!("Started query responder");
+
+    let mut buf = [0u8; 64];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        let proxy = proxies.borrow().clone();
+        if let Some(response) = respond(&proxy, &tokens, peer, &buf[..n]) {
+            // dropped responses are retried by the client
+            let _ = socket.send_to(&response, peer).await;
+        }
+    }
+}
+
+/// Build the response to a request, if it is valid.
+fn respond(proxy: &Proxy, tokens: &Tokens, peer: SocketAddr, request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < 7 || request[..2] != MAGIC {
+        return None;
+    }
+    let kind = request[2];
+    let session_id = &request[3..7];
+    let mut response = vec![kind];
+    response.extend_from_slice(session_id);
+
+    match kind {
+        HANDSHAKE => {
+            trace!("Issuing query challenge to {}", peer);
+            response.extend_from_slice(tokens.issue(peer.ip()).to_string().as_bytes());
+            response.push(0);
+        }
+        STAT => {
+            let token = i32::from_be_bytes(request.get(7..11)?.try_into().ok()?);
+            if !tokens.verify(peer.ip(), token) {
+                trace!("Rejected query from {} with an invalid challenge", peer);
+                return None;
+            }
+            // full stat requests are padded to 15 bytes
+            match request.len() >= 15 {
+                true => write_full_stat(proxy, &mut response),
+                false => write_basic_stat(proxy, &mut response),
+            }
+        }
+        _ => return None,
+    }
+    Some(response)
+}
+
+/// The players and limit of a proxy, aggregated over its routes.
+struct Summary {
+    motd: String,
+    players: Vec<String>,
+    online: usize,
+    max: usize,
+}
+
+impl Summary {
+    fn of(proxy: &Proxy) -> Self {
+        let mut players = vec![];
+        let mut online = 0;
+        for route in &proxy.routes {
+            online += registry::logins(&route.from);
+            players.extend(registry::usernames(&route.from));
+        }
+        Self {
+            motd: proxy.query_motd.clone(),
+            players,
+            online,
+            max: proxy
+                .routes
+                .iter()
+                .filter_map(|route| route.max_players)
+                .sum(),
+        }
+    }
+}
+
+/// Write a null-terminated string.
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(0);
+}
+
+/// Write a basic stat response.
+fn write_basic_stat(proxy: &Proxy, buf: &mut Vec<u8>) {
+    let summary = Summary::of(proxy);
+    put_str(buf, &summary.motd);
+    put_str(buf, "SMP");
+    put_str(buf, "world");
+    put_str(buf, &summary.online.to_string());
+    put_str(buf, &summary.max.to_string());
+    buf.extend_from_slice(&proxy.listen_addr.port().to_le_bytes());
+    put_str(buf, &proxy.listen_addr.ip().to_string());
+}
+
+/// Write a full stat response.
+fn write_full_stat(proxy: &Proxy, buf: &mut Vec<u8>) {
+    let summary = Summary::of(proxy);
+    // constant padding, which clients ignore
+    buf.extend_from_slice(b"splitnum\0\x80\0");
+    for (key, value) in [
+        ("hostname", summary.motd.as_str()),
+        ("gametype", "SMP"),
+        ("game_id", "MINECRAFT"),
+        ("version", "Magma"),
+        ("plugins", ""),
+        ("map", "world"),
+        ("numplayers", &summary.online.to_string()),
+        ("maxplayers", &summary.max.to_string()),
+        ("hostport", &proxy.listen_addr.port().to_string()),
+        ("hostip", &proxy.listen_addr.ip().to_string()),
+    ] {
+        put_str(buf, key);
+        put_str(buf, value);
+    }
+    buf.push(0);
+    buf.extend_from_slice(b"\x01player_\0\0");
+    for player in &summary.players {
+        put_str(buf, player);
+    }
+    buf.push(0);
+}
+
+/// Issues and verifies stateless challenge tokens.
+#[derive(Default)]
+struct Tokens {
+    key: RandomState,
+}
+
+impl Tokens {
+    /// The token for the given address in the given window.
+    fn token(&self, ip: IpAddr, window: u64) -> i32 {
+        let mut hasher = self.key.build_hasher();
+        ip.hash(&mut hasher);
+        window.hash(&mut hasher);
+        // tokens are sent as decimal strings, and must parse as a positive i32
+        (hasher.finish() & 0x7FFF_FFFF) as i32
+    }
+
+    /// The current token window.
+    fn window() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / TOKEN_WINDOW
+    }
+
+    /// Issue a token to the given address.
+    fn issue(&self, ip: IpAddr) -> i32 {
+        self.token(ip, Self::window())
+    }
+
+    /// Verify a token from the given address, accepting tokens from the previous window.
+    fn verify(&self, ip: IpAddr, token: i32) -> bool {
+        let window = Self::window();
+        token == self.token(ip, window) || token == self.token(ip, window.saturating_sub(1))
+    }
+}
@@ -0,0 +1,171 @@
+//! Defines rate limits, which turn away clients connecting to a listener faster than it allows.
+//!
+//! Status pings and logins are limited separately - server list refreshes legitimately ping every
+//! few seconds, while a client logging in that often is most likely a bot. Each is limited per
+//! source IP and across the listener, by token buckets which refill continuously at the configured
+//! rate per minute, and hold up to a minute's worth.
+//!
+//! Logins can also be throttled like vanilla and BungeeCord do, for backends which expect their
+//! proxy to - each IP may only try to log in once per throttle, and have a limited number of logins
+//! in progress at once.
+//!
+//! Buckets and login attempts are kept in bounded tables, which forget the least recently used
+//! entry when full, so a flood from many addresses costs the same to check as one from a few.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use lru::LruCache;
+
+use crate::{bridge::ProtocolState, config::RateLimits};
+
+/// How many buckets, and login attempts, are kept before the least recently used are forgotten.
+const MAX_ENTRIES: usize = 65536;
+
+/// A table of at most [MAX_ENTRIES] entries.
+fn bounded<K: Hash + Eq, V>() -> Mutex<LruCache<K, V>> {
+    Mutex::new(LruCache::new(NonZeroUsize::new(MAX_ENTRIES).unwrap()))
+}
+
+/// A token bucket, of which each connection takes one token.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refill the bucket for the time since it was last updated, returning its tokens.
+    fn refill(&mut self, per_minute: u32) -> f64 {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.updated).as_secs_f64() * per_minute as f64 / 60.0;
+        self.tokens = (self.tokens + refilled).min(per_minute as f64);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+/// A bucket's listener, source IP if it limits a single IP, and whether it limits logins.
+type Key = (SocketAddr, Option<IpAddr>, bool);
+
+/// The buckets of every listener.
+fn buckets() -> &'static Mutex<LruCache<Key, Bucket>> {
+    static BUCKETS: OnceLock<Mutex<LruCache<Key, Bucket>>> = OnceLock::new();
+    BUCKETS.get_or_init(bounded)
+}
+
+/// Test whether a connection to a listener may proceed, given the state it asked for in its
+/// handshake - taking a token from each of its buckets if so.
+pub fn allow(limits: &RateLimits, listener: SocketAddr, ip: IpAddr, state: ProtocolState) -> bool {
+    let login = state != ProtocolState::Status;
+    let limit = match login {
+        true => limits.login,
+        false => limits.status,
+    };
+    let buckets_of = [
+        (limit.per_ip, (listener, Some(ip), login)),
+        (limit.per_listener, (listener, None, login)),
+    ];
+
+    let mut buckets = buckets().lock().unwrap();
+    // check every bucket before taking from any, so connections turned away cost nothing
+    let allowed = buckets_of.iter().all(|(per_minute, key)| match per_minute {
+        Some(per_minute) => {
+            let bucket = buckets.get_or_insert_mut(*key, || Bucket {
+                tokens: *per_minute as f64,
+                updated: Instant::now(),
+            });
+            bucket.refill(*per_minute) >= 1.0
+        }
+        None => true,
+    });
+    if allowed {
+        for (_, key) in buckets_of
+            .iter()
+            .filter(|(per_minute, _)| per_minute.is_some())
+        {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+    }
+    allowed
+}
+
+/// A listener and source IP.
+type IpKey = (SocketAddr, IpAddr);
+
+/// When each IP last tried to log in to each listener.
+fn attempts() -> &'static Mutex<LruCache<IpKey, Instant>> {
+    static ATTEMPTS: OnceLock<Mutex<LruCache<IpKey, Instant>>> = OnceLock::new();
+    ATTEMPTS.get_or_init(bounded)
+}
+
+/// The number of logins in progress from each IP to each listener.
+fn logging_in() -> &'static Mutex<HashMap<IpKey, usize>> {
+    static LOGGING_IN: OnceLock<Mutex<HashMap<IpKey, usize>>> = OnceLock::new();
+    LOGGING_IN.get_or_init(Default::default)
+}
+
+/// Why a login was throttled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttled {
+    /// The IP tried to log in again too soon.
+    TooSoon,
+    /// The IP already has as many logins in progress as allowed.
+    TooManyLogins,
+}
+
+/// A login in progress, counted against its IP's concurrent logins until it is dropped.
+#[derive(Debug)]
+pub struct LoginPermit {
+    key: Option<IpKey>,
+}
+
+impl Drop for LoginPermit {
+    fn drop(&mut self) {
+        let Some(key) = self.key else {
+            return;
+        };
+        let mut logging_in = logging_in().lock().unwrap();
+        if let Some(count) = logging_in.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                logging_in.remove(&key);
+            }
+        }
+    }
+}
+
+/// Test whether an IP may start logging in to a listener, like the connection throttle of vanilla
+/// and BungeeCord - an attempt within the throttle of the last one is refused, and restarts it, so
+/// reconnect macros stay throttled until they back off. Returns a permit to hold until the client
+/// has logged in, if it may.
+pub fn throttle_login(
+    limits: &RateLimits,
+    listener: SocketAddr,
+    ip: IpAddr,
+) -> Result<LoginPermit, Throttled> {
+    let key = (listener, ip);
+    if let Some(throttle) = limits.login_throttle {
+        let last = attempts().lock().unwrap().put(key, Instant::now());
+        if last.is_some_and(|last| last.elapsed() < throttle) {
+            return Err(Throttled::TooSoon);
+        }
+    }
+    let Some(max) = limits.max_concurrent_logins else {
+        return Ok(LoginPermit { key: None });
+    };
+    let mut logging_in = logging_in().lock().unwrap();
+    let count = logging_in.entry(key).or_default();
+    if *count >= max {
+        return Err(Throttled::TooManyLogins);
+    }
+    *count += 1;
+    Ok(LoginPermit { key: Some(key) })
+}
@@ -0,0 +1,209 @@
+//! Defines the RCON protocol, and a proxy for RCON sessions.
+//!
+//! The proxy lets operators reach backend consoles through the same public address as the game.
+//! Clients authenticate against a password held by Magma, after which Magma logs in to the
+//! backend with the backend's own password and relays the session. Backend passwords therefore
+//! never leave the proxy.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use rand::{thread_rng, Rng};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::RconConfig,
+    secrets::{self, RconSecrets},
+    security::{self, SecurityEvent},
+    tunnel::constant_time_eq,
+};
+
+/// The packet type of a login request.
+pub const LOGIN: i32 = 3;
+/// The packet type of a command, and of a login response.
+pub const COMMAND: i32 = 2;
+/// The packet type of a command response.
+pub const RESPONSE: i32 = 0;
+/// The request id of a failed login response.
+const AUTH_FAILED: i32 = -1;
+/// The maximum length of a packet. Servers split responses into 4096 byte payloads.
+const MAX_PACKET_LENGTH: i32 = 4096 + 10;
+/// How long a client has to log in.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An RCON packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// The request id, echoed in responses.
+    pub id: i32,
+    /// The packet type.
+    pub kind: i32,
+    /// The payload.
+    pub payload: String,
+}
+
+/// Read a packet.
+pub async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Packet> {
+    let length = reader.read_i32_le().await?;
+    if !(10..=MAX_PACKET_LENGTH).contains(&length) {
+        bail!("Invalid RCON packet length {}", length);
+    }
+    let id = reader.read_i32_le().await?;
+    let kind = reader.read_i32_le().await?;
+    let mut payload = vec![0; length as usize - 8];
+    reader.read_exact(&mut payload).await?;
+    // the payload is null-terminated, and followed by an empty padding string
+    payload.truncate(payload.len() - 2);
+    Ok(Packet {
+        id,
+        kind,
+        payload: String::from_utf8_lossy(&payload).into_owned(),
+    })
+}
+
+/// Write a packet.
+pub async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: &Packet) -> Result<()> {
+    let mut buf = Vec::with_capacity(packet.payload.len() + 14);
+    buf.extend_from_slice(&(packet.payload.len() as i32 + 10).to_le_bytes());
+    buf.extend_from_slice(&packet.id.to_le_bytes());
+    buf.extend_from_slice(&packet.kind.to_le_bytes());
+    buf.extend_from_slice(packet.payload.as_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Log in to a server, returning whether the password was accepted.
+pub async fn login<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    password: &str,
+) -> Result<bool> {
+    let id = thread_rng().gen_range(1..i32::MAX);
+    write_packet(
+        stream,
+        &Packet {
+            id,
+            kind: LOGIN,
+            payload: password.to_string(),
+        },
+    )
+    .await?;
+    // some servers send an empty command response before the login response
+    loop {
+        let response = read_packet(stream).await?;
+        if response.kind == COMMAND {
+            return Ok(response.id == id);
+        }
+    }
+}
+
+/// Spawns an RCON proxy, and returns a handle to the task.
+pub fn spawn(config: RconConfig) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move { listen(config).await })
+}
+
+/// Listen for RCON clients.
+#[tracing::instrument(name = "rcon", skip_all, fields(addr = %config.listen_addr))]
+async fn listen(config: RconConfig) -> Result<()> {
+    let listener = TcpListener::bind(config.listen_addr).await.map_err(|err| {
+        error!("Error while starting RCON proxy: {}", err);
+        err
+    })?;
+    let config = Arc::new(config);
+
+    info!("Started RCON proxy");
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let config = config.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = handle_client(&config, peer, stream).await {
+                debug!("RCON session from {} failed: {:#}", peer, err);
+            }
+        });
+    }
+}
+
+/// Authenticate a client, then relay its session to a backend.
+async fn handle_client(config: &RconConfig, peer: SocketAddr, mut stream: TcpStream) -> Result<()> {
+    let login_packet = timeout(LOGIN_TIMEOUT, read_packet(&mut stream))
+        .await
+        .context("client did not log in in time")??;
+    // the passwords may have been rotated by a reload since the proxy started
+    let passwords = secrets::current()
+        .rcon
+        .get(&config.listen_addr)
+        .cloned()
+        .unwrap_or_else(|| RconSecrets {
+            password: config.password.clone(),
+            backend_password: config.backend_password.clone(),
+        });
+    if login_packet.kind != LOGIN
+        || !constant_time_eq(
+            login_packet.payload.as_bytes(),
+            passwords.password.as_bytes(),
+        )
+    {
+        security::report(
+            SecurityEvent::AuthFailure,
+            peer.ip(),
+            "invalid rcon password",
+        );
+        reply_login(&mut stream, AUTH_FAILED).await?;
+        bail!("Client sent an invalid password");
+    }
+
+    // log in to the backend before accepting the client, so failures are reported to it
+    let target = config.targets[thread_rng().gen_range(0..config.targets.len())];
+    let mut server_stream = match connect(target, &passwords.backend_password).await {
+        Ok(server_stream) => server_stream,
+        Err(err) => {
+            warn!("Failed to log in to RCON backend {}: {:#}", target, err);
+            reply_login(&mut stream, AUTH_FAILED).await?;
+            return Err(err);
+        }
+    };
+    reply_login(&mut stream, login_packet.id).await?;
+
+    info!("Relaying RCON session from {} to {}", peer, target);
+    copy_bidirectional(&mut stream, &mut server_stream).await?;
+    Ok(())
+}
+
+/// Connect and log in to a backend.
+async fn connect(target: SocketAddr, password: &str) -> Result<TcpStream> {
+    let stream = timeout(LOGIN_TIMEOUT, async {
+        let mut stream = TcpStream::connect(target).await?;
+        if !login(&mut stream, password).await? {
+            bail!("The backend rejected the password");
+        }
+        anyhow::Ok(stream)
+    })
+    .await
+    .context("backend did not respond in time")??;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Answer a client's login request.
+async fn reply_login(stream: &mut TcpStream, id: i32) -> Result<()> {
+    write_packet(
+        stream,
+        &Packet {
+            id,
+            kind: COMMAND,
+            payload: String::new(),
+        },
+    )
+    .await
+}
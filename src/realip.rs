@@ -0,0 +1,75 @@
+//! Defines RealIP payloads, which anti-DDoS providers such as TCPShield append to the handshake
+//! hostname to pass on the address of the client behind them.
+//!
+//! The payload takes the form `hostname///ip:port///timestamp///signature`, where the signature
+//! is a base64 SHA512withRSA signature of everything before it, made with the provider's private
+//! key. A payload is only trusted if its signature verifies against the provider's public key and
+//! its timestamp is recent, so clients connecting directly can neither forge an address nor replay
+//! a captured payload.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rsa::{
+    pkcs1v15::{Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+    sha2::Sha512,
+    signature::Verifier,
+    RsaPublicKey,
+};
+
+use crate::config::RealIp;
+
+/// The separator between the parts of a payload.
+const SEPARATOR: &str = "///";
+
+/// Parse the PEM-encoded public key of a provider.
+pub fn parse_key(pem: &str) -> Result<RsaPublicKey> {
+    RsaPublicKey::from_public_key_pem(pem).context("invalid RSA public key")
+}
+
+/// Extract the original hostname and the client's address from a handshake hostname, if it
+/// carries a payload. Fails if the payload is malformed, expired, or not signed by the provider.
+pub fn extract(address: &str, real_ip: &RealIp) -> Result<Option<(String, SocketAddr)>> {
+    let Some((signed, signature)) = address.rsplit_once(SEPARATOR) else {
+        return Ok(None);
+    };
+    let mut parts = signed.split(SEPARATOR);
+    let (Some(hostname), Some(client), Some(timestamp), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!("malformed RealIP payload");
+    };
+
+    let signature = STANDARD
+        .decode(signature)
+        .context("RealIP signature is not base64")?;
+    let signature =
+        Signature::try_from(signature.as_slice()).context("RealIP signature is malformed")?;
+    VerifyingKey::<Sha512>::new(real_ip.public_key.clone())
+        .verify(signed.as_bytes(), &signature)
+        .context("RealIP signature does not match")?;
+
+    // a signed payload can be replayed by anyone who saw it, so only fresh ones are accepted
+    let timestamp: u64 = timestamp
+        .parse()
+        .context("RealIP timestamp is not a number")?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if now.abs_diff(timestamp) > real_ip.max_age.as_secs() {
+        bail!("RealIP payload is {}s old", now.saturating_sub(timestamp));
+    }
+
+    // older providers send the address without a port
+    let client = client
+        .parse::<SocketAddr>()
+        .or_else(|_| client.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+        .context("RealIP address is malformed")?;
+    Ok(Some((hostname.to_string(), client)))
+}
@@ -0,0 +1,594 @@
+//! Tracks live sessions, per-route and per-tenant traffic, and the health of backends and
+//! listeners.
+//!
+//! Every bridged connection is registered for as long as it is open, so operators can see who is
+//! connected and kick them, and so route and tenant limits can be enforced. Backend health
+//! reflects the outcome of the most recent connection or probe of each backend, and listener
+//! health is reported by the supervisor restarting them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{
+    bridge::{Control, Session},
+    config::Tenant,
+};
+
+/// A live session.
+struct LiveSession {
+    peer: SocketAddr,
+    login: bool,
+    domain: String,
+    target: SocketAddr,
+    tenant: Option<String>,
+    started_at: u64,
+    session: Arc<Session>,
+}
+
+impl LiveSession {
+    /// The bytes sent in both directions so far.
+    fn bytes(&self) -> u64 {
+        self.session.upstream.load(Ordering::Relaxed)
+            + self.session.downstream.load(Ordering::Relaxed)
+    }
+}
+
+/// The traffic of sessions which have closed, per route.
+#[derive(Default)]
+struct RouteTotals {
+    tenant: Option<String>,
+    bytes_upstream: u64,
+    bytes_downstream: u64,
+}
+
+/// The traffic of a tenant's sessions which have closed, in the current month.
+#[derive(Default)]
+struct TenantUsage {
+    month: i32,
+    bytes: u64,
+}
+
+/// The current month, counted from year zero.
+fn month() -> i32 {
+    let now = OffsetDateTime::now_utc();
+    now.year() * 12 + u8::from(now.month()) as i32 - 1
+}
+
+/// The outcome of the most recent connection to a backend.
+struct BackendHealth {
+    checked_at: u64,
+    error: Option<String>,
+}
+
+/// The state of a listener, as reported by its supervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerState {
+    /// The listener is accepting connections.
+    Running,
+    /// The listener failed, and is waiting to be restarted.
+    Restarting,
+    /// The listener failed, and was given up on.
+    Failed,
+}
+
+/// The most recent state of a listener.
+struct ListenerHealth {
+    state: ListenerState,
+    restarts: u32,
+    changed_at: u64,
+    error: Option<String>,
+}
+
+/// The process-wide registry.
+#[derive(Default)]
+struct Registry {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, LiveSession>>,
+    routes: Mutex<HashMap<String, RouteTotals>>,
+    tenants: Mutex<HashMap<String, TenantUsage>>,
+    backends: Mutex<HashMap<SocketAddr, BackendHealth>>,
+    listeners: Mutex<HashMap<SocketAddr, ListenerHealth>>,
+}
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A registered session. The session is unregistered when this is dropped.
+pub struct Registration {
+    id: u64,
+}
+
+impl Registration {
+    /// The id of the session.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let registry = registry();
+        let Some(live) = registry.sessions.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+        if let Some(tenant) = &live.tenant {
+            let mut tenants = registry.tenants.lock().unwrap();
+            let usage = tenants.entry(tenant.clone()).or_default();
+            let month = month();
+            if usage.month != month {
+                *usage = TenantUsage { month, bytes: 0 };
+            }
+            usage.bytes += live.bytes();
+        }
+        let mut routes = registry.routes.lock().unwrap();
+        let totals = routes.entry(live.domain).or_default();
+        totals.tenant = live.tenant;
+        totals.bytes_upstream += live.session.upstream.load(Ordering::Relaxed);
+        totals.bytes_downstream += live.session.downstream.load(Ordering::Relaxed);
+    }
+}
+
+/// Why a session was not registered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The route has reached its player limit.
+    RouteFull,
+    /// The tenant has reached its connection limit.
+    TenantConnections,
+    /// The tenant has used its bandwidth budget for the month.
+    TenantBandwidth,
+}
+
+/// Register a live session until the returned registration is dropped.
+///
+/// Sessions which are logging in, rather than pinging the status, may be limited per domain -
+/// if the domain already has `limit` such sessions, the session is not registered. Sessions of a
+/// tenant are limited by the tenant's connection limit and bandwidth budget.
+pub fn register(
+    peer: SocketAddr,
+    domain: String,
+    target: SocketAddr,
+    login: bool,
+    limit: Option<usize>,
+    tenant: Option<&Tenant>,
+    session: Arc<Session>,
+) -> Result<Registration, Rejection> {
+    let registry = registry();
+    // count and insert under the same lock, so concurrent logins can't overshoot the limit
+    let mut sessions = registry.sessions.lock().unwrap();
+    if let Some(limit) = limit {
+        if count_logins(&sessions, &domain) >= limit {
+            return Err(Rejection::RouteFull);
+        }
+    }
+    if let Some(tenant) = tenant {
+        let live = || {
+            sessions
+                .values()
+                .filter(|live| live.tenant.as_ref() == Some(&tenant.name))
+        };
+        if let Some(max_connections) = tenant.max_connections {
+            if live().count() >= max_connections {
+                return Err(Rejection::TenantConnections);
+            }
+        }
+        if let Some(budget) = tenant.bandwidth_budget {
+            if tenant_bytes(registry, &tenant.name) + live().map(LiveSession::bytes).sum::<u64>()
+                >= budget
+            {
+                return Err(Rejection::TenantBandwidth);
+            }
+        }
+    }
+    let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+    sessions.insert(
+        id,
+        LiveSession {
+            peer,
+            login,
+            domain,
+            target,
+            tenant: tenant.map(|tenant| tenant.name.clone()),
+            started_at: now(),
+            session,
+        },
+    );
+    Ok(Registration { id })
+}
+
+/// The bytes of a tenant's closed sessions in the current month.
+fn tenant_bytes(registry: &Registry, tenant: &str) -> u64 {
+    registry
+        .tenants
+        .lock()
+        .unwrap()
+        .get(tenant)
+        .filter(|usage| usage.month == month())
+        .map(|usage| usage.bytes)
+        .unwrap_or(0)
+}
+
+/// The number of live sessions which logged in to the given domain.
+pub fn logins(domain: &str) -> usize {
+    count_logins(&registry().sessions.lock().unwrap(), domain)
+}
+
+/// The usernames of the live sessions which logged in to the given domain.
+pub fn usernames(domain: &str) -> Vec<String> {
+    registry()
+        .sessions
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|live| live.login && live.domain == domain)
+        .filter_map(|live| live.session.username.get().cloned())
+        .collect()
+}
+
+fn count_logins(sessions: &HashMap<u64, LiveSession>, domain: &str) -> usize {
+    sessions
+        .values()
+        .filter(|live| live.login && live.domain == domain)
+        .count()
+}
+
+/// Send a control to the session with the given id, returning whether it exists.
+pub fn control(id: u64, control: Control) -> bool {
+    match registry().sessions.lock().unwrap().get(&id) {
+        // the bridge may have just closed, in which case the control is moot
+        Some(live) => {
+            let _ = live.session.control.send(control);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Snapshot the live session of a logged-in player, found by username.
+pub fn player(username: &str) -> Option<SessionSnapshot> {
+    registry()
+        .sessions
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, live)| is_player(live, username))
+        .map(|(id, live)| snapshot(*id, live))
+}
+
+/// Snapshot the live session of a player logged in to the given domain, found by username.
+pub fn player_on(domain: &str, username: &str) -> Option<SessionSnapshot> {
+    registry()
+        .sessions
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, live)| live.domain == domain && is_player(live, username))
+        .map(|(id, live)| snapshot(*id, live))
+}
+
+/// Test whether a live session is a logged-in player with the given username.
+fn is_player(live: &LiveSession, username: &str) -> bool {
+    live.login
+        && live
+            .session
+            .username
+            .get()
+            .is_some_and(|name| name.eq_ignore_ascii_case(username))
+}
+
+/// The session with the given id, if it is live.
+pub fn session(id: u64) -> Option<Arc<Session>> {
+    let sessions = registry().sessions.lock().unwrap();
+    sessions.get(&id).map(|live| live.session.clone())
+}
+
+/// Send a control to every logged-in session on the given routes - or on every route, if none
+/// are given - returning how many were sent one.
+pub fn control_routes(domains: &[String], control: impl Fn() -> Control) -> usize {
+    let sessions = registry().sessions.lock().unwrap();
+    sessions
+        .values()
+        .filter(|live| live.login && (domains.is_empty() || domains.contains(&live.domain)))
+        .filter(|live| live.session.control.send(control()).is_ok())
+        .count()
+}
+
+/// Close every live status connection, returning how many were closed.
+pub fn kick_status() -> usize {
+    let sessions = registry().sessions.lock().unwrap();
+    let status = sessions.values().filter(|live| !live.login);
+    status
+        .map(|live| {
+            let reason = "shed to free memory".to_string();
+            let _ = live.session.control.send(Control::Close(reason));
+        })
+        .count()
+}
+
+/// Record the outcome of a connection to a backend.
+pub fn record_backend(target: SocketAddr, error: Option<String>) {
+    registry().backends.lock().unwrap().insert(
+        target,
+        BackendHealth {
+            checked_at: now(),
+            error,
+        },
+    );
+}
+
+/// Record a change in the state of a listener. Each time a listener is restarting counts as a
+/// restart, and errors are kept until it is running again.
+pub fn record_listener(addr: SocketAddr, state: ListenerState, error: Option<String>) {
+    let mut listeners = registry().listeners.lock().unwrap();
+    let restarts = listeners.get(&addr).map_or(0, |health| health.restarts);
+    listeners.insert(
+        addr,
+        ListenerHealth {
+            state,
+            restarts: restarts + (state == ListenerState::Restarting) as u32,
+            changed_at: now(),
+            error,
+        },
+    );
+}
+
+/// Forget a listener which was stopped.
+pub fn remove_listener(addr: SocketAddr) {
+    registry().listeners.lock().unwrap().remove(&addr);
+}
+
+/// A snapshot of a live session.
+#[derive(Debug, Serialize)]
+pub struct SessionSnapshot {
+    /// The id of the session, used to kick it.
+    pub id: u64,
+    /// The ID of the connection, shared by its events and log lines.
+    pub connection_id: String,
+    /// The address of the client.
+    pub peer: SocketAddr,
+    /// The username the client logged in with, if it has logged in.
+    pub username: Option<String>,
+    /// The domain the client connected with.
+    pub domain: String,
+    /// The tenant of the route, if any.
+    pub tenant: Option<String>,
+    /// The backend the client was routed to.
+    pub target: SocketAddr,
+    /// When the session started, in milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// The bytes of packet data sent by the client.
+    pub bytes_upstream: u64,
+    /// The bytes of packet data sent by the backend.
+    pub bytes_downstream: u64,
+    /// The metadata set by backend plugins.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A snapshot of a route's sessions and traffic.
+#[derive(Debug, Serialize)]
+pub struct RouteSnapshot {
+    /// The domain of the route.
+    pub domain: String,
+    /// The tenant of the route, if any.
+    pub tenant: Option<String>,
+    /// The number of live sessions.
+    pub connections: usize,
+    /// The bytes sent by clients, over every session since startup.
+    pub bytes_upstream: u64,
+    /// The bytes sent by backends, over every session since startup.
+    pub bytes_downstream: u64,
+}
+
+/// A snapshot of a tenant's sessions and traffic.
+#[derive(Debug, Serialize)]
+pub struct TenantSnapshot {
+    /// The name of the tenant.
+    pub name: String,
+    /// The number of live sessions.
+    pub connections: usize,
+    /// The bytes sent in both directions this month, including live sessions.
+    pub bytes_this_month: u64,
+}
+
+/// A snapshot of a backend's health.
+#[derive(Debug, Serialize)]
+pub struct BackendSnapshot {
+    /// The address of the backend.
+    pub target: SocketAddr,
+    /// Whether the most recent connection to the backend succeeded.
+    pub healthy: bool,
+    /// The number of live sessions.
+    pub connections: usize,
+    /// When the backend was last connected to, in milliseconds since the Unix epoch.
+    pub checked_at: u64,
+    /// Why the most recent connection failed, if it did.
+    pub error: Option<String>,
+}
+
+/// A snapshot of a listener's health.
+#[derive(Debug, Serialize)]
+pub struct ListenerSnapshot {
+    /// The address of the listener.
+    pub addr: SocketAddr,
+    /// The state of the listener.
+    pub state: ListenerState,
+    /// How many times the listener has been restarted.
+    pub restarts: u32,
+    /// When the state last changed, in milliseconds since the Unix epoch.
+    pub changed_at: u64,
+    /// Why the listener last failed, unless it has been running since.
+    pub error: Option<String>,
+}
+
+/// Snapshot every live session, oldest first.
+pub fn sessions() -> Vec<SessionSnapshot> {
+    let mut sessions: Vec<_> = registry()
+        .sessions
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, live)| snapshot(*id, live))
+        .collect();
+    sessions.sort_by_key(|session| session.id);
+    sessions
+}
+
+/// Snapshot a live session.
+fn snapshot(id: u64, live: &LiveSession) -> SessionSnapshot {
+    SessionSnapshot {
+        id,
+        connection_id: live.session.connection_id.clone(),
+        peer: live.peer,
+        username: live.session.username.get().cloned(),
+        domain: live.domain.clone(),
+        tenant: live.tenant.clone(),
+        target: live.target,
+        started_at: live.started_at,
+        bytes_upstream: live.session.upstream.load(Ordering::Relaxed),
+        bytes_downstream: live.session.downstream.load(Ordering::Relaxed),
+        metadata: live.session.metadata.lock().unwrap().clone(),
+    }
+}
+
+/// Snapshot every route which has seen a session.
+pub fn routes() -> Vec<RouteSnapshot> {
+    let registry = registry();
+    let mut routes: HashMap<String, RouteSnapshot> = registry
+        .routes
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(domain, totals)| {
+            (
+                domain.clone(),
+                RouteSnapshot {
+                    domain: domain.clone(),
+                    tenant: totals.tenant.clone(),
+                    connections: 0,
+                    bytes_upstream: totals.bytes_upstream,
+                    bytes_downstream: totals.bytes_downstream,
+                },
+            )
+        })
+        .collect();
+    for live in registry.sessions.lock().unwrap().values() {
+        let route = routes
+            .entry(live.domain.clone())
+            .or_insert_with(|| RouteSnapshot {
+                domain: live.domain.clone(),
+                tenant: live.tenant.clone(),
+                connections: 0,
+                bytes_upstream: 0,
+                bytes_downstream: 0,
+            });
+        route.connections += 1;
+        route.bytes_upstream += live.session.upstream.load(Ordering::Relaxed);
+        route.bytes_downstream += live.session.downstream.load(Ordering::Relaxed);
+    }
+    let mut routes: Vec<_> = routes.into_values().collect();
+    routes.sort_by(|a, b| a.domain.cmp(&b.domain));
+    routes
+}
+
+/// Snapshot every tenant which has seen a session.
+pub fn tenants() -> Vec<TenantSnapshot> {
+    let registry = registry();
+    let month = month();
+    let mut tenants: HashMap<String, TenantSnapshot> = registry
+        .tenants
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, usage)| {
+            (
+                name.clone(),
+                TenantSnapshot {
+                    name: name.clone(),
+                    connections: 0,
+                    bytes_this_month: if usage.month == month { usage.bytes } else { 0 },
+                },
+            )
+        })
+        .collect();
+    for live in registry.sessions.lock().unwrap().values() {
+        let Some(name) = &live.tenant else {
+            continue;
+        };
+        let tenant = tenants
+            .entry(name.clone())
+            .or_insert_with(|| TenantSnapshot {
+                name: name.clone(),
+                connections: 0,
+                bytes_this_month: 0,
+            });
+        tenant.connections += 1;
+        tenant.bytes_this_month += live.bytes();
+    }
+    let mut tenants: Vec<_> = tenants.into_values().collect();
+    tenants.sort_by(|a, b| a.name.cmp(&b.name));
+    tenants
+}
+
+/// Snapshot every backend which has been connected to.
+pub fn backends() -> Vec<BackendSnapshot> {
+    let registry = registry();
+    let mut connections: HashMap<SocketAddr, usize> = HashMap::new();
+    for live in registry.sessions.lock().unwrap().values() {
+        *connections.entry(live.target).or_default() += 1;
+    }
+    let mut backends: Vec<_> = registry
+        .backends
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(target, health)| BackendSnapshot {
+            target: *target,
+            healthy: health.error.is_none(),
+            connections: connections.get(target).copied().unwrap_or(0),
+            checked_at: health.checked_at,
+            error: health.error.clone(),
+        })
+        .collect();
+    backends.sort_by_key(|backend| backend.target);
+    backends
+}
+
+/// Snapshot the health of every listener, ordered by address.
+pub fn listeners() -> Vec<ListenerSnapshot> {
+    let mut listeners: Vec<_> = registry()
+        .listeners
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(addr, health)| ListenerSnapshot {
+            addr: *addr,
+            state: health.state,
+            restarts: health.restarts,
+            changed_at: health.changed_at,
+            error: health.error.clone(),
+        })
+        .collect();
+    listeners.sort_by_key(|listener| listener.addr);
+    listeners
+}
@@ -0,0 +1,306 @@
+//! Defines configuration reloading.
+//!
+//! On `SIGHUP`, or a request to the admin API, the configuration file is loaded again and the
+//! proxies are updated in place - listeners are started and stopped, and the routes of listeners
+//! which remain are swapped without dropping connections. Other sections of the configuration,
+//! such as the tunnel and event sinks, only take effect on restart - apart from their
+//! [secrets](crate::secrets), which are replaced.
+//!
+//! Each reload logs a diff of the listeners and routes it changed, and the result of the most
+//! recent reload is kept for the admin API.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{self, Config, Overrides, Proxy},
+    proxy::{self, Services},
+    registry, secrets,
+    signals::{self, Trigger},
+};
+
+/// A listener managed by the reloader.
+struct Listener {
+    proxy: watch::Sender<Arc<Proxy>>,
+    handle: JoinHandle<Result<()>>,
+}
+
+/// The listeners and routes changed by a reload.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReloadDiff {
+    /// The listeners which were started.
+    pub listeners_added: Vec<SocketAddr>,
+    /// The listeners which were stopped.
+    pub listeners_removed: Vec<SocketAddr>,
+    /// The listeners whose socket options changed, which only take effect on restart.
+    pub listeners_unchanged_until_restart: Vec<SocketAddr>,
+    /// The routes which were added, as `domain@listener`.
+    pub routes_added: Vec<String>,
+    /// The routes which were removed, as `domain@listener`.
+    pub routes_removed: Vec<String>,
+    /// The routes whose settings changed, as `domain@listener`.
+    pub routes_changed: Vec<String>,
+}
+
+impl ReloadDiff {
+    /// Test whether the reload changed nothing.
+    pub fn is_empty(&self) -> bool {
+        self.listeners_added.is_empty()
+            && self.listeners_removed.is_empty()
+            && self.listeners_unchanged_until_restart.is_empty()
+            && self.routes_added.is_empty()
+            && self.routes_removed.is_empty()
+            && self.routes_changed.is_empty()
+    }
+}
+
+/// The result of a reload.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadResult {
+    /// Whether the reload succeeded.
+    pub success: bool,
+    /// When the reload happened, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// Why the reload failed, if it did.
+    pub error: Option<String>,
+    /// What the reload changed, if it succeeded.
+    pub diff: Option<ReloadDiff>,
+}
+
+/// Reloads the proxies from the configuration file.
+pub struct Reloader {
+    paths: Vec<PathBuf>,
+    overrides: Overrides,
+    services: Services,
+    listeners: Mutex<HashMap<SocketAddr, Listener>>,
+    last: std::sync::Mutex<Option<ReloadResult>>,
+}
+
+impl Reloader {
+    /// Start the given proxies, returning a reloader managing them. The command-line overrides are
+    /// applied to every reloaded configuration.
+    pub fn start(
+        paths: Vec<PathBuf>,
+        overrides: Overrides,
+        proxies: Vec<Proxy>,
+        services: Services,
+    ) -> Arc<Self> {
+        let listeners = proxies
+            .into_iter()
+            .map(|proxy| (proxy.listen_addr, start_listener(proxy, services.clone())))
+            .collect();
+        Arc::new(Self {
+            paths,
+            overrides,
+            services,
+            listeners: Mutex::new(listeners),
+            last: Default::default(),
+        })
+    }
+
+    /// Spawns a task reloading on `SIGHUP`, and returns a handle to the task.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<Result<()>> {
+        let reloader = self.clone();
+        tokio::task::spawn(async move {
+            let mut hangups = signals::listen(
+                Trigger::Hangup,
+                "the configuration will not be reloaded on SIGHUP",
+            );
+            while signals::recv(&mut hangups).await.is_some() {
+                reloader.reload().await;
+            }
+            Ok(())
+        })
+    }
+
+    /// The proxies currently being served, ordered by address.
+    pub async fn proxies(&self) -> Vec<Arc<Proxy>> {
+        let mut proxies: Vec<_> = self
+            .listeners
+            .lock()
+            .await
+            .values()
+            .map(|listener| listener.proxy.borrow().clone())
+            .collect();
+        proxies.sort_by_key(|proxy| proxy.listen_addr);
+        proxies
+    }
+
+    /// The result of the most recent reload, if any.
+    pub fn last(&self) -> Option<ReloadResult> {
+        self.last.lock().unwrap().clone()
+    }
+
+    /// Reload the configuration, returning and recording the result.
+    pub async fn reload(&self) -> ReloadResult {
+        info!("Reloading configuration from {:?}...", self.paths);
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let result = match self.apply().await {
+            Ok(diff) => {
+                match diff.is_empty() {
+                    true => info!("Reloaded configuration - nothing changed"),
+                    false => info!(
+                        listeners_added = ?diff.listeners_added,
+                        listeners_removed = ?diff.listeners_removed,
+                        listeners_unchanged_until_restart = ?diff.listeners_unchanged_until_restart,
+                        routes_added = ?diff.routes_added,
+                        routes_removed = ?diff.routes_removed,
+                        routes_changed = ?diff.routes_changed,
+                        "Reloaded configuration"
+                    ),
+                }
+                ReloadResult {
+                    success: true,
+                    at,
+                    error: None,
+                    diff: Some(diff),
+                }
+            }
+            Err(err) => {
+                error!(
+                    "Failed to reload configuration - keeping the current one: {:#}",
+                    err
+                );
+                ReloadResult {
+                    success: false,
+                    at,
+                    error: Some(format!("{:#}", err)),
+                    diff: None,
+                }
+            }
+        };
+        *self.last.lock().unwrap() = Some(result.clone());
+        result
+    }
+
+    /// Load the configuration, and apply it to the listeners.
+    async fn apply(&self) -> Result<ReloadDiff> {
+        let config = config::load(&self.paths, &self.overrides).await?;
+        let config = config.build().context("failed to build configuration")?;
+        // the authenticator is made at startup, and only if some route authenticated logins then
+        let authenticates = config
+            .proxies
+            .iter()
+            .flat_map(|proxy| &proxy.routes)
+            .any(|route| route.authenticate);
+        if authenticates && self.services.authenticator.is_none() {
+            bail!("No route authenticated logins at startup - restart Magma to authenticate them");
+        }
+        secrets::publish(&config)?;
+
+        let mut listeners = self.listeners.lock().await;
+        let mut diff = ReloadDiff::default();
+        let mut proxies: HashMap<_, _> = config
+            .proxies
+            .into_iter()
+            .map(|proxy| (proxy.listen_addr, proxy))
+            .collect();
+
+        // stop listeners which were removed
+        let removed: Vec<_> = listeners
+            .keys()
+            .filter(|addr| !proxies.contains_key(addr))
+            .copied()
+            .collect();
+        for addr in removed {
+            let listener = listeners.remove(&addr).unwrap();
+            listener.handle.abort();
+            registry::remove_listener(addr);
+            let current = listener.proxy.borrow().clone();
+            diff_routes(&mut diff, Some(current.as_ref()), None);
+            diff.listeners_removed.push(addr);
+        }
+
+        // swap the routes of listeners which remain, and start listeners which were added - or
+        // which their supervisor gave up on, so a fixed configuration brings them back
+        for (addr, proxy) in proxies.drain() {
+            let running = listeners
+                .get(&addr)
+                .filter(|listener| !listener.handle.is_finished());
+            match running {
+                Some(listener) => {
+                    let current = listener.proxy.borrow().clone();
+                    diff_routes(&mut diff, Some(current.as_ref()), Some(&proxy));
+                    if current.transport != proxy.transport
+                        || current.backlog != proxy.backlog
+                        || current.query != proxy.query
+                        || current.v6_only != proxy.v6_only
+                    {
+                        warn!(
+                            "The socket options of listener {} changed - restart Magma to apply them",
+                            addr
+                        );
+                        diff.listeners_unchanged_until_restart.push(addr);
+                    }
+                    listener.proxy.send_replace(Arc::new(proxy));
+                }
+                None => {
+                    diff_routes(&mut diff, None, Some(&proxy));
+                    listeners.insert(addr, start_listener(proxy, self.services.clone()));
+                    diff.listeners_added.push(addr);
+                }
+            }
+        }
+
+        diff.listeners_added.sort();
+        diff.listeners_removed.sort();
+        diff.listeners_unchanged_until_restart.sort();
+        diff.routes_added.sort();
+        diff.routes_removed.sort();
+        diff.routes_changed.sort();
+        Ok(diff)
+    }
+}
+
+/// Start a listener for a proxy.
+fn start_listener(proxy: Proxy, services: Services) -> Listener {
+    let (tx, rx) = watch::channel(Arc::new(proxy));
+    Listener {
+        proxy: tx,
+        handle: proxy::spawn(rx, services),
+    }
+}
+
+/// Record the differences between the routes of a listener before and after a reload.
+fn diff_routes(diff: &mut ReloadDiff, before: Option<&Proxy>, after: Option<&Proxy>) {
+    let addr = before.or(after).map(|proxy| proxy.listen_addr).unwrap();
+    // routes hold no comparable state of their own, so compare their debug representations
+    let routes = |proxy: Option<&Proxy>| -> HashMap<String, String> {
+        proxy
+            .map(|proxy| {
+                proxy
+                    .routes
+                    .iter()
+                    .map(|route| (route.from.clone(), format!("{:?}", route)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let (before, after) = (routes(before), routes(after));
+    let domains: BTreeSet<_> = before.keys().chain(after.keys()).collect();
+    for domain in domains {
+        let label = format!("{}@{}", domain, addr);
+        match (before.get(domain), after.get(domain)) {
+            (None, Some(_)) => diff.routes_added.push(label),
+            (Some(_), None) => diff.routes_removed.push(label),
+            (Some(a), Some(b)) if a != b => diff.routes_changed.push(label),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Defines responses Magma sends to clients itself, rather than relaying them from a backend.
+//!
+//! These are used to turn clients away before a backend is involved - answering status pings with
+//! Magma's own MOTD, and disconnecting logins with a message.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    bridge::{ProtocolState, Stream},
+    config::PlayerSample,
+    io::{ProcotolAsyncWriteExt, ProtocolAsyncReadExt},
+    protocol::packets::{
+        Disconnect, Handshake, PacketCodec, PingRequest, PongResponse, StatusRequest,
+        StatusResponse,
+    },
+};
+
+/// How long a client has to send each packet Magma waits for before replying.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The id of the entries of a replaced player sample, which aren't players.
+const NIL_UUID: &str = "00000000-0000-0000-0000-000000000000";
+
+/// The player counts shown in a status response.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Players {
+    /// The number of players online.
+    pub online: usize,
+    /// The maximum number of players.
+    pub max: usize,
+}
+
+/// Answer a status ping with the given MOTD and favicon, then close the connection.
+pub async fn status<C: Stream>(
+    client_stream: &mut C,
+    protocol_version: i32,
+    motd: &str,
+    players: Players,
+    favicon: Option<&str>,
+) -> Result<()> {
+    status_component(
+        client_stream,
+        protocol_version,
+        &json!({ "text": motd }),
+        players,
+        favicon,
+    )
+    .await
+}
+
+/// Answer a status ping with the given chat component as the MOTD, then close the connection.
+pub async fn status_component<C: Stream>(
+    client_stream: &mut C,
+    protocol_version: i32,
+    motd: &Value,
+    players: Players,
+    favicon: Option<&str>,
+) -> Result<()> {
+    let mut json = json!({
+        "version": {
+            "name": "Magma",
+            "protocol": protocol_version,
+        },
+        "players": {
+            "online": players.online,
+            "max": players.max,
+        },
+        "description": motd,
+    });
+    if let Some(favicon) = favicon {
+        json["favicon"] = favicon.into();
+    }
+    answer_status(client_stream, json.to_string()).await
+}
+
+/// Answer a status ping with the given status response, then close the connection.
+pub async fn answer_status<C: Stream>(client_stream: &mut C, json: String) -> Result<()> {
+    let request = client_stream
+        .read_uncompressed_packet_timeout(READ_TIMEOUT)
+        .await?;
+    StatusRequest::decode(&request)?;
+    client_stream
+        .write_uncompressed_packet(&StatusResponse { json }.encode()?)
+        .await?;
+
+    // clients may close the connection rather than measuring latency
+    if let Ok(ping) = client_stream
+        .read_uncompressed_packet_timeout(READ_TIMEOUT)
+        .await
+    {
+        let ping = PingRequest::decode(&ping)?;
+        client_stream
+            .write_uncompressed_packet(
+                &PongResponse {
+                    payload: ping.payload,
+                }
+                .encode()?,
+            )
+            .await?;
+    }
+    client_stream.shutdown().await?;
+    Ok(())
+}
+
+/// Replace the player counts of a status response.
+pub fn override_players(json: &str, players: Players) -> Result<String> {
+    let mut json: Value = serde_json::from_str(json)?;
+    if let Some(status) = json.as_object_mut() {
+        let counts = status.entry("players").or_insert_with(|| json!({}));
+        if let Some(counts) = counts.as_object_mut() {
+            counts.insert("online".to_string(), players.online.into());
+            counts.insert("max".to_string(), players.max.into());
+        }
+    }
+    Ok(json.to_string())
+}
+
+/// Replace the description, or MOTD, of a status response.
+pub fn override_description(json: &str, description: &Value) -> Result<String> {
+    let mut json: Value = serde_json::from_str(json)?;
+    if let Some(status) = json.as_object_mut() {
+        status.insert("description".to_string(), description.clone());
+    }
+    Ok(json.to_string())
+}
+
+/// Replace or remove the player sample of a status response.
+pub fn override_sample(json: &str, sample: &PlayerSample) -> Result<String> {
+    let mut json: Value = serde_json::from_str(json)?;
+    if let Some(status) = json.as_object_mut() {
+        let counts = status.entry("players").or_insert_with(|| json!({}));
+        if let Some(counts) = counts.as_object_mut() {
+            match sample {
+                PlayerSample::Pass => {}
+                PlayerSample::Hide => {
+                    counts.remove("sample");
+                }
+                PlayerSample::Replace(lines) => {
+                    // the client only shows the names, but expects every entry to have an id
+                    let sample = lines
+                        .iter()
+                        .map(|line| json!({ "name": line, "id": NIL_UUID }))
+                        .collect();
+                    counts.insert("sample".to_string(), Value::Array(sample));
+                }
+            }
+        }
+    }
+    Ok(json.to_string())
+}
+
+/// Set whether a status response claims the server enforces secure chat.
+pub fn override_secure_chat(json: &str, enforced: bool) -> Result<String> {
+    let mut json: Value = serde_json::from_str(json)?;
+    if let Some(status) = json.as_object_mut() {
+        status.insert("enforcesSecureChat".to_string(), enforced.into());
+    }
+    Ok(json.to_string())
+}
+
+/// Add a favicon to a status response which has none of its own.
+pub fn default_favicon(json: &str, favicon: &str) -> Result<String> {
+    let mut json: Value = serde_json::from_str(json)?;
+    if let Some(status) = json.as_object_mut() {
+        status
+            .entry("favicon")
+            .or_insert_with(|| favicon.to_string().into());
+    }
+    Ok(json.to_string())
+}
+
+/// Disconnect a client during login with the given message, then close the connection.
+pub async fn disconnect<C: Stream>(client_stream: &mut C, message: &str) -> Result<()> {
+    disconnect_component(client_stream, &json!({ "text": message })).await
+}
+
+/// Disconnect a client during login with the given chat component, then close the connection.
+pub async fn disconnect_component<C: Stream>(client_stream: &mut C, reason: &Value) -> Result<()> {
+    client_stream
+        .write_uncompressed_packet(
+            &Disconnect {
+                reason: reason.to_string(),
+            }
+            .encode()?,
+        )
+        .await?;
+    client_stream.shutdown().await?;
+    Ok(())
+}
+
+/// Turn a client away - answering a status ping with the MOTD and favicon, or disconnecting a
+/// login with the message.
+pub async fn reject<C: Stream>(
+    client_stream: &mut C,
+    handshake: &Handshake,
+    message: &str,
+    motd: &str,
+    players: Players,
+    favicon: Option<&str>,
+) -> Result<()> {
+    match handshake.next_state {
+        ProtocolState::Status => {
+            status(
+                client_stream,
+                handshake.protocol_version,
+                motd,
+                players,
+                favicon,
+            )
+            .await
+        }
+        _ => {
+            // read the login start first - closing with unread data would reset the connection
+            // before the client sees the message
+            client_stream
+                .read_uncompressed_packet_timeout(READ_TIMEOUT)
+                .await?;
+            disconnect(client_stream, message).await
+        }
+    }
+}
+
+/// Turn a client away with a chat component - as the MOTD of a status ping, or as the message a
+/// login is disconnected with.
+pub async fn reject_component<C: Stream>(
+    client_stream: &mut C,
+    handshake: &Handshake,
+    component: &Value,
+) -> Result<()> {
+    match handshake.next_state {
+        ProtocolState::Status => {
+            status_component(
+                client_stream,
+                handshake.protocol_version,
+                component,
+                Players::default(),
+                None,
+            )
+            .await
+        }
+        _ => {
+            client_stream
+                .read_uncompressed_packet_timeout(READ_TIMEOUT)
+                .await?;
+            disconnect_component(client_stream, component).await
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Defines IP reputation lookups, used to detect clients connecting from VPNs and datacenters.
+//!
+//! Addresses are checked against a local database of networks, and then optionally an external
+//! API. API results are cached, as reputation services are typically rate limited and add latency
+//! to every handshake. Lookups fail open - if the API is unreachable, the client is treated as
+//! clean.
+
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use tracing::{info, warn};
+
+use crate::config::{ReputationApi, ReputationConfig};
+
+/// The maximum number of cached API results, after which expired results are evicted.
+const MAX_CACHE_ENTRIES: usize = 100_000;
+/// How long to wait for the API before treating the client as clean.
+const API_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// An IP reputation checker.
+pub struct Reputation {
+    /// Networks known to belong to VPNs or datacenters.
+    networks: Vec<IpNet>,
+    /// The external API to consult, if any.
+    api: Option<ReputationApi>,
+    /// The HTTP client used to query the API.
+    client: reqwest::Client,
+    /// Cached API results, and when they were looked up.
+    cache: Mutex<HashMap<IpAddr, (bool, Instant)>>,
+    /// How long API results are cached.
+    cache_ttl: Duration,
+}
+
+impl Reputation {
+    /// Load the configured database, and prepare the API client.
+    pub fn load(config: ReputationConfig) -> Result<Arc<Self>> {
+        let networks = match &config.database {
+            Some(path) => {
+                let networks =
+                    parse_database(&read_to_string(path).with_context(|| {
+                        format!("failed to read reputation database {:?}", path)
+                    })?)
+                    .with_context(|| format!("failed to parse reputation database {:?}", path))?;
+                info!(
+                    "Loaded {} network(s) from reputation database {:?}",
+                    networks.len(),
+                    path
+                );
+                networks
+            }
+            None => Vec::new(),
+        };
+        Ok(Arc::new(Self {
+            networks,
+            api: config.api,
+            client: reqwest::Client::builder().timeout(API_TIMEOUT).build()?,
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl: config.cache_ttl,
+        }))
+    }
+
+    /// Test whether the given address belongs to a VPN, proxy or datacenter.
+    pub async fn is_vpn(&self, ip: IpAddr) -> bool {
+        if self.networks.iter().any(|network| network.contains(&ip)) {
+            return true;
+        }
+        let Some(api) = &self.api else {
+            return false;
+        };
+
+        if let Some((vpn, looked_up)) = self.cache.lock().unwrap().get(&ip) {
+            if looked_up.elapsed() < self.cache_ttl {
+                return *vpn;
+            }
+        }
+        let vpn = match self.query(api, ip).await {
+            Ok(vpn) => vpn,
+            Err(err) => {
+                warn!("Failed to look up the reputation of {}: {:#}", ip, err);
+                return false;
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            let ttl = self.cache_ttl;
+            cache.retain(|_, (_, looked_up)| looked_up.elapsed() < ttl);
+        }
+        cache.insert(ip, (vpn, Instant::now()));
+        vpn
+    }
+
+    /// Query the API for the given address.
+    async fn query(&self, api: &ReputationApi, ip: IpAddr) -> Result<bool> {
+        let ip = ip.to_string();
+        let response: serde_json::Value = self
+            .client
+            .get(api.url.replace("{ip}", &ip))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let pointer = api.pointer.replace("{ip}", &ip);
+        match response.pointer(&pointer) {
+            Some(serde_json::Value::Bool(vpn)) => Ok(*vpn),
+            Some(serde_json::Value::String(vpn)) => Ok(matches!(
+                vpn.to_ascii_lowercase().as_str(),
+                "yes" | "true" | "1"
+            )),
+            Some(serde_json::Value::Number(vpn)) => Ok(vpn.as_f64() != Some(0.0)),
+            Some(_) | None => anyhow::bail!("Response has no verdict at {}", pointer),
+        }
+    }
+}
+
+/// Parse a database of networks - one address or CIDR block per line, with `#` comments.
+fn parse_database(buf: &str) -> Result<Vec<IpNet>> {
+    buf.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            line.parse::<IpNet>()
+                .or_else(|_| line.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("invalid network {:?} on line {}", line, i + 1))
+        })
+        .collect()
+}
@@ -0,0 +1,62 @@
+//! Defines resumption, which holds a player's place for a grace period after their backend fails.
+//!
+//! When a bridge closes because the backend failed, rather than because the player left, the
+//! player's slot on a full route and the backend they were on are reserved for their address. If
+//! they reconnect within the route's grace period, they take the slot back ahead of new players, and
+//! return to the same backend if it is still a candidate - such as once it has restarted. Otherwise
+//! the reservation expires, and the slot is freed.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// A place held for a player.
+struct Reservation {
+    target: SocketAddr,
+    expires_at: Instant,
+}
+
+/// The reservations, by address and route.
+fn reservations() -> &'static Mutex<HashMap<(IpAddr, String), Reservation>> {
+    static RESERVATIONS: OnceLock<Mutex<HashMap<(IpAddr, String), Reservation>>> = OnceLock::new();
+    RESERVATIONS.get_or_init(Default::default)
+}
+
+/// Hold the place of a player whose backend failed, for the given grace period.
+pub fn reserve(ip: IpAddr, domain: &str, target: SocketAddr, grace: Duration) {
+    let mut reservations = reservations().lock().unwrap();
+    reservations.retain(|_, reservation| reservation.expires_at > Instant::now());
+    reservations.insert(
+        (ip, domain.to_string()),
+        Reservation {
+            target,
+            expires_at: Instant::now() + grace,
+        },
+    );
+}
+
+/// Take the reservation for a login from the address to the route, returning the backend the
+/// player was on, if any.
+pub fn take(ip: IpAddr, domain: &str) -> Option<SocketAddr> {
+    reservations()
+        .lock()
+        .unwrap()
+        .remove(&(ip, domain.to_string()))
+        .filter(|reservation| reservation.expires_at > Instant::now())
+        .map(|reservation| reservation.target)
+}
+
+/// The number of slots held for players of the route.
+pub fn reserved(domain: &str) -> usize {
+    reservations()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((_, reserved), reservation)| {
+            reserved == domain && reservation.expires_at > Instant::now()
+        })
+        .count()
+}
@@ -0,0 +1,61 @@
+//! Defines time-of-day access restrictions for routes.
+//!
+//! A schedule lists the windows of the day during which a route is open, in a given timezone.
+//! Outside those windows, logins are rejected with a message, and status pings are answered with a
+//! closed MOTD - useful for school and community servers with enforced downtime.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+
+/// When a route is open.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    /// The timezone the windows are in.
+    pub timezone: Tz,
+    /// The windows during which the route is open, as start and end times. Windows whose end is
+    /// before their start cross midnight.
+    pub windows: Vec<(NaiveTime, NaiveTime)>,
+    /// The message logins are rejected with while the route is closed.
+    pub message: String,
+    /// The MOTD shown to status pings while the route is closed.
+    pub motd: String,
+}
+
+impl Schedule {
+    /// Test whether the route is open at the given time.
+    pub fn is_open_at(&self, now: DateTime<Utc>) -> bool {
+        let time = now.with_timezone(&self.timezone).time();
+        self.windows.iter().any(|&(start, end)| match start <= end {
+            true => start <= time && time < end,
+            false => time >= start || time < end,
+        })
+    }
+
+    /// Test whether the route is open now.
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(Utc::now())
+    }
+}
+
+/// Parse a timezone name, such as `Europe/London`.
+pub fn parse_timezone(timezone: &str) -> Result<Tz> {
+    timezone
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Unknown timezone {:?}", timezone))
+}
+
+/// Parse a time of day, such as `07:00`.
+pub fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .with_context(|| format!("Invalid time {:?} - expected HH:MM", time))
+}
+
+/// Parse a window, such as `07:00-22:00`.
+pub fn parse_window(window: &str) -> Result<(NaiveTime, NaiveTime)> {
+    let Some((start, end)) = window.split_once('-') else {
+        bail!("Invalid window {:?} - expected HH:MM-HH:MM", window);
+    };
+    let parse = |time: &str| parse_time(time).with_context(|| format!("in window {:?}", window));
+    Ok((parse(start)?, parse(end)?))
+}
@@ -0,0 +1,84 @@
+//! Defines the secrets which are re-read when the configuration is reloaded.
+//!
+//! Sections other than the proxies otherwise only take effect on restart, but the tokens and
+//! passwords they check are looked up here on each use, so rotating one only needs a reload. A
+//! reloaded configuration is validated before its secrets replace the current ones, and a section
+//! missing from it keeps the secrets it had, as its service keeps running until restart. TLS
+//! certificates are reloaded by their [acceptors and connectors](crate::tls) themselves.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use anyhow::{bail, Result};
+
+use crate::config::{AdminToken, MagmaConfig, TunnelConfig};
+
+/// The current secrets.
+static SECRETS: OnceLock<RwLock<Arc<Secrets>>> = OnceLock::new();
+
+/// The secrets of the running services.
+#[derive(Debug, Clone, Default)]
+pub struct Secrets {
+    /// The bearer tokens of the admin API.
+    pub admin_tokens: Vec<AdminToken>,
+    /// The token shared by tunnel edges and the hub.
+    pub tunnel_token: Option<String>,
+    /// The token the InfluxDB exporter authenticates with.
+    pub influx_token: Option<String>,
+    /// The passwords of the RCON proxies, by listening address.
+    pub rcon: HashMap<SocketAddr, RconSecrets>,
+}
+
+/// The passwords of an RCON proxy.
+#[derive(Debug, Clone)]
+pub struct RconSecrets {
+    /// The password clients must log in with.
+    pub password: String,
+    /// The password used to log in to the backends.
+    pub backend_password: String,
+}
+
+/// The current secrets.
+pub fn current() -> Arc<Secrets> {
+    SECRETS
+        .get()
+        .map(|secrets| secrets.read().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Replace the current secrets with those of a configuration. Fails, keeping the current secrets,
+/// if the configuration would leave the admin API without tokens after it had some.
+pub fn publish(config: &MagmaConfig) -> Result<()> {
+    let lock = SECRETS.get_or_init(Default::default);
+    let mut secrets = lock.write().unwrap();
+    let mut next = Secrets::clone(&secrets);
+    if let Some(admin) = &config.admin {
+        // the API can only be opened up by a restart, so a mistake can't expose it
+        if admin.tokens.is_empty() && !next.admin_tokens.is_empty() {
+            bail!("The admin API can't be left without tokens by a reload");
+        }
+        next.admin_tokens = admin.tokens.clone();
+    }
+    match &config.tunnel {
+        Some(TunnelConfig::Edge(edge)) => next.tunnel_token = Some(edge.token.clone()),
+        Some(TunnelConfig::Hub(hub)) => next.tunnel_token = Some(hub.token.clone()),
+        None => {}
+    }
+    if let Some(influx) = &config.influx {
+        next.influx_token = influx.token.clone();
+    }
+    for rcon in &config.rcon {
+        next.rcon.insert(
+            rcon.listen_addr,
+            RconSecrets {
+                password: rcon.password.clone(),
+                backend_password: rcon.backend_password.clone(),
+            },
+        );
+    }
+    *secrets = Arc::new(next);
+    Ok(())
+}
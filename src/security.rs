@@ -0,0 +1,136 @@
+//! Defines the security log, a dedicated stream of machine-parseable security events.
+//!
+//! Each line records a single event and the offending IP, in a fixed format that host-level tools
+//! such as fail2ban can match:
+//!
+//! ```text
+//! 2024-01-01T12:00:00Z malformed_protocol ip=203.0.113.7 detail="unexpected packet 0x10 during handshake"
+//! ```
+//!
+//! The log is written to its own file, separately from the application log, and is reopened on
+//! `SIGHUP` so it can be rotated. Events are dropped, rather than buffered without bound, if the
+//! file cannot keep up.
+
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+
+use crate::signals::{self, Trigger};
+
+/// The number of lines buffered for the file before events are dropped.
+const QUEUE_SIZE: usize = 8192;
+
+/// A kind of security event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityEvent {
+    /// A peer failed to authenticate, such as a tunnel edge presenting the wrong token.
+    AuthFailure,
+    /// A peer exceeded a rate limit.
+    RateLimited,
+    /// A peer sent data which is not valid Minecraft protocol.
+    MalformedProtocol,
+    /// A peer was denied by policy, such as a VPN client on a route which denies them.
+    Denied,
+    /// A peer sent data too slowly, such as a client dripping its handshake to hold a socket open.
+    SlowClient,
+}
+
+impl Display for SecurityEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SecurityEvent::AuthFailure => "auth_failure",
+            SecurityEvent::RateLimited => "rate_limited",
+            SecurityEvent::MalformedProtocol => "malformed_protocol",
+            SecurityEvent::Denied => "denied",
+            SecurityEvent::SlowClient => "slow_client",
+        })
+    }
+}
+
+/// The queue of lines for the security log, once it is enabled.
+static LOG: OnceLock<mpsc::Sender<String>> = OnceLock::new();
+
+/// The number of security events waiting to be written, if the security log is enabled.
+pub fn queued() -> Option<usize> {
+    LOG.get().map(|tx| tx.max_capacity() - tx.capacity())
+}
+
+/// Report a security event caused by the given IP.
+pub fn report(event: SecurityEvent, ip: IpAddr, detail: impl Display) {
+    debug!("Security event {} from {}: {}", event, ip, detail);
+    let Some(log) = LOG.get() else {
+        return;
+    };
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    // the detail is quoted and escaped, so it can never break the line format
+    let line = format!(
+        "{} {} ip={} detail={:?}\n",
+        timestamp,
+        event,
+        ip,
+        detail.to_string()
+    );
+    let _ = log.try_send(line);
+}
+
+/// Spawns the task writing the security log to the given file, and returns a handle to the task.
+pub fn spawn(path: PathBuf) -> JoinHandle<Result<()>> {
+    let (tx, mut rx) = mpsc::channel::<String>(QUEUE_SIZE);
+    if LOG.set(tx).is_err() {
+        warn!("The security log is already enabled");
+    }
+    tokio::task::spawn(async move {
+        let mut file = open(&path).await?;
+        info!("Writing security events to {:?}", path);
+        let mut hangups = signals::listen(Trigger::Hangup, "the security log will not be reopened");
+        loop {
+            tokio::select! {
+                line = rx.recv() => {
+                    let Some(line) = line else {
+                        return Ok(());
+                    };
+                    file.write_all(line.as_bytes()).await?;
+                    // batch any other queued lines before flushing
+                    while let Ok(line) = rx.try_recv() {
+                        file.write_all(line.as_bytes()).await?;
+                    }
+                    file.flush().await?;
+                }
+                Some(()) = signals::recv(&mut hangups) => {
+                    match open(&path).await {
+                        Ok(reopened) => {
+                            file = reopened;
+                            info!("Reopened security log {:?}", path);
+                        }
+                        Err(err) => warn!("Failed to reopen security log: {:#}", err),
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Open the security log for appending.
+async fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("failed to open security log {:?}", path))
+}
@@ -0,0 +1,70 @@
+//! Defines helpers for listening to Unix signals, which degrade to waiting forever on other
+//! platforms.
+
+use tracing::warn;
+
+/// A signal Magma responds to.
+#[derive(Debug, Clone, Copy)]
+pub enum Trigger {
+    /// `SIGHUP`, which reloads configuration and reopens files.
+    Hangup,
+    /// `SIGUSR1`, which dumps internal state to the log.
+    User1,
+}
+
+impl Trigger {
+    /// The name of the signal.
+    pub fn name(self) -> &'static str {
+        match self {
+            Trigger::Hangup => "SIGHUP",
+            Trigger::User1 => "SIGUSR1",
+        }
+    }
+}
+
+#[cfg(unix)]
+pub type Signals = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+pub type Signals = ();
+
+/// Listen for a signal, warning with the consequence if it can't be listened for.
+#[cfg(unix)]
+pub fn listen(trigger: Trigger, consequence: &str) -> Signals {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let kind = match trigger {
+        Trigger::Hangup => SignalKind::hangup(),
+        Trigger::User1 => SignalKind::user_defined1(),
+    };
+    match signal(kind) {
+        Ok(signals) => Some(signals),
+        Err(err) => {
+            warn!(
+                "Failed to listen for {} - {}: {}",
+                trigger.name(),
+                consequence,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Signals are unavailable on this platform.
+#[cfg(not(unix))]
+pub fn listen(_trigger: Trigger, _consequence: &str) -> Signals {}
+
+/// Wait for the next signal.
+#[cfg(unix)]
+pub async fn recv(signals: &mut Signals) -> Option<()> {
+    match signals {
+        Some(signals) => signals.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait forever, as signals are unavailable on this platform.
+#[cfg(not(unix))]
+pub async fn recv(_signals: &mut Signals) -> Option<()> {
+    std::future::pending().await
+}
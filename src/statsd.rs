@@ -0,0 +1,155 @@
+//! Defines the statsd exporter, which pushes metrics to a statsd or DogStatsD agent over UDP.
+//!
+//! Latencies are sent as timers as they are recorded, so the agent can compute percentiles
+//! itself, closed connections are counted as they close, and gauges are sampled and sent
+//! periodically. Routes and configured tags are sent as DogStatsD tags, which plain statsd agents
+//! ignore.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    select,
+    sync::mpsc,
+    task::JoinHandle,
+    time::interval,
+};
+use tracing::debug;
+
+use crate::{
+    bridge::CloseReason,
+    config::StatsdConfig,
+    metrics::{self, Timing},
+};
+
+/// How many lines may wait to be sent before new ones are dropped.
+const QUEUE: usize = 4096;
+/// The largest datagram sent, which fits in the MTU of most networks.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Formats lines and queues them to be sent.
+struct Exporter {
+    /// The prefix of every metric name.
+    prefix: String,
+    /// The configured tags, formatted as `name:value`.
+    tags: Vec<String>,
+    sender: mpsc::Sender<String>,
+}
+
+impl Exporter {
+    /// Queue a line, dropping it if the queue is full.
+    fn send(&self, stat: &str, value: f64, kind: &str, tags: &[(&str, &str)]) {
+        let mut line = format!("{}.{}:{}|{}", self.prefix, stat, value, kind);
+        let tags: Vec<_> = tags
+            .iter()
+            .map(|(name, value)| format!("{}:{}", name, sanitize(value)))
+            .chain(self.tags.iter().cloned())
+            .collect();
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        let _ = self.sender.try_send(line);
+    }
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+/// Replace the characters which delimit the statsd format.
+fn sanitize(value: &str) -> String {
+    value.replace(['|', ',', '#', '\n'], "_")
+}
+
+/// Send a latency for a route as a timer, if the exporter is running.
+pub fn timing(timing: Timing, route: &str, duration: Duration) {
+    if let Some(exporter) = EXPORTER.get() {
+        exporter.send(
+            timing.stat(),
+            duration.as_secs_f64() * 1000.0,
+            "ms",
+            &[("route", route)],
+        );
+    }
+}
+
+/// Count a closed connection to a route, if the exporter is running.
+pub fn close(route: &str, reason: CloseReason) {
+    if let Some(exporter) = EXPORTER.get() {
+        exporter.send(
+            "connections_closed",
+            1.0,
+            "c",
+            &[("route", route), ("reason", reason.label())],
+        );
+    }
+}
+
+/// Spawns the exporter, and returns a handle to the task.
+pub fn spawn(config: StatsdConfig) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let target = lookup_host(&config.address)
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .with_context(|| format!("failed to resolve statsd agent {}", config.address))?;
+        let local = match target {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(target).await?;
+
+        let (sender, mut receiver) = mpsc::channel(QUEUE);
+        let exporter = EXPORTER.get_or_init(|| Exporter {
+            prefix: config.prefix,
+            tags: config
+                .tags
+                .iter()
+                .map(|(name, value)| format!("{}:{}", sanitize(name), sanitize(value)))
+                .collect(),
+            sender,
+        });
+
+        let mut ticks = interval(config.interval);
+        loop {
+            select! {
+                Some(line) = receiver.recv() => {
+                    // batch whatever else is queued into as few datagrams as possible
+                    let mut datagram = line;
+                    while let Ok(line) = receiver.try_recv() {
+                        if datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                            send(&socket, &datagram).await;
+                            datagram = line;
+                        } else {
+                            datagram.push('\n');
+                            datagram.push_str(&line);
+                        }
+                    }
+                    send(&socket, &datagram).await;
+                }
+                _ = ticks.tick() => {
+                    for gauge in metrics::gauges() {
+                        let labels: Vec<_> = gauge
+                            .labels
+                            .iter()
+                            .map(|(name, value)| (*name, value.as_str()))
+                            .collect();
+                        exporter.send(gauge.stat, gauge.value, "g", &labels);
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Send a datagram, ignoring failures - the agent may not be running yet.
+async fn send(socket: &UdpSocket, datagram: &str) {
+    if let Err(err) = socket.send(datagram.as_bytes()).await {
+        debug!("Failed to send metrics to the statsd agent: {}", err);
+    }
+}
@@ -0,0 +1,168 @@
+//! Defines the status cache, which answers status pings without contacting the backend.
+//!
+//! Routes with a cache TTL answer status pings from the response most recently fetched from their
+//! backend. Once a response is older than the TTL it is still served, but a refresh is started in
+//! the background for the next ping - so ping latency stays flat even while a backend is slow.
+//! Only a route's first ping, or one after every refresh has failed, waits on the backend.
+//!
+//! Health checks may also fetch the favicon of each backend, which is kept so status responses
+//! show it even while the backend is down, or when the backend's response has none.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, time::timeout};
+
+use crate::{
+    bridge::Stream,
+    io::{ProcotolAsyncWriteExt, ProtocolAsyncReadExt},
+    protocol::packets::{Handshake, PacketCodec, StatusRequest, StatusResponse},
+};
+
+/// How long fetching a status response from a backend may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A cached status response.
+struct Entry {
+    json: String,
+    fetched_at: Instant,
+    /// Whether a refresh is in flight, so concurrent pings start only one.
+    refreshing: bool,
+}
+
+/// The cached status responses, by route and protocol version - backends may answer each
+/// version differently.
+fn cache() -> &'static Mutex<HashMap<(String, i32), Entry>> {
+    static CACHE: OnceLock<Mutex<HashMap<(String, i32), Entry>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// The result of looking a response up in the cache.
+pub enum Lookup {
+    /// A response younger than the TTL.
+    Fresh(String),
+    /// A response older than the TTL, which the caller should refresh.
+    Stale(String),
+    /// A response older than the TTL, which is already being refreshed.
+    Refreshing(String),
+    /// No response has been cached.
+    Missing,
+}
+
+/// Look up the response of a route for a protocol version.
+pub fn lookup(domain: &str, protocol_version: i32, ttl: Duration) -> Lookup {
+    let mut cache = cache().lock().unwrap();
+    let Some(entry) = cache.get_mut(&(domain.to_string(), protocol_version)) else {
+        return Lookup::Missing;
+    };
+    match (entry.fetched_at.elapsed() < ttl, entry.refreshing) {
+        (true, _) => Lookup::Fresh(entry.json.clone()),
+        (false, true) => Lookup::Refreshing(entry.json.clone()),
+        (false, false) => {
+            entry.refreshing = true;
+            Lookup::Stale(entry.json.clone())
+        }
+    }
+}
+
+/// Cache the response of a route for a protocol version.
+pub fn store(domain: &str, protocol_version: i32, json: String) {
+    cache().lock().unwrap().insert(
+        (domain.to_string(), protocol_version),
+        Entry {
+            json,
+            fetched_at: Instant::now(),
+            refreshing: false,
+        },
+    );
+}
+
+/// Record that refreshing a response failed, so the next ping tries again.
+pub fn refresh_failed(domain: &str, protocol_version: i32) {
+    if let Some(entry) = cache()
+        .lock()
+        .unwrap()
+        .get_mut(&(domain.to_string(), protocol_version))
+    {
+        entry.refreshing = false;
+    }
+}
+
+/// The favicons most recently fetched from each backend.
+fn favicons() -> &'static Mutex<HashMap<SocketAddr, String>> {
+    static FAVICONS: OnceLock<Mutex<HashMap<SocketAddr, String>>> = OnceLock::new();
+    FAVICONS.get_or_init(Default::default)
+}
+
+/// Store the favicon of a backend's status response, if it has one.
+pub fn store_favicon(target: SocketAddr, json: &str) -> Result<()> {
+    let json: serde_json::Value = serde_json::from_str(json)?;
+    if let Some(favicon) = json.get("favicon").and_then(|favicon| favicon.as_str()) {
+        favicons()
+            .lock()
+            .unwrap()
+            .insert(target, favicon.to_string());
+    }
+    Ok(())
+}
+
+/// The favicon most recently fetched from the first of the given backends to have one.
+pub fn favicon(targets: &[SocketAddr]) -> Option<String> {
+    let favicons = favicons().lock().unwrap();
+    targets
+        .iter()
+        .find_map(|target| favicons.get(target).cloned())
+}
+
+/// Fetch a status response from a backend over the given stream.
+pub async fn fetch<S: Stream>(mut stream: S, handshake: &Handshake) -> Result<String> {
+    let response = timeout(FETCH_TIMEOUT, async {
+        stream
+            .write_uncompressed_packet(&handshake.encode()?)
+            .await?;
+        stream
+            .write_uncompressed_packet(&StatusRequest {}.encode()?)
+            .await?;
+        StatusResponse::decode(&stream.read_uncompressed_packet().await?)
+    })
+    .await
+    .context("timed out fetching status")??;
+    let _ = stream.shutdown().await;
+    Ok(response.json)
+}
+
+/// A snapshot of a cached status response.
+#[derive(Debug, Serialize)]
+pub struct StatusCacheSnapshot {
+    /// The domain of the route.
+    pub domain: String,
+    /// The protocol version the response was fetched for.
+    pub protocol_version: i32,
+    /// How long ago the response was fetched, in milliseconds.
+    pub age_ms: u64,
+    /// Whether the response is being refreshed.
+    pub refreshing: bool,
+}
+
+/// Summarise the cached status responses, ordered by domain.
+pub fn snapshot() -> Vec<StatusCacheSnapshot> {
+    let mut entries: Vec<_> = cache()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((domain, protocol_version), entry)| StatusCacheSnapshot {
+            domain: domain.clone(),
+            protocol_version: *protocol_version,
+            age_ms: entry.fetched_at.elapsed().as_millis() as u64,
+            refreshing: entry.refreshing,
+        })
+        .collect();
+    entries.sort_by(|a, b| (&a.domain, a.protocol_version).cmp(&(&b.domain, b.protocol_version)));
+    entries
+}
@@ -0,0 +1,190 @@
+//! Defines the log tail, which streams log lines to the admin API.
+//!
+//! The tail is a tracing layer which keeps the most recent [BACKLOG] lines in memory, and
+//! broadcasts new lines to anyone following it. Each line is tagged with the connection ID and
+//! route of the connection span it was logged in, if any, so operators can follow a single
+//! player's connection attempt without access to the host.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// How many recent lines are kept for new followers.
+const BACKLOG: usize = 1000;
+
+/// A log line.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    /// When the line was logged, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// The level of the line.
+    pub level: String,
+    /// The module the line was logged from.
+    pub target: String,
+    /// The message, followed by any other fields.
+    pub message: String,
+    /// The ID of the connection the line was logged for, if any.
+    pub connection_id: Option<String>,
+    /// The route of the connection the line was logged for, once known.
+    pub route: Option<String>,
+}
+
+/// Selects which lines to follow.
+#[derive(Debug, Default, Deserialize)]
+pub struct TailFilter {
+    /// Only follow lines logged for this connection.
+    pub connection_id: Option<String>,
+    /// Only follow lines logged for connections to this route.
+    pub route: Option<String>,
+}
+
+impl TailFilter {
+    /// Test whether a line passes the filter.
+    pub fn matches(&self, line: &LogLine) -> bool {
+        let matches = |wanted: &Option<String>, value: &Option<String>| {
+            wanted.is_none() || wanted.as_ref() == value.as_ref()
+        };
+        matches(&self.connection_id, &line.connection_id) && matches(&self.route, &line.route)
+    }
+}
+
+/// The recent lines, and the channel new lines are broadcast on.
+struct Tail {
+    recent: Mutex<VecDeque<LogLine>>,
+    sender: broadcast::Sender<LogLine>,
+}
+
+fn tail() -> &'static Tail {
+    static TAIL: OnceLock<Tail> = OnceLock::new();
+    TAIL.get_or_init(|| Tail {
+        recent: Mutex::new(VecDeque::with_capacity(BACKLOG)),
+        sender: broadcast::channel(BACKLOG).0,
+    })
+}
+
+/// Follow the log, returning the recent lines and a receiver of new lines.
+pub fn follow() -> (Vec<LogLine>, broadcast::Receiver<LogLine>) {
+    let tail = tail();
+    // subscribe while holding the lock, so no line is missed or seen twice
+    let recent = tail.recent.lock().unwrap();
+    let receiver = tail.sender.subscribe();
+    (recent.iter().cloned().collect(), receiver)
+}
+
+/// The fields of a connection span which lines are tagged with.
+#[derive(Default)]
+struct ConnectionFields {
+    connection_id: Option<String>,
+    route: Option<String>,
+}
+
+impl Visit for ConnectionFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "id" => self.connection_id = Some(value.to_string()),
+            "route" => self.route = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "id" => self.connection_id = Some(format!("{:?}", value)),
+            "route" => self.route = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Formats the fields of an event into a message.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // writing to a string can't fail
+        let _ = match (field.name(), self.0.is_empty()) {
+            ("message", true) => write!(self.0, "{:?}", value),
+            ("message", false) => {
+                self.0 = format!("{:?} {}", value, self.0);
+                Ok(())
+            }
+            (name, true) => write!(self.0, "{}={:?}", name, value),
+            (name, false) => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+}
+
+/// A tracing layer feeding the log tail.
+pub struct TailLayer;
+
+impl<S> Layer<S> for TailLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != "connection" {
+            return;
+        }
+        let mut fields = ConnectionFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<ConnectionFields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        let (connection_id, route) = ctx
+            .event_scope(event)
+            .and_then(|mut scope| {
+                scope.find_map(|span| {
+                    let extensions = span.extensions();
+                    let fields = extensions.get::<ConnectionFields>()?;
+                    Some((fields.connection_id.clone(), fields.route.clone()))
+                })
+            })
+            .unwrap_or_default();
+        let line = LogLine {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            level: event.metadata().level().to_string(),
+            target: event.metadata().target().to_string(),
+            message: message.0,
+            connection_id,
+            route,
+        };
+
+        let tail = tail();
+        let mut recent = tail.recent.lock().unwrap();
+        if recent.len() == BACKLOG {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+        // nobody may be following
+        let _ = tail.sender.send(line);
+    }
+}
@@ -0,0 +1,59 @@
+//! Defines the tarpit, which holds denied connections open to slow down attackers.
+//!
+//! Dropping a denied connection lets a bot retry immediately. The tarpit instead accepts the
+//! connection and reads from it a byte at a time, every few seconds, until the configured duration
+//! has passed. The client's sends soon stall on the full receive window, tying up its capacity,
+//! while costing Magma little more than an idle socket. The number of held connections is capped,
+//! and connections over the cap are dropped as usual.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::Semaphore,
+    time::{sleep, timeout},
+};
+use tracing::trace;
+
+use crate::config::TarpitConfig;
+
+/// How often a byte is read from a held connection.
+const READ_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A tarpit for denied connections.
+pub struct Tarpit {
+    /// How long connections are held.
+    duration: Duration,
+    /// Limits the number of connections held at once.
+    slots: Semaphore,
+}
+
+impl Tarpit {
+    /// Create a tarpit with the given configuration.
+    pub fn new(config: TarpitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            duration: config.duration,
+            slots: Semaphore::new(config.max_connections),
+        })
+    }
+
+    /// Hold the given connection until the tarpit duration passes, or the client gives up. Returns
+    /// immediately if the tarpit is full.
+    pub async fn hold<S: AsyncRead + Unpin>(&self, mut stream: S) {
+        let Ok(_permit) = self.slots.try_acquire() else {
+            trace!("Tarpit is full - dropping connection");
+            return;
+        };
+        let mut buf = [0u8; 1];
+        let _ = timeout(self.duration, async {
+            loop {
+                sleep(READ_INTERVAL).await;
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                }
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,167 @@
+//! Defines templated routes, which map many subdomains onto their own backends.
+//!
+//! A templated route's domain contains a `{name}` placeholder, such as `{name}.play.example.com`.
+//! The name captured from a client's handshake is substituted into a target template, such as
+//! `10.0.0.{name}:25565`, or passed to a lookup hook which returns the target - so per-customer
+//! subdomains don't need one route entry each.
+//!
+//! Lookups are cached, failures included, and limited in how many run at once and how long each
+//! may take - the names come from handshakes, so a flood of made-up subdomains would otherwise
+//! start a request or command for each.
+
+use std::{
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use lru::LruCache;
+use tokio::{net::lookup_host, process::Command, sync::Semaphore, time::timeout};
+
+/// The placeholder captured from domains, and substituted into targets.
+pub const PLACEHOLDER: &str = "{name}";
+
+/// How long to wait for a lookup, including for a free slot, before giving up on the name.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a resolved target is cached for.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// How long a failed lookup is cached for, so a name which will exist shortly isn't kept out long.
+const FAILURE_CACHE_TTL: Duration = Duration::from_secs(10);
+/// The maximum number of cached names, after which the least recently used are evicted.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+/// The maximum number of lookups running at once, across every route.
+const MAX_CONCURRENT_LOOKUPS: usize = 32;
+
+/// The target each resolver and name resolved to, or why it failed, and when it was looked up.
+type Cache = Mutex<LruCache<(Resolver, String), (Result<SocketAddr, String>, Instant)>>;
+
+/// The targets names resolved to, and when they were looked up.
+fn cache() -> &'static Cache {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(LruCache::new(NonZeroUsize::new(MAX_CACHE_ENTRIES).unwrap())))
+}
+
+/// The slots lookups wait for.
+fn slots() -> &'static Semaphore {
+    static SLOTS: OnceLock<Semaphore> = OnceLock::new();
+    SLOTS.get_or_init(|| Semaphore::new(MAX_CONCURRENT_LOOKUPS))
+}
+
+/// The HTTP client lookup URLs are requested with.
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// A domain pattern with a single placeholder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    prefix: String,
+    suffix: String,
+}
+
+impl Pattern {
+    /// Parse a domain pattern, returning `None` if it has no placeholder.
+    pub fn parse(domain: &str) -> Option<Self> {
+        let (prefix, suffix) = domain.split_once(PLACEHOLDER)?;
+        Some(Self {
+            prefix: prefix.to_lowercase(),
+            suffix: suffix.to_lowercase(),
+        })
+    }
+
+    /// The part of the pattern after the placeholder, lowercased.
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+
+    /// Capture the name from a domain, if it matches. Names are limited to letters, digits and
+    /// dashes, as they end up in addresses, URLs and commands.
+    pub fn capture(&self, domain: &str) -> Option<String> {
+        let domain = domain.to_lowercase();
+        let name = domain
+            .strip_prefix(&self.prefix)?
+            .strip_suffix(&self.suffix)?;
+        let valid = !name.is_empty()
+            && name.len() <= 63
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        valid.then(|| name.to_string())
+    }
+}
+
+/// How a captured name is turned into a target.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Resolver {
+    /// Substitute the name into an address, such as `10.0.0.{name}:25565`.
+    Template(String),
+    /// Request a URL with the name substituted, whose body is the address.
+    Http(String),
+    /// Run a shell command with the name in the `MAGMA_NAME` environment variable, whose output
+    /// is the address.
+    Command(String),
+}
+
+impl Resolver {
+    /// Resolve the target for a captured name, from the cache if it was looked up recently.
+    pub async fn resolve(&self, name: &str) -> Result<SocketAddr> {
+        let key = (self.clone(), name.to_string());
+        if let Some((result, looked_up)) = cache().lock().unwrap().get(&key) {
+            let ttl = match result {
+                Ok(_) => CACHE_TTL,
+                Err(_) => FAILURE_CACHE_TTL,
+            };
+            if looked_up.elapsed() < ttl {
+                return result.clone().map_err(|err| anyhow!(err));
+            }
+        }
+        let result = match timeout(LOOKUP_TIMEOUT, self.lookup(name)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("Lookup of {:?} timed out", name)),
+        };
+        let cached = result.as_ref().copied().map_err(|err| format!("{:#}", err));
+        cache().lock().unwrap().put(key, (cached, Instant::now()));
+        result
+    }
+
+    /// Look up the target for a captured name, once a slot is free.
+    async fn lookup(&self, name: &str) -> Result<SocketAddr> {
+        let _slot = slots().acquire().await?;
+        let address = match self {
+            Resolver::Template(template) => template.replace(PLACEHOLDER, name),
+            Resolver::Http(url) => {
+                client()
+                    .get(url.replace(PLACEHOLDER, name))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await?
+            }
+            Resolver::Command(command) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .env("MAGMA_NAME", name)
+                    .kill_on_drop(true)
+                    .output()
+                    .await?;
+                if !output.status.success() {
+                    bail!("Command {:?} exited with {}", command, output.status);
+                }
+                String::from_utf8(output.stdout).context("lookup output is not UTF-8")?
+            }
+        };
+        let address = address.trim();
+        if address.is_empty() {
+            bail!("No target for {:?}", name);
+        }
+        let mut targets = lookup_host(address)
+            .await
+            .with_context(|| format!("Invalid target {:?} for {:?}", address, name))?;
+        targets
+            .next()
+            .with_context(|| format!("Target {:?} for {:?} did not resolve", address, name))
+    }
+}
@@ -0,0 +1,180 @@
+//! Defines mutual TLS for control-plane and tunnel connections.
+//!
+//! Both ends present a certificate signed by an operator-provided CA, and reject peers whose
+//! certificate is not. Certificates are loaded from disk, and reloaded when Magma receives
+//! `SIGHUP`, so they can be rotated without dropping connections.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore,
+        ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use tracing::{info, warn};
+
+use crate::config::TlsConfig;
+
+/// A TLS acceptor which requires clients to present a certificate signed by the CA.
+pub struct Acceptor {
+    config: TlsConfig,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl Acceptor {
+    /// Load an acceptor from the configured files, reloading it on `SIGHUP`.
+    pub fn new(config: TlsConfig) -> Result<Arc<Self>> {
+        let acceptor = Arc::new(Self {
+            acceptor: RwLock::new(load_acceptor(&config)?),
+            config,
+        });
+        spawn_reload(acceptor.clone(), |acceptor| {
+            *acceptor.acceptor.write().unwrap() = load_acceptor(&acceptor.config)?;
+            Ok(())
+        });
+        Ok(acceptor)
+    }
+
+    /// The current acceptor.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
+
+/// A TLS connector which presents a client certificate, and requires servers to present a
+/// certificate signed by the CA.
+pub struct Connector {
+    config: TlsConfig,
+    connector: RwLock<TlsConnector>,
+}
+
+impl Connector {
+    /// Load a connector from the configured files, reloading it on `SIGHUP`.
+    pub fn new(config: TlsConfig) -> Result<Arc<Self>> {
+        let connector = Arc::new(Self {
+            connector: RwLock::new(load_connector(&config)?),
+            config,
+        });
+        spawn_reload(connector.clone(), |connector| {
+            *connector.connector.write().unwrap() = load_connector(&connector.config)?;
+            Ok(())
+        });
+        Ok(connector)
+    }
+
+    /// The current connector.
+    pub fn connector(&self) -> TlsConnector {
+        self.connector.read().unwrap().clone()
+    }
+}
+
+/// Build an acceptor from the configured files.
+fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let roots = load_roots(&config.ca)?;
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        .with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .context("invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Build a connector from the configured files.
+fn load_connector(config: &TlsConfig) -> Result<TlsConnector> {
+    let roots = load_roots(&config.ca)?;
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .context("invalid TLS certificate or key")?;
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// Load the certificate chain from a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("failed to open certificate {:?}", path))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("failed to parse certificate {:?}", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {:?}", path);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Load the first private key from a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("failed to open private key {:?}", path))?,
+    );
+    for item in rustls_pemfile::read_all(&mut reader)
+        .with_context(|| format!("failed to parse private key {:?}", path))?
+    {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("No private key found in {:?}", path)
+}
+
+/// Load the trusted CA certificates from a PEM file.
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .with_context(|| format!("invalid CA certificate in {:?}", path))?;
+    }
+    Ok(roots)
+}
+
+/// Spawn a task which reloads the given value whenever Magma receives `SIGHUP`. Failed reloads
+/// keep the previous certificates.
+#[cfg(unix)]
+fn spawn_reload<T, F>(value: Arc<T>, reload: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn(&T) -> Result<()> + Send + 'static,
+{
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::task::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                warn!(
+                    "Failed to listen for SIGHUP - certificates will not be reloaded: {}",
+                    err
+                );
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match reload(&value) {
+                Ok(()) => info!("Reloaded TLS certificates"),
+                Err(err) => warn!("Failed to reload TLS certificates: {:#}", err),
+            }
+        }
+    });
+}
+
+/// Certificates are only reloaded on `SIGHUP`, which is unavailable on this platform.
+#[cfg(not(unix))]
+fn spawn_reload<T, F>(_value: Arc<T>, _reload: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn(&T) -> Result<()> + Send + 'static,
+{
+}
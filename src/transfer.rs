@@ -0,0 +1,50 @@
+//! Defines transfer pins, which route a transferred player to the backend an operator chose.
+//!
+//! Transferring a player asks their client to reconnect to a route, which would otherwise pick a
+//! backend as usual. A pin records the chosen backend for the player's address, and is taken by
+//! their next connection to the route - or expires after [PIN_TTL] if they never reconnect.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// How long a pin waits for the player to reconnect.
+const PIN_TTL: Duration = Duration::from_secs(30);
+
+/// A backend chosen for the next connection from an address to a route.
+struct Pin {
+    target: SocketAddr,
+    pinned_at: Instant,
+}
+
+/// The pins, by address and route.
+fn pins() -> &'static Mutex<HashMap<(IpAddr, String), Pin>> {
+    static PINS: OnceLock<Mutex<HashMap<(IpAddr, String), Pin>>> = OnceLock::new();
+    PINS.get_or_init(Default::default)
+}
+
+/// Route the next connection from the address to the route to the given backend.
+pub fn pin(ip: IpAddr, domain: &str, target: SocketAddr) {
+    let mut pins = pins().lock().unwrap();
+    pins.retain(|_, pin| pin.pinned_at.elapsed() < PIN_TTL);
+    pins.insert(
+        (ip, domain.to_string()),
+        Pin {
+            target,
+            pinned_at: Instant::now(),
+        },
+    );
+}
+
+/// Take the backend pinned for a connection from the address to the route, if any.
+pub fn take(ip: IpAddr, domain: &str) -> Option<SocketAddr> {
+    pins()
+        .lock()
+        .unwrap()
+        .remove(&(ip, domain.to_string()))
+        .filter(|pin| pin.pinned_at.elapsed() < PIN_TTL)
+        .map(|pin| pin.target)
+}
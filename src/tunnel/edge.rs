@@ -0,0 +1,131 @@
+//! Defines the edge end of a tunnel, which forwards connections to a hub.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::TcpStream,
+    sync::RwLock,
+    time::sleep,
+};
+use tokio_rustls::rustls::ServerName;
+use tracing::{info, warn};
+
+use super::{authenticate, mux::Mux};
+use crate::{config::EdgeConfig, link::Link, secrets, tls::Connector};
+
+/// The initial delay before reconnecting to the hub.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The maximum delay before reconnecting to the hub.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// A tunnel to a hub over a pool of links, which are kept connected in the background.
+pub struct Edge {
+    /// The edge configuration.
+    config: EdgeConfig,
+    /// The TLS connector, if the link is encrypted.
+    connector: Option<Arc<Connector>>,
+    /// The multiplexed links to the hub, each of which may be disconnected.
+    links: Vec<RwLock<Option<Mux>>>,
+}
+
+impl Edge {
+    /// Create an edge, and spawn tasks which keep its links connected to the hub.
+    pub fn spawn(config: EdgeConfig) -> Result<Arc<Self>> {
+        let connector = config.tls.clone().map(Connector::new).transpose()?;
+        let links = (0..config.connections.max(1))
+            .map(|_| RwLock::new(None))
+            .collect();
+        let edge = Arc::new(Self {
+            config,
+            connector,
+            links,
+        });
+        for index in 0..edge.links.len() {
+            tokio::task::spawn(edge.clone().maintain(index));
+        }
+        Ok(edge)
+    }
+
+    /// Open a stream through the hub to the given target, on the least busy link.
+    pub async fn open(&self, target: SocketAddr) -> Result<DuplexStream> {
+        let mut best: Option<Mux> = None;
+        for link in &self.links {
+            let Some(mux) = link.read().await.clone() else {
+                continue;
+            };
+            if mux.is_closed() {
+                continue;
+            }
+            if best
+                .as_ref()
+                .is_none_or(|best| mux.stream_count() < best.stream_count())
+            {
+                best = Some(mux);
+            }
+        }
+        best.context("tunnel to hub is not connected")?
+            .open(&target.to_string())
+            .await
+    }
+
+    /// Keep a link to the hub connected, reconnecting with a backoff.
+    #[tracing::instrument(name = "edge", skip_all, fields(hub = %self.config.hub_addr, link = index))]
+    async fn maintain(self: Arc<Self>, index: usize) {
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            match self.connect().await {
+                Ok(mux) => {
+                    info!("Connected to hub");
+                    delay = MIN_RECONNECT_DELAY;
+                    *self.links[index].write().await = Some(mux.clone());
+                    mux.closed().await;
+                    warn!("Lost connection to hub");
+                }
+                Err(err) => warn!("Failed to connect to hub: {:#}", err),
+            }
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    /// Connect and authenticate to the hub.
+    async fn connect(&self) -> Result<Mux> {
+        let stream = TcpStream::connect(self.config.hub_addr).await?;
+        stream.set_nodelay(true)?;
+        match &self.connector {
+            Some(connector) => {
+                let server_name = match &self.config.server_name {
+                    Some(name) => {
+                        ServerName::try_from(name.as_str()).context("invalid hub server name")?
+                    }
+                    None => ServerName::IpAddress(self.config.hub_addr.ip()),
+                };
+                let stream = connector.connector().connect(server_name, stream).await?;
+                self.establish(stream).await
+            }
+            None => self.establish(stream).await,
+        }
+    }
+
+    /// Establish and authenticate a link over the given stream.
+    async fn establish<S>(&self, stream: S) -> Result<Mux>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let link = Link::connect(stream, self.config.compression).await?;
+        let (mut reader, mut writer) = link.into_split();
+        // the token may have been rotated by a reload since the edge started
+        let token = secrets::current().tunnel_token.clone();
+        authenticate(
+            &mut reader,
+            &mut writer,
+            token.as_ref().unwrap_or(&self.config.token),
+        )
+        .await?;
+        // the hub never opens streams of its own
+        let (mux, _) = Mux::new(reader, writer);
+        Ok(mux)
+    }
+}
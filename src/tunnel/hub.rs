@@ -0,0 +1,132 @@
+//! Defines the hub end of a tunnel, which connects streams from edges to backends.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, DuplexStream},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    mux::{Incoming, Mux},
+    verify,
+};
+use crate::{
+    config::HubConfig,
+    link::Link,
+    secrets,
+    security::{self, SecurityEvent},
+    tls::Acceptor,
+};
+
+/// How long an edge has to establish and authenticate its link.
+const AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spawns a hub, and returns a handle to the task.
+pub fn spawn(config: HubConfig) -> JoinHandle<Result<()>> {
+    tokio::task::spawn(async move { listen(config).await })
+}
+
+/// Listen for tunnels from edges.
+#[tracing::instrument(name = "hub", skip_all, fields(addr = %config.listen_addr))]
+async fn listen(config: HubConfig) -> Result<()> {
+    let listener = TcpListener::bind(config.listen_addr).await.map_err(|err| {
+        error!("Error while starting tunnel hub: {}", err);
+        err
+    })?;
+    let acceptor = config.tls.clone().map(Acceptor::new).transpose()?;
+    let config = Arc::new(config);
+
+    info!("Started tunnel hub");
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let config = config.clone();
+        let acceptor = acceptor.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = handle_edge(config, acceptor, addr, stream).await {
+                warn!("Tunnel from {} failed: {:#}", addr, err);
+            }
+        });
+    }
+}
+
+/// Accept a connection from an edge, negotiating TLS if required.
+async fn handle_edge(
+    config: Arc<HubConfig>,
+    acceptor: Option<Arc<Acceptor>>,
+    addr: SocketAddr,
+    stream: TcpStream,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    match acceptor {
+        Some(acceptor) => {
+            let stream = timeout(AUTHENTICATION_TIMEOUT, acceptor.acceptor().accept(stream))
+                .await
+                .context("edge did not complete the TLS handshake in time")?
+                .inspect_err(|err| {
+                    security::report(SecurityEvent::AuthFailure, addr.ip(), err);
+                })?;
+            serve_edge(config, addr, stream).await
+        }
+        None => serve_edge(config, addr, stream).await,
+    }
+}
+
+/// Authenticate an edge, then serve the streams it opens.
+async fn serve_edge<S>(config: Arc<HubConfig>, addr: SocketAddr, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (reader, writer) = timeout(AUTHENTICATION_TIMEOUT, async {
+        let link = Link::accept(stream, config.compression).await?;
+        let (mut reader, mut writer) = link.into_split();
+        // the token may have been rotated by a reload since the hub started
+        let token = secrets::current().tunnel_token.clone();
+        verify(
+            &mut reader,
+            &mut writer,
+            token.as_ref().unwrap_or(&config.token),
+        )
+        .await
+        .inspect_err(|err| {
+            security::report(SecurityEvent::AuthFailure, addr.ip(), err);
+        })?;
+        anyhow::Ok((reader, writer))
+    })
+    .await
+    .context("edge did not authenticate in time")??;
+
+    info!("Edge connected");
+    let (_mux, mut incoming) = Mux::new(reader, writer);
+    while let Some(Incoming { target, stream }) = incoming.recv().await {
+        let config = config.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = forward(&config, &target, stream).await {
+                debug!("Failed to forward tunnel stream to {}: {:#}", target, err);
+            }
+        });
+    }
+    info!("Edge disconnected");
+    Ok(())
+}
+
+/// Connect a tunneled stream to its target.
+async fn forward(config: &HubConfig, target: &str, mut stream: DuplexStream) -> Result<()> {
+    let target: SocketAddr = target.parse().context("invalid tunnel target")?;
+    // edges may only reach the backends the hub allows
+    if !config.allowed_targets.contains(&target) {
+        bail!("Target {} is not allowed", target);
+    }
+    let mut server_stream = TcpStream::connect(target).await?;
+    server_stream.set_nodelay(true)?;
+    copy_bidirectional(&mut stream, &mut server_stream).await?;
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Defines tunnels between an edge Magma, near players, and a hub Magma, inside the private
+//! network hosting the backends.
+//!
+//! The edge accepts player connections as usual, but rather than connecting to backends directly,
+//! it opens a stream to the hub over a single authenticated [link](crate::link), which the hub
+//! connects to the backend on its behalf. Backends never need to be publicly exposed, and only
+//! the hub needs to be reachable from the edge.
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::link::{LinkReader, LinkWriter};
+
+pub mod edge;
+pub mod hub;
+pub mod mux;
+
+/// Sent by the hub to acknowledge a successful authentication.
+const AUTH_OK: &[u8] = b"ok";
+
+/// Authenticate with a hub, sending the shared token and awaiting its acknowledgement.
+async fn authenticate<R, W>(
+    reader: &mut LinkReader<R>,
+    writer: &mut LinkWriter<W>,
+    token: &str,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    writer.write_frame(token.as_bytes()).await?;
+    writer.flush().await?;
+    match reader.read_frame().await? {
+        Some(frame) if frame == AUTH_OK => Ok(()),
+        _ => bail!("Hub rejected tunnel authentication"),
+    }
+}
+
+/// Verify the token sent by an edge, acknowledging it if it matches.
+async fn verify<R, W>(
+    reader: &mut LinkReader<R>,
+    writer: &mut LinkWriter<W>,
+    token: &str,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let frame = reader.read_frame().await?.unwrap_or_default();
+    if !constant_time_eq(&frame, token.as_bytes()) {
+        bail!("Edge sent an invalid tunnel token");
+    }
+    writer.write_frame(AUTH_OK).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Compare two byte strings without leaking where they differ through timing.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,367 @@
+//! Multiplexes many streams over a single link.
+//!
+//! Every link frame carries a mux frame - a kind, a stream id, and a payload. The edge opens
+//! streams, naming the backend the hub should connect to, after which data flows in both
+//! directions until each side closes its half of the stream. Each stream is exposed as a
+//! [DuplexStream], so it can be used anywhere a socket can.
+//!
+//! Streams are flow controlled independently. Each side may only send up to a window of data
+//! on a stream before the receiver grants it more, which it does as the data is consumed. A slow
+//! player therefore only stalls their own stream, rather than every stream sharing the link.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    select,
+    sync::{mpsc, oneshot, Semaphore},
+};
+use tracing::{debug, trace};
+
+use crate::link::{LinkReader, LinkWriter};
+
+/// The size of the buffer between a stream and the mux.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+/// The maximum amount of data carried by a single frame.
+const MAX_DATA_LENGTH: usize = 16 * 1024;
+/// The amount of data that may be in flight on a stream before the receiver grants more.
+const WINDOW_SIZE: usize = 256 * 1024;
+/// The number of frames that may be queued for the link before writers wait.
+const OUTBOUND_QUEUE_SIZE: usize = 1024;
+
+/// A frame sent over a multiplexed link.
+#[derive(Debug)]
+enum Frame {
+    /// Opens a new stream to the given target.
+    Open { id: u32, target: String },
+    /// Carries data for a stream.
+    Data { id: u32, data: Vec<u8> },
+    /// Closes the sender's half of a stream.
+    Close { id: u32 },
+    /// Grants the receiver permission to send more data on a stream.
+    Window { id: u32, increment: u32 },
+}
+
+impl Frame {
+    /// Encode the frame as the payload of a link frame.
+    fn encode(&self) -> Vec<u8> {
+        let (kind, id, payload) = match self {
+            Frame::Open { id, target } => (0u8, id, target.as_bytes().to_vec()),
+            Frame::Data { id, data } => (1, id, data.clone()),
+            Frame::Close { id } => (2, id, vec![]),
+            Frame::Window { id, increment } => (3, id, increment.to_be_bytes().to_vec()),
+        };
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        buf.push(kind);
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    /// Decode a frame from the payload of a link frame.
+    fn decode(mut buf: Vec<u8>) -> Result<Self> {
+        if buf.len() < 5 {
+            bail!("Mux frame is too short ({} bytes)", buf.len());
+        }
+        let id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        let kind = buf[0];
+        let payload = buf.split_off(5);
+        match kind {
+            0 => Ok(Frame::Open {
+                id,
+                target: String::from_utf8(payload).context("invalid stream target")?,
+            }),
+            1 => Ok(Frame::Data { id, data: payload }),
+            2 => Ok(Frame::Close { id }),
+            3 => {
+                let increment: [u8; 4] = payload
+                    .as_slice()
+                    .try_into()
+                    .context("invalid window increment")?;
+                Ok(Frame::Window {
+                    id,
+                    increment: u32::from_be_bytes(increment),
+                })
+            }
+            kind => bail!("Unknown mux frame kind {}", kind),
+        }
+    }
+}
+
+/// A stream opened by the remote end of the mux.
+pub struct Incoming {
+    /// The target the stream should be connected to.
+    pub target: String,
+    /// The stream.
+    pub stream: DuplexStream,
+}
+
+/// The mux's view of an open stream.
+struct StreamHandle {
+    /// Sends data received from the remote to the user, until the remote closes its half.
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Data received from the remote which has not yet been granted back.
+    buffered: Arc<AtomicUsize>,
+    /// The data this end may send before the remote grants more.
+    credit: Arc<Semaphore>,
+    /// Whether the user has closed this end's half of the stream.
+    closed: bool,
+}
+
+/// State shared between the mux handle and its tasks.
+struct Shared {
+    /// Frames queued for the link.
+    outbound: mpsc::Sender<Frame>,
+    /// Every open stream.
+    streams: Mutex<HashMap<u32, StreamHandle>>,
+    /// The id of the next stream opened by this end.
+    next_id: AtomicU32,
+}
+
+/// A handle to a multiplexed link.
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+impl Mux {
+    /// Multiplex streams over the given link, returning the mux and a receiver of streams opened
+    /// by the remote end.
+    pub fn new<R, W>(
+        reader: LinkReader<R>,
+        writer: LinkWriter<W>,
+    ) -> (Self, mpsc::Receiver<Incoming>)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outbound_tx, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        let (done_tx, done_rx) = oneshot::channel();
+        let shared = Arc::new(Shared {
+            outbound: outbound_tx,
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+        });
+
+        tokio::task::spawn(write_link(writer, outbound_rx, done_rx));
+        tokio::task::spawn(read_link(shared.clone(), reader, incoming_tx, done_tx));
+
+        (Self { shared }, incoming_rx)
+    }
+
+    /// Open a new stream to the given target.
+    pub async fn open(&self, target: &str) -> Result<DuplexStream> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        // register first, so no reply from the remote can arrive before the stream exists
+        let stream = register(&self.shared, id);
+        self.shared
+            .outbound
+            .send(Frame::Open {
+                id,
+                target: target.to_string(),
+            })
+            .await
+            .context("tunnel link is closed")?;
+        trace!("Opened mux stream {} to {}", id, target);
+        Ok(stream)
+    }
+
+    /// The number of open streams.
+    pub fn stream_count(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    /// Test whether the underlying link has closed.
+    pub fn is_closed(&self) -> bool {
+        self.shared.outbound.is_closed()
+    }
+
+    /// Wait for the underlying link to close.
+    pub async fn closed(&self) {
+        self.shared.outbound.closed().await
+    }
+}
+
+/// Register a stream with the given id, spawning the tasks which pump data between it and the
+/// link. Returns the user's end of the stream.
+fn register(shared: &Arc<Shared>, id: u32) -> DuplexStream {
+    let (local, remote) = io::duplex(STREAM_BUFFER_SIZE);
+    let (mut remote_rx, mut remote_tx) = io::split(remote);
+    let (inbound_tx, mut inbound_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let buffered = Arc::new(AtomicUsize::new(0));
+    let credit = Arc::new(Semaphore::new(WINDOW_SIZE));
+    shared.streams.lock().unwrap().insert(
+        id,
+        StreamHandle {
+            inbound: Some(inbound_tx),
+            buffered: buffered.clone(),
+            credit: credit.clone(),
+            closed: false,
+        },
+    );
+
+    // data written by the user is sent over the link, as the remote grants credit
+    let stream_shared = shared.clone();
+    tokio::task::spawn(async move {
+        let shared = stream_shared;
+        let mut buf = vec![0u8; MAX_DATA_LENGTH];
+        loop {
+            let n = match remote_rx.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            // the semaphore is closed when the link is gone
+            match credit.acquire_many(n as u32).await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return,
+            }
+            let frame = Frame::Data {
+                id,
+                data: buf[..n].to_vec(),
+            };
+            if shared.outbound.send(frame).await.is_err() {
+                return;
+            }
+        }
+        // the stream is forgotten once both halves are closed
+        {
+            let mut streams = shared.streams.lock().unwrap();
+            if let Some(stream) = streams.get_mut(&id) {
+                stream.closed = true;
+                if stream.inbound.is_none() {
+                    streams.remove(&id);
+                }
+            }
+        }
+        let _ = shared.outbound.send(Frame::Close { id }).await;
+    });
+
+    // data received over the link is written to the user, granting credit as it is consumed
+    let outbound = shared.outbound.clone();
+    tokio::task::spawn(async move {
+        let mut writable = true;
+        let mut consumed = 0;
+        while let Some(data) = inbound_rx.recv().await {
+            // keep granting credit if the user went away, so the remote never stalls
+            if writable && remote_tx.write_all(&data).await.is_err() {
+                writable = false;
+            }
+            consumed += data.len();
+            buffered.fetch_sub(data.len(), Ordering::Relaxed);
+            if consumed >= WINDOW_SIZE / 2 {
+                let increment = consumed as u32;
+                consumed = 0;
+                if outbound
+                    .send(Frame::Window { id, increment })
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+        let _ = remote_tx.shutdown().await;
+    });
+
+    local
+}
+
+/// Write queued frames to the link until it fails or the reader finishes.
+async fn write_link<W: AsyncWrite + Unpin>(
+    mut writer: LinkWriter<W>,
+    mut outbound: mpsc::Receiver<Frame>,
+    mut done: oneshot::Receiver<()>,
+) {
+    let result: Result<()> = async {
+        loop {
+            let frame = select! {
+                frame = outbound.recv() => frame,
+                _ = &mut done => None,
+            };
+            let Some(frame) = frame else {
+                return Ok(());
+            };
+            writer.write_frame(&frame.encode()).await?;
+            // batch any other queued frames before flushing
+            while let Ok(frame) = outbound.try_recv() {
+                writer.write_frame(&frame.encode()).await?;
+            }
+            writer.flush().await?;
+        }
+    }
+    .await;
+    if let Err(err) = result {
+        debug!("Tunnel link write failed: {}", err);
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Read frames from the link, dispatching them to their streams.
+async fn read_link<R: AsyncRead + Unpin>(
+    shared: Arc<Shared>,
+    mut reader: LinkReader<R>,
+    incoming: mpsc::Sender<Incoming>,
+    _done: oneshot::Sender<()>,
+) {
+    let result: Result<()> = async {
+        while let Some(frame) = reader.read_frame().await? {
+            match Frame::decode(frame)? {
+                Frame::Open { id, target } => {
+                    trace!("Remote opened mux stream {} to {}", id, target);
+                    if shared.streams.lock().unwrap().contains_key(&id) {
+                        bail!("Remote reopened mux stream {}", id);
+                    }
+                    let stream = register(&shared, id);
+                    // if nobody is accepting streams, dropping it closes it
+                    let _ = incoming.send(Incoming { target, stream }).await;
+                }
+                Frame::Data { id, data } => {
+                    let streams = shared.streams.lock().unwrap();
+                    let Some(stream) = streams.get(&id) else {
+                        continue;
+                    };
+                    let Some(inbound) = &stream.inbound else {
+                        continue;
+                    };
+                    // a well-behaved remote never exceeds the window
+                    let length = data.len();
+                    if stream.buffered.fetch_add(length, Ordering::Relaxed) + length > WINDOW_SIZE {
+                        bail!("Remote exceeded the window of mux stream {}", id);
+                    }
+                    let _ = inbound.send(data);
+                }
+                Frame::Close { id } => {
+                    let mut streams = shared.streams.lock().unwrap();
+                    if let Some(stream) = streams.get_mut(&id) {
+                        stream.inbound = None;
+                        if stream.closed {
+                            streams.remove(&id);
+                        }
+                    }
+                }
+                Frame::Window { id, increment } => {
+                    if let Some(stream) = shared.streams.lock().unwrap().get(&id) {
+                        stream.credit.add_permits(increment as usize);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(err) = result {
+        debug!("Tunnel link read failed: {}", err);
+    }
+    // close every stream - the link is gone
+    for (_, stream) in shared.streams.lock().unwrap().drain() {
+        stream.credit.close();
+    }
+}
@@ -0,0 +1,53 @@
+//! Defines username policies, which turn away clients logging in with unwanted usernames.
+//!
+//! Usernames are checked as soon as the client sends its login start, so a rejected client is
+//! disconnected before its login reaches the backend or is authenticated. Online-mode backends
+//! only admit Mojang accounts, whose names follow the vanilla rules, but offline-mode backends
+//! accept whatever the client claims - including names which break plugins, or impersonate staff.
+//! Some also trust the uuid the client claims, so routes to offline backends can replace it with
+//! the offline uuid derived from the username, as a vanilla offline server would.
+
+use md5::{Digest, Md5};
+use regex::Regex;
+use uuid::{Builder, Uuid};
+
+/// The longest username the vanilla client and server accept.
+const MAX_LENGTH: usize = 16;
+/// The shortest username Mojang accounts may have.
+const MIN_LENGTH: usize = 3;
+
+/// Which usernames a route accepts.
+#[derive(Debug, Clone)]
+pub struct UsernamePolicy {
+    /// Whether usernames must follow the vanilla rules - 3 to 16 letters, digits and underscores.
+    pub vanilla: bool,
+    /// Usernames matching any of these are rejected, such as slurs or impersonations of staff.
+    pub deny: Vec<Regex>,
+    /// The message rejected clients are disconnected with.
+    pub message: String,
+}
+
+impl UsernamePolicy {
+    /// Test whether a client may log in with the given username.
+    pub fn allows(&self, username: &str) -> bool {
+        if self.vanilla && !is_vanilla(username) {
+            return false;
+        }
+        !self.deny.iter().any(|pattern| pattern.is_match(username))
+    }
+}
+
+/// Test whether a username follows the vanilla rules.
+pub fn is_vanilla(username: &str) -> bool {
+    (MIN_LENGTH..=MAX_LENGTH).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The uuid an offline server gives a username - a version 3 uuid of `OfflinePlayer:<username>`,
+/// hashed without a namespace.
+pub fn offline_uuid(username: &str) -> Uuid {
+    let digest = Md5::digest(format!("OfflinePlayer:{}", username));
+    Builder::from_md5_bytes(digest.into()).into_uuid()
+}
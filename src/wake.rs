@@ -0,0 +1,91 @@
+//! Defines wake-on-connect for cold backends.
+//!
+//! Rarely-used servers can be left stopped. When a client connects to a route whose target is
+//! down, Magma triggers the route's start hook, and either holds the login until the target comes
+//! up, or turns the client away with a message asking them to try again shortly.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use tokio::{net::TcpStream, time::sleep};
+use tracing::{info, warn};
+
+use crate::{health, hook::Hook};
+
+/// How often a held login retries its target.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a route wakes cold targets.
+#[derive(Debug, Clone)]
+pub struct Wake {
+    /// The hook starting a target.
+    pub hook: Hook,
+    /// How long logins are held while the target starts. Logins are turned away if unset.
+    pub hold: Option<Duration>,
+    /// How long to wait before triggering the hook again for the same target.
+    pub cooldown: Duration,
+    /// The message logins are turned away with while the target starts.
+    pub message: String,
+    /// The MOTD shown to status pings while the target starts.
+    pub motd: String,
+}
+
+/// When each target was last woken.
+fn woken() -> &'static Mutex<HashMap<SocketAddr, Instant>> {
+    static WOKEN: OnceLock<Mutex<HashMap<SocketAddr, Instant>>> = OnceLock::new();
+    WOKEN.get_or_init(Default::default)
+}
+
+/// When a target was last woken, if ever.
+pub fn woken_at(target: SocketAddr) -> Option<Instant> {
+    woken().lock().unwrap().get(&target).copied()
+}
+
+impl Wake {
+    /// Trigger the start hook for a target in the background, unless it was triggered within the
+    /// cooldown.
+    pub fn start(&self, route: &str, target: SocketAddr) {
+        {
+            let mut woken = woken().lock().unwrap();
+            if let Some(at) = woken.get(&target) {
+                if at.elapsed() < self.cooldown {
+                    return;
+                }
+            }
+            woken.insert(target, Instant::now());
+        }
+        info!("Waking backend {} for {}", target, route);
+        let hook = self.hook.clone();
+        let route = route.to_string();
+        tokio::task::spawn(async move {
+            if let Err(err) = hook.run(&route, target).await {
+                warn!("Failed to wake backend {}: {:#}", target, err);
+            }
+        });
+    }
+
+    /// Wait for a target to come up, connecting to it once it does. Targets with a health check
+    /// must also pass it.
+    pub async fn wait(&self, target: SocketAddr) -> Result<TcpStream> {
+        let Some(hold) = self.hold else {
+            bail!("backend {} is starting", target);
+        };
+        let deadline = Instant::now() + hold;
+        loop {
+            if health::is_healthy(target) {
+                if let Ok(stream) = TcpStream::connect(target).await {
+                    return Ok(stream);
+                }
+            }
+            if Instant::now() + RETRY_INTERVAL > deadline {
+                bail!("backend {} did not start in time", target);
+            }
+            sleep(RETRY_INTERVAL).await;
+        }
+    }
+}
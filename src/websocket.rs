@@ -0,0 +1,60 @@
+//! Defines the WebSocket transport, for clients which tunnel Minecraft over WebSockets.
+//!
+//! Web-based clients, and some tunneling setups, cannot open raw TCP connections, and instead
+//! send the Minecraft protocol as binary WebSocket messages. Listeners using this transport
+//! unwrap those messages into a plain byte stream, which is then proxied like any other client.
+
+use anyhow::{Context, Result};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpStream,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::trace;
+
+/// The size of the buffer between the WebSocket and the byte stream.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Accept a WebSocket connection, returning a byte stream carrying its binary messages.
+pub async fn accept(stream: TcpStream) -> Result<DuplexStream> {
+    let websocket = tokio_tungstenite::accept_async(stream)
+        .await
+        .context("failed to accept WebSocket connection")?;
+    let (mut sink, mut source) = websocket.split();
+    let (local, remote) = io::duplex(BUFFER_SIZE);
+    let (mut remote_rx, mut remote_tx) = io::split(remote);
+
+    // bytes written to the stream are sent as binary messages
+    tokio::task::spawn(async move {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let n = match remote_rx.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if sink.send(Message::Binary(buf[..n].to_vec())).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    // binary messages are written to the stream - anything else is ignored
+    tokio::task::spawn(async move {
+        while let Some(Ok(message)) = source.next().await {
+            match message {
+                Message::Binary(data) => {
+                    if remote_tx.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Message::Close(_) => break,
+                message => trace!("Ignoring WebSocket message: {:?}", message),
+            }
+        }
+        let _ = remote_tx.shutdown().await;
+    });
+
+    Ok(local)
+}
@@ -0,0 +1,264 @@
+//! Tests for loading configuration files, from how listen addresses expand into listeners to how
+//! layered files are merged.
+
+use std::{net::IpAddr, path::PathBuf};
+
+use anyhow::Result;
+use magma::config::{self, Config, MagmaConfig, Overrides};
+use toml::{Table, Value};
+use uuid::Uuid;
+
+/// Write a configuration file to a temporary path, returning the path.
+async fn write(contents: &str) -> Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!(
+        "magma-config-{}.toml",
+        Uuid::from_u128(rand::random())
+    ));
+    tokio::fs::write(&path, contents).await?;
+    Ok(path)
+}
+
+/// Load and build a version 1 configuration with the given proxy entries.
+async fn build(proxies: &str) -> Result<MagmaConfig> {
+    let path = write(&format!("version = 1\ndebug = false\n\n{}", proxies)).await?;
+    let built = config::from_path(&path).await?.build();
+    tokio::fs::remove_file(&path).await?;
+    Ok(built?)
+}
+
+/// The ports of a configuration's listeners, in order, with the domains routed by each.
+fn listeners(config: &MagmaConfig) -> Vec<(u16, Vec<String>)> {
+    let mut listeners: Vec<_> = config
+        .proxies
+        .iter()
+        .map(|proxy| {
+            let domains = proxy
+                .routes
+                .iter()
+                .map(|route| route.from.clone())
+                .collect();
+            (proxy.listen_addr.port(), domains)
+        })
+        .collect();
+    listeners.sort();
+    listeners
+}
+
+#[tokio::test]
+async fn port_ranges_expand_into_listeners() -> Result<()> {
+    let config = build(
+        r#"
+        [[proxies]]
+        listen = "127.0.0.1:30000-30002"
+        domain = "a.example.com"
+        target = "127.0.0.1:25575"
+        "#,
+    )
+    .await?;
+    assert_eq!(
+        listeners(&config),
+        (30000..=30002)
+            .map(|port| (port, vec!["a.example.com".to_string()]))
+            .collect::<Vec<_>>()
+    );
+    assert!(config
+        .proxies
+        .iter()
+        .all(|proxy| proxy.listen_addr.ip() == IpAddr::from([127, 0, 0, 1])));
+
+    // a range of one port is just that port
+    let config = build(
+        r#"
+        [[proxies]]
+        listen = "[::1]:30000-30000"
+        domain = "a.example.com"
+        target = "127.0.0.1:25575"
+        "#,
+    )
+    .await?;
+    assert_eq!(listeners(&config), [(30000, vec!["a.example.com".into()])]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reversed_and_overflowing_port_ranges_are_rejected() -> Result<()> {
+    for listen in [
+        "127.0.0.1:30002-30000",
+        "127.0.0.1:65530-65536",
+        "127.0.0.1:30000-",
+        "127.0.0.1:-30000",
+        "127.0.0.1:30000-30002-30004",
+    ] {
+        let built = build(&format!(
+            "[[proxies]]\nlisten = \"{listen}\"\ndomain = \"a.example.com\"\ntarget = \"127.0.0.1:25575\"\n"
+        ))
+        .await;
+        let err = format!("{:#}", built.expect_err(listen));
+        assert!(err.contains(listen), "{listen}: {err}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn overlapping_port_ranges_share_listeners() -> Result<()> {
+    // an entry listens on each of its ports once, however its ranges overlap
+    let config = build(
+        r#"
+        [[proxies]]
+        addresses = ["127.0.0.1:30000-30002", "127.0.0.1:30001-30003", "127.0.0.1:30002"]
+        domain = "a.example.com"
+        target = "127.0.0.1:25575"
+        "#,
+    )
+    .await?;
+    assert_eq!(
+        listeners(&config),
+        (30000..=30003)
+            .map(|port| (port, vec!["a.example.com".to_string()]))
+            .collect::<Vec<_>>()
+    );
+
+    // while entries whose ranges overlap share the listeners of the ports they have in common
+    let config = build(
+        r#"
+        [[proxies]]
+        listen = "127.0.0.1:30000-30001"
+        domain = "a.example.com"
+        target = "127.0.0.1:25575"
+
+        [[proxies]]
+        listen = "127.0.0.1:30001-30002"
+        domain = "b.example.com"
+        target = "127.0.0.1:25576"
+        "#,
+    )
+    .await?;
+    assert_eq!(
+        listeners(&config),
+        [
+            (30000, vec!["a.example.com".into()]),
+            (30001, vec!["a.example.com".into(), "b.example.com".into()]),
+            (30002, vec!["b.example.com".into()]),
+        ]
+    );
+    Ok(())
+}
+
+/// Merge configuration files with the given contents, in order, returning the merged table.
+async fn merge(files: &[&str], overrides: &Overrides) -> Result<Table> {
+    let mut paths = vec![];
+    for contents in files {
+        paths.push(write(contents).await?);
+    }
+    let merged = config::merge_files(&paths, overrides).await;
+    for path in &paths {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(merged?)
+}
+
+#[tokio::test]
+async fn later_files_are_merged_over_earlier_ones() -> Result<()> {
+    let merged = merge(
+        &[
+            "version = 1\ndebug = false\n[metrics]\naddress = \"127.0.0.1:9100\"\npath = \"/metrics\"\n",
+            "debug = true\n[metrics]\naddress = \"0.0.0.0:9100\"\n",
+            "[metrics]\npath = \"/stats\"\n",
+        ],
+        &Overrides::default(),
+    )
+    .await?;
+    // tables are merged key by key, the last file to set a key winning
+    assert_eq!(
+        merged,
+        toml::from_str::<Table>(
+            "version = 1\ndebug = true\nproxies = []\n[metrics]\naddress = \"0.0.0.0:9100\"\npath = \"/stats\"\n"
+        )?
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn arrays_and_tables_replace_each_other_whole() -> Result<()> {
+    let base = r#"
+        version = 1
+        allow = ["a", "b"]
+        deny = { pattern = "x" }
+
+        [[proxies]]
+        domain = "a.example.com"
+
+        [[proxies]]
+        domain = "b.example.com"
+        "#;
+    let overlay = r#"
+        allow = { pattern = "y" }
+        deny = ["c"]
+
+        [[proxies]]
+        domain = "c.example.com"
+        "#;
+    let merged = merge(&[base, overlay], &Overrides::default()).await?;
+    // neither an array of tables nor any other array is merged entry by entry
+    assert_eq!(
+        merged,
+        toml::from_str::<Table>(
+            r#"
+            version = 1
+            allow = { pattern = "y" }
+            deny = ["c"]
+
+            [[proxies]]
+            domain = "c.example.com"
+            "#
+        )?
+    );
+
+    // in either order
+    let merged = merge(&[overlay, base], &Overrides::default()).await?;
+    assert_eq!(merged["allow"], Value::from(vec!["a", "b"]));
+    assert_eq!(merged["deny"]["pattern"], Value::from("x"));
+    assert_eq!(merged["proxies"].as_array().unwrap().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn overrides_apply_after_merging() -> Result<()> {
+    let overrides = Overrides {
+        listen: Some("127.0.0.1:30000".into()),
+        routes: vec!["from=c.example.com,to=127.0.0.1:25577".parse()?],
+    };
+    let merged = merge(
+        &[
+            "version = 1\n[[proxies]]\nlisten = \"0.0.0.0:25565\"\ndomain = \"a.example.com\"\n",
+            "[[proxies]]\naddresses = [\"0.0.0.0:25566\"]\ndomain = \"b.example.com\"\n",
+        ],
+        &overrides,
+    )
+    .await?;
+    let proxies = merged["proxies"].as_array().unwrap();
+    // the last file's entries replace the first's, the route is added to them, and every entry
+    // listens where it was told
+    assert_eq!(proxies.len(), 2);
+    assert_eq!(proxies[0]["domain"], Value::from("b.example.com"));
+    assert_eq!(proxies[1]["domains"], Value::from(vec!["c.example.com"]));
+    for proxy in proxies {
+        assert_eq!(proxy["address"], Value::from("127.0.0.1:30000"));
+        assert!(proxy.get("addresses").is_none());
+    }
+
+    // a missing file is only skipped if the overrides add a route
+    let missing = std::env::temp_dir().join(format!(
+        "magma-config-{}.toml",
+        Uuid::from_u128(rand::random())
+    ));
+    assert!(
+        config::merge_files(std::slice::from_ref(&missing), &overrides)
+            .await
+            .is_ok()
+    );
+    assert!(config::merge_files(&[missing], &Overrides::default())
+        .await
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! Protocol conformance tests, replaying the handshakes of real clients through a Magma listener
+//! in front of a mock server.
+//!
+//! The vectors are assembled from the protocol's layout, not recorded off the wire. A vanilla
+//! handshake is fixed by its version, hostname, port and next state, so those are byte for byte
+//! what the clients send. Modded clients append the marker of their mod loader, while Floodgate
+//! appends data encrypted with the key of each server - its vector carries placeholder data of the
+//! same shape, as Magma only routes on what comes before the NUL.
+//!
+//! Run with `cargo test --features mock`.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use magma::{
+    bridge::ProtocolState,
+    config::{self, Config},
+    io::ProtocolAsyncReadExt,
+    mock::{MockConfig, MockServer},
+    protocol::packets::{Handshake, PacketCodec},
+    proxy::{self, Services},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time::{sleep, timeout, Instant},
+};
+
+/// How long to wait for something which should happen promptly.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The domain every vector connects to.
+const DOMAIN: &str = "mc.example.com";
+
+/// A status request, sent after handshakes for the status state.
+const STATUS_REQUEST: &[u8] = b"\x01\x00";
+
+/// The handshake a client sends, and what it should decode to.
+struct Vector {
+    /// The client which sent the handshake.
+    client: &'static str,
+    /// The handshake, as framed on the wire.
+    bytes: &'static [u8],
+    /// The protocol version of the client.
+    protocol_version: i32,
+    /// The hostname the client sent, including anything appended to the domain.
+    server_address: &'static str,
+    /// The port the client connected to.
+    server_port: u16,
+    /// The state the client switches to.
+    next_state: ProtocolState,
+}
+
+/// Handshakes of vanilla clients, across the versions Magma supports.
+const VANILLA: &[Vector] = &[
+    Vector {
+        client: "vanilla 1.8.9",
+        bytes: b"\x14\x00\x2f\x0emc.example.com\x63\xdd\x01",
+        protocol_version: 47,
+        server_address: "mc.example.com",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "vanilla 1.12.2",
+        bytes: b"\x15\x00\xd4\x02\x0emc.example.com\x63\xdd\x01",
+        protocol_version: 340,
+        server_address: "mc.example.com",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "vanilla 1.16.5",
+        bytes: b"\x15\x00\xf2\x05\x0emc.example.com\x63\xdd\x01",
+        protocol_version: 754,
+        server_address: "mc.example.com",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "vanilla 1.19.4",
+        bytes: b"\x15\x00\xfa\x05\x0emc.example.com\x63\xdd\x01",
+        protocol_version: 762,
+        server_address: "mc.example.com",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "vanilla 1.20.4",
+        bytes: b"\x15\x00\xfd\x05\x0emc.example.com\x63\xdd\x02",
+        protocol_version: 765,
+        server_address: "mc.example.com",
+        server_port: 25565,
+        next_state: ProtocolState::Login,
+    },
+    Vector {
+        client: "vanilla 1.21, transferred",
+        bytes: b"\x15\x00\xff\x05\x0emc.example.com\x63\xdd\x03",
+        protocol_version: 767,
+        server_address: "mc.example.com",
+        server_port: 25565,
+        next_state: ProtocolState::Login,
+    },
+    Vector {
+        client: "vanilla 1.21, resolved through SRV",
+        bytes: b"\x16\x00\xff\x05\x0fmc.example.com.\x63\xdd\x01",
+        protocol_version: 767,
+        server_address: "mc.example.com.",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+];
+
+/// Handshakes of modded clients and bridges, which append data to the domain after a NUL.
+const MODDED: &[Vector] = &[
+    Vector {
+        client: "Forge 1.12.2",
+        bytes: b"\x1a\x00\xd4\x02\x13mc.example.com\x00FML\x00\x63\xdd\x01",
+        protocol_version: 340,
+        server_address: "mc.example.com\0FML\0",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "Forge 1.16.5",
+        bytes: b"\x1b\x00\xf2\x05\x14mc.example.com\x00FML2\x00\x63\xdd\x01",
+        protocol_version: 754,
+        server_address: "mc.example.com\0FML2\0",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "Forge 1.20.1",
+        bytes: b"\x1b\x00\xfb\x05\x14mc.example.com\x00FML3\x00\x63\xdd\x01",
+        protocol_version: 763,
+        server_address: "mc.example.com\0FML3\0",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "Forge 1.21",
+        bytes: b"\x1b\x00\xff\x05\x14mc.example.com\x00FORGE\x63\xdd\x01",
+        protocol_version: 767,
+        server_address: "mc.example.com\0FORGE",
+        server_port: 25565,
+        next_state: ProtocolState::Status,
+    },
+    Vector {
+        client: "Geyser behind Floodgate, with placeholder data",
+        bytes: b"\x59\x00\xff\x05\x52mc.example.com\x00^Floodgate^cGxhY2Vob2xkZXIsIG5vdCBhIHJlYWwgZW5jcnlwdGVkIHBheWxvYWQ=\x63\xdd\x02",
+        protocol_version: 767,
+        server_address: "mc.example.com\0^Floodgate^cGxhY2Vob2xkZXIsIG5vdCBhIHJlYWwgZW5jcnlwdGVkIHBheWxvYWQ=",
+        server_port: 25565,
+        next_state: ProtocolState::Login,
+    },
+];
+
+/// Server list pings of clients older than 1.7, which predate the handshake.
+const LEGACY: &[(&str, &[u8])] = &[
+    ("beta 1.8 to 1.3", b"\xfe"),
+    ("1.4 to 1.5", b"\xfe\x01"),
+    (
+        "1.6",
+        b"\xfe\x01\xfa\x00\x0b\x00\x4d\x00\x43\x00\x7c\x00\x50\x00\x69\x00\x6e\x00\x67\x00\x48\x00\x6f\x00\x73\x00\x74\x00\x23\x4a\x00\x0e\x00\x6d\x00\x63\x00\x2e\x00\x65\x00\x78\x00\x61\x00\x6d\x00\x70\x00\x6c\x00\x65\x00\x2e\x00\x63\x00\x6f\x00\x6d\x00\x00\x63\xdd",
+    ),
+];
+
+/// Start Magma with a single route for [DOMAIN], which preserves the hostname of clients.
+///
+/// Clients have longer to send their handshake than [TIMEOUT], so those Magma waits on stand out.
+async fn start_magma(target: SocketAddr) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let path = std::env::temp_dir().join(format!("magma-conformance-{}.toml", addr.port()));
+    tokio::fs::write(
+        &path,
+        format!(
+            "version = 1\ndebug = false\n\n[[proxies]]\naddress = \"{}\"\ndomain = \"{}\"\ntarget = \"{}\"\nhostname_rewrite = \"preserve\"\n\n[proxies.slow_clients]\nhandshake_timeout_ms = 60000\n",
+            addr, DOMAIN, target
+        ),
+    )
+    .await?;
+    let config = config::from_path(&path).await?.build()?;
+    tokio::fs::remove_file(&path).await?;
+
+    let proxy = config.proxies.into_iter().next().unwrap();
+    let (_, proxies) = watch::channel(Arc::new(proxy));
+    tokio::task::spawn(proxy::serve(listener, proxies, Services::default()));
+    Ok(addr)
+}
+
+/// Decode a vector's bytes, as Magma reads a handshake off the wire.
+async fn decode(vector: &Vector) -> Result<Handshake> {
+    let mut bytes = vector.bytes;
+    let packet = bytes.read_uncompressed_packet().await?;
+    assert!(bytes.is_empty(), "{}: trailing bytes", vector.client);
+    Handshake::decode(&packet)
+}
+
+/// Assert each vector decodes as expected, and is routed to the mock server unchanged.
+async fn assert_routed(vectors: &[Vector]) -> Result<()> {
+    for vector in vectors {
+        let handshake = decode(vector).await?;
+        assert_eq!(handshake.protocol_version, vector.protocol_version);
+        assert_eq!(handshake.server_address, vector.server_address);
+        assert_eq!(handshake.server_port, vector.server_port);
+        assert_eq!(handshake.next_state, vector.next_state);
+
+        let server = MockServer::start(MockConfig::default()).await?;
+        let magma = start_magma(server.addr()).await?;
+        let mut stream = TcpStream::connect(magma).await?;
+        stream.write_all(vector.bytes).await?;
+        if vector.next_state == ProtocolState::Status {
+            stream.write_all(STATUS_REQUEST).await?;
+        }
+
+        let deadline = Instant::now() + TIMEOUT;
+        while server.handshakes().is_empty() {
+            assert!(
+                Instant::now() < deadline,
+                "{}: not routed to the target",
+                vector.client
+            );
+            sleep(Duration::from_millis(50)).await;
+        }
+        let forwarded = &server.handshakes()[0];
+        assert_eq!(forwarded.protocol_version, vector.protocol_version);
+        assert_eq!(forwarded.server_address, vector.server_address);
+        assert_eq!(forwarded.next_state, vector.next_state);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn vanilla_handshakes_are_routed() -> Result<()> {
+    assert_routed(VANILLA).await
+}
+
+#[tokio::test]
+async fn modded_handshakes_are_routed_by_their_domain() -> Result<()> {
+    assert_routed(MODDED).await
+}
+
+#[tokio::test]
+async fn legacy_pings_are_dropped_promptly() -> Result<()> {
+    for (client, bytes) in LEGACY {
+        let server = MockServer::start(MockConfig::default()).await?;
+        let magma = start_magma(server.addr()).await?;
+        let mut stream = TcpStream::connect(magma).await?;
+        stream.write_all(bytes).await?;
+
+        // the connection is closed, rather than left waiting for the rest of a handshake
+        let mut buf = Vec::new();
+        let read = timeout(TIMEOUT, stream.read_to_end(&mut buf)).await;
+        assert!(read.is_ok(), "{}: connection left open", client);
+        assert!(server.handshakes().is_empty(), "{}: routed", client);
+    }
+    Ok(())
+}
@@ -0,0 +1,570 @@
+//! End-to-end tests, running a real Magma listener in front of mock servers.
+//!
+//! Run with `cargo test --features mock`.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use magma::{
+    auth::{AuthError, Authenticator},
+    bridge::ProtocolState,
+    client::{Client, ClientBuilder, Session},
+    config::{self, AuthConfig, Config},
+    health,
+    io::{ProtocolWriteExt, UncompressedPacket},
+    mock::{MockConfig, MockServer, MockSessionServer},
+    protocol::{
+        packets::{Disconnect, LoginStart, PacketCodec},
+        version::{LogicalPacket, ProtocolVersion},
+    },
+    proxy::{self, Services},
+    username,
+};
+use tokio::{
+    net::TcpListener,
+    sync::watch,
+    time::{sleep, timeout},
+};
+use uuid::Uuid;
+
+/// How long to wait for something which should happen promptly.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start Magma with the given proxy entry, returning the address it listens on.
+///
+/// The entry is completed with the listening address, so it should only give routing options.
+async fn start_magma(entry: &str) -> Result<SocketAddr> {
+    start_magma_with(entry, Services::default()).await
+}
+
+/// Start Magma with the given proxy entry and services, returning the address it listens on.
+async fn start_magma_with(entry: &str, services: Services) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let path = std::env::temp_dir().join(format!("magma-e2e-{}.toml", addr.port()));
+    tokio::fs::write(
+        &path,
+        format!(
+            "version = 1\ndebug = false\n\n[[proxies]]\naddress = \"{}\"\n{}",
+            addr, entry
+        ),
+    )
+    .await?;
+    let config = config::from_path(&path).await?.build()?;
+    tokio::fs::remove_file(&path).await?;
+
+    for health_check in config.health_checks {
+        health::spawn(health_check);
+    }
+    let proxy = config.proxies.into_iter().next().unwrap();
+    let (_, proxies) = watch::channel(Arc::new(proxy));
+    tokio::task::spawn(proxy::serve(listener, proxies, services));
+    Ok(addr)
+}
+
+/// Build a configuration, returning the error it is rejected with, if any.
+async fn config_error(contents: &str) -> Result<Option<String>> {
+    let path = std::env::temp_dir().join(format!(
+        "magma-e2e-{}.toml",
+        Uuid::from_u128(rand::random())
+    ));
+    tokio::fs::write(&path, format!("version = 1\ndebug = false\n\n{}", contents)).await?;
+    let built = config::from_path(&path).await?.build();
+    tokio::fs::remove_file(&path).await?;
+    Ok(built
+        .err()
+        .map(|err| format!("{:#}", anyhow::Error::from(err))))
+}
+
+/// Ping a server through Magma, returning the status JSON.
+async fn ping(magma: SocketAddr, domain: &str) -> Result<String> {
+    Ok(ClientBuilder::offline("Steve")
+        .domain(domain)
+        .status(magma)
+        .await?
+        .json)
+}
+
+/// Log in to a server through Magma.
+async fn login(
+    magma: SocketAddr,
+    domain: &str,
+    version: ProtocolVersion,
+    username: &str,
+) -> Result<Client> {
+    ClientBuilder::offline(username)
+        .version(version)
+        .domain(domain)
+        .login(magma)
+        .await
+}
+
+/// Start logging in to a server through Magma with the raw login start of the given version,
+/// which for versions newer than Magma knows carries a uuid after the username, as 1.21 does.
+async fn start_login(
+    magma: SocketAddr,
+    domain: &str,
+    version: ProtocolVersion,
+    username: &str,
+) -> Result<Client> {
+    let mut client = Client::connect(magma).await?;
+    client
+        .handshake(version, domain, magma.port(), ProtocolState::Login)
+        .await?;
+    let mut data = vec![];
+    data.write_string(username.to_string())?;
+    if version > ProtocolVersion::LATEST {
+        data.write_uuid(&Uuid::from_u128(rand::random()))?;
+    }
+    client
+        .send(&UncompressedPacket {
+            id: LoginStart::ID,
+            data: data.into(),
+        })
+        .await?;
+    Ok(client)
+}
+
+/// Authenticate players with the given session server.
+fn authenticator(session_server: String, max_concurrent_lookups: usize) -> Result<Services> {
+    Ok(Services {
+        authenticator: Some(Authenticator::new(AuthConfig {
+            session_server,
+            max_concurrent_lookups,
+            max_queued_lookups: 1,
+            overflow_message: "Too many logins!".to_string(),
+        })?),
+        ..Default::default()
+    })
+}
+
+/// Wait until a condition holds, failing after the timeout.
+async fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for condition");
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Reserve an address nothing is listening on.
+async fn dead_addr() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    listener.local_addr().map_err(Into::into)
+}
+
+#[tokio::test]
+async fn status_ping_is_forwarded() -> Result<()> {
+    let server = MockServer::start(MockConfig {
+        motd: "Hello from the mock".to_string(),
+        ..Default::default()
+    })
+    .await?;
+    let magma = start_magma(&format!(
+        "domain = \"ping.test\"\ntarget = \"{}\"\n",
+        server.addr()
+    ))
+    .await?;
+
+    let json = ping(magma, "ping.test").await?;
+    assert!(json.contains("Hello from the mock"), "{}", json);
+    // the backend sees Magma's address, rather than the client's domain
+    assert_eq!(server.handshakes()[0].server_address, "127.0.0.1");
+    Ok(())
+}
+
+#[tokio::test]
+async fn login_is_passed_through() -> Result<()> {
+    let server = MockServer::start(MockConfig {
+        keep_alive_interval: Duration::from_millis(100),
+        ..Default::default()
+    })
+    .await?;
+    let magma = start_magma(&format!(
+        "domain = \"login.test\"\ntarget = \"{}\"\n",
+        server.addr()
+    ))
+    .await?;
+
+    let version = ProtocolVersion::V1_20_3;
+    let mut client = login(magma, "login.test", version, "Steve").await?;
+    assert_eq!(server.usernames(), vec!["Steve".to_string()]);
+
+    // finish configuration, then answer a keep-alive
+    let finish = client.recv().await?;
+    assert_eq!(
+        Some(finish.id),
+        version.packet_id(LogicalPacket::FinishConfiguration)
+    );
+    client
+        .send(&UncompressedPacket {
+            id: version
+                .packet_id(LogicalPacket::AcknowledgeFinishConfiguration)
+                .unwrap(),
+            data: Bytes::new(),
+        })
+        .await?;
+    let keep_alive = client.recv().await?;
+    assert_eq!(
+        Some(keep_alive.id),
+        version.packet_id(LogicalPacket::KeepAliveClientbound)
+    );
+    client
+        .send(&UncompressedPacket {
+            id: version
+                .packet_id(LogicalPacket::KeepAliveServerbound)
+                .unwrap(),
+            data: keep_alive.data,
+        })
+        .await?;
+    wait_until(|| server.keep_alives() >= 1).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_domains_are_dropped() -> Result<()> {
+    let server = MockServer::start(MockConfig::default()).await?;
+    let magma = start_magma(&format!(
+        "domain = \"known.test\"\ntarget = \"{}\"\n",
+        server.addr()
+    ))
+    .await?;
+
+    assert!(ping(magma, "unknown.test").await.is_err());
+    assert!(server.handshakes().is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn templated_domains_are_matched() -> Result<()> {
+    let server = MockServer::start(MockConfig::default()).await?;
+    // the name captured from the domain is the mock's port
+    let magma = start_magma(
+        "domains = [\"{name}.servers.test\"]\ntarget_template = \"127.0.0.1:{name}\"\n",
+    )
+    .await?;
+
+    let port = server.addr().port();
+    ping(magma, &format!("{}.servers.test", port)).await?;
+    ping(magma, &format!("{}.SERVERS.test", port)).await?;
+    assert!(ping(magma, &format!("{}.other.test", port)).await.is_err());
+    assert_eq!(server.handshakes().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn targets_looping_back_are_rejected() -> Result<()> {
+    // the name captured from the domain is a port, so the route can be pointed back at Magma -
+    // and with the hostname preserved, each connection it makes would be routed the same way
+    let magma = start_magma(
+        "domains = [\"{name}.loop.test\"]\ntarget_template = \"127.0.0.1:{name}\"\nhostname_rewrite = \"preserve\"\n",
+    )
+    .await?;
+
+    let domain = format!("{}.loop.test", magma.port());
+    let result = timeout(TIMEOUT, ping(magma, &domain)).await;
+    assert!(matches!(result, Ok(Err(_))), "not rejected promptly");
+    Ok(())
+}
+
+#[tokio::test]
+async fn failing_targets_are_routed_around() -> Result<()> {
+    let server = MockServer::start(MockConfig::default()).await?;
+    let dead = dead_addr().await?;
+    let magma = start_magma(&format!(
+        "domain = \"failover.test\"\ntargets = [\"{}\", \"{}\"]\n\n[proxies.health_check]\ninterval_secs = 1\n",
+        dead,
+        server.addr()
+    ))
+    .await?;
+
+    wait_until(|| !health::is_healthy(dead)).await;
+    for i in 0..10 {
+        login(
+            magma,
+            "failover.test",
+            ProtocolVersion::DEFAULT,
+            &format!("Player{}", i),
+        )
+        .await?;
+    }
+    assert_eq!(server.usernames().len(), 10);
+    Ok(())
+}
+
+#[tokio::test]
+async fn compression_is_negotiated() -> Result<()> {
+    let server = MockServer::start(MockConfig {
+        compression_threshold: Some(64),
+        keep_alive_interval: Duration::from_millis(100),
+        ..Default::default()
+    })
+    .await?;
+    let magma = start_magma(&format!(
+        "domain = \"compression.test\"\ntarget = \"{}\"\n",
+        server.addr()
+    ))
+    .await?;
+
+    // the client can only read the keep-alive if it switched to compressed framing
+    let version = ProtocolVersion::DEFAULT;
+    let mut client = login(magma, "compression.test", version, "Alex").await?;
+    let keep_alive = client.recv().await?;
+    assert_eq!(
+        Some(keep_alive.id),
+        version.packet_id(LogicalPacket::KeepAliveClientbound)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn online_logins_are_relayed_encrypted() -> Result<()> {
+    let server = MockServer::start(MockConfig {
+        online_mode: true,
+        compression_threshold: Some(64),
+        keep_alive_interval: Duration::from_millis(100),
+        ..Default::default()
+    })
+    .await?;
+    let magma = start_magma(&format!(
+        "domain = \"online.test\"\ntarget = \"{}\"\n",
+        server.addr()
+    ))
+    .await?;
+
+    let session = Session {
+        session_server: server.session_server().unwrap(),
+        ..Session::new("token".to_string(), Uuid::from_u128(rand::random()))
+    };
+    let version = ProtocolVersion::DEFAULT;
+    let mut client = ClientBuilder::offline("Notch")
+        .online(session.clone())
+        .domain("online.test")
+        .login(magma)
+        .await?;
+    assert_eq!(client.uuid(), Some(session.uuid));
+    assert_eq!(server.joins().len(), 1);
+
+    // the client can only read the keep-alive if it decrypts and decompresses it
+    let keep_alive = client.recv().await?;
+    assert_eq!(
+        Some(keep_alive.id),
+        version.packet_id(LogicalPacket::KeepAliveClientbound)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn online_logins_need_a_session() -> Result<()> {
+    let server = MockServer::start(MockConfig {
+        online_mode: true,
+        ..Default::default()
+    })
+    .await?;
+
+    assert!(ClientBuilder::offline("Notch")
+        .login(server.addr())
+        .await
+        .is_err());
+    assert!(server.joins().is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypted_logins_give_up_their_login_permit() -> Result<()> {
+    let server = MockServer::start(MockConfig {
+        online_mode: true,
+        ..Default::default()
+    })
+    .await?;
+    let magma = start_magma(&format!(
+        "domain = \"permits.test\"\ntarget = \"{}\"\n\n[proxies.rate_limits]\nmax_concurrent_logins = 1\n",
+        server.addr()
+    ))
+    .await?;
+
+    // Magma never sees the login succeed once it is encrypted, so the first player must not hold
+    // its IP's only login for as long as it plays
+    let session = Session {
+        session_server: server.session_server().unwrap(),
+        ..Session::new("token".to_string(), Uuid::from_u128(rand::random()))
+    };
+    let mut clients = vec![];
+    for username in ["Notch", "jeb_"] {
+        let builder = ClientBuilder::offline(username)
+            .online(session.clone())
+            .domain("permits.test");
+        clients.push(timeout(TIMEOUT, builder.login(magma)).await??);
+    }
+    assert_eq!(server.joins().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn usernames_are_checked_for_unknown_versions() -> Result<()> {
+    let server = MockServer::start(MockConfig::default()).await?;
+    let magma = start_magma(&format!(
+        "domain = \"names.test\"\ntarget = \"{}\"\n\n[proxies.usernames]\ndeny = [\"(?i)^admin\"]\nmessage = \"No admins!\"\n",
+        server.addr()
+    ))
+    .await?;
+
+    // versions older and newer than any Magma knows the packets of
+    let mut clients = vec![];
+    for version in [ProtocolVersion(47), ProtocolVersion(999)] {
+        let mut client = start_login(magma, "names.test", version, "Admin").await?;
+        let disconnect = Disconnect::decode(&timeout(TIMEOUT, client.recv()).await??)?;
+        assert!(disconnect.reason.contains("No admins!"), "{version}");
+
+        clients.push(start_login(magma, "names.test", version, "Steve").await?);
+    }
+    wait_until(|| server.usernames().len() == 2).await;
+    assert_eq!(server.usernames(), ["Steve", "Steve"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn offline_routes_refuse_unknown_versions() -> Result<()> {
+    let server = MockServer::start(MockConfig::default()).await?;
+    let magma = start_magma(&format!(
+        "domain = \"offline.test\"\ntarget = \"{}\"\noffline_mode = true\n",
+        server.addr()
+    ))
+    .await?;
+
+    // the uuid a client claims is replaced with its offline uuid, which the mock hands back
+    let client = login(magma, "offline.test", ProtocolVersion::DEFAULT, "Steve").await?;
+    assert_eq!(client.uuid(), Some(username::offline_uuid("Steve")));
+
+    // newer clients may claim a profile Magma can't find in their login, so they are refused
+    let mut client = start_login(magma, "offline.test", ProtocolVersion(999), "Alex").await?;
+    let disconnect = Disconnect::decode(&timeout(TIMEOUT, client.recv()).await??)?;
+    assert!(
+        disconnect.reason.contains("version"),
+        "{}",
+        disconnect.reason
+    );
+
+    // while older clients never send more than their username, so are let through
+    let _client = start_login(magma, "offline.test", ProtocolVersion(47), "Alex").await?;
+    wait_until(|| server.usernames().len() == 2).await;
+    assert_eq!(server.usernames(), ["Steve", "Alex"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn authenticated_logins_reach_offline_backends() -> Result<()> {
+    let sessions = MockSessionServer::start()?;
+    let server = MockServer::start(MockConfig::default()).await?;
+    let magma = start_magma_with(
+        &format!(
+            "domain = \"auth.test\"\ntarget = \"{}\"\nauthenticate = true\n",
+            server.addr()
+        ),
+        authenticator(sessions.url(), 16)?,
+    )
+    .await?;
+
+    let session = Session {
+        session_server: sessions.url(),
+        ..Session::new("token".to_string(), Uuid::from_u128(rand::random()))
+    };
+    let mut client = ClientBuilder::offline("Notch")
+        .online(session)
+        .domain("auth.test")
+        .login(magma)
+        .await?;
+    assert_eq!(sessions.joins().len(), 1);
+    assert_eq!(server.usernames(), vec!["Notch".to_string()]);
+
+    // the backend never encrypts, so the client can only read this through Magma's encryption
+    let keep_alive = client.recv().await?;
+    assert_eq!(
+        Some(keep_alive.id),
+        ProtocolVersion::DEFAULT.packet_id(LogicalPacket::KeepAliveClientbound)
+    );
+
+    // players who didn't join through the session server never reach the backend
+    assert!(
+        login(magma, "auth.test", ProtocolVersion::DEFAULT, "Herobrine")
+            .await
+            .is_err()
+    );
+    assert_eq!(server.usernames(), vec!["Notch".to_string()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn auth_lookups_are_shared_and_capped() -> Result<()> {
+    // a session server which never answers, so lookups stay in flight
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let session_server = format!("http://{}", listener.local_addr()?);
+    let accepted = Arc::new(AtomicUsize::new(0));
+    {
+        let accepted = accepted.clone();
+        tokio::task::spawn(async move {
+            let mut held = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                held.push(stream);
+            }
+        });
+    }
+    let authenticator = authenticator(session_server, 1)?.authenticator.unwrap();
+
+    // logins with the same username and hash share the one lookup allowed in flight, and a login
+    // with another username waits for it in the queue
+    for username in ["Notch", "Notch", "Notch", "jeb_"] {
+        let authenticator = authenticator.clone();
+        tokio::task::spawn(async move { authenticator.has_joined(username, "hash").await });
+    }
+    wait_until(|| accepted.load(Ordering::SeqCst) == 1).await;
+
+    // and with the queue full, the next is rejected at once
+    let overflow = timeout(TIMEOUT, authenticator.has_joined("Dinnerbone", "hash")).await?;
+    assert!(matches!(overflow, Err(AuthError::Overloaded)));
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    let unlimited =
+        config_error("proxies = []\n\n[authentication]\nmax_concurrent_lookups = 0\n").await?;
+    assert!(unlimited.is_some_and(|err| err.contains("at least one concurrent lookup")));
+    Ok(())
+}
+
+#[tokio::test]
+async fn conflicting_routes_are_rejected() -> Result<()> {
+    let looped = config_error(
+        "[[proxies]]\naddress = \"0.0.0.0:25999\"\ndomain = \"loop.test\"\ntarget = \"127.0.0.1:25999\"\n",
+    )
+    .await?;
+    assert!(looped.is_some_and(|err| err.contains("the listener itself")));
+
+    let duplicated = config_error(
+        "[[proxies]]\naddress = \"127.0.0.1:25999\"\ndomain = \"twice.test\"\ntarget = \"127.0.0.1:25001\"\n\n[[proxies]]\naddress = \"127.0.0.1:25999\"\ndomain = \"twice.test\"\ntarget = \"127.0.0.1:25002\"\n",
+    )
+    .await?;
+    assert!(duplicated.is_some_and(|err| err.contains("already routes it")));
+
+    let shadowed = config_error(
+        "[[proxies]]\naddress = \"127.0.0.1:25999\"\ndomains = [\"{name}.a.test\", \"{name}.A.test\"]\ntarget_template = \"127.0.0.1:{name}\"\n",
+    )
+    .await?;
+    assert!(shadowed.is_some_and(|err| err.contains("match the same domains")));
+
+    // the same domain sent to the same targets is only warned about
+    let repeated = config_error(
+        "[[proxies]]\naddress = \"127.0.0.1:25999\"\ndomain = \"twice.test\"\ntarget = \"127.0.0.1:25001\"\n\n[[proxies]]\naddress = \"127.0.0.1:25999\"\ndomain = \"twice.test\"\ntarget = \"127.0.0.1:25001\"\n",
+    )
+    .await?;
+    assert_eq!(repeated, None);
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Tests for the rate limits and login throttle of listeners. The buckets are shared by the whole
+//! process, so each test limits its own listener address.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use magma::{
+    bridge::ProtocolState::{Login, Status},
+    config::{RateLimit, RateLimits},
+    ratelimit::{self, Throttled},
+};
+
+/// A listener address no other test limits.
+fn listener(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// A client address.
+fn ip(last: u8) -> IpAddr {
+    IpAddr::from([203, 0, 113, last])
+}
+
+#[test]
+fn status_pings_and_logins_have_separate_budgets() {
+    let limits = RateLimits {
+        status: RateLimit {
+            per_ip: Some(3),
+            per_listener: None,
+        },
+        login: RateLimit {
+            per_ip: Some(1),
+            per_listener: None,
+        },
+        ..Default::default()
+    };
+    let addr = listener(40001);
+    for _ in 0..3 {
+        assert!(ratelimit::allow(&limits, addr, ip(1), Status));
+    }
+    assert!(!ratelimit::allow(&limits, addr, ip(1), Status));
+
+    // pinging the server list spent none of the login budget
+    assert!(ratelimit::allow(&limits, addr, ip(1), Login));
+    assert!(!ratelimit::allow(&limits, addr, ip(1), Login));
+    assert!(ratelimit::allow(&limits, addr, ip(2), Status));
+}
+
+#[test]
+fn ips_are_limited_alone_and_together() {
+    let limits = RateLimits {
+        login: RateLimit {
+            per_ip: Some(2),
+            per_listener: Some(3),
+        },
+        ..Default::default()
+    };
+    let addr = listener(40002);
+    assert!(ratelimit::allow(&limits, addr, ip(1), Login));
+    assert!(ratelimit::allow(&limits, addr, ip(1), Login));
+    assert!(!ratelimit::allow(&limits, addr, ip(1), Login));
+
+    // the IP turned away took nothing from the listener, which has room for one more
+    assert!(ratelimit::allow(&limits, addr, ip(2), Login));
+    assert!(!ratelimit::allow(&limits, addr, ip(3), Login));
+
+    // other listeners have buckets of their own
+    let other = listener(40003);
+    assert!(ratelimit::allow(&limits, other, ip(1), Login));
+}
+
+#[test]
+fn logins_are_throttled_per_ip() {
+    let limits = RateLimits {
+        login_throttle: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let addr = listener(40004);
+    assert!(ratelimit::throttle_login(&limits, addr, ip(1)).is_ok());
+    assert_eq!(
+        ratelimit::throttle_login(&limits, addr, ip(1)).unwrap_err(),
+        Throttled::TooSoon
+    );
+    assert!(ratelimit::throttle_login(&limits, addr, ip(2)).is_ok());
+}
+
+#[test]
+fn concurrent_logins_are_capped_until_permits_drop() {
+    let limits = RateLimits {
+        max_concurrent_logins: Some(2),
+        ..Default::default()
+    };
+    let addr = listener(40005);
+    let first = ratelimit::throttle_login(&limits, addr, ip(1)).unwrap();
+    let _second = ratelimit::throttle_login(&limits, addr, ip(1)).unwrap();
+    assert_eq!(
+        ratelimit::throttle_login(&limits, addr, ip(1)).unwrap_err(),
+        Throttled::TooManyLogins
+    );
+    assert!(ratelimit::throttle_login(&limits, addr, ip(2)).is_ok());
+
+    drop(first);
+    assert!(ratelimit::throttle_login(&limits, addr, ip(1)).is_ok());
+}
@@ -0,0 +1,116 @@
+//! Tests for the RealIP payloads anti-DDoS providers append to handshake hostnames, signed here
+//! with a key standing in for the provider's.
+
+use std::{
+    net::SocketAddr,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use magma::{config::RealIp, realip};
+use rsa::{
+    pkcs1v15::SigningKey,
+    sha2::Sha512,
+    signature::{SignatureEncoding, Signer},
+    RsaPrivateKey,
+};
+
+/// The hostname every payload is appended to.
+const HOSTNAME: &str = "mc.example.com";
+
+/// The client address every payload carries.
+const CLIENT: &str = "203.0.113.7:51234";
+
+/// The provider's private key, generated once as it is slow to.
+fn key() -> &'static RsaPrivateKey {
+    static KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+    KEY.get_or_init(|| RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap())
+}
+
+/// A listener's RealIP settings, trusting the provider's key.
+fn real_ip() -> RealIp {
+    RealIp {
+        public_key: key().to_public_key(),
+        max_age: Duration::from_secs(5),
+        required: false,
+    }
+}
+
+/// The current time, in seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Sign the parts of a payload as the provider does, returning the handshake hostname.
+fn sign(signed: &str) -> String {
+    let signature = SigningKey::<Sha512>::new(key().clone()).sign(signed.as_bytes());
+    format!("{signed}///{}", STANDARD.encode(signature.to_bytes()))
+}
+
+#[test]
+fn valid_payloads_are_extracted() {
+    let address = sign(&format!("{HOSTNAME}///{CLIENT}///{}", now()));
+    let (hostname, client) = realip::extract(&address, &real_ip()).unwrap().unwrap();
+    assert_eq!(hostname, HOSTNAME);
+    assert_eq!(client, CLIENT.parse::<SocketAddr>().unwrap());
+}
+
+#[test]
+fn hostnames_without_payloads_are_left_alone() {
+    assert!(realip::extract(HOSTNAME, &real_ip()).unwrap().is_none());
+}
+
+#[test]
+fn tampered_payloads_are_rejected() {
+    let address = sign(&format!("{HOSTNAME}///{CLIENT}///{}", now()));
+
+    let hostname = address.replacen(HOSTNAME, "other.example.com", 1);
+    assert!(realip::extract(&hostname, &real_ip()).is_err());
+
+    let client = address.replacen(CLIENT, "198.51.100.1:51234", 1);
+    assert!(realip::extract(&client, &real_ip()).is_err());
+
+    // a payload signed by anyone else is rejected too
+    let mut other = real_ip();
+    other.public_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024)
+        .unwrap()
+        .to_public_key();
+    assert!(realip::extract(&address, &other).is_err());
+}
+
+#[test]
+fn stale_and_future_payloads_are_rejected() {
+    let expired = sign(&format!("{HOSTNAME}///{CLIENT}///{}", now() - 60));
+    assert!(realip::extract(&expired, &real_ip()).is_err());
+
+    let future = sign(&format!("{HOSTNAME}///{CLIENT}///{}", now() + 60));
+    assert!(realip::extract(&future, &real_ip()).is_err());
+
+    let garbage = sign(&format!("{HOSTNAME}///{CLIENT}///yesterday"));
+    assert!(realip::extract(&garbage, &real_ip()).is_err());
+}
+
+#[test]
+fn extra_segments_are_rejected() {
+    // even when signed, as the hostname and address can't be told apart
+    let address = sign(&format!("{HOSTNAME}///extra///{CLIENT}///{}", now()));
+    assert!(realip::extract(&address, &real_ip()).is_err());
+
+    let missing = sign(&format!("{HOSTNAME}///{}", now()));
+    assert!(realip::extract(&missing, &real_ip()).is_err());
+}
+
+#[test]
+fn addresses_without_ports_are_accepted() {
+    let address = sign(&format!("{HOSTNAME}///203.0.113.7///{}", now()));
+    let (_, client) = realip::extract(&address, &real_ip()).unwrap().unwrap();
+    assert_eq!(client, "203.0.113.7:0".parse::<SocketAddr>().unwrap());
+
+    let v6 = sign(&format!("{HOSTNAME}///2001:db8::7///{}", now()));
+    let (_, client) = realip::extract(&v6, &real_ip()).unwrap().unwrap();
+    assert_eq!(client, "[2001:db8::7]:0".parse::<SocketAddr>().unwrap());
+}
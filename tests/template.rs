@@ -0,0 +1,54 @@
+//! Tests for the lookups templated routes resolve captured names with.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use magma::template::Resolver;
+use uuid::Uuid;
+
+/// A temporary file lookup commands append a line to each time they run.
+fn runs_file() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "magma-template-{}.log",
+        Uuid::from_u128(rand::random())
+    ))
+}
+
+/// The number of times a lookup command ran.
+async fn runs(path: &PathBuf) -> usize {
+    tokio::fs::read_to_string(path)
+        .await
+        .map(|runs| runs.lines().count())
+        .unwrap_or(0)
+}
+
+#[tokio::test]
+async fn lookups_are_cached() -> Result<()> {
+    let path = runs_file();
+    let resolver = Resolver::Command(format!(
+        "echo run >> {}; echo 127.0.0.1:25565",
+        path.display()
+    ));
+    for _ in 0..3 {
+        assert_eq!(resolver.resolve("alpha").await?, "127.0.0.1:25565".parse()?);
+    }
+    assert_eq!(runs(&path).await, 1);
+
+    // names are cached on their own
+    resolver.resolve("beta").await?;
+    assert_eq!(runs(&path).await, 2);
+    tokio::fs::remove_file(&path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_lookups_are_cached() -> Result<()> {
+    let path = runs_file();
+    let resolver = Resolver::Command(format!("echo run >> {}; exit 1", path.display()));
+    for _ in 0..3 {
+        assert!(resolver.resolve("missing").await.is_err());
+    }
+    assert_eq!(runs(&path).await, 1);
+    tokio::fs::remove_file(&path).await?;
+    Ok(())
+}
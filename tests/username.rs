@@ -0,0 +1,102 @@
+//! Tests for the username policies of routes, and the offline uuids given to usernames.
+
+use magma::username::{self, UsernamePolicy};
+use regex::Regex;
+use uuid::Uuid;
+
+/// A policy denying usernames which match any of the given patterns.
+fn policy(vanilla: bool, deny: &[&str]) -> UsernamePolicy {
+    UsernamePolicy {
+        vanilla,
+        deny: deny
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect(),
+        message: "That username isn't allowed here.".to_string(),
+    }
+}
+
+#[test]
+fn vanilla_usernames_are_recognized() {
+    for name in [
+        "Notch",
+        "jeb_",
+        "abc",
+        "___",
+        "0123456789abcdef",
+        "Player_42",
+    ] {
+        assert!(username::is_vanilla(name), "{name:?}");
+    }
+    for name in [
+        "",
+        "ab",
+        "0123456789abcdefg",
+        "with space",
+        "dash-name",
+        "dot.name",
+    ] {
+        assert!(!username::is_vanilla(name), "{name:?}");
+    }
+}
+
+#[test]
+fn non_ascii_usernames_are_not_vanilla() {
+    // letters outside ASCII are refused whatever their length, in characters or in bytes
+    for name in [
+        "Ñotch",
+        "Stève",
+        "ÄÄÄÄÄÄÄÄ",
+        "Nötch_Notch_Not",
+        "玩家玩家",
+        "Notch\u{200b}",
+    ] {
+        assert!(!username::is_vanilla(name), "{name:?}");
+    }
+}
+
+#[test]
+fn denied_usernames_are_rejected() {
+    let policy = policy(true, &["(?i)^admin", "(?i)^mod_", "staff$"]);
+    for name in [
+        "admin",
+        "Admin_Bob",
+        "ADMINISTRATOR",
+        "mod_Steve",
+        "Helpfulstaff",
+    ] {
+        assert!(!policy.allows(name), "{name:?}");
+    }
+    for name in ["Bob_admin", "moderator", "Staffan", "Notch"] {
+        assert!(policy.allows(name), "{name:?}");
+    }
+    // vanilla rules still apply to names no pattern matches
+    assert!(!policy.allows("no spaces"));
+}
+
+#[test]
+fn non_vanilla_usernames_can_be_allowed() {
+    let policy = policy(false, &["(?i)^admin"]);
+    for name in ["a", "with space", "Ñotch", "0123456789abcdefg"] {
+        assert!(policy.allows(name), "{name:?}");
+    }
+    assert!(!policy.allows("admin with space"));
+
+    // and without a pattern, everything is
+    assert!(self::policy(false, &[]).allows(""));
+}
+
+#[test]
+fn offline_uuids_match_vanilla() {
+    // the uuid an offline vanilla server gives Notch
+    assert_eq!(
+        username::offline_uuid("Notch"),
+        Uuid::parse_str("b50ad385-829d-3141-a216-7e7d7539ba7f").unwrap()
+    );
+    assert_eq!(username::offline_uuid("Notch").get_version_num(), 3);
+    // the username is hashed as sent, so case matters
+    assert_ne!(
+        username::offline_uuid("Notch"),
+        username::offline_uuid("notch")
+    );
+}